@@ -0,0 +1,12 @@
+ALTER TABLE user ADD COLUMN peer_key TEXT;
+
+ALTER TABLE friends ADD COLUMN peer_key TEXT;
+
+CREATE TABLE IF NOT EXISTS blocked (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    kind TEXT NOT NULL
+    CHECK (kind IN ('username', 'peer_key', 'address')),
+    value TEXT NOT NULL,
+    added_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (kind, value)
+);
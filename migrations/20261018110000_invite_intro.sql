@@ -0,0 +1 @@
+ALTER TABLE friends ADD COLUMN intro TEXT;
@@ -0,0 +1,9 @@
+ALTER TABLE friends ADD COLUMN fingerprint TEXT;
+
+ALTER TABLE friends ADD COLUMN invite_token TEXT;
+
+CREATE TABLE IF NOT EXISTS invite_tokens (
+    token TEXT PRIMARY KEY,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    used_at DATETIME
+);
@@ -0,0 +1 @@
+ALTER TABLE friends ADD COLUMN relay_address TEXT;
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS peer_health (
+    username TEXT PRIMARY KEY REFERENCES friends(username) ON DELETE CASCADE,
+    consecutive_failures INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT,
+    last_success_at DATETIME,
+    last_failure_at DATETIME,
+    next_attempt_at DATETIME
+);
@@ -0,0 +1,32 @@
+-- SQLite can't alter a CHECK constraint, so friends is rebuilt to allow
+-- status 4 (expired). peer_health rows would cascade away with the old
+-- table and are carried over by hand.
+
+CREATE TEMPORARY TABLE peer_health_backup AS SELECT * FROM peer_health;
+
+CREATE TABLE friends_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    username TEXT NOT NULL UNIQUE,
+    address TEXT NOT NULL,
+    added_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    status INTEGER NOT NULL DEFAULT 0 -- Default to 0 for 'invite_sent'
+    CHECK (status IN (0, 1, 2, 3, 4)), -- 0: invite_sent, 1: invite_received, 2: accepted, 3: rejected, 4: expired
+    sent BOOLEAN DEFAULT 0,
+    peer_key TEXT,
+    intro TEXT,
+    fingerprint TEXT,
+    invite_token TEXT,
+    relay_address TEXT
+);
+
+INSERT INTO friends_new (id, username, address, added_at, status, sent, peer_key, intro, fingerprint, invite_token, relay_address)
+SELECT id, username, address, added_at, status, sent, peer_key, intro, fingerprint, invite_token, relay_address
+FROM friends;
+
+DROP TABLE friends;
+
+ALTER TABLE friends_new RENAME TO friends;
+
+INSERT OR REPLACE INTO peer_health SELECT * FROM peer_health_backup;
+
+DROP TABLE peer_health_backup;
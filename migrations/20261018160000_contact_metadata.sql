@@ -0,0 +1,9 @@
+ALTER TABLE friends ADD COLUMN nickname TEXT;
+
+ALTER TABLE friends ADD COLUMN notes TEXT;
+
+CREATE TABLE IF NOT EXISTS friend_labels (
+    friend_id INTEGER NOT NULL REFERENCES friends(id) ON DELETE CASCADE,
+    label TEXT NOT NULL,
+    PRIMARY KEY (friend_id, label)
+);
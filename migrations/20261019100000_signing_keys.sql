@@ -0,0 +1,4 @@
+-- The peer key becomes the public half of an ed25519 keypair, the secret
+-- half lives next to it. Keys from before this have no secret and are
+-- replaced on the next start, invite links made with them stop matching.
+ALTER TABLE user ADD COLUMN signing_key TEXT;
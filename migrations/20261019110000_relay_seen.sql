@@ -0,0 +1,6 @@
+-- Digests of sealed relay mail we took in, so a relay handing the same mail
+-- out again under a new id doesn't deliver it twice
+CREATE TABLE IF NOT EXISTS relay_seen (
+    digest TEXT PRIMARY KEY,
+    seen_at DATETIME DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,4 @@
+-- Mailboxes are keyed by the recipient's peer key now and payloads are
+-- sealed. Mail left under a username in plaintext can't be collected
+-- anymore, so drop it.
+DELETE FROM mailbox;
@@ -0,0 +1,5 @@
+-- IP that deposited each envelope, for the per-origin quota. Mail from
+-- before this shares the empty origin.
+ALTER TABLE mailbox ADD COLUMN origin TEXT NOT NULL DEFAULT '';
+
+CREATE INDEX IF NOT EXISTS mailbox_deposited_at ON mailbox (deposited_at);
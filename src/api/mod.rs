@@ -1,47 +1,184 @@
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Instant;
 
-use crate::db::fetch_messages_for_user;
+use crate::db::{IncomingInvite, InviteOutcome, Peer};
+use crate::error::{Error, Result};
+use crate::events::{Event, EventBus};
+use crate::identity::{self, Identity};
+use crate::invite::fingerprint;
+use crate::metrics;
+use crate::store::Store;
 use axum::{
-    Extension, Router, extract::Json, http::StatusCode, response::IntoResponse, routing::get,
+    Extension, Router,
+    extract::{ConnectInfo, Json, MatchedPath, Request},
+    http::StatusCode,
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::get,
     routing::post,
 };
 use serde::{Deserialize, Serialize};
-use sqlx::SqlitePool;
-use tracing::error;
+use tracing::{Instrument, debug, error, info_span};
+use utoipa::{OpenApi, ToSchema};
 
 #[cfg(test)]
 mod tests;
 
-#[derive(Serialize, Deserialize, Debug)]
+// Longest introduction note we accept with an invite, in characters
+pub const MAX_INTRO_LEN: usize = 280;
+
+// Protocol versions we serve under /v<N>/, newest last. Version 0 is the
+// unprefixed routes nodes used before /info existed, still served for them.
+pub const PROTOCOL_VERSIONS: &[u32] = &[1];
+pub const LEGACY_VERSION: u32 = 0;
+// Optional protocol features a peer may rely on once it has seen them in /info
+pub const CAPABILITIES: &[&str] = &[
+    "intro_notes",
+    "invite_tokens",
+    "peer_keys",
+    "signed_requests",
+];
+// How far a signed request's timestamp may be from our clock, in seconds
+pub const SIGNATURE_MAX_AGE: i64 = 600;
+
+// Path of `route` (e.g. "fetch_messages") in protocol `version`
+pub fn versioned_path(version: u32, route: &str) -> String {
+    if version == LEGACY_VERSION {
+        format!("/{}", route)
+    } else {
+        format!("/v{}/{}", version, route)
+    }
+}
+
+// Newest version both sides speak, None if there is none
+pub fn highest_common_version(ours: &[u32], theirs: &[u32]) -> Option<u32> {
+    ours.iter().filter(|v| theirs.contains(v)).max().copied()
+}
+
+// What GET /info answers, unversioned so any peer can ask before choosing
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct NodeInfo {
+    pub protocol_versions: Vec<u32>,
+    pub username: String,
+    pub address: String,
+    // fingerprint of our peer key, what invite links pin
+    pub fingerprint: Option<String>,
+    pub capabilities: Vec<String>,
+    pub software: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
 pub struct FetchMessageInput {
     pub username: String,
     pub address: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
 pub struct Message {
     pub sender: String,
     pub subject: String,
     pub body: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
 pub struct FetchMessageResponse {
     pub messages: Vec<Message>,
+    // seconds the peer wants us to wait before polling it again
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
 }
-#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, serde::Serialize, serde::Deserialize)]
+#[derive(
+    Debug, PartialEq, Eq, Hash, Clone, Copy, serde::Serialize, serde::Deserialize, ToSchema,
+)]
 pub enum FriendRequestStatus {
     InviteSent,
     InviteReceived,
     Accepted,
     Rejected,
+    Expired,
+}
+impl FriendRequestStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FriendRequestStatus::InviteSent => "invite_sent",
+            FriendRequestStatus::InviteReceived => "invite_received",
+            FriendRequestStatus::Accepted => "accepted",
+            FriendRequestStatus::Rejected => "rejected",
+            FriendRequestStatus::Expired => "expired",
+        }
+    }
 }
-#[derive(Serialize, Deserialize, Debug)]
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
 pub struct FriendInput {
+    // who the request is for
     pub username: String,
+    // who sends it, and where they can be reached
     pub hostname: String,
     pub address: String,
     pub req_type: FriendRequestStatus,
+    #[serde(default)]
+    pub peer_key: Option<String>,
+    #[serde(default)]
+    pub intro: Option<String>,
+    #[serde(default)]
+    pub invite_token: Option<String>,
+    // unix time of the signature, which covers every field above and is
+    // made with peer_key. Requests carrying a key must be signed with it
+    #[serde(default)]
+    pub signed_at: Option<i64>,
+    #[serde(default)]
+    pub signature: Option<String>,
+}
+
+impl FriendInput {
+    // JSON keeps the fields apart whatever they contain
+    fn signed_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(&(
+            "mankeli-friend-request",
+            &self.username,
+            &self.hostname,
+            &self.address,
+            self.req_type.as_str(),
+            &self.peer_key,
+            &self.intro,
+            &self.invite_token,
+            self.signed_at,
+        ))
+        .expect("tuples of strings serialize")
+    }
+
+    // Puts our peer key on the request and signs it as of `now`
+    pub fn sign(&mut self, identity: &Identity, now: i64) {
+        self.peer_key = Some(identity.peer_key());
+        self.signed_at = Some(now);
+        self.signature = Some(identity.sign(&self.signed_bytes()));
+    }
+
+    // Whether the request was recently signed with the key it carries
+    pub fn is_signed(&self, now: i64) -> bool {
+        let (Some(peer_key), Some(signed_at), Some(signature)) =
+            (&self.peer_key, self.signed_at, &self.signature)
+        else {
+            return false;
+        };
+        (now - signed_at).abs() <= SIGNATURE_MAX_AGE
+            && identity::verify(peer_key, &self.signed_bytes(), signature)
+    }
+}
+
+// Body of every successful /friend_request answer
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct FriendRequestAck {
+    // the request type in snake_case, e.g. "invite_sent"
+    pub status: String,
+}
+
+// Body of every error answer
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct ErrorBody {
+    pub error: String,
 }
 
 #[derive(Debug, Serialize)]
@@ -51,6 +188,20 @@ pub enum ApiError {
     InternalServerError(String),
 }
 
+impl From<Error> for ApiError {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Validation(msg) => ApiError::InvalidInput(msg),
+            Error::NotFound(msg) => ApiError::NotFound(msg),
+            // the details stay in our log, peers only learn that it failed
+            e => {
+                error!("Request failed: {}", e);
+                ApiError::InternalServerError("Internal error".into())
+            }
+        }
+    }
+}
+
 impl IntoResponse for ApiError {
     fn into_response(self) -> axum::response::Response {
         let (status, error_message) = match self {
@@ -60,68 +211,140 @@ impl IntoResponse for ApiError {
         };
         (
             status,
-            Json(serde_json::json!({
-                "error": error_message,
-            })),
+            Json(ErrorBody {
+                error: error_message,
+            }),
         )
             .into_response()
     }
 }
 
-pub fn app(pool: SqlitePool) -> Router {
-    Router::new()
+pub fn app<S: Store>(store: S, events: EventBus) -> Router {
+    let mut router = Router::new()
         .route(
             "/",
             get(|| async { "Hello, this is a mankeli-chat server" }),
         )
-        .route("/fetch_messages", post(fetch_messages_handler))
-        .route("/friend_request", post(friend_request_handler))
-        .layer(Extension(Arc::new(pool)))
-}
-
-pub async fn mark_messages_as_sent(
-    pool: &SqlitePool,
-    message_ids: &[i64],
-) -> Result<(), sqlx::Error> {
-    if message_ids.is_empty() {
-        return Ok(());
+        .route("/info", get(info_handler::<S>))
+        .route("/openapi.json", get(|| async { Json(ApiDoc::openapi()) }));
+    // every version shares the handlers while the payloads are unchanged
+    for &version in [LEGACY_VERSION].iter().chain(PROTOCOL_VERSIONS) {
+        router = router
+            .route(
+                &versioned_path(version, "fetch_messages"),
+                post(fetch_messages_handler::<S>),
+            )
+            .route(
+                &versioned_path(version, "friend_request"),
+                post(friend_request_handler::<S>),
+            );
     }
+    router
+        .layer(Extension(Arc::new(store)))
+        .layer(Extension(events))
+        .layer(middleware::from_fn(trace_request))
+}
 
-    let placeholders = std::iter::repeat("?")
-        .take(message_ids.len())
-        .collect::<Vec<_>>()
-        .join(",");
-
-    let sql = format!(
-        "UPDATE outgoing SET sent = 1 WHERE id IN ({})",
-        placeholders
+// Wraps every request in a span, logs how it went and records its latency
+async fn trace_request(request: Request, next: Next) -> Response {
+    let method = request.method().to_string();
+    // the route pattern, not the raw path, keeps the label set small
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched", |path| path.as_str())
+        .to_string();
+    let span = info_span!(
+        "api_request",
+        method = %method,
+        path = %request.uri().path(),
     );
-
-    let mut query = sqlx::query(&sql);
-    for id in message_ids {
-        query = query.bind(id);
+    async move {
+        let started = Instant::now();
+        let response = next.run(request).await;
+        let status = response.status().as_u16();
+        let elapsed = started.elapsed();
+        debug!(
+            status,
+            elapsed_ms = elapsed.as_millis() as u64,
+            "Request handled"
+        );
+        metrics::http_request(&method, &route, status, elapsed);
+        response
     }
+    .instrument(span)
+    .await
+}
 
-    query.execute(pool).await?;
+#[utoipa::path(
+    get,
+    path = "/info",
+    responses((status = 200, description = "Who this node is and what it speaks", body = NodeInfo)),
+)]
+pub async fn info_handler<S: Store>(
+    Extension(store): Extension<Arc<S>>,
+) -> Result<Json<NodeInfo>, ApiError> {
+    let user = store.local_user().await?;
+    Ok(Json(NodeInfo {
+        protocol_versions: PROTOCOL_VERSIONS.to_vec(),
+        username: user.username,
+        address: user.address,
+        fingerprint: user.peer_key.as_deref().map(fingerprint),
+        capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        software: format!("mankeli-chat/{}", env!("CARGO_PKG_VERSION")),
+    }))
+}
 
-    Ok(())
+async fn peer_blocked<S: Store>(store: &S, peer: &Peer<'_>) -> Result<bool, ApiError> {
+    store.is_blocked(peer).await.map_err(|e| {
+        error!("Block list check failed: {:?}", e);
+        ApiError::InternalServerError("DB check failed".into())
+    })
 }
 
-pub async fn fetch_messages_handler(
-    Extension(pool): Extension<Arc<SqlitePool>>,
+#[utoipa::path(
+    post,
+    path = "/v1/fetch_messages",
+    request_body = FetchMessageInput,
+    responses(
+        (status = 200, description = "Messages queued for the caller, marked sent once returned. Blocked peers get an empty list", body = FetchMessageResponse),
+        (status = 500, body = ErrorBody),
+    ),
+)]
+pub async fn fetch_messages_handler<S: Store>(
+    Extension(store): Extension<Arc<S>>,
+    Extension(events): Extension<EventBus>,
+    connect_info: Option<Extension<ConnectInfo<SocketAddr>>>,
     Json(input): Json<FetchMessageInput>,
 ) -> Result<Json<FetchMessageResponse>, ApiError> {
-    let username = input.username;
+    let peer = Peer {
+        username: &input.username,
+        peer_key: None,
+        address: Some(&input.address),
+        ip: connect_info.map(|Extension(ConnectInfo(addr))| addr.ip()),
+    };
 
-    let db_messages = fetch_messages_for_user(&pool, username)
-        .await
-        .map_err(|e| ApiError::InternalServerError(e.to_string()))?;
+    // Blocked peers just see an empty mailbox
+    if peer_blocked(&*store, &peer).await? {
+        return Ok(Json(FetchMessageResponse {
+            messages: vec![],
+            retry_after: None,
+        }));
+    }
+
+    let db_messages = store.pending_messages_for(&input.username).await?;
 
     let message_ids: Vec<i64> = db_messages.iter().map(|msg| msg.id).collect();
 
-    mark_messages_as_sent(&pool, &message_ids)
-        .await
-        .map_err(|e| ApiError::InternalServerError(e.to_string()))?;
+    store.mark_messages_sent(&message_ids).await?;
+    if !message_ids.is_empty() {
+        metrics::messages_fetched(message_ids.len(), false);
+        events.publish(Event::DeliveryConfirmed {
+            recipient: input.username.clone(),
+            count: message_ids.len(),
+            relay: false,
+        });
+    }
 
     let messages: Vec<Message> = db_messages
         .into_iter()
@@ -132,67 +355,157 @@ pub async fn fetch_messages_handler(
         })
         .collect();
 
-    let response = FetchMessageResponse { messages };
+    let response = FetchMessageResponse {
+        messages,
+        retry_after: None,
+    };
 
     Ok(Json(response))
 }
 
-pub async fn friend_request_handler(
-    Extension(pool): Extension<Arc<SqlitePool>>,
+fn friend_request_ok(req_type: FriendRequestStatus) -> axum::response::Response {
+    (
+        StatusCode::OK,
+        Json(FriendRequestAck {
+            status: req_type.as_str().to_string(),
+        }),
+    )
+        .into_response()
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/friend_request",
+    request_body = FriendInput,
+    responses(
+        (status = 200, description = "Request recorded", body = FriendRequestAck),
+        (status = 400, description = "Invalid request type, overlong intro, no pending invite, a bad signature or a key not matching the pinned fingerprint", body = ErrorBody),
+        (status = 404, description = "Acceptance for an invite we never sent", body = ErrorBody),
+        (status = 500, body = ErrorBody),
+    ),
+)]
+pub async fn friend_request_handler<S: Store>(
+    Extension(store): Extension<Arc<S>>,
+    Extension(events): Extension<EventBus>,
+    connect_info: Option<Extension<ConnectInfo<SocketAddr>>>,
     Json(input): Json<FriendInput>,
 ) -> impl IntoResponse {
+    // a key is only taken from whoever holds its secret half, unsigned
+    // requests from older nodes carry none
+    if input.peer_key.is_some() && !input.is_signed(chrono::Utc::now().timestamp()) {
+        return ApiError::InvalidInput(
+            "Friend request signature is missing, invalid or too old.".into(),
+        )
+        .into_response();
+    }
+
     let FriendInput {
-        username,
+        username: _,
         hostname,
         address,
         req_type,
+        peer_key,
+        intro,
+        invite_token,
+        ..
     } = input;
 
+    let peer = Peer {
+        username: &hostname,
+        peer_key: peer_key.as_deref(),
+        address: Some(&address),
+        ip: connect_info.map(|Extension(ConnectInfo(addr))| addr.ip()),
+    };
+
+    // Blocked peers get the usual answer but nothing is recorded
+    match peer_blocked(&*store, &peer).await {
+        Ok(true) => return friend_request_ok(req_type),
+        Ok(false) => {}
+        Err(e) => return e.into_response(),
+    }
+    // only requests that got past the signature and block checks count
+    metrics::friend_request("received", req_type);
+
     match req_type {
         FriendRequestStatus::InviteSent => {
-            // Insert invite_sent from A to B
-            let res = sqlx::query!(
-                r#"
-                INSERT INTO friends (username, address, status, sent)
-                VALUES (?, ?, 1, 1)
-                ON CONFLICT(username) DO UPDATE SET
-                    status = 1,
-                    added_at = CURRENT_TIMESTAMP
-                "#,
-                hostname,
-                address,
-            )
-            .execute(&*pool)
-            .await;
-
-            match res {
-                Ok(_) => (
-                    StatusCode::OK,
-                    Json(serde_json::json!({ "status": "invite_sent" })),
-                )
-                    .into_response(),
+            if intro
+                .as_deref()
+                .is_some_and(|note| note.chars().count() > MAX_INTRO_LEN)
+            {
+                return ApiError::InvalidInput(format!(
+                    "Introduction note is longer than {} characters.",
+                    MAX_INTRO_LEN
+                ))
+                .into_response();
+            }
+
+            // We invited them too and the invites crossed, take theirs as the
+            // answer to ours
+            match store.fetch_invite_state(&hostname, &address).await {
+                Ok(Some((0, pinned))) => {
+                    if !pin_matches(pinned.as_deref(), peer_key.as_deref()) {
+                        return pin_mismatch();
+                    }
+                    return match store
+                        .accept_invite(&hostname, &address, peer_key.as_deref())
+                        .await
+                    {
+                        Ok(_) => {
+                            events.publish(Event::InviteAccepted {
+                                username: hostname.clone(),
+                            });
+                            friend_request_ok(req_type)
+                        }
+                        Err(e) => {
+                            error!("Failed to accept friend: {:?}", e);
+                            ApiError::InternalServerError("Failed to accept friend".into())
+                                .into_response()
+                        }
+                    };
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    error!("DB check failed: {:?}", e);
+                    return ApiError::InternalServerError("DB check failed".into()).into_response();
+                }
+            }
+
+            // A valid one-time token from our invite link accepts right away
+            let invite = IncomingInvite {
+                username: &hostname,
+                address: &address,
+                peer_key: peer_key.as_deref(),
+                intro: intro.as_deref(),
+                invite_token: invite_token.as_deref(),
+            };
+            match store.receive_invite(&invite).await {
+                // we already know them, the answer doesn't tell the sender so
+                Ok(InviteOutcome::Ignored) => friend_request_ok(req_type),
+                Ok(InviteOutcome::Accepted) => {
+                    events.publish(Event::InviteAccepted {
+                        username: hostname.clone(),
+                    });
+                    friend_request_ok(req_type)
+                }
+                Ok(InviteOutcome::Received) => {
+                    events.publish(Event::InviteReceived {
+                        username: hostname.clone(),
+                        address: address.clone(),
+                        intro: intro.clone(),
+                    });
+                    friend_request_ok(req_type)
+                }
                 Err(e) => {
                     error!("Failed to send invite: {:?}", e);
                     ApiError::InternalServerError("DB insert failed".into()).into_response()
                 }
             }
         }
-        FriendRequestStatus::InviteReceived => {
-            return ApiError::InvalidInput("why would you request this".to_string())
-                .into_response();
+        FriendRequestStatus::InviteReceived | FriendRequestStatus::Expired => {
+            ApiError::InvalidInput("why would you request this".to_string()).into_response()
         }
         FriendRequestStatus::Accepted => {
-            let existing: Option<i64> = match sqlx::query_scalar!(
-                r#"
-                SELECT status as "status: i64" FROM friends
-                WHERE username = ? AND address = ?
-                "#,
-                hostname,
-                address
-            )
-            .fetch_optional(&*pool)
-            .await
-            {
+            let existing = match store.fetch_invite_state(&hostname, &address).await {
                 Ok(val) => val,
                 Err(e) => {
                     error!("DB check failed: {:?}", e);
@@ -200,28 +513,22 @@ pub async fn friend_request_handler(
                 }
             };
 
-            if let Some(s) = existing {
-                if s == 0 {
-                    let res = sqlx::query!(
-                        r#"
-                        INSERT INTO friends (username, address, status, sent)
-                        VALUES (?, ?, 2, 1)
-                        ON CONFLICT(username) DO UPDATE SET
-                            status = 2,
-                            added_at = CURRENT_TIMESTAMP
-                        "#,
-                        hostname,
-                        address
-                    )
-                    .execute(&*pool)
-                    .await;
-
-                    match res {
-                        Ok(_) => (
-                            StatusCode::OK,
-                            Json(serde_json::json!({ "status": "accepted" })),
-                        )
-                            .into_response(),
+            if let Some((status, pinned)) = existing {
+                if !pin_matches(pinned.as_deref(), peer_key.as_deref()) {
+                    return pin_mismatch();
+                }
+
+                if status == 0 {
+                    match store
+                        .accept_invite(&hostname, &address, peer_key.as_deref())
+                        .await
+                    {
+                        Ok(_) => {
+                            events.publish(Event::InviteAccepted {
+                                username: hostname.clone(),
+                            });
+                            friend_request_ok(req_type)
+                        }
                         Err(e) => {
                             error!("Failed to accept friend: {:?}", e);
                             ApiError::InternalServerError("Failed to accept friend".into())
@@ -237,30 +544,20 @@ pub async fn friend_request_handler(
             }
         }
         FriendRequestStatus::Rejected => {
-            let updated = sqlx::query!(
-                r#"
-                UPDATE friends SET status = 3, sent=1 ,added_at = CURRENT_TIMESTAMP
-                WHERE username = ? AND address = ? AND status = 0
-                "#,
-                hostname,
-                address
-            )
-            .execute(&*pool)
-            .await;
-
-            match updated {
-                Ok(result) => {
-                    if result.rows_affected() == 0 {
-                        ApiError::InvalidInput("No pending invitation to reject.".into())
-                            .into_response()
-                    } else {
-                        (
-                            StatusCode::OK,
-                            Json(serde_json::json!({ "status": "rejected" })),
-                        )
-                            .into_response()
-                    }
+            match store.fetch_invite_state(&hostname, &address).await {
+                Ok(Some((_, pinned))) if !pin_matches(pinned.as_deref(), peer_key.as_deref()) => {
+                    return pin_mismatch();
                 }
+                Ok(_) => {}
+                Err(e) => {
+                    error!("DB check failed: {:?}", e);
+                    return ApiError::InternalServerError("DB check failed".into()).into_response();
+                }
+            }
+            match store.reject_invite(&hostname, &address).await {
+                Ok(false) => ApiError::InvalidInput("No pending invitation to reject.".into())
+                    .into_response(),
+                Ok(true) => friend_request_ok(req_type),
                 Err(e) => {
                     error!("Failed to reject friend: {:?}", e);
                     ApiError::InternalServerError("Failed to reject friend.".into()).into_response()
@@ -269,3 +566,35 @@ pub async fn friend_request_handler(
         }
     }
 }
+
+// Invites made from a link must be answered by the node it pinned
+fn pin_matches(pinned: Option<&str>, peer_key: Option<&str>) -> bool {
+    pinned.is_none_or(|pinned| peer_key.map(fingerprint).as_deref() == Some(pinned))
+}
+
+fn pin_mismatch() -> axum::response::Response {
+    ApiError::InvalidInput("Peer key does not match the invite fingerprint.".into()).into_response()
+}
+
+// The peer protocol as an OpenAPI document, served at /openapi.json. Paths
+// are listed under the newest version prefix, older prefixes and the
+// unprefixed legacy routes take the same payloads.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "mankeli-chat peer protocol",
+        description = "What mankeli-chat nodes serve to each other. Ask /info first and use the newest version both sides list."
+    ),
+    paths(info_handler, fetch_messages_handler, friend_request_handler),
+    components(schemas(
+        NodeInfo,
+        FetchMessageInput,
+        FetchMessageResponse,
+        Message,
+        FriendInput,
+        FriendRequestStatus,
+        FriendRequestAck,
+        ErrorBody
+    ))
+)]
+pub struct ApiDoc;
@@ -1,5 +1,7 @@
 use super::*;
 use crate::db::{MIGRATOR, OutgoingMessage, User, send_message_to_que, setup_db};
+use crate::error::Result;
+use crate::events::{Event, EventBus};
 use axum::{
     Router,
     body::{Body, to_bytes},
@@ -8,17 +10,20 @@ use axum::{
 };
 use serde_json::json;
 use sqlx::SqlitePool;
-use std::usize;
 use tokio;
 use tower::ServiceExt;
 
+fn now() -> i64 {
+    chrono::Utc::now().timestamp()
+}
+
 async fn setup_test_db() -> SqlitePool {
     let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
     MIGRATOR.run(&pool).await.unwrap();
     pool
 }
 
-async fn send_test_messages(pool: &sqlx::Pool<sqlx::Sqlite>) -> Result<(), sqlx::Error> {
+async fn send_test_messages(pool: &sqlx::Pool<sqlx::Sqlite>) -> Result<()> {
     sqlx::query("INSERT INTO friends (username, address) VALUES ('user3', '3.3.3.3')")
         .execute(pool)
         .await?;
@@ -29,7 +34,7 @@ async fn send_test_messages(pool: &sqlx::Pool<sqlx::Sqlite>) -> Result<(), sqlx:
         content: "Hello world!".to_string(),
     };
 
-    let _result = send_message_to_que(&pool, &message).await?;
+    send_message_to_que(pool, &message).await?;
 
     Ok(())
 }
@@ -41,6 +46,7 @@ async fn test_fetch_messages_success() {
         id: 0,
         username: "testuser".to_string(),
         address: "127.0.0.1".to_string(),
+        peer_key: None,
     };
     setup_db(&pool, &chat_user)
         .await
@@ -49,7 +55,7 @@ async fn test_fetch_messages_success() {
         .await
         .expect("Failed to send test messages");
 
-    let app = app(pool);
+    let app = app(pool, EventBus::new());
     let input = FetchMessageInput {
         username: "user3".to_string(),
         address: "1.1.1.1".to_string(),
@@ -84,18 +90,31 @@ async fn test_fetch_messages_success() {
 async fn test_friend_invite_sent_and_accepted_successfully() {
     let pool = setup_test_db().await;
     let shared_pool = Arc::new(pool.clone());
+    let events = EventBus::new();
+    let mut rx = events.subscribe();
 
     let app = Router::new()
-        .route("/friend_request", post(super::friend_request_handler))
-        .layer(Extension(shared_pool));
+        .route(
+            "/friend_request",
+            post(super::friend_request_handler::<SqlitePool>),
+        )
+        .layer(Extension(shared_pool))
+        .layer(Extension(events));
     // send invite
-    let invite_input = FriendInput {
+    let bob = Identity::generate();
+    let mut invite_input = FriendInput {
         username: "alice".into(),
         hostname: "bob".into(),
         address: "1.1.1.1".into(),
         req_type: FriendRequestStatus::InviteSent,
+        peer_key: None,
+        intro: Some("Hi, it's Bob from the office".into()),
+        invite_token: None,
+        signed_at: None,
+        signature: None,
     };
 
+    invite_input.sign(&bob, now());
     let invite_body = serde_json::to_string(&invite_input).unwrap();
 
     let invite_response = app
@@ -120,18 +139,36 @@ async fn test_friend_invite_sent_and_accepted_successfully() {
 
     assert_eq!(invite_json["status"], "invite_sent");
 
-    let row: (i64,) = sqlx::query_as("SELECT status FROM friends WHERE username = 'alice'")
-        .fetch_one(&pool)
+    // Incoming invites are stored under the sender's name
+    let row: (i64, Option<String>, Option<String>) =
+        sqlx::query_as("SELECT status, peer_key, intro FROM friends WHERE username = 'bob'")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert_eq!(row.0, 1);
+    assert_eq!(row.1, Some(bob.peer_key()));
+    assert_eq!(row.2.as_deref(), Some("Hi, it's Bob from the office"));
+    assert!(matches!(
+        rx.try_recv().unwrap(),
+        Event::InviteReceived { username, .. } if username == "bob"
+    ));
+
+    // Step 2: carol accepts the invite we sent her
+    sqlx::query("INSERT INTO friends (username, address, status) VALUES ('carol', '2.2.2.2', 0)")
+        .execute(&pool)
         .await
         .unwrap();
-    assert_eq!(row.0, 1);
 
-    // Step 2: Accept invite
     let accept_input = FriendInput {
         username: "alice".into(),
-        hostname: "bob".into(),
-        address: "1.1.1.1".into(),
+        hostname: "carol".into(),
+        address: "2.2.2.2".into(),
         req_type: FriendRequestStatus::Accepted,
+        peer_key: None,
+        intro: None,
+        invite_token: None,
+        signed_at: None,
+        signature: None,
     };
 
     let accept_body = serde_json::to_string(&accept_input).unwrap();
@@ -156,10 +193,417 @@ async fn test_friend_invite_sent_and_accepted_successfully() {
     let accept_json: serde_json::Value = serde_json::from_slice(&accept_body_bytes).unwrap();
 
     assert_eq!(accept_json["status"], "accepted");
+    assert_eq!(
+        rx.try_recv().unwrap(),
+        Event::InviteAccepted {
+            username: "carol".into()
+        }
+    );
 
-    let updated_row: (i64,) = sqlx::query_as("SELECT status FROM friends WHERE username = 'alice'")
+    let updated_row: (i64,) = sqlx::query_as("SELECT status FROM friends WHERE username = 'carol'")
         .fetch_one(&pool)
         .await
         .unwrap();
     assert_eq!(updated_row.0, 2); // 2 means Accepted
 }
+
+#[tokio::test]
+async fn test_blocked_invite_is_silently_dropped() {
+    let pool = setup_test_db().await;
+    add_block_for_test(&pool, "10.0.0.0/8").await;
+
+    let app = app(pool.clone(), EventBus::new());
+    let invite_input = FriendInput {
+        username: "alice".into(),
+        hostname: "mallory".into(),
+        address: "10.1.2.3:8080".into(),
+        req_type: FriendRequestStatus::InviteSent,
+        peer_key: None,
+        intro: None,
+        invite_token: None,
+        signed_at: None,
+        signature: None,
+    };
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/friend_request")
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::to_string(&invite_input).unwrap()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let count: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM friends")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(count.0, 0);
+}
+
+async fn add_block_for_test(pool: &SqlitePool, cidr: &str) {
+    crate::db::add_block(pool, crate::db::BlockKind::Address, cidr)
+        .await
+        .expect("Failed to add block");
+}
+
+#[tokio::test]
+async fn test_invite_with_overlong_intro_is_rejected() {
+    let pool = setup_test_db().await;
+
+    let app = app(pool.clone(), EventBus::new());
+    let invite_input = FriendInput {
+        username: "alice".into(),
+        hostname: "bob".into(),
+        address: "1.1.1.1".into(),
+        req_type: FriendRequestStatus::InviteSent,
+        peer_key: None,
+        intro: Some("a".repeat(MAX_INTRO_LEN + 1)),
+        invite_token: None,
+        signed_at: None,
+        signature: None,
+    };
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/friend_request")
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::to_string(&invite_input).unwrap()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let count: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM friends")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(count.0, 0);
+}
+
+async fn post_friend_request(app: Router, input: &FriendInput) -> StatusCode {
+    app.oneshot(
+        Request::builder()
+            .method("POST")
+            .uri("/friend_request")
+            .header("Content-Type", "application/json")
+            .body(Body::from(serde_json::to_string(input).unwrap()))
+            .unwrap(),
+    )
+    .await
+    .unwrap()
+    .status()
+}
+
+#[tokio::test]
+async fn test_invite_with_one_time_token_is_auto_accepted() {
+    let pool = setup_test_db().await;
+    let token = crate::db::create_invite_token(&pool).await.unwrap();
+
+    let mut invite_input = FriendInput {
+        username: "alice".into(),
+        hostname: "bob".into(),
+        address: "1.1.1.1".into(),
+        req_type: FriendRequestStatus::InviteSent,
+        peer_key: None,
+        intro: None,
+        invite_token: Some(token.clone()),
+        signed_at: None,
+        signature: None,
+    };
+    invite_input.sign(&Identity::generate(), now());
+    assert_eq!(
+        post_friend_request(app(pool.clone(), EventBus::new()), &invite_input).await,
+        StatusCode::OK
+    );
+
+    let row: (i64, bool) =
+        sqlx::query_as("SELECT status, sent FROM friends WHERE username = 'bob'")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert_eq!(row, (2, false));
+
+    // Tokens are single use, a replay is just an ordinary invite
+    let mut replay = FriendInput {
+        hostname: "carol".into(),
+        ..invite_input
+    };
+    replay.sign(&Identity::generate(), now());
+    post_friend_request(app(pool.clone(), EventBus::new()), &replay).await;
+    let row: (i64,) = sqlx::query_as("SELECT status FROM friends WHERE username = 'carol'")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(row.0, 1);
+}
+
+#[tokio::test]
+async fn test_ignored_invite_keeps_its_token() {
+    let pool = setup_test_db().await;
+    sqlx::query("INSERT INTO friends (username, address, status) VALUES ('bob', '1.1.1.1', 2)")
+        .execute(&pool)
+        .await
+        .unwrap();
+    let token = crate::db::create_invite_token(&pool).await.unwrap();
+
+    // someone posing as a friend we have doesn't burn the token
+    let mut invite_input = FriendInput {
+        username: "alice".into(),
+        hostname: "bob".into(),
+        address: "6.6.6.6".into(),
+        req_type: FriendRequestStatus::InviteSent,
+        peer_key: None,
+        intro: None,
+        invite_token: Some(token.clone()),
+        signed_at: None,
+        signature: None,
+    };
+    invite_input.sign(&Identity::generate(), now());
+    assert_eq!(
+        post_friend_request(app(pool.clone(), EventBus::new()), &invite_input).await,
+        StatusCode::OK
+    );
+    assert!(crate::db::redeem_invite_token(&pool, &token).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_accept_with_wrong_fingerprint_is_refused() {
+    let pool = setup_test_db().await;
+    let carol = Identity::generate();
+    let pinned = crate::invite::fingerprint(&carol.peer_key());
+    sqlx::query(
+        "INSERT INTO friends (username, address, status, fingerprint) VALUES ('carol', '2.2.2.2', 0, ?)",
+    )
+    .bind(&pinned)
+    .execute(&pool)
+    .await
+    .unwrap();
+
+    let mut accept_input = FriendInput {
+        username: "alice".into(),
+        hostname: "carol".into(),
+        address: "2.2.2.2".into(),
+        req_type: FriendRequestStatus::Accepted,
+        peer_key: None,
+        intro: None,
+        invite_token: None,
+        signed_at: None,
+        signature: None,
+    };
+    // an impostor signing with their own key doesn't match the pin
+    accept_input.sign(&Identity::generate(), now());
+    assert_eq!(
+        post_friend_request(app(pool.clone(), EventBus::new()), &accept_input).await,
+        StatusCode::BAD_REQUEST
+    );
+
+    // and can't claim carol's key without her signature
+    accept_input.peer_key = Some(carol.peer_key());
+    assert_eq!(
+        post_friend_request(app(pool.clone(), EventBus::new()), &accept_input).await,
+        StatusCode::BAD_REQUEST
+    );
+
+    // nor replay a signature of hers from long ago
+    accept_input.sign(&carol, now() - SIGNATURE_MAX_AGE - 60);
+    assert_eq!(
+        post_friend_request(app(pool.clone(), EventBus::new()), &accept_input).await,
+        StatusCode::BAD_REQUEST
+    );
+
+    accept_input.sign(&carol, now());
+    assert_eq!(
+        post_friend_request(app(pool.clone(), EventBus::new()), &accept_input).await,
+        StatusCode::OK
+    );
+}
+
+#[tokio::test]
+async fn test_crossed_invites_make_friends() {
+    let pool = setup_test_db().await;
+    let carol = Identity::generate();
+    let pinned = crate::invite::fingerprint(&carol.peer_key());
+    sqlx::query(
+        "INSERT INTO friends (username, address, status, fingerprint) VALUES ('carol', '2.2.2.2', 0, ?)",
+    )
+    .bind(&pinned)
+    .execute(&pool)
+    .await
+    .unwrap();
+
+    let mut invite_input = FriendInput {
+        username: "alice".into(),
+        hostname: "carol".into(),
+        address: "2.2.2.2".into(),
+        req_type: FriendRequestStatus::InviteSent,
+        peer_key: None,
+        intro: Some("hi alice".into()),
+        invite_token: None,
+        signed_at: None,
+        signature: None,
+    };
+    // the pin still holds
+    invite_input.sign(&Identity::generate(), now());
+    assert_eq!(
+        post_friend_request(app(pool.clone(), EventBus::new()), &invite_input).await,
+        StatusCode::BAD_REQUEST
+    );
+
+    // carol invited us while our invite was on its way, she's a friend now
+    invite_input.sign(&carol, now());
+    assert_eq!(
+        post_friend_request(app(pool.clone(), EventBus::new()), &invite_input).await,
+        StatusCode::OK
+    );
+    let (status, key): (i64, Option<String>) =
+        sqlx::query_as("SELECT status, peer_key FROM friends WHERE username = 'carol'")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert_eq!(status, 2);
+    assert_eq!(key, Some(carol.peer_key()));
+}
+
+#[tokio::test]
+async fn test_reject_with_wrong_fingerprint_is_refused() {
+    let pool = setup_test_db().await;
+    let carol = Identity::generate();
+    let pinned = crate::invite::fingerprint(&carol.peer_key());
+    sqlx::query(
+        "INSERT INTO friends (username, address, status, fingerprint) VALUES ('carol', '2.2.2.2', 0, ?)",
+    )
+    .bind(&pinned)
+    .execute(&pool)
+    .await
+    .unwrap();
+
+    let mut reject_input = FriendInput {
+        username: "alice".into(),
+        hostname: "carol".into(),
+        address: "2.2.2.2".into(),
+        req_type: FriendRequestStatus::Rejected,
+        peer_key: None,
+        intro: None,
+        invite_token: None,
+        signed_at: None,
+        signature: None,
+    };
+    // an impostor can't turn down the invite for her
+    reject_input.sign(&Identity::generate(), now());
+    assert_eq!(
+        post_friend_request(app(pool.clone(), EventBus::new()), &reject_input).await,
+        StatusCode::BAD_REQUEST
+    );
+    let status: i64 = sqlx::query_scalar("SELECT status FROM friends WHERE username = 'carol'")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(status, 0);
+
+    reject_input.sign(&carol, now());
+    assert_eq!(
+        post_friend_request(app(pool.clone(), EventBus::new()), &reject_input).await,
+        StatusCode::OK
+    );
+}
+
+#[tokio::test]
+async fn test_info_and_versioned_routes() {
+    let pool = setup_test_db().await;
+    let user = User {
+        id: 0,
+        username: "alice".to_string(),
+        address: "alice.test:8080".to_string(),
+        peer_key: None,
+    };
+    setup_db(&pool, &user).await.unwrap();
+    let peer_key = crate::db::ensure_identity(&pool).await.unwrap().peer_key();
+    let app = app(pool, EventBus::new());
+
+    let response = app
+        .clone()
+        .oneshot(Request::get("/info").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let info: NodeInfo = serde_json::from_slice(&body).unwrap();
+    assert_eq!(info.protocol_versions, PROTOCOL_VERSIONS);
+    assert_eq!(info.username, "alice");
+    assert_eq!(
+        info.fingerprint,
+        Some(crate::invite::fingerprint(&peer_key))
+    );
+    assert!(info.capabilities.iter().any(|c| c == "invite_tokens"));
+
+    // the same handler answers under every version, legacy included
+    for version in [LEGACY_VERSION, 1] {
+        let input = FetchMessageInput {
+            username: "bob".into(),
+            address: "bob.test:8080".into(),
+        };
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri(versioned_path(version, "fetch_messages"))
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(json!(input).to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
+
+#[tokio::test]
+async fn test_openapi_document() {
+    let response = app(setup_test_db().await, EventBus::new())
+        .oneshot(Request::get("/openapi.json").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let doc: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    for path in ["/info", "/v1/fetch_messages", "/v1/friend_request"] {
+        assert!(doc["paths"][path].is_object(), "missing {}", path);
+    }
+    for schema in [
+        "FriendInput",
+        "FriendRequestStatus",
+        "NodeInfo",
+        "ErrorBody",
+    ] {
+        assert!(
+            doc["components"]["schemas"][schema].is_object(),
+            "missing {}",
+            schema
+        );
+    }
+}
+
+#[test]
+fn test_highest_common_version() {
+    assert_eq!(highest_common_version(&[1, 2, 3], &[2, 3, 4]), Some(3));
+    assert_eq!(highest_common_version(&[1], &[2]), None);
+    assert_eq!(versioned_path(0, "friend_request"), "/friend_request");
+    assert_eq!(versioned_path(2, "friend_request"), "/v2/friend_request");
+}
+
+#[test]
+fn test_internal_errors_stay_internal() {
+    let error = ApiError::from(crate::error::Error::Storage(sqlx::Error::PoolClosed));
+    assert!(matches!(error, ApiError::InternalServerError(msg) if msg == "Internal error"));
+}
@@ -0,0 +1,69 @@
+use mankeli_chat::conformance::{check_handshake, check_node};
+use std::process::ExitCode;
+use std::time::Duration;
+
+// usage: mankeli-conformance <address> [--invite-token TOKEN --listen ADDR
+//        --callback ADDR] [--timeout SECONDS]
+#[tokio::main]
+async fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1);
+    let Some(address) = args.next() else {
+        eprintln!(
+            "usage: mankeli-conformance <address> [--invite-token TOKEN --listen ADDR --callback ADDR] [--timeout SECONDS]"
+        );
+        return ExitCode::FAILURE;
+    };
+    let mut invite_token = None;
+    let mut listen = "0.0.0.0:9191".to_string();
+    let mut callback = None;
+    let mut timeout = 120;
+    while let Some(flag) = args.next() {
+        let Some(value) = args.next() else {
+            eprintln!("{} needs a value", flag);
+            return ExitCode::FAILURE;
+        };
+        match flag.as_str() {
+            "--invite-token" => invite_token = Some(value),
+            "--listen" => listen = value,
+            "--callback" => callback = Some(value),
+            "--timeout" => match value.parse() {
+                Ok(seconds) => timeout = seconds,
+                Err(_) => {
+                    eprintln!("--timeout takes seconds, got {}", value);
+                    return ExitCode::FAILURE;
+                }
+            },
+            _ => {
+                eprintln!("unknown option {}", flag);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    let report = check_node(&reqwest::Client::new(), &address).await;
+    println!("{}", report);
+    let mut passed = report.passed();
+
+    if let Some(token) = invite_token {
+        let listener = tokio::net::TcpListener::bind(&listen)
+            .await
+            .expect("Failed to bind listen address");
+        let callback = callback.unwrap_or(listen);
+        let report = check_handshake(
+            &address,
+            &token,
+            listener,
+            &callback,
+            Duration::from_secs(timeout),
+        )
+        .await;
+        println!("\n{}", report);
+        passed &= report.passed();
+    }
+
+    if passed {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
@@ -0,0 +1,46 @@
+use mankeli_chat::logging;
+use mankeli_chat::relay::{RELAY_MIGRATOR, relay_app};
+use sqlx::{SqlitePool, sqlite::SqliteConnectOptions};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use tracing::{error, info};
+
+// usage: mankeli-relay [bind_address] [database_url]
+// the relay has no terminal UI to protect, it logs to stderr at
+// MANKELI_LOG_LEVEL (default info)
+#[tokio::main]
+async fn main() {
+    let level = std::env::var("MANKELI_LOG_LEVEL").unwrap_or_else(|_| "info".to_string());
+    tracing_subscriber::fmt()
+        .with_env_filter(logging::filter(&level))
+        .with_writer(std::io::stderr)
+        .init();
+
+    let mut args = std::env::args().skip(1);
+    let bind_address = args.next().unwrap_or_else(|| "0.0.0.0:9090".to_string());
+    let database_url = args
+        .next()
+        .unwrap_or_else(|| "sqlite://relay.db".to_string());
+
+    let options = SqliteConnectOptions::from_str(&database_url)
+        .expect("Failed to parse database URL")
+        .create_if_missing(true);
+
+    let pool = SqlitePool::connect_with(options)
+        .await
+        .expect("Failed to connect to database");
+
+    RELAY_MIGRATOR.run(&pool).await.unwrap();
+
+    let listener = tokio::net::TcpListener::bind(&bind_address)
+        .await
+        .expect("Failed to bind relay address");
+
+    info!(%bind_address, "Relay listening");
+
+    // deposits are limited per depositing IP
+    let service = relay_app(pool).into_make_service_with_connect_info::<SocketAddr>();
+    if let Err(err) = axum::serve(listener, service).await {
+        error!("Relay server error: {}", err);
+    }
+}
@@ -9,186 +9,927 @@
 // if success then set sent flag to true
 
 use crate::StatusLabel;
-use crate::api::{FetchMessageInput, FetchMessageResponse, FriendInput};
-use crate::db::{
-    Friend, batch_ingest, fetch_active_friends, fetch_unsent_friend_updt,
-    update_friend_status_as_sent,
+use crate::api::{
+    FetchMessageInput, FriendInput, FriendRequestStatus, LEGACY_VERSION, Message,
+    PROTOCOL_VERSIONS, highest_common_version,
 };
+use crate::config::Config;
+use crate::db::{Friend, PeerHealth};
+use crate::error::{Error, Result};
+use crate::events::{Event, EventBus};
+use crate::identity::{self, Identity};
+use crate::metrics;
+use crate::relay::{AckInput, CollectInput, CollectResponse, DepositInput, RelayEnvelope};
+use crate::store::Store;
+use crate::transport::{Transport, http_client};
+use crate::wakeup::{Pause, Resume};
 use futures::stream::{self, StreamExt};
 use reqwest::Client;
-use sqlx::SqlitePool;
-use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::{Receiver, error::TryRecvError};
+use tokio::sync::watch;
+use tracing::{Instrument, debug, error, info, info_span, warn};
 
 #[cfg(test)]
 mod tests;
 
-pub async fn process_friend_messages(
-    pool: &SqlitePool,
+// Retry delay after the first failure, doubled for every further one
+pub const BACKOFF_BASE_SECS: u64 = 10;
+pub const BACKOFF_MAX_SECS: u64 = 60 * 60;
+
+// Exponential backoff with jitter over the upper half of the delay, so
+// peers that went down together don't get retried in lockstep
+pub fn backoff_delay(failures: i64) -> Duration {
+    let exponent = failures.saturating_sub(1).clamp(0, 20) as u32;
+    let delay = BACKOFF_BASE_SECS
+        .saturating_mul(1 << exponent)
+        .min(BACKOFF_MAX_SECS);
+    let jitter: f64 = rand::random();
+    Duration::from_secs_f64(delay as f64 * (0.5 + jitter / 2.0))
+}
+
+// Protocol version negotiated with each peer address, shared by both
+// fetchers. A failed request drops the entry, so a peer that was upgraded
+// or rolled back gets asked again on the next attempt.
+#[derive(Debug, Clone, Default)]
+pub struct ProtocolVersions {
+    versions: Arc<Mutex<HashMap<String, u32>>>,
+}
+
+impl ProtocolVersions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, address: &str) -> Option<u32> {
+        self.versions.lock().unwrap().get(address).copied()
+    }
+
+    pub fn forget(&self, address: &str) {
+        self.versions.lock().unwrap().remove(address);
+    }
+
+    // Cached version for `address`, asking its /info the first time
+    pub async fn negotiate<T: Transport>(&self, transport: &T, address: &str) -> Result<u32> {
+        if let Some(version) = self.get(address) {
+            return Ok(version);
+        }
+
+        let version = match transport.info(address).await {
+            Ok(info) => highest_common_version(PROTOCOL_VERSIONS, &info.protocol_versions)
+                .ok_or_else(|| {
+                    Error::protocol(format!(
+                        "no common protocol version, peer speaks {:?}",
+                        info.protocol_versions
+                    ))
+                })?,
+            // nodes from before /info only have the unprefixed routes
+            Err(Error::Protocol {
+                status: Some(404), ..
+            }) => LEGACY_VERSION,
+            Err(e) => return Err(e),
+        };
+
+        debug!(address, version, "Negotiated protocol version");
+        self.versions
+            .lock()
+            .unwrap()
+            .insert(address.to_string(), version);
+        Ok(version)
+    }
+}
+
+// Bounds of the per-friend poll interval, see PollSchedule
+#[derive(Debug, Clone, Copy)]
+pub struct PollBounds {
+    pub min: Duration,
+    // where friends start, also the longest pause between fetch cycles
+    pub start: Duration,
+    pub max: Duration,
+    // friends polled at once
+    pub concurrency: usize,
+}
+
+impl PollBounds {
+    pub fn from_config(config: &Config) -> Self {
+        let (min, max) = (
+            config.message_fetch_min_interval,
+            config.message_fetch_max_interval,
+        );
+        PollBounds {
+            min: Duration::from_secs(min),
+            start: Duration::from_secs(config.message_fetch_interval.clamp(min, max)),
+            max: Duration::from_secs(max),
+            concurrency: config.message_fetch_concurrency,
+        }
+    }
+}
+
+// When each friend is polled next. Hearing from a friend, or them fetching
+// our mail, drops them to `min`; every poll after that doubles the wait up
+// to `max`. A peer's retry hint pushes its next poll out, even past `max`.
+// Kept in memory, after a restart every friend starts over at `start`.
+#[derive(Debug)]
+pub struct PollSchedule {
+    bounds: PollBounds,
+    friends: HashMap<String, PollState>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PollState {
+    // wait after the next poll
+    interval: Duration,
+    next_at: Instant,
+    not_before: Option<Instant>,
+}
+
+// Longest retry hint we honour, a peer can't make us forget it
+pub const RETRY_HINT_MAX: Duration = Duration::from_secs(BACKOFF_MAX_SECS);
+
+impl PollState {
+    fn push_back(&mut self, next_at: Instant) {
+        self.next_at = self
+            .not_before
+            .map_or(next_at, |not_before| next_at.max(not_before));
+    }
+}
+
+impl PollSchedule {
+    pub fn new(bounds: PollBounds) -> Self {
+        PollSchedule {
+            bounds,
+            friends: HashMap::new(),
+        }
+    }
+
+    // New bounds from a config reload, nobody waits longer than `max` from now
+    pub fn set_bounds(&mut self, bounds: PollBounds, now: Instant) {
+        self.bounds = bounds;
+        for state in self.friends.values_mut() {
+            state.interval = state.interval.clamp(bounds.min, bounds.max);
+            let next_at = state.next_at.min(now + bounds.max);
+            state.push_back(next_at);
+        }
+    }
+
+    pub fn concurrency(&self) -> usize {
+        self.bounds.concurrency
+    }
+
+    // Friends we haven't polled yet are always due
+    pub fn is_due(&self, username: &str, now: Instant) -> bool {
+        self.friends
+            .get(username)
+            .is_none_or(|state| state.next_at <= now)
+    }
+
+    pub fn polled(&mut self, username: &str, now: Instant, retry_after: Option<Duration>) {
+        let bounds = self.bounds;
+        let state = self
+            .friends
+            .entry(username.to_string())
+            .or_insert(PollState {
+                interval: bounds.start,
+                next_at: now,
+                not_before: None,
+            });
+        state.not_before = retry_after.map(|hint| now + hint.min(RETRY_HINT_MAX));
+        state.push_back(now + state.interval);
+        state.interval = (state.interval * 2).min(bounds.max);
+    }
+
+    // `username` is in a conversation with us
+    pub fn activity(&mut self, username: &str, now: Instant) {
+        let min = self.bounds.min;
+        let state = self
+            .friends
+            .entry(username.to_string())
+            .or_insert(PollState {
+                interval: min,
+                next_at: now,
+                not_before: None,
+            });
+        state.interval = min;
+        let next_at = state.next_at.min(now + min);
+        state.push_back(next_at);
+    }
+
+    // Everyone is due now, unless they asked for a pause
+    pub fn all_due(&mut self, now: Instant) {
+        for state in self.friends.values_mut() {
+            state.push_back(now);
+        }
+    }
+
+    // Forgets friends that are gone
+    pub fn retain(&mut self, friends: &[Friend]) {
+        self.friends
+            .retain(|username, _| friends.iter().any(|f| &f.username == username));
+    }
+
+    // How long until the next friend is due, with a cycle every `start` at
+    // the latest and no closer together than `min`
+    pub fn pause(&self, now: Instant) -> Duration {
+        self.friends
+            .values()
+            .map(|state| state.next_at.saturating_duration_since(now))
+            .min()
+            .map_or(self.bounds.start, |due| due.min(self.bounds.start))
+            .max(self.bounds.min)
+    }
+}
+
+// Friends we just heard from, or who just fetched our mail, are talking to us
+fn note_activity(schedule: &mut PollSchedule, events: &mut Receiver<Event>) {
+    let now = Instant::now();
+    loop {
+        match events.try_recv() {
+            Ok(Event::NewMessage { sender, .. }) => schedule.activity(&sender, now),
+            Ok(Event::DeliveryConfirmed { recipient, .. }) => schedule.activity(&recipient, now),
+            Ok(_) | Err(TryRecvError::Lagged(_)) => {}
+            Err(TryRecvError::Empty | TryRecvError::Closed) => break,
+        }
+    }
+}
+
+// Friends behind a relay get their mail deposited whether or not they answer
+// directly, so it doesn't wait for their backoff or poll interval. A relay
+// failing says nothing about the friend, it isn't counted in their health.
+async fn deposit_relay_messages<S: Store>(
+    store: &S,
+    events: &EventBus,
     client: &Client,
+    identity: &Identity,
+    friends: &[Friend],
+    concurrency: usize,
+) {
+    let relayed = friends
+        .iter()
+        .filter_map(|friend| Some((friend, friend.relay_address.as_deref()?)));
+    stream::iter(relayed)
+        .for_each_concurrent(concurrency, |(friend, relay)| {
+            let span = info_span!("friend", username = %friend.username);
+            async move {
+                match deposit_friend_messages(store, events, client, identity, friend, relay).await
+                {
+                    Ok(0) => {}
+                    Ok(count) => info!(relay, count, "Deposited messages at relay"),
+                    Err(e) => {
+                        metrics::relay_failure(relay, "deposit");
+                        warn!(relay, "Error depositing messages at relay: {}", e);
+                    }
+                }
+            }
+            .instrument(span)
+        })
+        .await;
+}
+
+async fn load_peer_health<S: Store>(store: &S) -> HashMap<String, PeerHealth> {
+    match store.fetch_peer_health().await {
+        Ok(health) => health
+            .into_iter()
+            .map(|h| (h.username.clone(), h))
+            .collect(),
+        Err(e) => {
+            error!("Error fetching peer health: {}", e);
+            HashMap::new()
+        }
+    }
+}
+
+// Stores fetched mail and tells subscribers about each message
+async fn ingest<S: Store>(store: &S, events: &EventBus, messages: Vec<Message>) -> Result<()> {
+    let new = new_message_events(&messages);
+    store.ingest_messages(messages).await?;
+    announce(events, new);
+    Ok(())
+}
+
+fn new_message_events(messages: &[Message]) -> Vec<Event> {
+    messages
+        .iter()
+        .map(|msg| Event::NewMessage {
+            sender: msg.sender.clone(),
+            subject: msg.subject.clone(),
+        })
+        .collect()
+}
+
+fn announce(events: &EventBus, new: Vec<Event>) {
+    metrics::messages_ingested(new.len());
+    for event in new {
+        events.publish(event);
+    }
+}
+
+// Drops peers that are still backing off
+fn due_friends(friends: Vec<Friend>, health: &HashMap<String, PeerHealth>) -> Vec<Friend> {
+    let now = chrono::Utc::now().naive_utc();
+    friends
+        .into_iter()
+        .filter(|f| health.get(&f.username).is_none_or(|h| h.is_due(now)))
+        .collect()
+}
+
+// Persists how talking to a peer went, only warning when its state changes
+async fn track_peer_result<S: Store, T>(
+    store: &S,
+    events: &EventBus,
+    username: &str,
+    previous_failures: i64,
+    result: &Result<T>,
+) {
+    match result {
+        // a refusal still means the peer is up, backing off won't change its answer
+        Err(error) if error.peer_responded() && !error.is_retryable() => {
+            if let Err(e) = store.record_peer_success(username).await {
+                error!(username, "Error recording peer health: {}", e);
+            }
+        }
+        // our own database or input failed, the peer did nothing wrong
+        Err(error) if !error.is_peer_failure() => {
+            error!(username, "Local error while talking to peer: {}", error);
+        }
+        Ok(_) => {
+            if let Err(e) = store.record_peer_success(username).await {
+                error!(username, "Error recording peer health: {}", e);
+            } else if previous_failures > 0 {
+                info!(
+                    username,
+                    previous_failures, "Peer is reachable again after failed attempts"
+                );
+            }
+        }
+        Err(error) => {
+            metrics::peer_failure(username);
+            let failures = match store
+                .record_peer_failure(username, &error.to_string())
+                .await
+            {
+                Ok(failures) => failures,
+                Err(e) => {
+                    error!(username, "Error recording peer health: {}", e);
+                    return;
+                }
+            };
+
+            let delay = backoff_delay(failures);
+            let next_attempt = chrono::Utc::now().naive_utc()
+                + chrono::Duration::from_std(delay).unwrap_or(chrono::Duration::zero());
+            if let Err(e) = store.schedule_peer_retry(username, next_attempt).await {
+                error!(username, "Error recording peer health: {}", e);
+            }
+
+            if failures == 1 {
+                warn!(username, "Peer is unreachable, backing off: {}", error);
+                events.publish(Event::PeerUnreachable {
+                    username: username.to_string(),
+                    error: error.to_string(),
+                });
+            } else {
+                debug!(
+                    username,
+                    failures,
+                    ?delay,
+                    "Peer still unreachable: {}",
+                    error
+                );
+            }
+        }
+    }
+}
+
+// Fetches what `friend` has queued for us, returns their retry hint
+pub async fn process_friend_messages<S: Store, T: Transport>(
+    store: &S,
+    transport: &T,
+    versions: &ProtocolVersions,
+    events: &EventBus,
     our_username: &str,
     our_address: &str,
     friend: &Friend,
-) -> Result<(), String> {
-    let target_url = format!("http://{}/fetch_messages", friend.address);
-
+) -> Result<Option<Duration>> {
     let req_body = FetchMessageInput {
         username: our_username.to_string(),
         address: our_address.to_string(),
     };
 
+    let version = versions.negotiate(transport, &friend.address).await?;
+    let apiresponse = transport
+        .fetch_messages(&friend.address, version, &req_body)
+        .await
+        .inspect_err(|_| versions.forget(&friend.address))?;
+
+    if !apiresponse.messages.is_empty() {
+        ingest(store, events, apiresponse.messages).await?;
+    }
+
+    Ok(apiresponse.retry_after.map(Duration::from_secs))
+}
+
+// Hands our queued mail for a friend to their relay, sealed to their key
+pub async fn deposit_friend_messages<S: Store>(
+    store: &S,
+    events: &EventBus,
+    client: &Client,
+    identity: &Identity,
+    friend: &Friend,
+    relay_address: &str,
+) -> Result<usize> {
+    let outgoing = store.pending_messages_for(&friend.username).await?;
+
+    if outgoing.is_empty() {
+        return Ok(0);
+    }
+
+    let Some(friend_key) = friend
+        .peer_key
+        .clone()
+        .filter(|key| identity::is_peer_key(key))
+    else {
+        return Err(Error::Validation(format!(
+            "{} has no peer key to seal relay mail to",
+            friend.username
+        )));
+    };
+
+    let mut envelopes = Vec::with_capacity(outgoing.len());
+    for msg in &outgoing {
+        let message = serde_json::to_vec(&RelayLetter {
+            sealed_at: chrono::Utc::now().timestamp(),
+            message: Message {
+                sender: msg.sender.clone(),
+                subject: msg.subject.clone(),
+                body: msg.body.clone(),
+            },
+        })?;
+        let payload = identity
+            .seal(&friend_key, &message)
+            .ok_or_else(|| Error::Validation("failed to seal relay mail".into()))?;
+        envelopes.push(RelayEnvelope {
+            sender: msg.sender.clone(),
+            payload,
+        });
+    }
+
+    let req_body = DepositInput {
+        recipient: friend_key,
+        envelopes,
+    };
+
     let res = client
-        .post(&target_url)
+        .post(format!("http://{}/deposit", relay_address))
         .json(&req_body)
         .send()
-        .await
-        .map_err(|e| format!("Request error: {}", e))?;
+        .await?;
 
     if !res.status().is_success() {
-        return Err(format!("Bad status: {}", res.status()));
+        return Err(Error::from_status(res.status()));
     }
 
-    let apiresponse = res
-        .json::<FetchMessageResponse>()
-        .await
-        .map_err(|e| format!("Parse error: {}", e))?;
+    let ids: Vec<i64> = outgoing.iter().map(|msg| msg.id).collect();
+    store.mark_messages_sent(&ids).await?;
+    metrics::messages_fetched(ids.len(), true);
+    events.publish(Event::DeliveryConfirmed {
+        recipient: friend.username.clone(),
+        count: ids.len(),
+        relay: true,
+    });
 
-    if !apiresponse.messages.is_empty() {
-        batch_ingest(pool, apiresponse.messages)
-            .await
-            .map_err(|e| format!("DB error: {}", e))?;
+    Ok(ids.len())
+}
+
+// Picks up mail friends left for us at our relay, then acks it so the relay
+// can drop it
+pub async fn collect_relay_messages<S: Store>(
+    store: &S,
+    events: &EventBus,
+    client: &Client,
+    relay_address: &str,
+    identity: &Identity,
+) -> Result<usize> {
+    let res = client
+        .post(format!("http://{}/collect", relay_address))
+        .json(&CollectInput::new(identity, chrono::Utc::now().timestamp()))
+        .send()
+        .await?;
+
+    if !res.status().is_success() {
+        return Err(Error::from_status(res.status()));
     }
 
-    Ok(())
+    let response = res.json::<CollectResponse>().await?;
+    if response.mail.is_empty() {
+        return Ok(0);
+    }
+
+    let friends = store.fetch_active_friends().await?;
+
+    // anyone can drop mail at a relay. What isn't from a friend, doesn't
+    // open or parse, or has been around longer than a relay keeps mail is
+    // logged and acked away with the rest.
+    let now = chrono::Utc::now().timestamp();
+    let max_age = crate::relay::MAILBOX_TTL_DAYS * 24 * 60 * 60;
+    let ids: Vec<i64> = response.mail.iter().map(|mail| mail.id).collect();
+    let mut letters = Vec::new();
+    let mut strangers = Vec::new();
+    let mut dropped = Vec::new();
+    for mail in response.mail {
+        let Some(friend) = friends.iter().find(|f| f.username == mail.sender) else {
+            strangers.push(mail.sender);
+            continue;
+        };
+        let letter = friend
+            .peer_key
+            .as_deref()
+            .and_then(|key| identity.open(key, &mail.payload))
+            .and_then(|plaintext| serde_json::from_slice::<RelayLetter>(&plaintext).ok())
+            .filter(|letter| {
+                letter.message.sender == mail.sender && now - letter.sealed_at <= max_age
+            });
+        match letter {
+            Some(letter) => letters.push((identity::sealed_digest(&mail.payload), letter.message)),
+            None => dropped.push(mail.sender),
+        }
+    }
+    warn_dropped(relay_address, strangers, "from non-friends");
+    warn_dropped(
+        relay_address,
+        dropped,
+        "that didn't open, parse or was too old",
+    );
+
+    // a relay handing out mail again under a new id doesn't deliver it twice
+    let fresh = store.ingest_relay_mail(letters).await?;
+    let count = fresh.len();
+    announce(events, new_message_events(&fresh));
+
+    let res = client
+        .post(format!("http://{}/ack", relay_address))
+        .json(&AckInput::new(
+            identity,
+            ids,
+            chrono::Utc::now().timestamp(),
+        ))
+        .send()
+        .await?;
+    if !res.status().is_success() {
+        return Err(Error::from_status(res.status()));
+    }
+
+    Ok(count)
 }
 
-pub async fn message_fetcher(
-    pool: &SqlitePool,
-    our_username: &str,
-    our_address: &str,
-    sleep_time: u64,
+// What a relay envelope carries once opened. The seal time bounds how long
+// a relay can hold on to mail, and how long we remember what we took in.
+#[derive(Serialize, Deserialize, Debug)]
+struct RelayLetter {
+    sealed_at: i64,
+    message: Message,
+}
+
+// One warning per kind of relay mail we drop, naming the senders
+fn warn_dropped(relay_address: &str, mut senders: Vec<String>, what: &str) {
+    if senders.is_empty() {
+        return;
+    }
+    let count = senders.len();
+    senders.sort();
+    senders.dedup();
+    warn!(
+        relay_address,
+        count,
+        "Dropping relay mail {}, sent as {}",
+        what,
+        senders.join(", ")
+    );
+}
+
+// Who the message fetcher fetches for
+#[derive(Debug, Clone, Copy)]
+pub struct LocalNode<'a> {
+    pub username: &'a str,
+    pub address: &'a str,
+    pub relay: Option<&'a str>,
+}
+
+pub async fn message_fetcher<S: Store, T: Transport>(
+    store: &S,
+    transport: &T,
+    versions: &ProtocolVersions,
+    events: &EventBus,
+    me: LocalNode<'_>,
+    // read again every cycle, a reload may have changed the intervals or limit
+    config: watch::Receiver<Config>,
+    mut pause: Pause,
 ) {
-    let client = Client::new();
-    println!("Message fetcher started.");
+    let client = http_client(&config.borrow());
+    let mut activity = events.subscribe();
+    let mut schedule = PollSchedule::new(PollBounds::from_config(&config.borrow()));
+    info!("Message fetcher started");
 
-    loop {
-        let friend_list = match fetch_active_friends(pool).await {
-            Ok(friends) => friends,
-            Err(e) => {
-                eprintln!("Error fetching friend list: {}. Retrying in 60s.", e);
-                tokio::time::sleep(Duration::from_secs(60)).await;
-                continue;
-            }
-        };
+    for cycle in 1u64.. {
+        let bounds = PollBounds::from_config(&config.borrow());
+        schedule.set_bounds(bounds, Instant::now());
+        note_activity(&mut schedule, &mut activity);
+        message_fetch_cycle(
+            store,
+            transport,
+            versions,
+            events,
+            &client,
+            me,
+            &mut schedule,
+        )
+        .instrument(info_span!("message_fetch_cycle", cycle))
+        .await;
+        note_activity(&mut schedule, &mut activity);
 
-        if friend_list.is_empty() {
-            //println!("No active friends found. Checking again in 30s.");
-            tokio::time::sleep(Duration::from_secs(30)).await;
-            continue;
+        match pause.wait(schedule.pause(Instant::now())).await {
+            Resume::Shutdown => break,
+            // someone asked for a sync or handed us work, check on everyone
+            Resume::Woken => schedule.all_due(Instant::now()),
+            Resume::Elapsed => {}
         }
+    }
+    info!("Message fetcher stopped");
+}
 
-        const CONCURRENT_REQUESTS: usize = 10;
-
-        stream::iter(friend_list)
-            .for_each_concurrent(CONCURRENT_REQUESTS, |friend| {
-                let client = client.clone();
-                let pool = pool.clone();
-                let our_username = our_username.to_string();
-                let our_address = our_address.to_string();
-
-                async move {
-                    match process_friend_messages(
-                        &pool,
-                        &client,
-                        &our_username,
-                        &our_address,
-                        &friend,
-                    )
-                    .await
-                    {
-                        Ok(_) => println!("Processed messages from {}", friend.username),
-                        Err(e) => {
-                            eprintln!("Error processing messages from {}: {}", friend.username, e)
-                        }
+// One pass over our relay and every friend that is due
+async fn message_fetch_cycle<S: Store, T: Transport>(
+    store: &S,
+    transport: &T,
+    versions: &ProtocolVersions,
+    events: &EventBus,
+    // relays still speak plain HTTP
+    client: &Client,
+    me: LocalNode<'_>,
+    schedule: &mut PollSchedule,
+) {
+    let started = Instant::now();
+    let friend_list = match store.fetch_active_friends().await {
+        Ok(friends) => friends,
+        Err(e) => {
+            error!("Error fetching friend list: {}", e);
+            return;
+        }
+    };
+
+    let concurrency = schedule.concurrency();
+    // relay mail is sealed and signed with our keypair
+    match store.local_identity().await {
+        Ok(identity) => {
+            if let Some(relay) = me.relay {
+                match collect_relay_messages(store, events, client, relay, &identity).await {
+                    Ok(0) => {}
+                    Ok(count) => info!(relay, count, "Collected messages from relay"),
+                    Err(e) => {
+                        metrics::relay_failure(relay, "collect");
+                        warn!(relay, "Error collecting messages from relay: {}", e)
                     }
                 }
-            })
-            .await;
+            }
+
+            deposit_relay_messages(store, events, client, &identity, &friend_list, concurrency)
+                .await;
+        }
+        Err(e) => error!("Error loading our identity, skipping relays: {}", e),
+    }
 
-        //println!("Fetch cycle complete. Waiting for {}s.", sleep_time);
-        tokio::time::sleep(Duration::from_secs(sleep_time)).await;
+    schedule.retain(&friend_list);
+    if friend_list.is_empty() {
+        debug!("No active friends found");
+        return;
     }
+
+    let health = load_peer_health(store).await;
+    let now = Instant::now();
+    let friend_list: Vec<Friend> = due_friends(friend_list, &health)
+        .into_iter()
+        .filter(|f| schedule.is_due(&f.username, now))
+        .collect();
+    let due = friend_list.len();
+
+    let polled: Vec<(String, Option<Duration>)> = stream::iter(friend_list)
+        .map(|friend| {
+            let previous_failures = health
+                .get(&friend.username)
+                .map_or(0, |h| h.consecutive_failures);
+            let span = info_span!("friend", username = %friend.username);
+
+            async move {
+                let result = process_friend_messages(
+                    store,
+                    transport,
+                    versions,
+                    events,
+                    me.username,
+                    me.address,
+                    &friend,
+                )
+                .await;
+                track_peer_result(store, events, &friend.username, previous_failures, &result)
+                    .await;
+                let retry_after = result.ok().flatten();
+                if let Some(hint) = retry_after {
+                    debug!(?hint, "Peer asked to be polled later");
+                }
+                (friend.username, retry_after)
+            }
+            .instrument(span)
+        })
+        .buffer_unordered(concurrency)
+        .collect()
+        .await;
+
+    let now = Instant::now();
+    for (username, retry_after) in polled {
+        schedule.polled(&username, now, retry_after);
+    }
+
+    match store.outgoing_queue_depth().await {
+        Ok(depth) => metrics::outgoing_queue_depth(depth as u64),
+        Err(e) => warn!("Error counting queued messages: {}", e),
+    }
+
+    let elapsed = started.elapsed();
+    metrics::fetch_cycle("messages", elapsed);
+    debug!(
+        friends = due,
+        elapsed_ms = elapsed.as_millis() as u64,
+        "Fetch cycle complete"
+    );
 }
 
-pub async fn send_friend_request(
-    pool: &SqlitePool,
-    client: &Client,
+pub async fn send_friend_request<S: Store, T: Transport>(
+    store: &S,
+    transport: &T,
+    versions: &ProtocolVersions,
     our_username: &str,
     friend: &Friend,
     address: &str,
-) -> Result<(), String> {
-    let req_body = FriendInput {
+    identity: &Identity,
+) -> Result<()> {
+    let req_type = friend.status.status_enum();
+    // Only our own invites carry our note and token, otherwise the fields hold theirs
+    let (intro, invite_token) = match req_type {
+        FriendRequestStatus::InviteSent => (friend.intro.clone(), friend.invite_token.clone()),
+        _ => (None, None),
+    };
+
+    let mut req_body = FriendInput {
         username: friend.username.clone(),
         hostname: our_username.to_string(),
         address: address.to_string(),
-        req_type: friend.status.status_enum(),
+        req_type,
+        peer_key: None,
+        intro,
+        invite_token,
+        signed_at: None,
+        signature: None,
     };
+    req_body.sign(identity, chrono::Utc::now().timestamp());
 
-    let target_url = format!("http://{}/friend_request", friend.address);
-
-    let response = client
-        .post(&target_url)
-        .json(&req_body)
-        .send()
+    let version = versions.negotiate(transport, &friend.address).await?;
+    transport
+        .friend_request(&friend.address, version, &req_body)
         .await
-        .map_err(|e| format!("Network error: {}", e))?;
+        .inspect_err(|_| versions.forget(&friend.address))?;
+    metrics::friend_request("sent", req_type);
+    store.mark_friend_update_sent(&friend.username).await?;
+    Ok(())
+}
+
+pub async fn friend_fetcher<S: Store, T: Transport>(
+    store: &S,
+    transport: &T,
+    versions: &ProtocolVersions,
+    events: &EventBus,
+    // read again every cycle, a reload may have changed the interval or limit
+    config: watch::Receiver<Config>,
+    mut pause: Pause,
+) {
+    info!("Friend fetcher service started");
 
-    if response.status().is_success() {
-        update_friend_status_as_sent(pool, &friend.username)
-            .await
-            .map_err(|e| format!("DB error: {}", e))?;
-        Ok(())
-    } else {
-        Err(format!("Non-200 response: {}", response.status()))
+    for cycle in 1u64.. {
+        let (sleep_time, invite_expiry, concurrency) = {
+            let config = config.borrow();
+            (
+                Duration::from_secs(config.friend_fetch_interval),
+                config.invite_expiry(),
+                config.friend_fetch_concurrency,
+            )
+        };
+        let next = friend_fetch_cycle(
+            store,
+            transport,
+            versions,
+            events,
+            invite_expiry,
+            concurrency,
+            sleep_time,
+        )
+        .instrument(info_span!("friend_fetch_cycle", cycle))
+        .await;
+
+        if pause.wait(next).await == Resume::Shutdown {
+            break;
+        }
     }
+    info!("Friend fetcher stopped");
 }
 
-pub async fn friend_fetcher(pool: &SqlitePool, sleep_time: u64) {
-    let client = Client::new();
-    println!("Friend fetcher service started.");
+async fn friend_fetch_cycle<S: Store, T: Transport>(
+    store: &S,
+    transport: &T,
+    versions: &ProtocolVersions,
+    events: &EventBus,
+    invite_expiry: Option<Duration>,
+    concurrency: usize,
+    sleep_time: Duration,
+) -> Duration {
+    let started = Instant::now();
+    if let Some(max_age) = invite_expiry {
+        match store.expire_stale_invites(max_age).await {
+            Ok(0) => {}
+            Ok(count) => info!(count, "Friend invites expired without an answer"),
+            Err(e) => error!("Error expiring friend invites: {}", e),
+        }
+    }
 
-    loop {
-        let (our_username, friend_list) = match fetch_unsent_friend_updt(pool).await {
-            Ok(data) => data,
-            Err(e) => {
-                eprintln!("DB Error fetching friend updates: {}. Retrying in 60s.", e);
-                tokio::time::sleep(Duration::from_secs(60)).await;
-                continue;
-            }
-        };
+    let (our_username, friend_list) = match store.fetch_unsent_friend_updates().await {
+        Ok(data) => data,
+        Err(e) => {
+            error!("DB Error fetching friend updates: {}", e);
+            return sleep_time;
+        }
+    };
 
-        if friend_list.is_empty() {
-            tokio::time::sleep(Duration::from_secs(15)).await;
-            continue;
+    if friend_list.is_empty() {
+        return sleep_time;
+    }
+
+    // every request is signed, without our key nothing can go out
+    let identity = match store.local_identity().await {
+        Ok(identity) => identity,
+        Err(e) => {
+            error!("DB Error loading our identity: {}", e);
+            return sleep_time;
         }
-        const CONCURRENT_REQUESTS: usize = 5;
+    };
+
+    let health = load_peer_health(store).await;
+    let friend_list = due_friends(friend_list, &health);
+    let due = friend_list.len();
 
-        stream::iter(friend_list)
-            .for_each_concurrent(CONCURRENT_REQUESTS, |friend| {
-                let client = client.clone();
-                let pool = pool.clone();
-                let username = our_username.username.clone();
-                let address = our_username.address.clone();
+    stream::iter(friend_list)
+        .for_each_concurrent(concurrency, |friend| {
+            let our_username = &our_username;
+            let identity = &identity;
+            let previous_failures = health
+                .get(&friend.username)
+                .map_or(0, |h| h.consecutive_failures);
+            let span = info_span!("friend", username = %friend.username);
 
-                async move {
-                    match send_friend_request(&pool, &client, &username, &friend, &address).await {
-                        Ok(_) => println!("Friend request sent to {}", friend.username),
-                        Err(e) => eprintln!("Error sending request to {}: {}", friend.username, e),
+            async move {
+                let result = send_friend_request(
+                    store,
+                    transport,
+                    versions,
+                    &our_username.username,
+                    &friend,
+                    &our_username.address,
+                    identity,
+                )
+                .await;
+                match &result {
+                    Ok(_) => info!("Friend request sent"),
+                    // the peer refused it, sending the same request again won't help
+                    Err(e) if !e.is_retryable() => {
+                        warn!("Friend request refused, not retrying: {}", e);
+                        if let Err(e) = store.mark_friend_update_sent(&friend.username).await {
+                            error!("Error updating friend request: {}", e);
+                        }
                     }
+                    Err(_) => {}
                 }
-            })
-            .await;
+                track_peer_result(store, events, &friend.username, previous_failures, &result)
+                    .await;
+            }
+            .instrument(span)
+        })
+        .await;
 
-        //println!("Friend update cycle complete. Waiting for {}s.", sleep_time);
-        tokio::time::sleep(Duration::from_secs(sleep_time)).await;
-    }
+    let elapsed = started.elapsed();
+    metrics::fetch_cycle("friends", elapsed);
+    debug!(
+        friends = due,
+        elapsed_ms = elapsed.as_millis() as u64,
+        "Friend update cycle complete"
+    );
+    sleep_time
 }
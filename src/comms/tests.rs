@@ -1,6 +1,11 @@
 use super::*;
-use crate::api::{FetchMessageResponse, Message};
-use httpmock::{Method::POST, MockServer};
+use crate::api::{FetchMessageResponse, Message, NodeInfo};
+use crate::store::FriendStore;
+use crate::transport::HttpTransport;
+use httpmock::{
+    Method::{GET, POST},
+    MockServer,
+};
 use reqwest::Client;
 use sqlx::{SqlitePool, migrate::Migrator};
 
@@ -22,6 +27,13 @@ async fn test_process_friend_messages_success() {
         address: server.address().to_string(),
         status: 2,
         added_at: None,
+        peer_key: None,
+        intro: None,
+        fingerprint: None,
+        invite_token: None,
+        relay_address: None,
+        nickname: None,
+        notes: None,
     };
     let _mock = server.mock(|when, then| {
         when.method(POST).path("/fetch_messages");
@@ -31,16 +43,34 @@ async fn test_process_friend_messages_success() {
                 subject: "hi".into(),
                 body: "hello".into(),
             }],
+            retry_after: Some(120),
         });
     });
 
-    let client = Client::new();
+    let transport = HttpTransport::default();
     let pool = setup_test_db().await;
+    let events = EventBus::new();
+    let mut rx = events.subscribe();
 
-    let result = process_friend_messages(&pool, &client, "bob", "1.2.3.4", &friend)
-        .await
-        .map_err(|e| eprintln!("{}", e));
-    assert!(result.is_ok());
+    let result = process_friend_messages(
+        &pool,
+        &transport,
+        &ProtocolVersions::new(),
+        &events,
+        "bob",
+        "1.2.3.4",
+        &friend,
+    )
+    .await
+    .map_err(|e| eprintln!("{}", e));
+    assert_eq!(result, Ok(Some(Duration::from_secs(120))));
+    assert_eq!(
+        rx.try_recv().unwrap(),
+        Event::NewMessage {
+            sender: "alice".into(),
+            subject: "hi".into(),
+        }
+    );
 }
 
 #[tokio::test]
@@ -53,6 +83,13 @@ async fn test_send_friend_request_success() {
         address: server.address().to_string(),
         status: 1,
         added_at: None,
+        peer_key: None,
+        intro: None,
+        fingerprint: None,
+        invite_token: None,
+        relay_address: None,
+        nickname: None,
+        notes: None,
     };
 
     let _mock = server.mock(|when, then| {
@@ -60,10 +97,414 @@ async fn test_send_friend_request_success() {
         then.status(200);
     });
 
-    let client = Client::new();
+    let transport = HttpTransport::default();
     let pool = setup_test_db().await;
 
-    let result = send_friend_request(&pool, &client, "bob", &friend, "127.0.0.1").await;
+    let result = send_friend_request(
+        &pool,
+        &transport,
+        &ProtocolVersions::new(),
+        "bob",
+        &friend,
+        "127.0.0.1",
+        &Identity::generate(),
+    )
+    .await;
 
     assert!(result.is_ok());
 }
+
+#[tokio::test]
+async fn test_messages_travel_through_relay() {
+    let relay_pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+    crate::relay::RELAY_MIGRATOR.run(&relay_pool).await.unwrap();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let relay = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        axum::serve(listener, crate::relay::relay_app(relay_pool))
+            .await
+            .unwrap();
+    });
+
+    let bob = setup_test_db().await;
+    sqlx::query("INSERT INTO user (username, address) VALUES ('bob', '192.168.1.2:8080')")
+        .execute(&bob)
+        .await
+        .unwrap();
+    let bob_identity = bob.local_identity().await.unwrap();
+
+    // alice queues mail for bob, who sits behind the relay
+    let alice = setup_test_db().await;
+    sqlx::query("INSERT INTO user (username, address) VALUES ('alice', '10.0.0.1:8080')")
+        .execute(&alice)
+        .await
+        .unwrap();
+    let alice_identity = alice.local_identity().await.unwrap();
+    sqlx::query(
+        "INSERT INTO friends (username, address, status, relay_address, peer_key) VALUES ('bob', '192.168.1.2:8080', 2, ?, ?)",
+    )
+    .bind(&relay)
+    .bind(bob_identity.peer_key())
+    .execute(&alice)
+    .await
+    .unwrap();
+    crate::db::send_message_to_que(
+        &alice,
+        &crate::db::OutgoingMessage {
+            send_to: "bob".into(),
+            subject: "hi".into(),
+            content: "via relay".into(),
+        },
+    )
+    .await
+    .unwrap();
+
+    // bob can't be fetched from directly and is backed off, his mail still
+    // goes to the relay
+    alice
+        .record_peer_failure("bob", "unreachable")
+        .await
+        .unwrap();
+    let later = chrono::Utc::now().naive_utc() + chrono::Duration::hours(1);
+    alice.schedule_peer_retry("bob", later).await.unwrap();
+    let client = Client::new();
+    let me = LocalNode {
+        username: "alice",
+        address: "10.0.0.1:8080",
+        relay: None,
+    };
+    let mut schedule = PollSchedule::new(PollBounds::from_config(&Config::default()));
+    message_fetch_cycle(
+        &alice,
+        &HttpTransport::default(),
+        &ProtocolVersions::new(),
+        &EventBus::new(),
+        &client,
+        me,
+        &mut schedule,
+    )
+    .await;
+    assert!(
+        crate::db::fetch_messages_for_user(&alice, "bob".into())
+            .await
+            .unwrap()
+            .is_empty()
+    );
+
+    sqlx::query(
+        "INSERT INTO friends (username, address, status, peer_key) VALUES ('alice', '10.0.0.1:8080', 2, ?)",
+    )
+    .bind(alice_identity.peer_key())
+    .execute(&bob)
+    .await
+    .unwrap();
+
+    // what the relay holds for bob, to hand out again later
+    let held = client
+        .post(format!("http://{}/collect", relay))
+        .json(&CollectInput::new(
+            &bob_identity,
+            chrono::Utc::now().timestamp(),
+        ))
+        .send()
+        .await
+        .unwrap()
+        .json::<CollectResponse>()
+        .await
+        .unwrap();
+    assert_eq!(held.mail.len(), 1);
+
+    let collected = collect_relay_messages(&bob, &EventBus::new(), &client, &relay, &bob_identity)
+        .await
+        .unwrap();
+    assert_eq!(collected, 1);
+
+    let inbox = crate::db::fetch_inbox(&bob).await.unwrap();
+    assert_eq!(inbox.len(), 1);
+    assert_eq!(inbox[0].sender, "alice");
+    assert_eq!(inbox[0].message, "via relay");
+
+    // the relay dropped what bob acked
+    let collected = collect_relay_messages(&bob, &EventBus::new(), &client, &relay, &bob_identity)
+        .await
+        .unwrap();
+    assert_eq!(collected, 0);
+    assert_eq!(crate::db::fetch_inbox(&bob).await.unwrap().len(), 1);
+
+    // the same mail deposited again under a new id, mail from a stranger
+    // and garbled mail from a friend are all dropped
+    let deposit = crate::relay::DepositInput {
+        recipient: bob_identity.peer_key(),
+        envelopes: vec![
+            RelayEnvelope {
+                sender: "alice".into(),
+                payload: held.mail[0].payload.clone(),
+            },
+            RelayEnvelope {
+                sender: "carol".into(),
+                payload: "sealed for bob".into(),
+            },
+            RelayEnvelope {
+                sender: "alice".into(),
+                payload: "garbled".into(),
+            },
+        ],
+    };
+    client
+        .post(format!("http://{}/deposit", relay))
+        .json(&deposit)
+        .send()
+        .await
+        .unwrap();
+    let collected = collect_relay_messages(&bob, &EventBus::new(), &client, &relay, &bob_identity)
+        .await
+        .unwrap();
+    assert_eq!(collected, 0);
+    assert_eq!(crate::db::fetch_inbox(&bob).await.unwrap().len(), 1);
+    let left = client
+        .post(format!("http://{}/collect", relay))
+        .json(&CollectInput::new(
+            &bob_identity,
+            chrono::Utc::now().timestamp(),
+        ))
+        .send()
+        .await
+        .unwrap()
+        .json::<CollectResponse>()
+        .await
+        .unwrap();
+    assert!(left.mail.is_empty());
+}
+
+#[test]
+fn test_poll_schedule_adapts_to_activity() {
+    let secs = Duration::from_secs;
+    let mut schedule = PollSchedule::new(PollBounds {
+        min: secs(5),
+        start: secs(30),
+        max: secs(120),
+        concurrency: 1,
+    });
+    let t0 = Instant::now();
+    assert!(schedule.is_due("alice", t0));
+    // nothing scheduled yet, the cycle comes back at `start`
+    assert_eq!(schedule.pause(t0), secs(30));
+
+    // quiet friends back off up to `max`
+    schedule.polled("alice", t0, None);
+    assert!(!schedule.is_due("alice", t0 + secs(29)));
+    assert!(schedule.is_due("alice", t0 + secs(30)));
+    schedule.polled("alice", t0 + secs(30), None);
+    assert!(!schedule.is_due("alice", t0 + secs(89)));
+    schedule.polled("alice", t0 + secs(90), None);
+    schedule.polled("alice", t0 + secs(210), None);
+    assert!(!schedule.is_due("alice", t0 + secs(329)));
+    assert!(schedule.is_due("alice", t0 + secs(330)));
+
+    // hearing from them brings them down to `min`
+    schedule.activity("alice", t0 + secs(300));
+    assert!(schedule.is_due("alice", t0 + secs(305)));
+    assert_eq!(schedule.pause(t0 + secs(300)), secs(5));
+    schedule.polled("alice", t0 + secs(305), None);
+    assert!(schedule.is_due("alice", t0 + secs(310)));
+
+    // a retry hint wins over activity and syncs, even past `max`
+    schedule.polled("bob", t0, Some(secs(600)));
+    schedule.activity("bob", t0 + secs(1));
+    schedule.all_due(t0 + secs(2));
+    assert!(!schedule.is_due("bob", t0 + secs(599)));
+    assert!(schedule.is_due("bob", t0 + secs(600)));
+    assert!(schedule.is_due("alice", t0 + secs(2)));
+
+    // but only up to a point
+    schedule.polled("carol", t0, Some(secs(7 * 24 * 60 * 60)));
+    assert!(schedule.is_due("carol", t0 + RETRY_HINT_MAX));
+
+    schedule.retain(&[]);
+    assert_eq!(schedule.pause(t0), secs(30));
+}
+
+#[test]
+fn test_backoff_delay_grows_and_is_capped() {
+    for failures in 1..30 {
+        let delay = backoff_delay(failures).as_secs_f64();
+        let full = (BACKOFF_BASE_SECS as f64 * 2f64.powi(failures as i32 - 1))
+            .min(BACKOFF_MAX_SECS as f64);
+        assert!(
+            delay >= full / 2.0 && delay <= full,
+            "{} -> {}",
+            failures,
+            delay
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_unreachable_friend_is_backed_off() {
+    let pool = setup_test_db().await;
+    sqlx::query(
+        "INSERT INTO friends (username, address, status) VALUES ('dave', '127.0.0.1:1', 2)",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+
+    let friends = crate::db::fetch_active_friends(&pool).await.unwrap();
+    let transport = HttpTransport::default();
+    let events = EventBus::new();
+    let mut rx = events.subscribe();
+    let result = process_friend_messages(
+        &pool,
+        &transport,
+        &ProtocolVersions::new(),
+        &events,
+        "bob",
+        "1.2.3.4",
+        &friends[0],
+    )
+    .await;
+    assert!(result.is_err());
+
+    track_peer_result(&pool, &events, "dave", 0, &result).await;
+    assert!(matches!(
+        rx.try_recv().unwrap(),
+        Event::PeerUnreachable { username, .. } if username == "dave"
+    ));
+
+    // only the first failure is announced
+    track_peer_result(&pool, &events, "dave", 1, &result).await;
+    assert!(rx.try_recv().is_err());
+
+    // our own database failing is not the peer's fault
+    let local: Result<()> = Err(Error::Storage(sqlx::Error::PoolClosed));
+    track_peer_result(&pool, &events, "dave", 2, &local).await;
+    assert!(rx.try_recv().is_err());
+
+    let health = load_peer_health(&pool).await;
+    assert_eq!(health["dave"].consecutive_failures, 2);
+    assert!(due_friends(friends, &health).is_empty());
+}
+
+#[tokio::test]
+async fn test_refused_friend_request_is_not_retryable() {
+    let server = MockServer::start();
+    let _mock = server.mock(|when, then| {
+        when.method(POST).path("/friend_request");
+        then.status(400);
+    });
+
+    let friend = Friend {
+        id: 1,
+        username: "carol".into(),
+        address: server.address().to_string(),
+        status: 2,
+        added_at: None,
+        peer_key: None,
+        intro: None,
+        fingerprint: None,
+        invite_token: None,
+        relay_address: None,
+        nickname: None,
+        notes: None,
+    };
+
+    let pool = setup_test_db().await;
+    let error = send_friend_request(
+        &pool,
+        &HttpTransport::default(),
+        &ProtocolVersions::new(),
+        "bob",
+        &friend,
+        "127.0.0.1",
+        &Identity::generate(),
+    )
+    .await
+    .unwrap_err();
+    assert!(matches!(
+        error,
+        Error::Protocol {
+            status: Some(400),
+            ..
+        }
+    ));
+    assert!(!error.is_retryable());
+
+    // nobody listening is a network failure worth retrying
+    let gone = Friend {
+        address: "127.0.0.1:1".into(),
+        ..friend
+    };
+    let error = send_friend_request(
+        &pool,
+        &HttpTransport::default(),
+        &ProtocolVersions::new(),
+        "bob",
+        &gone,
+        "127.0.0.1",
+        &Identity::generate(),
+    )
+    .await
+    .unwrap_err();
+    assert!(matches!(error, Error::Network(_)));
+    assert!(error.is_retryable());
+}
+
+#[tokio::test]
+async fn test_protocol_version_is_negotiated_once() {
+    let server = MockServer::start();
+    let info = server.mock(|when, then| {
+        when.method(GET).path("/info");
+        then.status(200).json_body_obj(&NodeInfo {
+            protocol_versions: vec![1, 7],
+            username: "alice".into(),
+            address: server.address().to_string(),
+            fingerprint: None,
+            capabilities: vec![],
+            software: "mankeli-chat/9.0.0".into(),
+        });
+    });
+    let address = server.address().to_string();
+    let transport = HttpTransport::default();
+    let versions = ProtocolVersions::new();
+
+    assert_eq!(versions.negotiate(&transport, &address).await.unwrap(), 1);
+    assert_eq!(versions.negotiate(&transport, &address).await.unwrap(), 1);
+    info.assert_hits(1);
+
+    versions.forget(&address);
+    versions.negotiate(&transport, &address).await.unwrap();
+    info.assert_hits(2);
+
+    // a node without /info speaks the unprefixed routes
+    let legacy = MockServer::start();
+    let legacy_address = legacy.address().to_string();
+    assert_eq!(
+        versions
+            .negotiate(&transport, &legacy_address)
+            .await
+            .unwrap(),
+        LEGACY_VERSION
+    );
+    assert_eq!(versions.get(&legacy_address), Some(LEGACY_VERSION));
+
+    let future = MockServer::start();
+    future.mock(|when, then| {
+        when.method(GET).path("/info");
+        then.status(200).json_body_obj(&NodeInfo {
+            protocol_versions: vec![7],
+            username: "carol".into(),
+            address: future.address().to_string(),
+            fingerprint: None,
+            capabilities: vec![],
+            software: "mankeli-chat/9.0.0".into(),
+        });
+    });
+    let future_address = future.address().to_string();
+    assert!(
+        versions
+            .negotiate(&transport, &future_address)
+            .await
+            .is_err()
+    );
+    assert_eq!(versions.get(&future_address), None);
+}
@@ -0,0 +1,652 @@
+// Layered configuration
+// defaults < config file < MANKELI_* environment variables < command line
+// flags. Every value is validated once all layers are applied.
+
+use crate::discovery::DiscoveryConfig;
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[cfg(test)]
+mod tests;
+
+pub const ENV_PREFIX: &str = "MANKELI_";
+// advertised_address value asking for interface auto-detection
+pub const AUTO_ADDRESS: &str = "auto";
+// metrics_address value mounting /metrics on the public peer server
+pub const PEER_SERVER: &str = "peer";
+// Longest invite expiry we accept, keeps the expiry arithmetic in range
+pub const MAX_INVITE_EXPIRY_DAYS: u64 = 100 * 365;
+const LOG_LEVELS: [&str; 6] = ["off", "error", "warn", "info", "debug", "trace"];
+const LOG_FORMATS: [&str; 2] = ["text", "json"];
+const LOG_ROTATIONS: [&str; 4] = ["minutely", "hourly", "daily", "never"];
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub data_dir: PathBuf,
+    pub database: PathBuf,
+    // control socket of daemon mode, relative paths are inside the data dir
+    pub control_socket: PathBuf,
+    // where the peer server listens
+    pub bind_address: String,
+    // what friends are told to dial, None falls back to bind_address
+    pub advertised_address: Option<String>,
+    // where each friend's poll interval starts, and the longest pause
+    // between message fetch cycles
+    pub message_fetch_interval: u64,
+    // bounds of the per-friend poll interval
+    pub message_fetch_min_interval: u64,
+    pub message_fetch_max_interval: u64,
+    pub friend_fetch_interval: u64,
+    // how many friends each fetch cycle talks to at once
+    pub message_fetch_concurrency: usize,
+    pub friend_fetch_concurrency: usize,
+    // seconds a peer or relay gets to accept our connection, and to answer
+    pub connect_timeout: u64,
+    pub request_timeout: u64,
+    // 0 keeps invites pending forever
+    pub invite_expiry_days: u64,
+    pub relay_address: Option<String>,
+    // separate listener for /metrics, PEER_SERVER serves it on the peer
+    // server and None not at all
+    pub metrics_address: Option<String>,
+    pub discovery: Option<DiscoveryConfig>,
+    pub log_level: String,
+    // text or json
+    pub log_format: String,
+    // log files go here, relative paths are inside the data dir
+    pub log_dir: PathBuf,
+    pub log_rotation: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            data_dir: PathBuf::from("."),
+            database: PathBuf::from("mankeli.db"),
+            control_socket: PathBuf::from("mankeli.sock"),
+            bind_address: "127.0.0.1:8080".to_string(),
+            advertised_address: None,
+            message_fetch_interval: 30,
+            message_fetch_min_interval: 5,
+            message_fetch_max_interval: 300,
+            friend_fetch_interval: 15,
+            message_fetch_concurrency: 10,
+            friend_fetch_concurrency: 5,
+            connect_timeout: 10,
+            request_timeout: 30,
+            invite_expiry_days: 14,
+            relay_address: None,
+            metrics_address: None,
+            discovery: None,
+            log_level: "warn".to_string(),
+            log_format: "text".to_string(),
+            log_dir: PathBuf::from("logs"),
+            log_rotation: "daily".to_string(),
+        }
+    }
+}
+
+// One layer of settings, anything left out falls through to the layer below
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigLayer {
+    pub data_dir: Option<PathBuf>,
+    pub database: Option<PathBuf>,
+    pub control_socket: Option<PathBuf>,
+    // older config files only know server_address
+    #[serde(alias = "server_address")]
+    pub bind_address: Option<String>,
+    pub advertised_address: Option<String>,
+    pub message_fetch_interval: Option<u64>,
+    pub message_fetch_min_interval: Option<u64>,
+    pub message_fetch_max_interval: Option<u64>,
+    pub friend_fetch_interval: Option<u64>,
+    pub message_fetch_concurrency: Option<usize>,
+    pub friend_fetch_concurrency: Option<usize>,
+    pub connect_timeout: Option<u64>,
+    pub request_timeout: Option<u64>,
+    pub invite_expiry_days: Option<u64>,
+    pub relay_address: Option<String>,
+    pub metrics_address: Option<String>,
+    pub discovery: Option<DiscoveryConfig>,
+    pub log_level: Option<String>,
+    pub log_format: Option<String>,
+    pub log_dir: Option<PathBuf>,
+    pub log_rotation: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Parser)]
+#[command(name = "mankeli-chat", about = "Peer-to-peer terminal chat", version)]
+pub struct CliArgs {
+    /// Config file (default: <data dir>/config.json)
+    #[arg(short, long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    /// Directory holding the database and config file
+    #[arg(long, value_name = "DIR")]
+    pub data_dir: Option<PathBuf>,
+    /// SQLite database file, relative paths are inside the data dir
+    #[arg(long, value_name = "FILE")]
+    pub database: Option<PathBuf>,
+    /// Control socket used by --daemon and --attach
+    #[arg(long, value_name = "FILE")]
+    pub control_socket: Option<PathBuf>,
+    /// Address the peer server listens on
+    #[arg(long, value_name = "HOST:PORT")]
+    pub bind_address: Option<String>,
+    /// Address friends should connect to, or "auto" to detect one
+    #[arg(long, value_name = "HOST:PORT")]
+    pub advertised_address: Option<String>,
+    /// Seconds between message fetch cycles, and each friend's first poll interval
+    #[arg(long, value_name = "SECS")]
+    pub message_fetch_interval: Option<u64>,
+    /// Shortest poll interval, for friends we are talking to
+    #[arg(long, value_name = "SECS")]
+    pub message_fetch_min_interval: Option<u64>,
+    /// Longest poll interval, for friends that have gone quiet
+    #[arg(long, value_name = "SECS")]
+    pub message_fetch_max_interval: Option<u64>,
+    /// Seconds between friend request delivery cycles
+    #[arg(long, value_name = "SECS")]
+    pub friend_fetch_interval: Option<u64>,
+    /// Friends polled at once in each message fetch cycle
+    #[arg(long, value_name = "N")]
+    pub message_fetch_concurrency: Option<usize>,
+    /// Friends sent updates at once in each friend fetch cycle
+    #[arg(long, value_name = "N")]
+    pub friend_fetch_concurrency: Option<usize>,
+    /// Seconds a peer or relay gets to accept a connection
+    #[arg(long, value_name = "SECS")]
+    pub connect_timeout: Option<u64>,
+    /// Seconds a peer or relay gets to answer a request
+    #[arg(long, value_name = "SECS")]
+    pub request_timeout: Option<u64>,
+    /// Days before unanswered invites expire, 0 disables expiry
+    #[arg(long, value_name = "DAYS")]
+    pub invite_expiry_days: Option<u64>,
+    /// Relay holding our mailbox
+    #[arg(long, value_name = "HOST:PORT")]
+    pub relay_address: Option<String>,
+    /// Serve /metrics on this listener, or "peer" for the peer server
+    #[arg(long, value_name = "HOST:PORT")]
+    pub metrics_address: Option<String>,
+    /// off, error, warn, info, debug or trace
+    #[arg(long, value_name = "LEVEL")]
+    pub log_level: Option<String>,
+    /// text or json
+    #[arg(long, value_name = "FORMAT")]
+    pub log_format: Option<String>,
+    /// Directory for log files, relative paths are inside the data dir
+    #[arg(long, value_name = "DIR")]
+    pub log_dir: Option<PathBuf>,
+    /// minutely, hourly, daily or never
+    #[arg(long, value_name = "PERIOD")]
+    pub log_rotation: Option<String>,
+    /// Run headless: only the server, fetchers and the control socket
+    #[arg(long, conflicts_with = "attach")]
+    pub daemon: bool,
+    /// Drive a running daemon through its control socket
+    #[arg(long)]
+    pub attach: bool,
+    /// Full-screen terminal UI instead of the command prompt
+    #[arg(long, conflicts_with_all = ["daemon", "attach"])]
+    pub tui: bool,
+    /// Username for a new node, instead of asking for it
+    #[arg(long, value_name = "NAME")]
+    pub username: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        error: String,
+    },
+    Invalid {
+        field: String,
+        reason: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read { path, error } => {
+                write!(f, "cannot read config file {}: {}", path.display(), error)
+            }
+            ConfigError::Parse { path, error } => {
+                write!(f, "invalid config file {}: {}", path.display(), error)
+            }
+            ConfigError::Invalid { field, reason } => {
+                write!(f, "invalid value for {}: {}", field, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+// When a changed setting takes effect
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Applies {
+    // the running tasks pick it up
+    Live,
+    // a listener has to be bound again
+    Rebind,
+    Restart,
+    // should have applied live but didn't, the old value stays
+    Failed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConfigChange {
+    pub field: String,
+    pub old: String,
+    pub new: String,
+    pub applies: Applies,
+}
+
+impl fmt::Display for ConfigChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {} -> {}", self.field, self.old, self.new)?;
+        match self.applies {
+            Applies::Live => Ok(()),
+            Applies::Rebind => write!(f, " (restart the node to rebind)"),
+            Applies::Restart => write!(f, " (takes effect after a restart)"),
+            Applies::Failed => write!(f, " (failed to apply, kept the old value)"),
+        }
+    }
+}
+
+fn invalid(field: &str, reason: impl Into<String>) -> ConfigError {
+    ConfigError::Invalid {
+        field: field.to_string(),
+        reason: reason.into(),
+    }
+}
+
+impl From<CliArgs> for ConfigLayer {
+    fn from(args: CliArgs) -> Self {
+        ConfigLayer {
+            data_dir: args.data_dir,
+            database: args.database,
+            control_socket: args.control_socket,
+            bind_address: args.bind_address,
+            advertised_address: args.advertised_address,
+            message_fetch_interval: args.message_fetch_interval,
+            message_fetch_min_interval: args.message_fetch_min_interval,
+            message_fetch_max_interval: args.message_fetch_max_interval,
+            friend_fetch_interval: args.friend_fetch_interval,
+            message_fetch_concurrency: args.message_fetch_concurrency,
+            friend_fetch_concurrency: args.friend_fetch_concurrency,
+            connect_timeout: args.connect_timeout,
+            request_timeout: args.request_timeout,
+            invite_expiry_days: args.invite_expiry_days,
+            relay_address: args.relay_address,
+            metrics_address: args.metrics_address,
+            discovery: None,
+            log_level: args.log_level,
+            log_format: args.log_format,
+            log_dir: args.log_dir,
+            log_rotation: args.log_rotation,
+        }
+    }
+}
+
+impl ConfigLayer {
+    // Reads MANKELI_<FIELD> variables through `var`, so tests don't need the real environment
+    pub fn from_env(var: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        let get = |field: &str| var(&format!("{}{}", ENV_PREFIX, field.to_uppercase()));
+        let number = |field: &str| -> Result<Option<u64>, ConfigError> {
+            get(field)
+                .map(|value| {
+                    value.trim().parse::<u64>().map_err(|e| {
+                        invalid(
+                            &format!("{} ({}{})", field, ENV_PREFIX, field.to_uppercase()),
+                            e.to_string(),
+                        )
+                    })
+                })
+                .transpose()
+        };
+
+        Ok(ConfigLayer {
+            data_dir: get("data_dir").map(PathBuf::from),
+            database: get("database").map(PathBuf::from),
+            control_socket: get("control_socket").map(PathBuf::from),
+            bind_address: get("bind_address").or_else(|| get("server_address")),
+            advertised_address: get("advertised_address"),
+            message_fetch_interval: number("message_fetch_interval")?,
+            message_fetch_min_interval: number("message_fetch_min_interval")?,
+            message_fetch_max_interval: number("message_fetch_max_interval")?,
+            friend_fetch_interval: number("friend_fetch_interval")?,
+            message_fetch_concurrency: number("message_fetch_concurrency")?.map(|n| n as usize),
+            friend_fetch_concurrency: number("friend_fetch_concurrency")?.map(|n| n as usize),
+            connect_timeout: number("connect_timeout")?,
+            request_timeout: number("request_timeout")?,
+            invite_expiry_days: number("invite_expiry_days")?,
+            relay_address: get("relay_address"),
+            metrics_address: get("metrics_address"),
+            discovery: None,
+            log_level: get("log_level"),
+            log_format: get("log_format"),
+            log_dir: get("log_dir").map(PathBuf::from),
+            log_rotation: get("log_rotation"),
+        })
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let data = std::fs::read_to_string(path).map_err(|error| ConfigError::Read {
+            path: path.to_path_buf(),
+            error,
+        })?;
+        serde_json::from_str(&data).map_err(|e| ConfigError::Parse {
+            path: path.to_path_buf(),
+            error: e.to_string(),
+        })
+    }
+}
+
+impl Config {
+    pub fn apply(&mut self, layer: ConfigLayer) {
+        macro_rules! set {
+            ($($field:ident),*) => {
+                $(if let Some(value) = layer.$field {
+                    self.$field = value;
+                })*
+            };
+        }
+        set!(
+            data_dir,
+            database,
+            control_socket,
+            bind_address,
+            message_fetch_interval,
+            message_fetch_min_interval,
+            message_fetch_max_interval,
+            friend_fetch_interval,
+            message_fetch_concurrency,
+            friend_fetch_concurrency,
+            connect_timeout,
+            request_timeout,
+            invite_expiry_days,
+            log_level,
+            log_format,
+            log_dir,
+            log_rotation
+        );
+        if layer.advertised_address.is_some() {
+            self.advertised_address = layer.advertised_address;
+        }
+        if layer.relay_address.is_some() {
+            self.relay_address = layer.relay_address;
+        }
+        if layer.metrics_address.is_some() {
+            self.metrics_address = layer.metrics_address;
+        }
+        if layer.discovery.is_some() {
+            self.discovery = layer.discovery;
+        }
+    }
+
+    // Loads all layers from the process environment and the given flags
+    pub fn load(args: CliArgs) -> Result<Config, ConfigError> {
+        Config::load_with(args, |key| std::env::var(key).ok())
+    }
+
+    pub fn load_with(
+        args: CliArgs,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Result<Config, ConfigError> {
+        let env = ConfigLayer::from_env(&var)?;
+
+        let (path, explicit) = Config::file_path(&args, &var);
+        let file = if explicit || path.exists() {
+            Some(ConfigLayer::from_file(&path)?)
+        } else {
+            None
+        };
+
+        let mut config = Config::default();
+        if let Some(file) = file {
+            config.apply(file);
+        }
+        config.apply(env);
+        config.apply(args.into());
+        config.validate()?;
+        Ok(config)
+    }
+
+    // The config file, and whether it was named explicitly. Its location can
+    // only come from the environment or flags.
+    pub fn file_path(args: &CliArgs, var: impl Fn(&str) -> Option<String>) -> (PathBuf, bool) {
+        if let Some(path) = args
+            .config
+            .clone()
+            .or_else(|| var(&format!("{}CONFIG", ENV_PREFIX)).map(PathBuf::from))
+        {
+            return (path, true);
+        }
+        let data_dir = args
+            .data_dir
+            .clone()
+            .or_else(|| var(&format!("{}DATA_DIR", ENV_PREFIX)).map(PathBuf::from))
+            .unwrap_or_else(|| Config::default().data_dir);
+        (data_dir.join("config.json"), false)
+    }
+
+    // What differs in `new`, and when each difference takes effect
+    pub fn changes(&self, new: &Config) -> Vec<ConfigChange> {
+        let mut changes = Vec::new();
+        macro_rules! diff {
+            ($applies:expr => $($field:ident),*) => {
+                $(if self.$field != new.$field {
+                    changes.push(ConfigChange {
+                        field: stringify!($field).to_string(),
+                        old: format!("{:?}", self.$field),
+                        new: format!("{:?}", new.$field),
+                        applies: $applies,
+                    });
+                })*
+            };
+        }
+        diff!(Applies::Live =>
+            message_fetch_interval,
+            message_fetch_min_interval,
+            message_fetch_max_interval,
+            friend_fetch_interval,
+            message_fetch_concurrency,
+            friend_fetch_concurrency,
+            invite_expiry_days,
+            log_level
+        );
+        diff!(Applies::Rebind => bind_address, metrics_address, control_socket, discovery);
+        diff!(Applies::Restart =>
+            data_dir,
+            database,
+            advertised_address,
+            relay_address,
+            connect_timeout,
+            request_timeout,
+            log_format,
+            log_dir,
+            log_rotation
+        );
+        changes
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.database.as_os_str().is_empty() {
+            return Err(invalid("database", "must not be empty"));
+        }
+        if self.control_socket.as_os_str().is_empty() {
+            return Err(invalid("control_socket", "must not be empty"));
+        }
+        validate_address("bind_address", &self.bind_address)?;
+        if let Some(advertised) = &self.advertised_address
+            && advertised != AUTO_ADDRESS
+        {
+            validate_address("advertised_address", advertised)?;
+        }
+        if let Some(relay) = &self.relay_address {
+            validate_address("relay_address", relay)?;
+        }
+        if let Some(metrics) = &self.metrics_address
+            && metrics != PEER_SERVER
+        {
+            validate_address("metrics_address", metrics)?;
+        }
+        if self.message_fetch_interval == 0 {
+            return Err(invalid(
+                "message_fetch_interval",
+                "must be at least 1 second",
+            ));
+        }
+        if self.message_fetch_min_interval == 0 {
+            return Err(invalid(
+                "message_fetch_min_interval",
+                "must be at least 1 second",
+            ));
+        }
+        if self.message_fetch_max_interval < self.message_fetch_min_interval {
+            return Err(invalid(
+                "message_fetch_max_interval",
+                "must not be below message_fetch_min_interval",
+            ));
+        }
+        if self.friend_fetch_interval == 0 {
+            return Err(invalid(
+                "friend_fetch_interval",
+                "must be at least 1 second",
+            ));
+        }
+        if self.message_fetch_concurrency == 0 {
+            return Err(invalid("message_fetch_concurrency", "must be at least 1"));
+        }
+        if self.friend_fetch_concurrency == 0 {
+            return Err(invalid("friend_fetch_concurrency", "must be at least 1"));
+        }
+        if self.connect_timeout == 0 {
+            return Err(invalid("connect_timeout", "must be at least 1 second"));
+        }
+        if self.request_timeout == 0 {
+            return Err(invalid("request_timeout", "must be at least 1 second"));
+        }
+        if self.invite_expiry_days > MAX_INVITE_EXPIRY_DAYS {
+            return Err(invalid(
+                "invite_expiry_days",
+                format!("must be at most {}", MAX_INVITE_EXPIRY_DAYS),
+            ));
+        }
+        one_of("log_level", &self.log_level, &LOG_LEVELS)?;
+        one_of("log_format", &self.log_format, &LOG_FORMATS)?;
+        one_of("log_rotation", &self.log_rotation, &LOG_ROTATIONS)?;
+        Ok(())
+    }
+
+    // Address we hand out to friends. "auto", or an unspecified bind address
+    // like 0.0.0.0, picks the interface address used for outbound traffic.
+    pub fn resolve_advertised_address(&self) -> Result<String, ConfigError> {
+        let unspecified = address_ip(&self.bind_address).is_some_and(|ip| ip.is_unspecified());
+        match self.advertised_address.as_deref() {
+            Some(AUTO_ADDRESS) => {}
+            Some(address) => return Ok(address.to_string()),
+            None if !unspecified => return Ok(self.bind_address.clone()),
+            None => {}
+        }
+
+        let port = self
+            .bind_address
+            .rsplit_once(':')
+            .map(|(_, port)| port)
+            .unwrap_or_default();
+        match detect_interface_ip() {
+            Some(ip) => Ok(SocketAddr::new(ip, port.parse().unwrap_or_default()).to_string()),
+            None => Err(invalid(
+                "advertised_address",
+                "no non-loopback interface address found, set it explicitly",
+            )),
+        }
+    }
+
+    // How long our unanswered invites stay pending, None keeps them forever
+    pub fn invite_expiry(&self) -> Option<Duration> {
+        (self.invite_expiry_days > 0)
+            .then(|| Duration::from_secs(self.invite_expiry_days * 24 * 60 * 60))
+    }
+
+    // Database file with relative paths resolved against the data dir
+    pub fn database_path(&self) -> PathBuf {
+        self.data_dir.join(&self.database)
+    }
+
+    pub fn log_dir_path(&self) -> PathBuf {
+        self.data_dir.join(&self.log_dir)
+    }
+
+    pub fn control_socket_path(&self) -> PathBuf {
+        self.data_dir.join(&self.control_socket)
+    }
+}
+
+fn one_of(field: &str, value: &str, allowed: &[&str]) -> Result<(), ConfigError> {
+    if allowed.contains(&value.to_lowercase().as_str()) {
+        Ok(())
+    } else {
+        Err(invalid(
+            field,
+            format!("must be one of {}", allowed.join(", ")),
+        ))
+    }
+}
+
+fn address_ip(address: &str) -> Option<IpAddr> {
+    let (host, _) = address.rsplit_once(':')?;
+    host.trim_matches(['[', ']']).parse().ok()
+}
+
+// True for addresses only reachable from this machine
+pub fn is_loopback_address(address: &str) -> bool {
+    match address_ip(address) {
+        Some(ip) => ip.is_loopback(),
+        None => address
+            .rsplit_once(':')
+            .is_some_and(|(host, _)| host.eq_ignore_ascii_case("localhost")),
+    }
+}
+
+// Connecting a UDP socket sends nothing, it only makes the OS pick the
+// source address it would route through
+fn detect_interface_ip() -> Option<IpAddr> {
+    ["8.8.8.8:80", "[2001:4860:4860::8888]:80"]
+        .iter()
+        .find_map(|target| {
+            let bind = if target.starts_with('[') {
+                "[::]:0"
+            } else {
+                "0.0.0.0:0"
+            };
+            let socket = UdpSocket::bind(bind).ok()?;
+            socket.connect(target).ok()?;
+            let ip = socket.local_addr().ok()?.ip();
+            (!ip.is_loopback() && !ip.is_unspecified()).then_some(ip)
+        })
+}
+
+fn validate_address(field: &str, address: &str) -> Result<(), ConfigError> {
+    match address.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(()),
+        _ => Err(invalid(
+            field,
+            format!("'{}' is not in host:port form", address),
+        )),
+    }
+}
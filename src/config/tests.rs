@@ -0,0 +1,185 @@
+use super::*;
+use std::collections::HashMap;
+use tempfile::TempDir;
+
+fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+    let vars: HashMap<String, String> = vars
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    move |key| vars.get(key).cloned()
+}
+
+// removed again when dropped
+fn temp_dir() -> TempDir {
+    tempfile::tempdir().unwrap()
+}
+
+#[test]
+fn test_defaults_without_file() {
+    let dir = temp_dir();
+    let args = CliArgs {
+        data_dir: Some(dir.path().to_path_buf()),
+        ..Default::default()
+    };
+    let config = Config::load_with(args, env(&[])).unwrap();
+    assert_eq!(config.bind_address, "127.0.0.1:8080");
+    assert_eq!(config.message_fetch_interval, 30);
+    assert!(config.database_path().ends_with("mankeli.db"));
+}
+
+#[test]
+fn test_layers_override_in_order() {
+    let dir = temp_dir();
+    std::fs::write(
+        dir.path().join("config.json"),
+        r#"{ "server_address": "0.0.0.0:3000", "message_fetch_interval": 5, "friend_fetch_interval": 7 }"#,
+    )
+    .unwrap();
+
+    let args = CliArgs {
+        data_dir: Some(dir.path().to_path_buf()),
+        friend_fetch_interval: Some(9),
+        ..Default::default()
+    };
+    let config = Config::load_with(
+        args,
+        env(&[
+            ("MANKELI_MESSAGE_FETCH_INTERVAL", "6"),
+            ("MANKELI_FRIEND_FETCH_INTERVAL", "8"),
+            ("MANKELI_LOG_LEVEL", "debug"),
+        ]),
+    )
+    .unwrap();
+
+    assert_eq!(config.bind_address, "0.0.0.0:3000"); // file, legacy key
+    assert_eq!(config.message_fetch_interval, 6); // env beats file
+    assert_eq!(config.friend_fetch_interval, 9); // flag beats env
+    assert_eq!(config.log_level, "debug");
+}
+
+#[test]
+fn test_errors_name_the_bad_field() {
+    let dir = temp_dir();
+
+    let err = Config::load_with(
+        CliArgs {
+            data_dir: Some(dir.path().to_path_buf()),
+            ..Default::default()
+        },
+        env(&[("MANKELI_MESSAGE_FETCH_INTERVAL", "soon")]),
+    )
+    .unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("message_fetch_interval (MANKELI_MESSAGE_FETCH_INTERVAL)"),
+        "{}",
+        err
+    );
+
+    let err = Config::load_with(
+        CliArgs {
+            data_dir: Some(dir.path().to_path_buf()),
+            bind_address: Some("localhost".into()),
+            ..Default::default()
+        },
+        env(&[]),
+    )
+    .unwrap_err();
+    assert!(err.to_string().contains("bind_address"), "{}", err);
+
+    let err = Config::load_with(
+        CliArgs {
+            data_dir: Some(dir.path().to_path_buf()),
+            ..Default::default()
+        },
+        env(&[("MANKELI_METRICS_ADDRESS", "9100")]),
+    )
+    .unwrap_err();
+    assert!(err.to_string().contains("metrics_address"), "{}", err);
+
+    // "peer" is not an address but opts into the peer server
+    let config = Config::load_with(
+        CliArgs {
+            data_dir: Some(dir.path().to_path_buf()),
+            ..Default::default()
+        },
+        env(&[("MANKELI_METRICS_ADDRESS", PEER_SERVER)]),
+    )
+    .unwrap();
+    assert_eq!(config.metrics_address.as_deref(), Some(PEER_SERVER));
+
+    let err = Config::load_with(
+        CliArgs {
+            data_dir: Some(dir.path().to_path_buf()),
+            message_fetch_min_interval: Some(60),
+            ..Default::default()
+        },
+        env(&[("MANKELI_MESSAGE_FETCH_MAX_INTERVAL", "10")]),
+    )
+    .unwrap_err();
+    assert!(
+        err.to_string().contains("message_fetch_max_interval"),
+        "{}",
+        err
+    );
+
+    let err = Config::load_with(
+        CliArgs {
+            data_dir: Some(dir.path().to_path_buf()),
+            friend_fetch_interval: Some(0),
+            ..Default::default()
+        },
+        env(&[]),
+    )
+    .unwrap_err();
+    assert!(err.to_string().contains("friend_fetch_interval"), "{}", err);
+
+    let err = Config::load_with(
+        CliArgs {
+            data_dir: Some(dir.path().to_path_buf()),
+            ..Default::default()
+        },
+        env(&[("MANKELI_INVITE_EXPIRY_DAYS", "18446744073709551615")]),
+    )
+    .unwrap_err();
+    assert!(err.to_string().contains("invite_expiry_days"), "{}", err);
+}
+
+#[test]
+fn test_explicit_config_file_must_exist() {
+    let args = CliArgs {
+        config: Some(PathBuf::from("/nonexistent/mankeli.json")),
+        ..Default::default()
+    };
+    assert!(matches!(
+        Config::load_with(args, env(&[])),
+        Err(ConfigError::Read { .. })
+    ));
+}
+
+#[test]
+fn test_advertised_address_resolution() {
+    let mut config = Config::default();
+    assert_eq!(
+        config.resolve_advertised_address().unwrap(),
+        "127.0.0.1:8080"
+    );
+    assert!(is_loopback_address("127.0.0.1:8080"));
+    assert!(is_loopback_address("localhost:8080"));
+    assert!(!is_loopback_address("192.168.1.20:8080"));
+
+    config.bind_address = "0.0.0.0:8080".into();
+    config.advertised_address = Some("chat.example.org:443".into());
+    assert_eq!(
+        config.resolve_advertised_address().unwrap(),
+        "chat.example.org:443"
+    );
+
+    // detection depends on the machine, but never yields loopback
+    config.advertised_address = Some(AUTO_ADDRESS.into());
+    if let Ok(address) = config.resolve_advertised_address() {
+        assert!(!is_loopback_address(&address));
+        assert!(address.ends_with(":8080"));
+    }
+}
@@ -0,0 +1,362 @@
+// Protocol conformance checks
+// Points at any node address over plain HTTP and checks that it answers
+// the way the peer protocol (see /openapi.json) says, so other
+// implementations can be held to the same expectations as this one.
+// check_node only needs the address. It leaves a pending invite from a
+// "conformance-..." user behind, which the node's owner can reject.
+// check_handshake also plays the peer the node talks back to. It needs a
+// one-time invite token from the node and a listener the node can reach.
+
+use crate::api::{
+    ErrorBody, FetchMessageInput, FetchMessageResponse, FriendInput, FriendRequestAck,
+    FriendRequestStatus, LEGACY_VERSION, MAX_INTRO_LEN, NodeInfo, PROTOCOL_VERSIONS, app,
+    highest_common_version, versioned_path,
+};
+use crate::comms::{ProtocolVersions, process_friend_messages, send_friend_request};
+use crate::db::{FriendRequest, OutgoingMessage};
+use crate::events::EventBus;
+use crate::store::{FriendStore, MemoryStore, MessageStore};
+use crate::transport::HttpTransport;
+use reqwest::{Client, StatusCode};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::fmt;
+use std::future::{Future, IntoFuture};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+
+#[cfg(test)]
+mod tests;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub result: Result<(), String>,
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|check| check.result.is_ok())
+    }
+
+    fn record(&mut self, name: &'static str, result: Result<(), String>) {
+        self.checks.push(Check { name, result });
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            match &check.result {
+                Ok(()) => writeln!(f, "ok    {}", check.name)?,
+                Err(reason) => writeln!(f, "FAIL  {}: {}", check.name, reason)?,
+            }
+        }
+        let failed = self.checks.iter().filter(|c| c.result.is_err()).count();
+        write!(f, "{} checks, {} failed", self.checks.len(), failed)
+    }
+}
+
+fn stranger() -> String {
+    format!("conformance-{:08x}", rand::random::<u32>())
+}
+
+// Sends `body` and returns the status plus the raw response text
+async fn post<B: Serialize>(
+    client: &Client,
+    url: &str,
+    body: &B,
+) -> Result<(StatusCode, String), String> {
+    let res = client
+        .post(url)
+        .json(body)
+        .send()
+        .await
+        .map_err(|e| format!("request failed: {}", e))?;
+    let status = res.status();
+    let text = res.text().await.map_err(|e| e.to_string())?;
+    Ok((status, text))
+}
+
+fn expect<T: DeserializeOwned>(
+    (status, text): (StatusCode, String),
+    expected: StatusCode,
+) -> Result<T, String> {
+    if status != expected {
+        return Err(format!("expected {}, got {}: {}", expected, status, text));
+    }
+    serde_json::from_str(&text).map_err(|e| format!("unexpected body {}: {}", text, e))
+}
+
+// Everything a stranger can check without the node's owner doing anything
+pub async fn check_node(client: &Client, address: &str) -> Report {
+    let mut report = Report::default();
+
+    let info = fetch_info(client, address).await;
+    let (target, version) = match &info {
+        Ok((info, version)) => (info.username.clone(), *version),
+        // keep going on the legacy routes, the rest still says something
+        Err(_) => (String::new(), LEGACY_VERSION),
+    };
+    report.record("info", info.map(|_| ()));
+
+    let url = |route| format!("http://{}{}", address, versioned_path(version, route));
+    let me = stranger();
+    let my_address = "conformance.invalid:1".to_string();
+
+    let fetch = FetchMessageInput {
+        username: me.clone(),
+        address: my_address.clone(),
+    };
+    let result = post(client, &url("fetch_messages"), &fetch)
+        .await
+        .and_then(|res| expect::<FetchMessageResponse>(res, StatusCode::OK))
+        .and_then(|res| match res.messages.len() {
+            0 => Ok(()),
+            n => Err(format!("a stranger was handed {} messages", n)),
+        });
+    report.record("fetch_messages_for_stranger_is_empty", result);
+
+    let result = post(client, &url("fetch_messages"), &serde_json::json!({}))
+        .await
+        .and_then(|(status, text)| match status.is_client_error() {
+            true => Ok(()),
+            false => Err(format!("expected a 4xx, got {}: {}", status, text)),
+        });
+    report.record("fetch_messages_rejects_malformed_input", result);
+
+    let request = |hostname: &str, req_type, intro: Option<String>| FriendInput {
+        username: target.clone(),
+        hostname: hostname.to_string(),
+        address: my_address.clone(),
+        req_type,
+        peer_key: None,
+        intro,
+        invite_token: None,
+        signed_at: None,
+        signature: None,
+    };
+
+    let invite = request(
+        &me,
+        FriendRequestStatus::InviteSent,
+        Some("conformance check, safe to reject".into()),
+    );
+    let result = post(client, &url("friend_request"), &invite)
+        .await
+        .and_then(|res| expect::<FriendRequestAck>(res, StatusCode::OK))
+        .and_then(|ack| match ack.status.as_str() {
+            "invite_sent" => Ok(()),
+            other => Err(format!("expected status invite_sent, got {}", other)),
+        });
+    report.record("invite_is_recorded", result);
+
+    let overlong = request(
+        &stranger(),
+        FriendRequestStatus::InviteSent,
+        Some("a".repeat(MAX_INTRO_LEN + 1)),
+    );
+    let result = post(client, &url("friend_request"), &overlong)
+        .await
+        .and_then(|res| expect::<ErrorBody>(res, StatusCode::BAD_REQUEST))
+        .map(|_| ());
+    report.record("overlong_intro_is_rejected", result);
+
+    let accept = request(&stranger(), FriendRequestStatus::Accepted, None);
+    let result = post(client, &url("friend_request"), &accept)
+        .await
+        .and_then(|res| expect::<ErrorBody>(res, StatusCode::NOT_FOUND))
+        .map(|_| ());
+    report.record("accept_without_invite_is_not_found", result);
+
+    let reject = request(&stranger(), FriendRequestStatus::Rejected, None);
+    let result = post(client, &url("friend_request"), &reject)
+        .await
+        .and_then(|res| expect::<ErrorBody>(res, StatusCode::BAD_REQUEST))
+        .map(|_| ());
+    report.record("reject_without_invite_is_refused", result);
+
+    let bogus = request(&stranger(), FriendRequestStatus::InviteReceived, None);
+    let result = post(client, &url("friend_request"), &bogus)
+        .await
+        .and_then(|res| expect::<ErrorBody>(res, StatusCode::BAD_REQUEST))
+        .map(|_| ());
+    report.record("invite_received_is_refused", result);
+
+    report
+}
+
+async fn fetch_info(client: &Client, address: &str) -> Result<(NodeInfo, u32), String> {
+    let res = client
+        .get(format!("http://{}/info", address))
+        .send()
+        .await
+        .map_err(|e| format!("request failed: {}", e))?;
+    let status = res.status();
+    let text = res.text().await.map_err(|e| e.to_string())?;
+    let info: NodeInfo = expect((status, text), StatusCode::OK)?;
+    if info.username.is_empty() {
+        return Err("info has no username".into());
+    }
+    let version = highest_common_version(PROTOCOL_VERSIONS, &info.protocol_versions)
+        .ok_or_else(|| format!("no common version in {:?}", info.protocol_versions))?;
+    Ok((info, version))
+}
+
+// Polls `done` until it holds or `timeout` runs out
+async fn wait_for<F, Fut>(timeout: Duration, mut done: F) -> bool
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = bool>,
+{
+    let deadline = Instant::now() + timeout;
+    loop {
+        if done().await {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+// Befriends the node with `invite_token` and trades a message with it.
+// Our side is an in-memory node served on `listener`, which the target
+// must reach at `callback_address`. `timeout` bounds each wait for the
+// target's background fetchers, so give it more than their intervals.
+pub async fn check_handshake(
+    address: &str,
+    invite_token: &str,
+    listener: TcpListener,
+    callback_address: &str,
+    timeout: Duration,
+) -> Report {
+    let mut report = Report::default();
+    let client = Client::new();
+
+    let info = match fetch_info(&client, address).await {
+        Ok((info, _)) => info,
+        Err(e) => {
+            report.record("info", Err(e));
+            return report;
+        }
+    };
+    report.record("info", Ok(()));
+
+    let me = MemoryStore::new(&stranger(), callback_address);
+    let server =
+        tokio::spawn(axum::serve(listener, app(me.clone(), EventBus::new())).into_future());
+
+    let transport = HttpTransport::new(client);
+    let versions = ProtocolVersions::new();
+    let events = EventBus::new();
+    let user = me
+        .local_user()
+        .await
+        .expect("memory store always has a user");
+
+    let invite = FriendRequest {
+        username: info.username.clone(),
+        address: address.to_string(),
+        intro: Some("conformance check".into()),
+        // the target's acceptance must come with the key /info advertised
+        fingerprint: info.fingerprint.clone(),
+        invite_token: Some(invite_token.to_string()),
+    };
+    let sent: crate::error::Result<()> = async {
+        let identity = me.local_identity().await?;
+        me.send_invite(&invite).await?;
+        let (_, pending) = me.fetch_unsent_friend_updates().await?;
+        for friend in pending {
+            send_friend_request(
+                &me,
+                &transport,
+                &versions,
+                &user.username,
+                &friend,
+                &user.address,
+                &identity,
+            )
+            .await?;
+        }
+        Ok(())
+    }
+    .await;
+    let sent_ok = sent.is_ok();
+    report.record(
+        "invite_with_token_is_delivered",
+        sent.map_err(|e| e.to_string()),
+    );
+
+    let accepted = sent_ok
+        && wait_for(timeout, || async {
+            me.fetch_active_friends()
+                .await
+                .is_ok_and(|friends| friends.iter().any(|f| f.username == info.username))
+        })
+        .await;
+    report.record(
+        "target_sends_acceptance",
+        match (sent_ok, accepted) {
+            (false, _) => Err("skipped, the invite was not delivered".into()),
+            (true, false) => Err(format!("no acceptance within {:?}", timeout)),
+            (true, true) => Ok(()),
+        },
+    );
+
+    if accepted {
+        let message = OutgoingMessage {
+            send_to: info.username.clone(),
+            subject: "conformance".into(),
+            content: "conformance check message".into(),
+        };
+        let picked_up = me.queue_message(&message).await.is_ok()
+            && wait_for(timeout, || async {
+                me.pending_messages_for(&info.username)
+                    .await
+                    .is_ok_and(|pending| pending.is_empty())
+            })
+            .await;
+        report.record(
+            "target_fetches_queued_message",
+            match picked_up {
+                true => Ok(()),
+                false => Err(format!("message not fetched within {:?}", timeout)),
+            },
+        );
+
+        let friend = me
+            .fetch_active_friends()
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .find(|f| f.username == info.username);
+        let result = match friend {
+            Some(friend) => process_friend_messages(
+                &me,
+                &transport,
+                &versions,
+                &events,
+                &user.username,
+                &user.address,
+                &friend,
+            )
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string()),
+            None => Err("target is no longer a friend".into()),
+        };
+        report.record("fetch_from_target", result);
+    }
+
+    server.abort();
+    report
+}
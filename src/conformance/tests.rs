@@ -0,0 +1,128 @@
+use super::*;
+use crate::comms::{LocalNode, friend_fetcher, message_fetcher};
+use crate::config::Config;
+use crate::db::{MIGRATOR, User, create_invite_token, ensure_identity, setup_db};
+use crate::shutdown::Shutdown;
+use crate::store::MessageStore;
+use crate::wakeup::Wakeup;
+use sqlx::SqlitePool;
+use tokio::sync::watch;
+
+// A real node on a local port, fetchers included
+async fn target(shutdown: &Shutdown) -> (SqlitePool, String) {
+    let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+    MIGRATOR.run(&pool).await.unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let user = User {
+        id: 0,
+        username: "target".into(),
+        address: address.clone(),
+        peer_key: None,
+    };
+    setup_db(&pool, &user).await.unwrap();
+    ensure_identity(&pool).await.unwrap();
+
+    let events = EventBus::new();
+    let wakeup = Wakeup::new();
+    // short intervals, the checks wait on the fetchers
+    let (_, config) = watch::channel(Config {
+        friend_fetch_interval: 1,
+        message_fetch_interval: 1,
+        message_fetch_min_interval: 1,
+        message_fetch_max_interval: 1,
+        invite_expiry_days: 0,
+        ..Default::default()
+    });
+    tokio::spawn(axum::serve(listener, app(pool.clone(), events.clone())).into_future());
+    tokio::spawn(
+        wakeup
+            .clone()
+            .follow_events(events.subscribe(), shutdown.subscribe()),
+    );
+    tokio::spawn({
+        let (pool, events, config) = (pool.clone(), events.clone(), config.clone());
+        let pause = wakeup.friend_fetcher(shutdown.subscribe());
+        async move {
+            let versions = ProtocolVersions::new();
+            friend_fetcher(
+                &pool,
+                &HttpTransport::default(),
+                &versions,
+                &events,
+                config,
+                pause,
+            )
+            .await
+        }
+    });
+    tokio::spawn({
+        let (pool, address) = (pool.clone(), address.clone());
+        let pause = wakeup.message_fetcher(shutdown.subscribe());
+        async move {
+            let me = LocalNode {
+                username: "target",
+                address: &address,
+                relay: None,
+            };
+            let versions = ProtocolVersions::new();
+            message_fetcher(
+                &pool,
+                &HttpTransport::default(),
+                &versions,
+                &events,
+                me,
+                config,
+                pause,
+            )
+            .await
+        }
+    });
+    (pool, address)
+}
+
+#[tokio::test]
+async fn test_our_node_passes_check_node() {
+    let shutdown = Shutdown::new();
+    let (_pool, address) = target(&shutdown).await;
+    let report = check_node(&Client::new(), &address).await;
+    shutdown.trigger();
+    assert!(report.passed(), "{}", report);
+    assert_eq!(report.checks.len(), 8);
+}
+
+#[tokio::test]
+async fn test_unreachable_node_fails() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    drop(listener);
+    let report = check_node(&Client::new(), &address).await;
+    assert!(!report.passed());
+    assert!(report.to_string().contains("FAIL  info: request failed"));
+}
+
+#[tokio::test]
+async fn test_our_node_passes_check_handshake() {
+    let shutdown = Shutdown::new();
+    let (pool, address) = target(&shutdown).await;
+    let token = create_invite_token(&pool).await.unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let callback = listener.local_addr().unwrap().to_string();
+    let report = check_handshake(
+        &address,
+        &token,
+        listener,
+        &callback,
+        Duration::from_secs(5),
+    )
+    .await;
+    shutdown.trigger();
+    assert!(report.passed(), "{}", report);
+    assert_eq!(report.checks.len(), 5);
+
+    // the message we queued for the target landed in its inbox
+    let inbox = pool.fetch_inbox().await.unwrap();
+    assert_eq!(inbox.len(), 1);
+    assert_eq!(inbox[0].message, "conformance check message");
+}
@@ -0,0 +1,226 @@
+// Contact import and export
+// the friends table (with keys, addresses and local metadata) is written
+// to a versioned JSON document that another node can import
+
+use crate::StatusLabel;
+use crate::error::Result;
+use crate::store::FriendStore;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+#[cfg(test)]
+mod tests;
+
+pub const EXPORT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Contact {
+    pub username: String,
+    pub address: String,
+    pub status: String,
+    #[serde(default)]
+    pub peer_key: Option<String>,
+    #[serde(default)]
+    pub fingerprint: Option<String>,
+    #[serde(default)]
+    pub relay_address: Option<String>,
+    #[serde(default)]
+    pub nickname: Option<String>,
+    #[serde(default)]
+    pub notes: Option<String>,
+    #[serde(default)]
+    pub labels: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ContactExport {
+    pub version: u32,
+    pub contacts: Vec<Contact>,
+}
+
+// What to do when an imported username is already in our friends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    // keep the local entry untouched
+    Skip,
+    // replace the local entry with the imported one
+    Overwrite,
+    // keep local values, fill in whatever the local entry is missing
+    Merge,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    pub imported: usize,
+    pub skipped: usize,
+    pub updated: usize,
+    pub reinvited: usize,
+}
+
+pub(crate) fn status_code(status: &str) -> Option<i64> {
+    (0..=4).find(|code: &i64| code.status_str() == status)
+}
+
+pub async fn export_contacts<S: FriendStore>(store: &S) -> Result<ContactExport> {
+    let friends = store.fetch_friends().await?;
+    let mut labels = store.fetch_friend_labels().await?;
+
+    let contacts = friends
+        .into_iter()
+        .map(|f| Contact {
+            labels: labels.remove(&f.id).unwrap_or_default(),
+            status: f.status.status_str().to_string(),
+            username: f.username,
+            address: f.address,
+            peer_key: f.peer_key,
+            fingerprint: f.fingerprint,
+            relay_address: f.relay_address,
+            nickname: f.nickname,
+            notes: f.notes,
+        })
+        .collect();
+
+    Ok(ContactExport {
+        version: EXPORT_VERSION,
+        contacts,
+    })
+}
+
+// Re-creates friends from an export. With resend_pending, invites that
+// were still waiting for an answer are queued to be sent again
+pub async fn import_contacts(
+    pool: &SqlitePool,
+    export: &ContactExport,
+    policy: ConflictPolicy,
+    resend_pending: bool,
+) -> Result<ImportReport> {
+    let mut report = ImportReport::default();
+    let mut tx = pool.begin().await?;
+
+    for contact in &export.contacts {
+        let Some(status) = status_code(&contact.status) else {
+            report.skipped += 1;
+            continue;
+        };
+        let resend = resend_pending && status == 0;
+        // only resent invites should go out, everything else is already known to the peer
+        let sent = !resend;
+
+        let existing = sqlx::query_scalar!(
+            r#"SELECT id as "id!" FROM friends WHERE username = ?"#,
+            contact.username
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        // the row now holds a fresh outgoing invite
+        let mut reset = false;
+        let friend_id = match (existing, policy) {
+            (Some(_), ConflictPolicy::Skip) => {
+                report.skipped += 1;
+                continue;
+            }
+            (Some(id), ConflictPolicy::Overwrite) => {
+                // an intro or invite token of the old row doesn't belong to a reset invite
+                let pending = status == 0;
+                sqlx::query!(
+                    r#"
+                    UPDATE friends
+                    SET address = ?, status = ?, sent = ?, peer_key = ?, fingerprint = ?,
+                        relay_address = ?, nickname = ?, notes = ?,
+                        intro = CASE WHEN ? THEN NULL ELSE intro END,
+                        invite_token = CASE WHEN ? THEN NULL ELSE invite_token END
+                    WHERE id = ?
+                    "#,
+                    contact.address,
+                    status,
+                    sent,
+                    contact.peer_key,
+                    contact.fingerprint,
+                    contact.relay_address,
+                    contact.nickname,
+                    contact.notes,
+                    pending,
+                    pending,
+                    id
+                )
+                .execute(&mut *tx)
+                .await?;
+                sqlx::query!("DELETE FROM friend_labels WHERE friend_id = ?", id)
+                    .execute(&mut *tx)
+                    .await?;
+                report.updated += 1;
+                reset = resend;
+                id
+            }
+            (Some(id), ConflictPolicy::Merge) => {
+                sqlx::query!(
+                    r#"
+                    UPDATE friends
+                    SET peer_key = COALESCE(peer_key, ?), fingerprint = COALESCE(fingerprint, ?),
+                        relay_address = COALESCE(relay_address, ?),
+                        nickname = COALESCE(nickname, ?), notes = COALESCE(notes, ?)
+                    WHERE id = ?
+                    "#,
+                    contact.peer_key,
+                    contact.fingerprint,
+                    contact.relay_address,
+                    contact.nickname,
+                    contact.notes,
+                    id
+                )
+                .execute(&mut *tx)
+                .await?;
+                report.updated += 1;
+                id
+            }
+            (None, _) => {
+                let id = sqlx::query_scalar!(
+                    r#"
+                    INSERT INTO friends (username, address, status, sent, peer_key, fingerprint,
+                        relay_address, nickname, notes)
+                    VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+                    RETURNING id as "id!"
+                    "#,
+                    contact.username,
+                    contact.address,
+                    status,
+                    sent,
+                    contact.peer_key,
+                    contact.fingerprint,
+                    contact.relay_address,
+                    contact.nickname,
+                    contact.notes
+                )
+                .fetch_one(&mut *tx)
+                .await?;
+                report.imported += 1;
+                reset = resend;
+                id
+            }
+        };
+
+        for label in &contact.labels {
+            sqlx::query!(
+                "INSERT OR IGNORE INTO friend_labels (friend_id, label) VALUES (?, ?)",
+                friend_id,
+                label
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        if reset {
+            sqlx::query!(
+                "UPDATE friends SET added_at = CURRENT_TIMESTAMP WHERE id = ?",
+                friend_id
+            )
+            .execute(&mut *tx)
+            .await?;
+            report.reinvited += 1;
+        }
+    }
+
+    tx.commit().await?;
+    Ok(report)
+}
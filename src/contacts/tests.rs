@@ -0,0 +1,133 @@
+use super::*;
+use crate::db::{MIGRATOR, set_friend_details, set_friend_labels};
+
+async fn setup_test_db() -> SqlitePool {
+    let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+    MIGRATOR.run(&pool).await.unwrap();
+    pool
+}
+
+async fn seeded_db() -> SqlitePool {
+    let pool = setup_test_db().await;
+    sqlx::query(
+        "INSERT INTO friends (username, address, status, sent, peer_key) VALUES ('alice', '1.1.1.1:8080', 2, 1, 'alicekey'), ('bob', '2.2.2.2:8080', 0, 1, NULL)",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    set_friend_details(&pool, 1, Some("Ally"), Some("sauna club"))
+        .await
+        .unwrap();
+    set_friend_labels(&pool, 1, &["work".to_string()])
+        .await
+        .unwrap();
+    pool
+}
+
+#[tokio::test]
+async fn test_export_import_round_trip() {
+    let source = seeded_db().await;
+    let export = export_contacts(&source).await.unwrap();
+    assert_eq!(export.version, EXPORT_VERSION);
+    assert_eq!(export.contacts.len(), 2);
+
+    // through the file format and back
+    let json = serde_json::to_string(&export).unwrap();
+    let export: ContactExport = serde_json::from_str(&json).unwrap();
+
+    let target = setup_test_db().await;
+    let report = import_contacts(&target, &export, ConflictPolicy::Skip, true)
+        .await
+        .unwrap();
+    assert_eq!(
+        report,
+        ImportReport {
+            imported: 2,
+            skipped: 0,
+            updated: 0,
+            reinvited: 1,
+        }
+    );
+
+    let reexport = export_contacts(&target).await.unwrap();
+    assert_eq!(reexport.contacts, export.contacts);
+
+    // the pending invite to bob goes out again, alice is left alone
+    let unsent: Vec<(String,)> = sqlx::query_as("SELECT username FROM friends WHERE sent = 0")
+        .fetch_all(&target)
+        .await
+        .unwrap();
+    assert_eq!(unsent, vec![("bob".to_string(),)]);
+}
+
+#[tokio::test]
+async fn test_import_conflicts() {
+    let source = seeded_db().await;
+    let export = export_contacts(&source).await.unwrap();
+
+    let target = setup_test_db().await;
+    sqlx::query("INSERT INTO friends (username, address, status, nickname) VALUES ('alice', '9.9.9.9:8080', 2, 'Local Alice')")
+        .execute(&target)
+        .await
+        .unwrap();
+
+    let report = import_contacts(&target, &export, ConflictPolicy::Skip, false)
+        .await
+        .unwrap();
+    assert_eq!((report.imported, report.skipped), (1, 1));
+
+    import_contacts(&target, &export, ConflictPolicy::Merge, false)
+        .await
+        .unwrap();
+    let (address, nickname, key): (String, String, Option<String>) =
+        sqlx::query_as("SELECT address, nickname, peer_key FROM friends WHERE username = 'alice'")
+            .fetch_one(&target)
+            .await
+            .unwrap();
+    assert_eq!(address, "9.9.9.9:8080");
+    assert_eq!(nickname, "Local Alice");
+    assert_eq!(key.as_deref(), Some("alicekey"));
+
+    import_contacts(&target, &export, ConflictPolicy::Overwrite, false)
+        .await
+        .unwrap();
+    let (address, nickname): (String, String) =
+        sqlx::query_as("SELECT address, nickname FROM friends WHERE username = 'alice'")
+            .fetch_one(&target)
+            .await
+            .unwrap();
+    assert_eq!(address, "1.1.1.1:8080");
+    assert_eq!(nickname, "Ally");
+}
+
+#[tokio::test]
+async fn test_overwrite_resets_an_invite() {
+    let source = seeded_db().await;
+    let export = export_contacts(&source).await.unwrap();
+
+    // bob already answered us here, with a note and a token of his own
+    let target = setup_test_db().await;
+    sqlx::query("INSERT INTO friends (username, address, status, sent, intro, invite_token) VALUES ('bob', '2.2.2.2:8080', 1, 1, 'hello', 'tok')")
+        .execute(&target)
+        .await
+        .unwrap();
+
+    let report = import_contacts(&target, &export, ConflictPolicy::Overwrite, true)
+        .await
+        .unwrap();
+    assert_eq!((report.updated, report.reinvited), (1, 1));
+    let (status, sent, intro, token): (i64, bool, Option<String>, Option<String>) = sqlx::query_as(
+        "SELECT status, sent, intro, invite_token FROM friends WHERE username = 'bob'",
+    )
+    .fetch_one(&target)
+    .await
+    .unwrap();
+    assert_eq!((status, sent), (0, false));
+    assert_eq!((intro, token), (None, None));
+
+    // merging leaves the row as it is, nothing is resent
+    let report = import_contacts(&target, &export, ConflictPolicy::Merge, true)
+        .await
+        .unwrap();
+    assert_eq!((report.updated, report.reinvited), (2, 0));
+}
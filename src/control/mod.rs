@@ -0,0 +1,350 @@
+// Local control API
+// A daemon node listens on a Unix socket next to its database. Clients send
+// one JSON ControlRequest per line and get one ControlResponse line back, so
+// the interactive CLI (or a shell script with socat) can drive a headless node.
+
+use crate::api::MAX_INTRO_LEN;
+use crate::config::ConfigChange;
+use crate::db::{Friend, FriendRequest, InboxMessage, Outgoing, OutgoingMessage};
+use crate::error::Result;
+use crate::reload::Reloader;
+use crate::shutdown::ShutdownSignal;
+use crate::store::Store;
+use crate::wakeup::Wakeup;
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tracing::warn;
+
+#[cfg(test)]
+mod tests;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlRequest {
+    Status,
+    Inbox,
+    DeleteMessage {
+        id: i64,
+    },
+    Outbound,
+    // `to` is a username, a local nickname or #label
+    Send {
+        to: String,
+        subject: String,
+        content: String,
+    },
+    Friends,
+    AddFriend {
+        username: String,
+        address: String,
+        #[serde(default)]
+        intro: Option<String>,
+    },
+    RespondInvite {
+        id: i64,
+        accept: bool,
+    },
+    RemoveFriend {
+        id: i64,
+    },
+    // fetch and send friend updates now instead of at the next interval
+    Sync,
+    // read the config file again, see the README for what applies live
+    Reload,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "result", content = "data", rename_all = "snake_case")]
+pub enum ControlResponse {
+    Status { username: String, address: String },
+    Inbox(Vec<InboxMessage>),
+    Outbound(Vec<Outgoing>),
+    Friends(Vec<FriendView>),
+    Queued { recipients: Vec<String> },
+    Reloaded(Vec<ConfigChange>),
+    Ok,
+    Error(String),
+}
+
+// A friend as the control socket shows it, their peer key and invite token
+// stay in the database
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FriendView {
+    pub id: i64,
+    pub username: String,
+    pub address: String,
+    pub status: i64,
+    pub added_at: Option<NaiveDateTime>,
+    pub intro: Option<String>,
+    pub fingerprint: Option<String>,
+    pub relay_address: Option<String>,
+    pub nickname: Option<String>,
+    pub notes: Option<String>,
+}
+
+impl FriendView {
+    pub fn display_name(&self) -> &str {
+        self.nickname.as_deref().unwrap_or(&self.username)
+    }
+}
+
+impl From<Friend> for FriendView {
+    fn from(friend: Friend) -> Self {
+        FriendView {
+            id: friend.id,
+            username: friend.username,
+            address: friend.address,
+            status: friend.status,
+            added_at: friend.added_at,
+            intro: friend.intro,
+            fingerprint: friend.fingerprint,
+            relay_address: friend.relay_address,
+            nickname: friend.nickname,
+            notes: friend.notes,
+        }
+    }
+}
+
+// Binds the control socket. A leftover socket file from a crashed daemon is
+// replaced, but a live daemon on the same path is left alone.
+pub async fn bind_control(path: &Path) -> std::io::Result<UnixListener> {
+    if path.exists() {
+        if UnixStream::connect(path).await.is_ok() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AddrInUse,
+                format!("another daemon is listening on {}", path.display()),
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+
+    // only our own user gets to drive the node. The socket is bound inside a
+    // private directory and only moved into place once it is locked down, so
+    // nobody can connect in between
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| std::io::Error::other("control socket path has no file name"))?;
+    let mut private = path.as_os_str().to_os_string();
+    private.push(".bind");
+    let private = PathBuf::from(private);
+    if private.exists() {
+        std::fs::remove_dir_all(&private)?;
+    }
+    std::fs::DirBuilder::new().mode(0o700).create(&private)?;
+
+    let bound = private.join(file_name);
+    let result = UnixListener::bind(&bound).and_then(|listener| {
+        std::fs::set_permissions(&bound, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&bound, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_dir_all(&private);
+    result
+}
+
+// Serves control connections until shutdown, then removes the socket file
+pub async fn serve_control<S: Store>(
+    listener: UnixListener,
+    path: PathBuf,
+    store: S,
+    wakeup: Wakeup,
+    reloader: Reloader,
+    mut shutdown: ShutdownSignal,
+) {
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    tokio::spawn(handle_connection(
+                        stream,
+                        store.clone(),
+                        wakeup.clone(),
+                        reloader.clone(),
+                    ));
+                }
+                Err(e) => warn!("Control socket accept error: {}", e),
+            },
+            _ = shutdown.wait() => break,
+        }
+    }
+
+    let _ = std::fs::remove_file(path);
+}
+
+async fn handle_connection<S: Store>(
+    stream: UnixStream,
+    store: S,
+    wakeup: Wakeup,
+    reloader: Reloader,
+) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<ControlRequest>(&line) {
+            Ok(request) => handle_request(&store, &wakeup, &reloader, request).await,
+            Err(e) => ControlResponse::Error(format!("Invalid request: {}", e)),
+        };
+
+        let mut out = serde_json::to_string(&response).unwrap_or_else(|e| {
+            serde_json::to_string(&ControlResponse::Error(e.to_string())).unwrap()
+        });
+        out.push('\n');
+        if writer.write_all(out.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+pub async fn handle_request<S: Store>(
+    store: &S,
+    wakeup: &Wakeup,
+    reloader: &Reloader,
+    request: ControlRequest,
+) -> ControlResponse {
+    let result = match request {
+        ControlRequest::Status => store
+            .local_user()
+            .await
+            .map(|user| ControlResponse::Status {
+                username: user.username,
+                address: user.address,
+            }),
+        ControlRequest::Inbox => store.fetch_inbox().await.map(ControlResponse::Inbox),
+        ControlRequest::DeleteMessage { id } => {
+            store.delete_message(id).await.map(|_| ControlResponse::Ok)
+        }
+        ControlRequest::Outbound => store.fetch_outgoing().await.map(ControlResponse::Outbound),
+        ControlRequest::Send {
+            to,
+            subject,
+            content,
+        } => queue_message(store, to, subject, content)
+            .await
+            // friends behind a relay get theirs deposited by the message fetcher
+            .inspect(|_| wakeup.wake_message_fetcher()),
+        ControlRequest::Friends => store
+            .fetch_friends()
+            .await
+            .map(|friends| ControlResponse::Friends(friends.into_iter().map(Into::into).collect())),
+        ControlRequest::AddFriend {
+            username,
+            address,
+            intro,
+        } => {
+            if intro
+                .as_ref()
+                .is_some_and(|intro| intro.chars().count() > MAX_INTRO_LEN)
+            {
+                return ControlResponse::Error(format!(
+                    "Introduction is longer than {} characters",
+                    MAX_INTRO_LEN
+                ));
+            }
+            let request = FriendRequest {
+                username,
+                address,
+                intro: intro.filter(|intro| !intro.is_empty()),
+                fingerprint: None,
+                invite_token: None,
+            };
+            store.send_invite(&request).await.map(|_| {
+                wakeup.wake_friend_fetcher();
+                ControlResponse::Ok
+            })
+        }
+        ControlRequest::RespondInvite { id, accept } => {
+            store.invite_decision(id, accept).await.map(|_| {
+                // an accepted friend is also someone new to fetch from
+                wakeup.sync_now();
+                ControlResponse::Ok
+            })
+        }
+        ControlRequest::RemoveFriend { id } => {
+            store.delete_friend(id).await.map(|_| ControlResponse::Ok)
+        }
+        ControlRequest::Sync => {
+            wakeup.sync_now();
+            Ok(ControlResponse::Ok)
+        }
+        ControlRequest::Reload => match reloader.reload() {
+            Ok(changes) => Ok(ControlResponse::Reloaded(changes)),
+            Err(e) => Ok(ControlResponse::Error(e.to_string())),
+        },
+    };
+
+    result.unwrap_or_else(|e| ControlResponse::Error(e.to_string()))
+}
+
+async fn queue_message<S: Store>(
+    store: &S,
+    to: String,
+    subject: String,
+    content: String,
+) -> Result<ControlResponse> {
+    if let Some(label) = to.strip_prefix('#') {
+        let recipients = store
+            .queue_message_to_label(label, &subject, &content)
+            .await?;
+        return Ok(ControlResponse::Queued { recipients });
+    }
+
+    // nicknames are local, the queue wants the peer's username
+    let send_to = store.resolve_recipient(&to).await?;
+
+    let message = OutgoingMessage {
+        send_to,
+        subject,
+        content,
+    };
+    store.queue_message(&message).await?;
+    Ok(ControlResponse::Queued {
+        recipients: vec![message.send_to],
+    })
+}
+
+// Client side of the control socket, one request in flight at a time
+pub struct ControlClient {
+    path: PathBuf,
+    reader: tokio::io::Lines<BufReader<tokio::net::unix::OwnedReadHalf>>,
+    writer: tokio::net::unix::OwnedWriteHalf,
+}
+
+impl ControlClient {
+    pub async fn connect(path: &Path) -> std::io::Result<Self> {
+        let stream = UnixStream::connect(path).await?;
+        let (reader, writer) = stream.into_split();
+        Ok(ControlClient {
+            path: path.to_path_buf(),
+            reader: BufReader::new(reader).lines(),
+            writer,
+        })
+    }
+
+    pub async fn request(&mut self, request: &ControlRequest) -> Result<ControlResponse, String> {
+        let mut line = serde_json::to_string(request).map_err(|e| e.to_string())?;
+        line.push('\n');
+        self.writer
+            .write_all(line.as_bytes())
+            .await
+            .map_err(|e| format!("Control socket error: {}", e))?;
+
+        match self.reader.next_line().await {
+            Ok(Some(line)) => {
+                serde_json::from_str(&line).map_err(|e| format!("Invalid response: {}", e))
+            }
+            Ok(None) => Err(format!(
+                "Daemon at {} closed the connection",
+                self.path.display()
+            )),
+            Err(e) => Err(format!("Control socket error: {}", e)),
+        }
+    }
+}
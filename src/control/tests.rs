@@ -0,0 +1,131 @@
+use super::*;
+use crate::config::{CliArgs, Config};
+use crate::db::{MIGRATOR, User, setup_db};
+use crate::shutdown::Shutdown;
+use crate::wakeup::{Resume, Wakeup};
+use sqlx::SqlitePool;
+use std::time::Duration;
+
+async fn setup_test_db() -> SqlitePool {
+    let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+    MIGRATOR.run(&pool).await.unwrap();
+    let user = User {
+        id: 0,
+        username: "alice".to_string(),
+        address: "1.1.1.1:8080".to_string(),
+        peer_key: None,
+    };
+    setup_db(&pool, &user).await.unwrap();
+    pool
+}
+
+#[test]
+fn test_request_wire_format() {
+    let request: ControlRequest =
+        serde_json::from_str(r#"{"command":"respond_invite","id":3,"accept":true}"#).unwrap();
+    assert_eq!(
+        request,
+        ControlRequest::RespondInvite {
+            id: 3,
+            accept: true
+        }
+    );
+    let request: ControlRequest = serde_json::from_str(r#"{"command":"sync"}"#).unwrap();
+    assert_eq!(request, ControlRequest::Sync);
+    let request: ControlRequest = serde_json::from_str(r#"{"command":"reload"}"#).unwrap();
+    assert_eq!(request, ControlRequest::Reload);
+}
+
+#[tokio::test]
+async fn test_control_socket_round_trip() {
+    let pool = setup_test_db().await;
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("mankeli.sock");
+    let shutdown = Shutdown::new();
+    let wakeup = Wakeup::new();
+    let mut friend_fetcher = wakeup.friend_fetcher(shutdown.subscribe());
+    let args = CliArgs {
+        data_dir: Some(dir.path().to_path_buf()),
+        ..Default::default()
+    };
+    let config = Config::load_with(args.clone(), |_| None).unwrap();
+    let reloader = Reloader::new(args, config, wakeup.clone(), None);
+
+    let listener = bind_control(&path).await.unwrap();
+    // owner-only from the start, the private bind directory is gone again
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    let server = tokio::spawn(serve_control(
+        listener,
+        path.clone(),
+        pool,
+        wakeup,
+        reloader,
+        shutdown.subscribe(),
+    ));
+
+    // a second daemon can't take over a live socket
+    assert!(bind_control(&path).await.is_err());
+
+    let mut client = ControlClient::connect(&path).await.unwrap();
+
+    match client.request(&ControlRequest::Status).await.unwrap() {
+        ControlResponse::Status { username, .. } => assert_eq!(username, "alice"),
+        other => panic!("unexpected response {:?}", other),
+    }
+
+    let add = ControlRequest::AddFriend {
+        username: "bob".into(),
+        address: "2.2.2.2:8080".into(),
+        intro: None,
+    };
+    assert!(matches!(
+        client.request(&add).await.unwrap(),
+        ControlResponse::Ok
+    ));
+    // the invite goes out now, not at the next interval
+    let started = std::time::Instant::now();
+    assert_eq!(
+        friend_fetcher.wait(Duration::from_secs(60)).await,
+        Resume::Woken
+    );
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    match client.request(&ControlRequest::Friends).await.unwrap() {
+        ControlResponse::Friends(friends) => {
+            assert_eq!(friends.len(), 1);
+            assert_eq!(friends[0].username, "bob");
+            assert_eq!(friends[0].status, 0);
+            // keys and invite tokens don't leave the daemon
+            let json = serde_json::to_string(&friends).unwrap();
+            assert!(!json.contains("peer_key") && !json.contains("invite_token"));
+        }
+        other => panic!("unexpected response {:?}", other),
+    }
+
+    // unknown recipients surface as errors instead of killing the connection
+    let send = ControlRequest::Send {
+        to: "nobody".into(),
+        subject: "hi".into(),
+        content: "hello".into(),
+    };
+    assert!(matches!(
+        client.request(&send).await.unwrap(),
+        ControlResponse::Error(_)
+    ));
+    assert!(matches!(
+        client.request(&ControlRequest::Inbox).await.unwrap(),
+        ControlResponse::Inbox(messages) if messages.is_empty()
+    ));
+
+    // nothing to reload without a config file
+    assert!(matches!(
+        client.request(&ControlRequest::Reload).await.unwrap(),
+        ControlResponse::Reloaded(changes) if changes.is_empty()
+    ));
+
+    shutdown.trigger();
+    server.await.unwrap();
+    assert!(!path.exists());
+}
@@ -1,30 +1,67 @@
 use crate::api::Message;
+use crate::error::{Error, Result};
+use crate::identity::Identity;
 use chrono::NaiveDateTime;
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
 use sqlx::{FromRow, QueryBuilder, SqlitePool, migrate::Migrator};
+use std::collections::HashMap;
+use std::net::IpAddr;
 
 pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
 
 #[cfg(test)]
 mod tests;
 
-#[derive(FromRow)]
+#[derive(Debug, Clone, FromRow)]
 pub struct User {
     pub id: i64,
     pub username: String,
     pub address: String,
+    pub peer_key: Option<String>,
 }
 
-#[derive(Debug, FromRow)]
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
 pub struct Friend {
     pub id: i64,
     pub username: String,
     pub address: String,
     pub status: i64,
     pub added_at: Option<NaiveDateTime>,
+    pub peer_key: Option<String>,
+    pub intro: Option<String>,
+    pub fingerprint: Option<String>,
+    pub invite_token: Option<String>,
+    pub relay_address: Option<String>,
+    pub nickname: Option<String>,
+    pub notes: Option<String>,
 }
 
-#[derive(Debug, FromRow)]
+impl Friend {
+    // Local nickname if one is set
+    pub fn display_name(&self) -> &str {
+        self.nickname.as_deref().unwrap_or(&self.username)
+    }
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct PeerHealth {
+    pub username: String,
+    pub consecutive_failures: i64,
+    pub last_error: Option<String>,
+    pub last_success_at: Option<NaiveDateTime>,
+    pub last_failure_at: Option<NaiveDateTime>,
+    pub next_attempt_at: Option<NaiveDateTime>,
+}
+
+impl PeerHealth {
+    // Whether the fetchers should contact this peer at `now`
+    pub fn is_due(&self, now: NaiveDateTime) -> bool {
+        self.next_attempt_at.is_none_or(|next| next <= now)
+    }
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
 pub struct InboxMessage {
     pub id: i64,
     pub sender: String,
@@ -33,7 +70,7 @@ pub struct InboxMessage {
     pub received_at: Option<NaiveDateTime>,
 }
 
-#[derive(Debug, FromRow)]
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
 pub struct Outgoing {
     pub id: i64,
     pub sender: String,
@@ -45,7 +82,7 @@ pub struct Outgoing {
     pub sent: Option<bool>,
 }
 
-#[derive(sqlx::FromRow, Debug, Serialize, Deserialize)]
+#[derive(sqlx::FromRow, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct OutgoingMessage {
     pub send_to: String,
     pub subject: String,
@@ -55,9 +92,106 @@ pub struct OutgoingMessage {
 pub struct FriendRequest {
     pub username: String,
     pub address: String,
+    pub intro: Option<String>,
+    pub fingerprint: Option<String>,
+    pub invite_token: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockKind {
+    Username,
+    PeerKey,
+    Address,
+}
+
+impl BlockKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BlockKind::Username => "username",
+            BlockKind::PeerKey => "peer_key",
+            BlockKind::Address => "address",
+        }
+    }
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct Blocked {
+    pub id: i64,
+    pub kind: String,
+    pub value: String,
+    pub added_at: Option<NaiveDateTime>,
+}
+
+// An invite a peer sent us over the api
+pub struct IncomingInvite<'a> {
+    pub username: &'a str,
+    pub address: &'a str,
+    pub peer_key: Option<&'a str>,
+    pub intro: Option<&'a str>,
+    // one of our one-time tokens, from an invite link
+    pub invite_token: Option<&'a str>,
+}
+
+// What became of an incoming invite
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InviteOutcome {
+    // we already know them, nothing was stored
+    Ignored,
+    Received,
+    // it came with a valid token and they are a friend now
+    Accepted,
 }
 
-pub async fn setup_db(pool: &SqlitePool, initial_user: &User) -> Result<(), sqlx::Error> {
+// What we know about a remote node when it talks to us
+pub struct Peer<'a> {
+    pub username: &'a str,
+    pub peer_key: Option<&'a str>,
+    pub address: Option<&'a str>,
+    pub ip: Option<IpAddr>,
+}
+
+impl Blocked {
+    pub fn matches(&self, peer: &Peer) -> bool {
+        match self.kind.as_str() {
+            "username" => self.value == peer.username,
+            "peer_key" => peer.peer_key == Some(self.value.as_str()),
+            "address" => {
+                let host = peer.address.map(address_host);
+                let mut ips: Vec<IpAddr> = peer.ip.into_iter().collect();
+                if let Some(ip) = host.and_then(|h| h.parse::<IpAddr>().ok()) {
+                    ips.push(ip);
+                }
+
+                if let Ok(net) = self.value.parse::<IpNet>() {
+                    ips.iter().any(|ip| net.contains(ip))
+                } else if let Ok(blocked_ip) = self.value.parse::<IpAddr>() {
+                    ips.contains(&blocked_ip)
+                } else {
+                    host.is_some_and(|h| h.eq_ignore_ascii_case(&self.value))
+                }
+            }
+            _ => false,
+        }
+    }
+}
+
+// Strips the port from "host:port" / "[v6]:port" style addresses
+fn address_host(address: &str) -> &str {
+    if let Some(rest) = address.strip_prefix('[') {
+        return rest.split(']').next().unwrap_or(rest);
+    }
+    match address.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') && port.parse::<u16>().is_ok() => host,
+        _ => address,
+    }
+}
+
+pub fn generate_invite_token() -> String {
+    let bytes: [u8; 12] = rand::random();
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub async fn setup_db(pool: &SqlitePool, initial_user: &User) -> Result<()> {
     let user_exists: Option<i64> = sqlx::query_scalar!(
         "SELECT id FROM user WHERE username = ?",
         initial_user.username
@@ -65,11 +199,16 @@ pub async fn setup_db(pool: &SqlitePool, initial_user: &User) -> Result<(), sqlx
     .fetch_optional(pool)
     .await?;
 
+    // the key is always ours, one without its secret half can't sign
     if user_exists.is_none() {
+        let identity = Identity::generate();
+        let (peer_key, secret) = (identity.peer_key(), identity.secret());
         sqlx::query!(
-            "INSERT INTO user (username, address) VALUES (?, ?)",
+            "INSERT INTO user (username, address, peer_key, signing_key) VALUES (?, ?, ?, ?)",
             initial_user.username,
-            initial_user.address
+            initial_user.address,
+            peer_key,
+            secret
         )
         .execute(pool)
         .await?;
@@ -78,24 +217,64 @@ pub async fn setup_db(pool: &SqlitePool, initial_user: &User) -> Result<(), sqlx
     Ok(())
 }
 
-pub async fn retr_user(pool: &SqlitePool) -> Result<User, sqlx::Error> {
-    let user = sqlx::query_as!(User, "SELECT id, username, address FROM user LIMIT 1")
+pub async fn retr_user(pool: &SqlitePool) -> Result<User> {
+    let user = sqlx::query_as!(
+        User,
+        "SELECT id, username, address, peer_key FROM user LIMIT 1"
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(user)
+}
+
+// Our keypair. Nodes created before keypairs existed, with no key or only
+// a random one, get a fresh keypair on first start
+pub async fn ensure_identity(pool: &SqlitePool) -> Result<Identity> {
+    let row = sqlx::query!("SELECT id, peer_key, signing_key FROM user LIMIT 1")
         .fetch_one(pool)
         .await?;
-    Ok(user)
+    if let Some(identity) = row.signing_key.as_deref().and_then(Identity::from_secret)
+        && row.peer_key.as_deref() == Some(identity.peer_key().as_str())
+    {
+        return Ok(identity);
+    }
+
+    let identity = Identity::generate();
+    let (peer_key, secret) = (identity.peer_key(), identity.secret());
+    sqlx::query!(
+        "UPDATE user SET peer_key = ?, signing_key = ? WHERE id = ?",
+        peer_key,
+        secret,
+        row.id
+    )
+    .execute(pool)
+    .await?;
+    Ok(identity)
+}
+
+// Keeps the address we advertise in step with the config across restarts
+pub async fn set_user_address(pool: &SqlitePool, address: &str) -> Result<bool> {
+    let result = sqlx::query!(
+        "UPDATE user SET address = ? WHERE address IS NOT ?",
+        address,
+        address
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
 }
 
-pub async fn fetch_users(pool: &SqlitePool) -> Result<Vec<Friend>, sqlx::Error> {
+pub async fn fetch_users(pool: &SqlitePool) -> Result<Vec<Friend>> {
     let friends = sqlx::query_as!(
         Friend,
-        "SELECT id, username, address, status, added_at FROM friends"
+        "SELECT id, username, address, status, added_at, peer_key, intro, fingerprint, invite_token, relay_address, nickname, notes FROM friends"
     )
     .fetch_all(pool)
     .await?;
     Ok(friends)
 }
 
-pub async fn fetch_inbox(pool: &SqlitePool) -> Result<Vec<InboxMessage>, sqlx::Error> {
+pub async fn fetch_inbox(pool: &SqlitePool) -> Result<Vec<InboxMessage>> {
     let messages = sqlx::query_as!(
         InboxMessage,
         "SELECT id, sender, subject, message, received_at FROM inbox"
@@ -105,7 +284,7 @@ pub async fn fetch_inbox(pool: &SqlitePool) -> Result<Vec<InboxMessage>, sqlx::E
     Ok(messages)
 }
 
-pub async fn fetch_outgoing(pool: &SqlitePool) -> Result<Vec<Outgoing>, sqlx::Error> {
+pub async fn fetch_outgoing(pool: &SqlitePool) -> Result<Vec<Outgoing>> {
     let messages = sqlx::query_as!(
         Outgoing,
         "SELECT id, sender, recipient, recipient_address, subject, message as body, queued_at, sent FROM outgoing"
@@ -115,17 +294,15 @@ pub async fn fetch_outgoing(pool: &SqlitePool) -> Result<Vec<Outgoing>, sqlx::Er
     Ok(messages)
 }
 
-pub async fn send_message_to_que(
-    pool: &SqlitePool,
-    message: &OutgoingMessage,
-) -> Result<(), sqlx::Error> {
+pub async fn send_message_to_que(pool: &SqlitePool, message: &OutgoingMessage) -> Result<()> {
     let sender = retr_user(pool).await?;
 
     let recipient_address: (String,) =
         sqlx::query_as("SELECT address FROM friends WHERE username = ?")
             .bind(&message.send_to)
-            .fetch_one(pool)
-            .await?;
+            .fetch_optional(pool)
+            .await?
+            .ok_or_else(|| Error::NotFound(format!("no friend named {}", message.send_to)))?;
 
     sqlx::query!(
         "INSERT INTO outgoing (sender, recipient, recipient_address, subject, message) VALUES (?, ?, ?, ?, ?)",
@@ -137,53 +314,232 @@ pub async fn send_message_to_que(
     )
     .execute(pool)
     .await?;
+    crate::metrics::messages_queued(1);
 
     Ok(())
 }
 
-pub async fn send_invite(pool: &SqlitePool, request: &FriendRequest) -> Result<(), sqlx::Error> {
+pub async fn send_invite(pool: &SqlitePool, request: &FriendRequest) -> Result<()> {
+    // Someone who rejected us, or whose invite expired, can be invited again
+    let reinvited = sqlx::query!(
+        r#"
+        UPDATE friends
+        SET status = 0, sent = 0, added_at = CURRENT_TIMESTAMP, address = ?, intro = ?,
+            fingerprint = COALESCE(?, fingerprint), invite_token = ?
+        WHERE username = ? AND status IN (3, 4)
+        "#,
+        request.address,
+        request.intro,
+        request.fingerprint,
+        request.invite_token,
+        request.username
+    )
+    .execute(pool)
+    .await?;
+
+    if reinvited.rows_affected() > 0 {
+        return Ok(());
+    }
+
     sqlx::query!(
-        "INSERT INTO Friends (username, address) VALUES (?, ?)",
+        "INSERT INTO Friends (username, address, intro, fingerprint, invite_token) VALUES (?, ?, ?, ?, ?)",
         request.username,
-        request.address
+        request.address,
+        request.intro,
+        request.fingerprint,
+        request.invite_token
     )
     .execute(pool)
     .await?;
     Ok(())
 }
 
-pub async fn delete_message(pool: &SqlitePool, id: i64) -> Result<(), sqlx::Error> {
+// Sends an existing invite again: pending, rejected and expired ones start over
+pub async fn reinvite(pool: &SqlitePool, id: i64) -> Result<bool> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE friends
+        SET status = 0, sent = 0, added_at = CURRENT_TIMESTAMP
+        WHERE id = ? AND status IN (0, 3, 4)
+        "#,
+        id
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+// Marks our invites older than max_age as expired, returns how many
+pub async fn expire_stale_invites(pool: &SqlitePool, max_age: std::time::Duration) -> Result<u64> {
+    let max_age_secs = format!("-{} seconds", max_age.as_secs());
+    let result = sqlx::query!(
+        r#"
+        UPDATE friends
+        SET status = 4, sent = 1
+        WHERE status = 0 AND added_at < datetime('now', ?)
+        "#,
+        max_age_secs
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+pub async fn delete_message(pool: &SqlitePool, id: i64) -> Result<()> {
     sqlx::query!("DELETE FROM inbox WHERE id = ?", id)
         .execute(pool)
         .await?;
     Ok(())
 }
 
-pub async fn delete_user(pool: &SqlitePool, id: i64) -> Result<(), sqlx::Error> {
+pub async fn delete_user(pool: &SqlitePool, id: i64) -> Result<()> {
     sqlx::query!("DELETE FROM Friends WHERE id = ?", id)
         .execute(pool)
         .await?;
     Ok(())
 }
 
-pub async fn invite_decision(pool: &SqlitePool, id: i64, accept: bool) -> Result<(), sqlx::Error> {
+// Answers an invite we received, any other row is left alone
+pub async fn invite_decision(pool: &SqlitePool, id: i64, accept: bool) -> Result<()> {
     let status = if accept { 2 } else { 3 };
 
-    sqlx::query!(
-        "UPDATE Friends SET status = ?, sent = ? WHERE id = ?",
+    let result = sqlx::query!(
+        "UPDATE Friends SET status = ?, sent = ? WHERE id = ? AND status = 1",
         status,
         0,
         id
     )
     .execute(pool)
     .await?;
+    if result.rows_affected() == 0 {
+        return Err(Error::NotFound(format!(
+            "no invite to answer with id {}",
+            id
+        )));
+    }
     Ok(())
 }
 
-pub async fn fetch_messages_for_user(
+// Stores an incoming invite, or accepts it straight away when it came with
+// one of our tokens (left unsent so the friend fetcher reports back). Only a
+// rejected or expired row is replaced, anyone can send an invite in a
+// friend's name and must not undo or re-key a friendship that way. The token
+// is only used up when the invite is stored.
+pub async fn receive_invite(
+    pool: &SqlitePool,
+    invite: &IncomingInvite<'_>,
+) -> Result<InviteOutcome> {
+    let mut tx = pool.begin().await?;
+    let auto_accept = match invite.invite_token {
+        Some(token) => {
+            sqlx::query!(
+                "UPDATE invite_tokens SET used_at = CURRENT_TIMESTAMP WHERE token = ? AND used_at IS NULL",
+                token
+            )
+            .execute(&mut *tx)
+            .await?
+            .rows_affected()
+                == 1
+        }
+        None => false,
+    };
+
+    let (status, sent) = if auto_accept { (2, 0) } else { (1, 1) };
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO friends (username, address, status, sent, peer_key, intro)
+        VALUES (?, ?, ?, ?, ?, ?)
+        ON CONFLICT(username) DO UPDATE SET
+            address = excluded.address,
+            status = excluded.status,
+            sent = excluded.sent,
+            added_at = CURRENT_TIMESTAMP,
+            peer_key = COALESCE(excluded.peer_key, peer_key),
+            intro = excluded.intro
+        WHERE status IN (3, 4)
+        "#,
+        invite.username,
+        invite.address,
+        status,
+        sent,
+        invite.peer_key,
+        invite.intro,
+    )
+    .execute(&mut *tx)
+    .await?;
+    if result.rows_affected() == 0 {
+        // dropping the transaction leaves the token unused
+        return Ok(InviteOutcome::Ignored);
+    }
+    tx.commit().await?;
+
+    Ok(if auto_accept {
+        InviteOutcome::Accepted
+    } else {
+        InviteOutcome::Received
+    })
+}
+
+// Status and pinned fingerprint of our row for a peer answering an invite
+pub async fn fetch_invite_state(
     pool: &SqlitePool,
-    username: String,
-) -> Result<Vec<Outgoing>, sqlx::Error> {
+    username: &str,
+    address: &str,
+) -> Result<Option<(i64, Option<String>)>> {
+    let row = sqlx::query!(
+        r#"
+        SELECT status as "status: i64", fingerprint FROM friends
+        WHERE username = ? AND address = ?
+        "#,
+        username,
+        address
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|row| (row.status, row.fingerprint)))
+}
+
+// The peer accepted our invite
+pub async fn accept_invite(
+    pool: &SqlitePool,
+    username: &str,
+    address: &str,
+    peer_key: Option<&str>,
+) -> Result<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO friends (username, address, status, sent, peer_key)
+        VALUES (?, ?, 2, 1, ?)
+        ON CONFLICT(username) DO UPDATE SET
+            status = 2,
+            added_at = CURRENT_TIMESTAMP,
+            peer_key = COALESCE(excluded.peer_key, peer_key)
+        "#,
+        username,
+        address,
+        peer_key
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+// The peer rejected our invite, false if there was no pending one
+pub async fn reject_invite(pool: &SqlitePool, username: &str, address: &str) -> Result<bool> {
+    let result = sqlx::query!(
+        r#"
+        UPDATE friends SET status = 3, sent=1 ,added_at = CURRENT_TIMESTAMP
+        WHERE username = ? AND address = ? AND status = 0
+        "#,
+        username,
+        address
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn fetch_messages_for_user(pool: &SqlitePool, username: String) -> Result<Vec<Outgoing>> {
     let messages: Vec<Outgoing> = sqlx::query_as!(
         Outgoing,
         r#"
@@ -199,13 +555,47 @@ pub async fn fetch_messages_for_user(
     Ok(messages)
 }
 
+pub async fn mark_messages_as_sent(pool: &SqlitePool, message_ids: &[i64]) -> Result<()> {
+    if message_ids.is_empty() {
+        return Ok(());
+    }
+
+    let placeholders = std::iter::repeat_n("?", message_ids.len())
+        .collect::<Vec<_>>()
+        .join(",");
+
+    let sql = format!(
+        "UPDATE outgoing SET sent = 1 WHERE id IN ({})",
+        placeholders
+    );
+
+    let mut query = sqlx::query(&sql);
+    for id in message_ids {
+        query = query.bind(id);
+    }
+
+    query.execute(pool).await?;
+
+    Ok(())
+}
+
+// Queued messages nobody has picked up yet
+pub async fn count_unsent_messages(pool: &SqlitePool) -> Result<i64> {
+    let count =
+        sqlx::query_scalar!(r#"SELECT COUNT(*) as "count: i64" FROM outgoing WHERE sent = 0"#)
+            .fetch_one(pool)
+            .await?;
+    Ok(count)
+}
+
 //Fetch accepted friends
-pub async fn fetch_active_friends(pool: &SqlitePool) -> Result<Vec<Friend>, sqlx::Error> {
+pub async fn fetch_active_friends(pool: &SqlitePool) -> Result<Vec<Friend>> {
     let status = 2;
     let friends: Vec<Friend> = sqlx::query_as!(
         Friend,
         r#"
-        SELECT id, username, address, status, added_at
+        SELECT id, username, address, status, added_at, peer_key, intro, fingerprint, invite_token,
+            relay_address, nickname, notes
         FROM friends
         WHERE status = ?
         "#,
@@ -219,16 +609,15 @@ pub async fn fetch_active_friends(pool: &SqlitePool) -> Result<Vec<Friend>, sqlx
 
 //Fetch unsent requests
 
-pub async fn fetch_unsent_friend_updt(
-    pool: &SqlitePool,
-) -> Result<(User, Vec<Friend>), sqlx::Error> {
+pub async fn fetch_unsent_friend_updt(pool: &SqlitePool) -> Result<(User, Vec<Friend>)> {
     let user = retr_user(pool).await?;
 
     let status = false;
     let friends: Vec<Friend> = sqlx::query_as!(
         Friend,
         r#"
-        SELECT id, username, address, status, added_at
+        SELECT id, username, address, status, added_at, peer_key, intro, fingerprint, invite_token,
+            relay_address, nickname, notes
         FROM friends
         WHERE sent = ?
         "#,
@@ -240,7 +629,7 @@ pub async fn fetch_unsent_friend_updt(
     Ok((user, friends))
 }
 
-pub async fn batch_ingest(pool: &SqlitePool, messages: Vec<Message>) -> Result<(), sqlx::Error> {
+pub async fn batch_ingest(pool: &SqlitePool, messages: Vec<Message>) -> Result<()> {
     if messages.is_empty() {
         return Ok(()); // Nothing to insert
     }
@@ -259,10 +648,48 @@ pub async fn batch_ingest(pool: &SqlitePool, messages: Vec<Message>) -> Result<(
     Ok(())
 }
 
-pub async fn update_friend_status_as_sent(
+// Relay mail keyed by the digest of its sealed payload. Mail taken in before
+// is skipped, what was new is returned. Digests are kept a day longer than
+// sealed mail is accepted for.
+pub async fn ingest_relay_mail(
     pool: &SqlitePool,
-    username: &String,
-) -> Result<(), sqlx::Error> {
+    mail: Vec<(String, Message)>,
+) -> Result<Vec<Message>> {
+    let mut tx = pool.begin().await?;
+    let keep = format!("-{} days", crate::relay::MAILBOX_TTL_DAYS + 1);
+    sqlx::query!(
+        "DELETE FROM relay_seen WHERE seen_at < datetime('now', ?)",
+        keep
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let mut fresh = Vec::new();
+    for (digest, msg) in mail {
+        let seen = sqlx::query!(
+            "INSERT INTO relay_seen (digest) VALUES (?) ON CONFLICT(digest) DO NOTHING",
+            digest
+        )
+        .execute(&mut *tx)
+        .await?;
+        if seen.rows_affected() == 0 {
+            continue;
+        }
+        sqlx::query!(
+            "INSERT INTO inbox (sender, subject, message) VALUES (?, ?, ?)",
+            msg.sender,
+            msg.subject,
+            msg.body
+        )
+        .execute(&mut *tx)
+        .await?;
+        fresh.push(msg);
+    }
+    tx.commit().await?;
+    Ok(fresh)
+}
+
+pub async fn update_friend_status_as_sent(pool: &SqlitePool, username: &String) -> Result<()> {
     sqlx::query!(
         r#"
         UPDATE friends
@@ -276,3 +703,306 @@ pub async fn update_friend_status_as_sent(
 
     Ok(())
 }
+
+pub async fn add_block(pool: &SqlitePool, kind: BlockKind, value: &str) -> Result<()> {
+    let kind = kind.as_str();
+    sqlx::query!(
+        "INSERT INTO blocked (kind, value) VALUES (?, ?) ON CONFLICT(kind, value) DO NOTHING",
+        kind,
+        value
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn remove_block(pool: &SqlitePool, id: i64) -> Result<()> {
+    sqlx::query!("DELETE FROM blocked WHERE id = ?", id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn fetch_blocks(pool: &SqlitePool) -> Result<Vec<Blocked>> {
+    let blocks = sqlx::query_as!(
+        Blocked,
+        "SELECT id, kind, value, added_at FROM blocked ORDER BY id"
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(blocks)
+}
+
+pub async fn is_blocked(pool: &SqlitePool, peer: &Peer<'_>) -> Result<bool> {
+    let blocks = fetch_blocks(pool).await?;
+    Ok(blocks.iter().any(|b| b.matches(peer)))
+}
+
+// Blocks a friend by username (and key if we know it) and drops their row
+pub async fn block_friend(pool: &SqlitePool, friend: &Friend) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    let username_kind = BlockKind::Username.as_str();
+    sqlx::query!(
+        "INSERT INTO blocked (kind, value) VALUES (?, ?) ON CONFLICT(kind, value) DO NOTHING",
+        username_kind,
+        friend.username
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    if let Some(key) = &friend.peer_key {
+        let key_kind = BlockKind::PeerKey.as_str();
+        sqlx::query!(
+            "INSERT INTO blocked (kind, value) VALUES (?, ?) ON CONFLICT(kind, value) DO NOTHING",
+            key_kind,
+            key
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    sqlx::query!("DELETE FROM friends WHERE id = ?", friend.id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+pub async fn create_invite_token(pool: &SqlitePool) -> Result<String> {
+    let token = generate_invite_token();
+    sqlx::query!("INSERT INTO invite_tokens (token) VALUES (?)", token)
+        .execute(pool)
+        .await?;
+    Ok(token)
+}
+
+// Marks a one-time token as used, returns false if it was unknown or spent
+pub async fn redeem_invite_token(pool: &SqlitePool, token: &str) -> Result<bool> {
+    let result = sqlx::query!(
+        "UPDATE invite_tokens SET used_at = CURRENT_TIMESTAMP WHERE token = ? AND used_at IS NULL",
+        token
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() == 1)
+}
+
+// Relay where we deposit mail for a friend who can't be reached directly
+pub async fn set_friend_relay(
+    pool: &SqlitePool,
+    id: i64,
+    relay_address: Option<&str>,
+) -> Result<()> {
+    sqlx::query!(
+        "UPDATE friends SET relay_address = ? WHERE id = ?",
+        relay_address,
+        id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn fetch_peer_health(pool: &SqlitePool) -> Result<Vec<PeerHealth>> {
+    let health = sqlx::query_as!(
+        PeerHealth,
+        r#"
+        SELECT username as "username!", consecutive_failures, last_error, last_success_at,
+            last_failure_at, next_attempt_at
+        FROM peer_health
+        ORDER BY username
+        "#
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(health)
+}
+
+pub async fn record_peer_success(pool: &SqlitePool, username: &str) -> Result<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO peer_health (username, consecutive_failures, last_success_at)
+        VALUES (?, 0, CURRENT_TIMESTAMP)
+        ON CONFLICT(username) DO UPDATE SET
+            consecutive_failures = 0,
+            last_success_at = CURRENT_TIMESTAMP,
+            next_attempt_at = NULL
+        "#,
+        username
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+// Bumps the failure count and returns it, the caller schedules the retry
+pub async fn record_peer_failure(pool: &SqlitePool, username: &str, error: &str) -> Result<i64> {
+    let failures = sqlx::query_scalar!(
+        r#"
+        INSERT INTO peer_health (username, consecutive_failures, last_error, last_failure_at)
+        VALUES (?, 1, ?, CURRENT_TIMESTAMP)
+        ON CONFLICT(username) DO UPDATE SET
+            consecutive_failures = consecutive_failures + 1,
+            last_error = excluded.last_error,
+            last_failure_at = CURRENT_TIMESTAMP
+        RETURNING consecutive_failures
+        "#,
+        username,
+        error
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(failures)
+}
+
+pub async fn schedule_peer_retry(
+    pool: &SqlitePool,
+    username: &str,
+    next_attempt_at: NaiveDateTime,
+) -> Result<()> {
+    sqlx::query!(
+        "UPDATE peer_health SET next_attempt_at = ? WHERE username = ?",
+        next_attempt_at,
+        username
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+// Local-only contact details, never sent to the peer. Nicknames name a
+// recipient, so no two friends share one and none shadows a username
+pub async fn set_friend_details(
+    pool: &SqlitePool,
+    id: i64,
+    nickname: Option<&str>,
+    notes: Option<&str>,
+) -> Result<()> {
+    if let Some(nickname) = nickname {
+        let taken = sqlx::query_scalar!(
+            "SELECT username FROM friends WHERE id != ? AND (nickname = ? OR username = ?)",
+            id,
+            nickname,
+            nickname
+        )
+        .fetch_optional(pool)
+        .await?;
+        if let Some(username) = taken {
+            return Err(Error::Validation(format!(
+                "nickname '{}' is already used for {}",
+                nickname, username
+            )));
+        }
+    }
+    sqlx::query!(
+        "UPDATE friends SET nickname = ?, notes = ? WHERE id = ?",
+        nickname,
+        notes,
+        id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn set_friend_labels(pool: &SqlitePool, friend_id: i64, labels: &[String]) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    sqlx::query!("DELETE FROM friend_labels WHERE friend_id = ?", friend_id)
+        .execute(&mut *tx)
+        .await?;
+    for label in labels {
+        sqlx::query!(
+            "INSERT OR IGNORE INTO friend_labels (friend_id, label) VALUES (?, ?)",
+            friend_id,
+            label
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+// Labels of every friend keyed by friend id
+pub async fn fetch_friend_labels(pool: &SqlitePool) -> Result<HashMap<i64, Vec<String>>> {
+    let rows = sqlx::query!("SELECT friend_id, label FROM friend_labels ORDER BY label")
+        .fetch_all(pool)
+        .await?;
+
+    let mut labels: HashMap<i64, Vec<String>> = HashMap::new();
+    for row in rows {
+        labels.entry(row.friend_id).or_default().push(row.label);
+    }
+    Ok(labels)
+}
+
+// Username of the friend `name` refers to. Usernames win over nicknames,
+// unknown names are passed on as they are
+pub async fn resolve_recipient(pool: &SqlitePool, name: &str) -> Result<String> {
+    let known = sqlx::query_scalar!("SELECT username FROM friends WHERE username = ?", name)
+        .fetch_optional(pool)
+        .await?;
+    if let Some(username) = known {
+        return Ok(username);
+    }
+
+    // imports can bring in nicknames that clash
+    let mut matches = sqlx::query_scalar!(
+        "SELECT username FROM friends WHERE nickname = ? ORDER BY username",
+        name
+    )
+    .fetch_all(pool)
+    .await?;
+    match matches.len() {
+        0 => Ok(name.to_string()),
+        1 => Ok(matches.remove(0)),
+        _ => Err(Error::Validation(format!(
+            "ambiguous recipient '{}', it is the nickname of {}",
+            name,
+            matches.join(", ")
+        ))),
+    }
+}
+
+// Queues the same message to every accepted friend with the label, all of
+// them or none
+pub async fn send_message_to_label(
+    pool: &SqlitePool,
+    label: &str,
+    subject: &str,
+    content: &str,
+) -> Result<Vec<String>> {
+    let mut tx = pool.begin().await?;
+    let sender = sqlx::query_scalar!("SELECT username FROM user LIMIT 1")
+        .fetch_one(&mut *tx)
+        .await?;
+    let rows = sqlx::query!(
+        r#"
+        SELECT f.username, f.address FROM friends f
+        JOIN friend_labels l ON l.friend_id = f.id
+        WHERE l.label = ? AND f.status = 2
+        ORDER BY f.username
+        "#,
+        label
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    for row in &rows {
+        sqlx::query!(
+            "INSERT INTO outgoing (sender, recipient, recipient_address, subject, message) VALUES (?, ?, ?, ?, ?)",
+            sender,
+            row.username,
+            row.address,
+            subject,
+            content
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+    crate::metrics::messages_queued(rows.len());
+
+    let recipients = rows.into_iter().map(|row| row.username).collect();
+    Ok(recipients)
+}
@@ -101,13 +101,16 @@ async fn test_send_invite() {
     let request = FriendRequest {
         username: "alice".to_string(),
         address: "alice@example.com".to_string(),
+        intro: Some("we met at RustConf".to_string()),
+        fingerprint: None,
+        invite_token: None,
     };
 
     let result = send_invite(&pool, &request).await;
     assert!(result.is_ok());
 
-    let friend = sqlx::query_as::<_, (String, String)>(
-        "SELECT username, address FROM friends WHERE username = ?",
+    let friend = sqlx::query_as::<_, (String, String, Option<String>)>(
+        "SELECT username, address, intro FROM friends WHERE username = ?",
     )
     .bind(&request.username)
     .fetch_one(&pool)
@@ -116,4 +119,339 @@ async fn test_send_invite() {
 
     assert_eq!(friend.0, "alice");
     assert_eq!(friend.1, "alice@example.com");
+    assert_eq!(friend.2.as_deref(), Some("we met at RustConf"));
+}
+
+#[tokio::test]
+async fn test_block_list_matches_username_key_and_cidr() {
+    let pool = setup_test_db().await;
+
+    add_block(&pool, BlockKind::Username, "mallory")
+        .await
+        .unwrap();
+    add_block(&pool, BlockKind::PeerKey, "badkey")
+        .await
+        .unwrap();
+    add_block(&pool, BlockKind::Address, "192.168.0.0/16")
+        .await
+        .unwrap();
+
+    let peer = |username, peer_key, address| Peer {
+        username,
+        peer_key,
+        address,
+        ip: None,
+    };
+
+    assert!(
+        is_blocked(&pool, &peer("mallory", None, None))
+            .await
+            .unwrap()
+    );
+    assert!(
+        is_blocked(&pool, &peer("eve", Some("badkey"), None))
+            .await
+            .unwrap()
+    );
+    assert!(
+        is_blocked(&pool, &peer("eve", None, Some("192.168.4.2:8080")))
+            .await
+            .unwrap()
+    );
+    assert!(
+        !is_blocked(
+            &pool,
+            &peer("alice", Some("goodkey"), Some("10.0.0.1:8080"))
+        )
+        .await
+        .unwrap()
+    );
+
+    let socket_peer = Peer {
+        username: "eve",
+        peer_key: None,
+        address: Some("example.com:8080"),
+        ip: Some("192.168.1.1".parse().unwrap()),
+    };
+    assert!(is_blocked(&pool, &socket_peer).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_peer_health_tracks_failures() {
+    let pool = setup_test_db().await;
+
+    sqlx::query("INSERT INTO friends (username, address, status) VALUES ('alice', '1.1.1.1', 2)")
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        record_peer_failure(&pool, "alice", "connection refused")
+            .await
+            .unwrap(),
+        1
+    );
+    assert_eq!(
+        record_peer_failure(&pool, "alice", "timed out")
+            .await
+            .unwrap(),
+        2
+    );
+
+    let later = chrono::Utc::now().naive_utc() + chrono::Duration::minutes(5);
+    schedule_peer_retry(&pool, "alice", later).await.unwrap();
+
+    let health = fetch_peer_health(&pool).await.unwrap();
+    assert_eq!(health.len(), 1);
+    assert_eq!(health[0].consecutive_failures, 2);
+    assert_eq!(health[0].last_error.as_deref(), Some("timed out"));
+    assert!(!health[0].is_due(chrono::Utc::now().naive_utc()));
+
+    record_peer_success(&pool, "alice").await.unwrap();
+    let health = fetch_peer_health(&pool).await.unwrap();
+    assert_eq!(health[0].consecutive_failures, 0);
+    assert!(health[0].last_success_at.is_some());
+    assert!(health[0].is_due(chrono::Utc::now().naive_utc()));
+
+    // removing the friend drops their record too
+    delete_user(&pool, 1).await.unwrap();
+    assert!(fetch_peer_health(&pool).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_invites_expire_and_can_be_resent() {
+    let pool = setup_test_db().await;
+
+    sqlx::query(
+        "INSERT INTO friends (username, address, status, sent, added_at) VALUES ('old', '1.1.1.1', 0, 1, datetime('now', '-30 days')), ('new', '2.2.2.2', 0, 1, CURRENT_TIMESTAMP)",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+
+    let expired = expire_stale_invites(&pool, std::time::Duration::from_secs(14 * 24 * 60 * 60))
+        .await
+        .unwrap();
+    assert_eq!(expired, 1);
+
+    let friends = fetch_users(&pool).await.unwrap();
+    assert_eq!(friends[0].status, 4);
+    assert_eq!(friends[1].status, 0);
+
+    assert!(reinvite(&pool, friends[0].id).await.unwrap());
+    let (status, sent): (i64, bool) =
+        sqlx::query_as("SELECT status, sent FROM friends WHERE username = 'old'")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert_eq!((status, sent), (0, false));
+}
+
+#[tokio::test]
+async fn test_send_invite_after_rejection() {
+    let pool = setup_test_db().await;
+
+    sqlx::query("INSERT INTO friends (username, address, status, sent) VALUES ('alice', '1.1.1.1', 3, 1), ('bob', '2.2.2.2', 2, 1)")
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let request = |username: &str| FriendRequest {
+        username: username.to_string(),
+        address: "3.3.3.3".to_string(),
+        intro: Some("second try".to_string()),
+        fingerprint: None,
+        invite_token: None,
+    };
+
+    send_invite(&pool, &request("alice")).await.unwrap();
+    let (status, sent, address): (i64, bool, String) =
+        sqlx::query_as("SELECT status, sent, address FROM friends WHERE username = 'alice'")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert_eq!((status, sent, address.as_str()), (0, false, "3.3.3.3"));
+
+    // accepted friends are still unique
+    assert!(send_invite(&pool, &request("bob")).await.is_err());
+    assert!(!reinvite(&pool, 2).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_labels_target_broadcasts() {
+    let pool = setup_test_db().await;
+
+    sqlx::query("INSERT INTO user (id, username, address) VALUES (0, 'testuser', '127.0.0.1')")
+        .execute(&pool)
+        .await
+        .unwrap();
+    sqlx::query("INSERT INTO friends (username, address, status) VALUES ('alice', '1.1.1.1', 2), ('bob', '2.2.2.2', 2), ('carol', '3.3.3.3', 1)")
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let work = vec!["work".to_string()];
+    set_friend_labels(&pool, 1, &work).await.unwrap();
+    set_friend_labels(&pool, 3, &work).await.unwrap();
+    set_friend_labels(&pool, 2, &["family".to_string()])
+        .await
+        .unwrap();
+    set_friend_details(&pool, 1, Some("Ally"), Some("met at the sauna"))
+        .await
+        .unwrap();
+
+    let labels = fetch_friend_labels(&pool).await.unwrap();
+    assert_eq!(labels[&1], work);
+    assert_eq!(labels[&2], vec!["family".to_string()]);
+
+    let friends = fetch_users(&pool).await.unwrap();
+    assert_eq!(friends[0].display_name(), "Ally");
+    assert_eq!(friends[1].display_name(), "bob");
+
+    // carol hasn't accepted yet so only alice gets the broadcast
+    let recipients = send_message_to_label(&pool, "work", "standup", "at 10")
+        .await
+        .unwrap();
+    assert_eq!(recipients, vec!["alice".to_string()]);
+    assert_eq!(fetch_outgoing(&pool).await.unwrap().len(), 1);
+
+    // a broadcast that fails halfway queues nothing
+    set_friend_labels(&pool, 2, &work).await.unwrap();
+    sqlx::query("CREATE TRIGGER no_bob BEFORE INSERT ON outgoing WHEN NEW.recipient = 'bob' BEGIN SELECT RAISE(ABORT, 'bob is full'); END")
+        .execute(&pool)
+        .await
+        .unwrap();
+    assert!(
+        send_message_to_label(&pool, "work", "retro", "at 3")
+            .await
+            .is_err()
+    );
+    assert_eq!(fetch_outgoing(&pool).await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_set_user_address() {
+    let pool = setup_test_db().await;
+    let user = User {
+        id: 0,
+        username: "me".to_string(),
+        address: "127.0.0.1:8080".to_string(),
+        peer_key: None,
+    };
+    setup_db(&pool, &user).await.unwrap();
+
+    assert!(set_user_address(&pool, "192.168.1.20:8080").await.unwrap());
+    assert!(!set_user_address(&pool, "192.168.1.20:8080").await.unwrap());
+    assert_eq!(retr_user(&pool).await.unwrap().address, "192.168.1.20:8080");
+}
+
+#[tokio::test]
+async fn test_nicknames_resolve_to_one_friend() {
+    let pool = setup_test_db().await;
+    sqlx::query("INSERT INTO friends (username, address, status, nickname) VALUES ('alice', '1.1.1.1', 2, NULL), ('bob', '2.2.2.2', 2, NULL), ('carol', '3.3.3.3', 2, 'Cee')")
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    set_friend_details(&pool, 1, Some("Ally"), None)
+        .await
+        .unwrap();
+    assert_eq!(resolve_recipient(&pool, "Ally").await.unwrap(), "alice");
+    assert_eq!(resolve_recipient(&pool, "bob").await.unwrap(), "bob");
+    assert_eq!(resolve_recipient(&pool, "dave").await.unwrap(), "dave");
+
+    // taken nicknames and other friends' usernames are refused
+    let err = set_friend_details(&pool, 2, Some("Ally"), None)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::Validation(_)), "{}", err);
+    let err = set_friend_details(&pool, 2, Some("carol"), None)
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("carol"), "{}", err);
+    // keeping your own nickname is fine
+    set_friend_details(&pool, 1, Some("Ally"), Some("sauna"))
+        .await
+        .unwrap();
+
+    // an import can still bring in a clash, nobody gets the message then
+    sqlx::query("UPDATE friends SET nickname = 'Cee' WHERE username = 'bob'")
+        .execute(&pool)
+        .await
+        .unwrap();
+    let err = resolve_recipient(&pool, "Cee").await.unwrap_err();
+    assert!(err.to_string().contains("ambiguous recipient"), "{}", err);
+}
+
+#[tokio::test]
+async fn test_invites_cannot_downgrade_a_friend() {
+    let pool = setup_test_db().await;
+    sqlx::query("INSERT INTO friends (username, address, status, peer_key) VALUES ('alice', '1.1.1.1', 2, 'alicekey'), ('bob', '2.2.2.2', 3, 'oldkey')")
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    let forged = IncomingInvite {
+        username: "alice",
+        address: "6.6.6.6",
+        peer_key: Some("mallorykey"),
+        intro: Some("it's me, honest"),
+        invite_token: None,
+    };
+    assert_eq!(
+        receive_invite(&pool, &forged).await.unwrap(),
+        InviteOutcome::Ignored
+    );
+    let (status, address, key): (i64, String, Option<String>) =
+        sqlx::query_as("SELECT status, address, peer_key FROM friends WHERE username = 'alice'")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert_eq!((status, address.as_str()), (2, "1.1.1.1"));
+    assert_eq!(key.as_deref(), Some("alicekey"));
+
+    // someone we turned down may ask again, as may a newcomer
+    for (username, address) in [("bob", "2.2.2.3"), ("carol", "3.3.3.3")] {
+        let invite = IncomingInvite {
+            username,
+            address,
+            peer_key: Some("newkey"),
+            intro: None,
+            invite_token: None,
+        };
+        assert_eq!(
+            receive_invite(&pool, &invite).await.unwrap(),
+            InviteOutcome::Received
+        );
+    }
+    let (status, address, key): (i64, String, Option<String>) =
+        sqlx::query_as("SELECT status, address, peer_key FROM friends WHERE username = 'bob'")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    assert_eq!((status, address.as_str()), (1, "2.2.2.3"));
+    assert_eq!(key.as_deref(), Some("newkey"));
+}
+
+#[tokio::test]
+async fn test_only_received_invites_can_be_answered() {
+    let pool = setup_test_db().await;
+    sqlx::query("INSERT INTO friends (username, address, status) VALUES ('alice', '1.1.1.1', 0), ('bob', '2.2.2.2', 4), ('carol', '3.3.3.3', 1)")
+        .execute(&pool)
+        .await
+        .unwrap();
+
+    // our own invite and an expired one aren't ours to accept
+    for id in [1, 2, 42] {
+        let error = invite_decision(&pool, id, true).await.unwrap_err();
+        assert!(matches!(error, Error::NotFound(_)), "{:?}", error);
+    }
+    invite_decision(&pool, 3, true).await.unwrap();
+    let statuses: Vec<i64> = sqlx::query_scalar("SELECT status FROM friends ORDER BY id")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+    assert_eq!(statuses, vec![0, 4, 2]);
+    // nor can it be answered twice
+    assert!(invite_decision(&pool, 3, false).await.is_err());
 }
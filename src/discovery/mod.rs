@@ -0,0 +1,197 @@
+// LAN discovery
+// every node periodically sends a small JSON announcement over UDP
+// (broadcast by default) and listens for the announcements of others.
+// Peers not heard from for a few intervals drop out of the nearby list.
+
+use crate::shutdown::ShutdownSignal;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use tracing::error;
+
+#[cfg(test)]
+mod tests;
+
+const SERVICE: &str = "mankeli-chat";
+const MAX_DATAGRAM: usize = 1024;
+// how many missed announcements before a peer is considered gone
+const MISSED_ANNOUNCEMENTS: u32 = 3;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct DiscoveryConfig {
+    #[serde(default = "default_bind")]
+    pub bind: SocketAddr,
+    #[serde(default = "default_targets")]
+    pub targets: Vec<SocketAddr>,
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+}
+
+pub const DEFAULT_PORT: u16 = 47474;
+
+fn default_bind() -> SocketAddr {
+    SocketAddr::from(([0, 0, 0, 0], DEFAULT_PORT))
+}
+
+fn default_targets() -> Vec<SocketAddr> {
+    vec![SocketAddr::from(([255, 255, 255, 255], DEFAULT_PORT))]
+}
+
+fn default_interval() -> u64 {
+    5
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        DiscoveryConfig {
+            bind: default_bind(),
+            targets: default_targets(),
+            interval: default_interval(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Announcement {
+    pub service: String,
+    pub username: String,
+    pub address: String,
+    pub fingerprint: String,
+}
+
+impl Announcement {
+    pub fn new(username: &str, address: &str, fingerprint: &str) -> Self {
+        Announcement {
+            service: SERVICE.to_string(),
+            username: username.to_string(),
+            address: address.to_string(),
+            fingerprint: fingerprint.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NearbyPeer {
+    pub username: String,
+    pub address: String,
+    pub fingerprint: String,
+    pub last_seen: Instant,
+}
+
+#[derive(Clone)]
+pub struct Discovery {
+    peers: Arc<Mutex<HashMap<String, NearbyPeer>>>,
+    local_addr: SocketAddr,
+    ttl: Duration,
+}
+
+impl Discovery {
+    // Binds the configured socket and starts announcing/listening until
+    // shutdown, the handle finishes once the socket is closed
+    pub async fn start(
+        config: &DiscoveryConfig,
+        me: Announcement,
+        shutdown: ShutdownSignal,
+    ) -> std::io::Result<(Self, JoinHandle<()>)> {
+        let socket = UdpSocket::bind(config.bind).await?;
+        Discovery::spawn(
+            socket,
+            config.targets.clone(),
+            config.interval,
+            me,
+            shutdown,
+        )
+    }
+
+    pub fn spawn(
+        socket: UdpSocket,
+        targets: Vec<SocketAddr>,
+        interval: u64,
+        me: Announcement,
+        mut shutdown: ShutdownSignal,
+    ) -> std::io::Result<(Self, JoinHandle<()>)> {
+        socket.set_broadcast(true)?;
+        let interval = Duration::from_secs(interval.max(1));
+
+        let discovery = Discovery {
+            peers: Arc::new(Mutex::new(HashMap::new())),
+            local_addr: socket.local_addr()?,
+            ttl: interval * MISSED_ANNOUNCEMENTS,
+        };
+
+        let peers = discovery.peers.clone();
+        let task = tokio::spawn(async move {
+            let payload = match serde_json::to_vec(&me) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    error!("Discovery disabled, cannot encode announcement: {}", e);
+                    return;
+                }
+            };
+            let mut ticker = tokio::time::interval(interval);
+            let mut buf = [0u8; MAX_DATAGRAM];
+
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {
+                        for target in &targets {
+                            // unreachable targets are normal on a LAN, keep going
+                            let _ = socket.send_to(&payload, target).await;
+                        }
+                    }
+                    received = socket.recv_from(&mut buf) => {
+                        if let Ok((len, _)) = received {
+                            record_announcement(&peers, &me, &buf[..len]);
+                        }
+                    }
+                    _ = shutdown.wait() => break,
+                }
+            }
+        });
+
+        Ok((discovery, task))
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    // Peers heard from recently, most recent first
+    pub fn nearby(&self) -> Vec<NearbyPeer> {
+        let mut peers = self.peers.lock().unwrap();
+        peers.retain(|_, peer| peer.last_seen.elapsed() < self.ttl);
+
+        let mut nearby: Vec<NearbyPeer> = peers.values().cloned().collect();
+        nearby.sort_by_key(|peer| std::cmp::Reverse(peer.last_seen));
+        nearby
+    }
+}
+
+fn record_announcement(
+    peers: &Mutex<HashMap<String, NearbyPeer>>,
+    me: &Announcement,
+    datagram: &[u8],
+) {
+    let Ok(announcement) = serde_json::from_slice::<Announcement>(datagram) else {
+        return;
+    };
+
+    // our own broadcasts loop back to us
+    if announcement.service != SERVICE || announcement.fingerprint == me.fingerprint {
+        return;
+    }
+
+    peers.lock().unwrap().insert(
+        announcement.fingerprint.clone(),
+        NearbyPeer {
+            username: announcement.username,
+            address: announcement.address,
+            fingerprint: announcement.fingerprint,
+            last_seen: Instant::now(),
+        },
+    );
+}
@@ -0,0 +1,75 @@
+use super::*;
+use crate::shutdown::Shutdown;
+
+async fn loopback_socket() -> UdpSocket {
+    UdpSocket::bind("127.0.0.1:0").await.unwrap()
+}
+
+#[tokio::test]
+async fn test_nodes_discover_each_other_on_loopback() {
+    let sockets = [
+        loopback_socket().await,
+        loopback_socket().await,
+        loopback_socket().await,
+    ];
+    let addrs: Vec<SocketAddr> = sockets.iter().map(|s| s.local_addr().unwrap()).collect();
+
+    let shutdown = Shutdown::new();
+    let mut nodes = Vec::new();
+    let mut tasks = Vec::new();
+    for (i, socket) in sockets.into_iter().enumerate() {
+        let me = Announcement::new(
+            &format!("node{}", i),
+            &format!("127.0.0.1:{}", 9000 + i),
+            &format!("fp{}", i),
+        );
+        let (node, task) =
+            Discovery::spawn(socket, addrs.clone(), 1, me, shutdown.subscribe()).unwrap();
+        nodes.push(node);
+        tasks.push(task);
+    }
+
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    for (i, node) in nodes.iter().enumerate() {
+        let mut seen: Vec<String> = node.nearby().into_iter().map(|p| p.username).collect();
+        seen.sort();
+        let expected: Vec<String> = (0..3)
+            .filter(|j| *j != i)
+            .map(|j| format!("node{}", j))
+            .collect();
+        assert_eq!(seen, expected);
+    }
+
+    let peer = nodes[0]
+        .nearby()
+        .into_iter()
+        .find(|p| p.username == "node1")
+        .unwrap();
+    assert_eq!(peer.address, "127.0.0.1:9001");
+    assert_eq!(peer.fingerprint, "fp1");
+
+    // the announce loops stop with the node
+    shutdown.trigger();
+    for task in tasks {
+        tokio::time::timeout(Duration::from_secs(5), task)
+            .await
+            .expect("discovery kept running after shutdown")
+            .unwrap();
+    }
+}
+
+#[test]
+fn test_foreign_and_own_datagrams_are_ignored() {
+    let peers = Mutex::new(HashMap::new());
+    let me = Announcement::new("me", "127.0.0.1:1", "mine");
+
+    record_announcement(&peers, &me, b"not json");
+    record_announcement(&peers, &me, &serde_json::to_vec(&me).unwrap());
+
+    let mut other = Announcement::new("other", "127.0.0.1:2", "theirs");
+    other.service = "something-else".into();
+    record_announcement(&peers, &me, &serde_json::to_vec(&other).unwrap());
+
+    assert!(peers.lock().unwrap().is_empty());
+}
@@ -0,0 +1,136 @@
+// Crate-wide error type
+// Callers match on the kind of failure instead of parsing strings: a peer
+// that can't be reached is worth retrying, one that answered 4xx is not.
+
+use std::fmt;
+
+#[cfg(test)]
+mod tests;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug)]
+pub enum Error {
+    // the peer or relay could not be reached, or the connection broke
+    Network(String),
+    // the peer answered, but not with something we understand or accept
+    Protocol {
+        status: Option<u16>,
+        message: String,
+    },
+    Storage(sqlx::Error),
+    NotFound(String),
+    Validation(String),
+}
+
+impl Error {
+    pub fn protocol(message: impl Into<String>) -> Self {
+        Error::Protocol {
+            status: None,
+            message: message.into(),
+        }
+    }
+
+    // Non-success HTTP answer from a peer or relay
+    pub fn from_status(status: reqwest::StatusCode) -> Self {
+        Error::Protocol {
+            status: Some(status.as_u16()),
+            message: format!("Bad status: {}", status),
+        }
+    }
+
+    // Whether trying the same thing again later can succeed. A 4xx means the
+    // peer understood and refused us (except for rate limiting).
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Network(_) | Error::Storage(_) => true,
+            Error::Protocol {
+                status: Some(status),
+                ..
+            } => !(400..500).contains(status) || *status == 429,
+            Error::Protocol { status: None, .. } => true,
+            Error::NotFound(_) | Error::Validation(_) => false,
+        }
+    }
+
+    // Whether it counts against the peer: it couldn't be reached or failed
+    // to answer properly. Refusals and our own errors don't
+    pub fn is_peer_failure(&self) -> bool {
+        match self {
+            Error::Network(_) => true,
+            Error::Protocol { .. } => self.is_retryable(),
+            Error::Storage(_) | Error::NotFound(_) | Error::Validation(_) => false,
+        }
+    }
+
+    // Whether the other side answered at all
+    pub fn peer_responded(&self) -> bool {
+        matches!(
+            self,
+            Error::Protocol {
+                status: Some(_),
+                ..
+            }
+        )
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Network(e) => write!(f, "Network error: {}", e),
+            Error::Protocol { message, .. } => write!(f, "Protocol error: {}", message),
+            Error::Storage(e) => write!(f, "DB error: {}", e),
+            Error::NotFound(what) => write!(f, "Not found: {}", what),
+            Error::Validation(e) => write!(f, "Invalid input: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Storage(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<sqlx::Error> for Error {
+    fn from(e: sqlx::Error) -> Self {
+        match e {
+            sqlx::Error::RowNotFound => Error::NotFound("no matching row".into()),
+            e => Error::Storage(e),
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        // a timeout may also surface while reading the body, it is still the
+        // peer not answering
+        if e.is_timeout() {
+            Error::Network(e.to_string())
+        } else if let Some(status) = e.status() {
+            Error::Protocol {
+                status: Some(status.as_u16()),
+                message: e.to_string(),
+            }
+        } else if e.is_decode() {
+            Error::protocol(format!("Parse error: {}", e))
+        } else {
+            Error::Network(e.to_string())
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        let kind = match e.classify() {
+            serde_json::error::Category::Io => "JSON I/O error",
+            serde_json::error::Category::Syntax | serde_json::error::Category::Eof => "Parse error",
+            serde_json::error::Category::Data => "JSON data error",
+        };
+        Error::protocol(format!("{}: {}", kind, e))
+    }
+}
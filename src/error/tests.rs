@@ -0,0 +1,50 @@
+use super::*;
+use reqwest::StatusCode;
+
+#[test]
+fn test_retry_classification() {
+    assert!(Error::Network("connection refused".into()).is_retryable());
+    assert!(Error::from_status(StatusCode::INTERNAL_SERVER_ERROR).is_retryable());
+    assert!(Error::from_status(StatusCode::TOO_MANY_REQUESTS).is_retryable());
+    assert!(!Error::from_status(StatusCode::BAD_REQUEST).is_retryable());
+    assert!(!Error::from_status(StatusCode::NOT_FOUND).is_retryable());
+    assert!(!Error::Validation("too long".into()).is_retryable());
+
+    assert!(Error::from_status(StatusCode::BAD_REQUEST).peer_responded());
+    assert!(!Error::Network("timed out".into()).peer_responded());
+
+    // only the peer's own trouble counts against it
+    assert!(Error::Network("timed out".into()).is_peer_failure());
+    assert!(Error::from_status(StatusCode::BAD_GATEWAY).is_peer_failure());
+    assert!(!Error::from_status(StatusCode::FORBIDDEN).is_peer_failure());
+    assert!(!Error::Storage(sqlx::Error::PoolClosed).is_peer_failure());
+    assert!(!Error::Validation("too long".into()).is_peer_failure());
+}
+
+#[test]
+fn test_json_errors_name_what_went_wrong() {
+    let syntax = serde_json::from_str::<u32>("12 x").unwrap_err();
+    assert!(
+        Error::from(syntax)
+            .to_string()
+            .starts_with("Protocol error: Parse error")
+    );
+    let data = serde_json::from_str::<u32>("\"seven\"").unwrap_err();
+    assert!(
+        Error::from(data)
+            .to_string()
+            .starts_with("Protocol error: JSON data error")
+    );
+}
+
+#[test]
+fn test_missing_rows_are_not_found() {
+    assert!(matches!(
+        Error::from(sqlx::Error::RowNotFound),
+        Error::NotFound(_)
+    ));
+    assert!(matches!(
+        Error::from(sqlx::Error::PoolClosed),
+        Error::Storage(_)
+    ));
+}
@@ -0,0 +1,101 @@
+// Event bus
+// api and comms publish what happened as it happens, the CLI (and anything
+// embedding the crate) subscribes instead of polling the database.
+// Built on a tokio broadcast channel: publishing never blocks, and a
+// subscriber that falls too far behind skips the oldest events.
+
+use serde::Serialize;
+use std::fmt;
+use tokio::sync::broadcast;
+
+#[cfg(test)]
+mod tests;
+
+// How many events a slow subscriber may lag behind before losing some
+const CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    NewMessage {
+        sender: String,
+        subject: String,
+    },
+    InviteReceived {
+        username: String,
+        address: String,
+        intro: Option<String>,
+    },
+    // a friendship became active, either side may have accepted
+    InviteAccepted {
+        username: String,
+    },
+    // our queued mail left the outbox, picked up by the friend or their relay
+    DeliveryConfirmed {
+        recipient: String,
+        count: usize,
+        relay: bool,
+    },
+    PeerUnreachable {
+        username: String,
+        error: String,
+    },
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::NewMessage { sender, subject } => {
+                write!(f, "New message from {}: {}", sender, subject)
+            }
+            Event::InviteReceived {
+                username, intro, ..
+            } => match intro {
+                Some(intro) => write!(f, "Friend invite from {}: \"{}\"", username, intro),
+                None => write!(f, "Friend invite from {}", username),
+            },
+            Event::InviteAccepted { username } => {
+                write!(f, "You are now friends with {}", username)
+            }
+            Event::DeliveryConfirmed {
+                recipient,
+                count,
+                relay,
+            } => {
+                let via = if *relay { " (via relay)" } else { "" };
+                write!(f, "Delivered {} message(s) to {}{}", count, recipient, via)
+            }
+            Event::PeerUnreachable { username, error } => {
+                write!(f, "{} is unreachable: {}", username, error)
+            }
+        }
+    }
+}
+
+// Cheap to clone, every clone publishes to the same subscribers
+#[derive(Clone)]
+pub struct EventBus {
+    tx: broadcast::Sender<Event>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(CAPACITY);
+        EventBus { tx }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.tx.subscribe()
+    }
+
+    pub fn publish(&self, event: Event) {
+        // nobody listening is fine
+        let _ = self.tx.send(event);
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        EventBus::new()
+    }
+}
@@ -0,0 +1,41 @@
+use super::*;
+
+#[tokio::test]
+async fn test_every_subscriber_gets_events() {
+    let bus = EventBus::new();
+    // publishing without subscribers is not an error
+    bus.publish(Event::InviteAccepted {
+        username: "nobody".into(),
+    });
+
+    let mut first = bus.subscribe();
+    let mut second = bus.clone().subscribe();
+    let event = Event::NewMessage {
+        sender: "alice".into(),
+        subject: "lunch".into(),
+    };
+    bus.publish(event.clone());
+
+    assert_eq!(first.recv().await.unwrap(), event);
+    assert_eq!(second.recv().await.unwrap(), event);
+    assert!(first.try_recv().is_err());
+}
+
+#[test]
+fn test_event_json_shape() {
+    let event = Event::DeliveryConfirmed {
+        recipient: "bob".into(),
+        count: 2,
+        relay: false,
+    };
+    assert_eq!(
+        serde_json::to_value(&event).unwrap(),
+        serde_json::json!({
+            "event": "delivery_confirmed",
+            "recipient": "bob",
+            "count": 2,
+            "relay": false,
+        })
+    );
+    assert_eq!(event.to_string(), "Delivered 2 message(s) to bob");
+}
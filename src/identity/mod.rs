@@ -0,0 +1,187 @@
+// Node identity
+// Every node has an ed25519 keypair. The public half is the peer key friends
+// pin through the fingerprint in invite links, the secret half stays in the
+// user table and signs what we send, so a pinned key can't be claimed by
+// someone who merely saw it. Keys and signatures travel as unpadded
+// URL-safe base64.
+//
+// Mail left at a relay is sealed: encrypted to the recipient's key (X25519
+// of the same keypair, a fresh ephemeral key per message, ChaCha20-Poly1305)
+// and signed by the sender, so the relay can neither read nor forge it.
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+const SEAL_CONTEXT: &[u8] = b"mankeli-sealed-v1";
+// ephemeral key, nonce and signature ahead of the ciphertext
+const EPHEMERAL_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const SIGNATURE_LEN: usize = 64;
+
+#[cfg(test)]
+mod tests;
+
+#[derive(Clone)]
+pub struct Identity {
+    signing: SigningKey,
+}
+
+impl Identity {
+    pub fn generate() -> Self {
+        Identity {
+            signing: SigningKey::from_bytes(&rand::random()),
+        }
+    }
+
+    // None when `secret` isn't a key we wrote
+    pub fn from_secret(secret: &str) -> Option<Self> {
+        let bytes: [u8; 32] = URL_SAFE_NO_PAD.decode(secret).ok()?.try_into().ok()?;
+        Some(Identity {
+            signing: SigningKey::from_bytes(&bytes),
+        })
+    }
+
+    pub fn secret(&self) -> String {
+        URL_SAFE_NO_PAD.encode(self.signing.to_bytes())
+    }
+
+    pub fn peer_key(&self) -> String {
+        URL_SAFE_NO_PAD.encode(self.signing.verifying_key().to_bytes())
+    }
+
+    pub fn sign(&self, message: &[u8]) -> String {
+        URL_SAFE_NO_PAD.encode(self.signing.sign(message).to_bytes())
+    }
+
+    // Encrypts `plaintext` so only the holder of `recipient` can read it,
+    // signed by us. None when `recipient` isn't a peer key.
+    pub fn seal(&self, recipient: &str, plaintext: &[u8]) -> Option<String> {
+        let recipient_key = decode_key(recipient)?;
+        let ephemeral = StaticSecret::from(rand::random::<[u8; 32]>());
+        let ephemeral_public = PublicKey::from(&ephemeral);
+        let shared = ephemeral.diffie_hellman(&exchange_key(&recipient_key));
+        let cipher = seal_cipher(shared.as_bytes(), &ephemeral_public, &recipient_key);
+
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce), plaintext).ok()?;
+        let signature = self.signing.sign(&sealed_bytes(
+            &recipient_key,
+            ephemeral_public.as_bytes(),
+            &nonce,
+            &ciphertext,
+        ));
+
+        let mut sealed =
+            Vec::with_capacity(EPHEMERAL_LEN + NONCE_LEN + SIGNATURE_LEN + ciphertext.len());
+        sealed.extend_from_slice(ephemeral_public.as_bytes());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&signature.to_bytes());
+        sealed.extend_from_slice(&ciphertext);
+        Some(URL_SAFE_NO_PAD.encode(sealed))
+    }
+
+    // Reads what `sender` sealed for us. None unless it is intact, meant for
+    // us and signed by `sender`.
+    pub fn open(&self, sender: &str, sealed: &str) -> Option<Vec<u8>> {
+        let sender_key = decode_key(sender)?;
+        let sealed = URL_SAFE_NO_PAD.decode(sealed).ok()?;
+        if sealed.len() < EPHEMERAL_LEN + NONCE_LEN + SIGNATURE_LEN {
+            return None;
+        }
+        let (ephemeral, rest) = sealed.split_at(EPHEMERAL_LEN);
+        let (nonce, rest) = rest.split_at(NONCE_LEN);
+        let (signature, ciphertext) = rest.split_at(SIGNATURE_LEN);
+
+        let our_key = self.signing.verifying_key();
+        let signature = Signature::from_slice(signature).ok()?;
+        sender_key
+            .verify(
+                &sealed_bytes(&our_key, ephemeral, nonce, ciphertext),
+                &signature,
+            )
+            .ok()?;
+
+        let ephemeral_public = PublicKey::from(<[u8; 32]>::try_from(ephemeral).ok()?);
+        let secret = StaticSecret::from(self.signing.to_scalar_bytes());
+        let shared = secret.diffie_hellman(&ephemeral_public);
+        seal_cipher(shared.as_bytes(), &ephemeral_public, &our_key)
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .ok()
+    }
+}
+
+// The X25519 key of an ed25519 peer key, its secret half is to_scalar_bytes
+fn exchange_key(key: &VerifyingKey) -> PublicKey {
+    PublicKey::from(key.to_montgomery().to_bytes())
+}
+
+fn seal_cipher(shared: &[u8], ephemeral: &PublicKey, recipient: &VerifyingKey) -> ChaCha20Poly1305 {
+    let key = Sha256::new()
+        .chain_update(SEAL_CONTEXT)
+        .chain_update(shared)
+        .chain_update(ephemeral.as_bytes())
+        .chain_update(recipient.as_bytes())
+        .finalize();
+    ChaCha20Poly1305::new_from_slice(&key).expect("SHA-256 output is a ChaCha20 key")
+}
+
+// What the sender signs, binding the ciphertext to its recipient
+fn sealed_bytes(
+    recipient: &VerifyingKey,
+    ephemeral: &[u8],
+    nonce: &[u8],
+    ciphertext: &[u8],
+) -> Vec<u8> {
+    [
+        SEAL_CONTEXT,
+        recipient.as_bytes(),
+        ephemeral,
+        nonce,
+        ciphertext,
+    ]
+    .concat()
+}
+
+// keeps the secret out of logs
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Identity")
+            .field("peer_key", &self.peer_key())
+            .finish_non_exhaustive()
+    }
+}
+
+// Whether `signature` is `peer_key`'s signature over `message`. Keys that
+// aren't ed25519 keys, like the random strings of older nodes, never verify.
+pub fn verify(peer_key: &str, message: &[u8], signature: &str) -> bool {
+    let Some(key) = decode_key(peer_key) else {
+        return false;
+    };
+    let Ok(signature) = URL_SAFE_NO_PAD.decode(signature) else {
+        return false;
+    };
+    let Ok(signature) = Signature::from_slice(&signature) else {
+        return false;
+    };
+    key.verify(message, &signature).is_ok()
+}
+
+// Names a sealed payload, the same mail handed out twice has the same digest
+pub fn sealed_digest(sealed: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(sealed.as_bytes()))
+}
+
+pub fn is_peer_key(peer_key: &str) -> bool {
+    decode_key(peer_key).is_some()
+}
+
+fn decode_key(peer_key: &str) -> Option<VerifyingKey> {
+    let bytes: [u8; 32] = URL_SAFE_NO_PAD.decode(peer_key).ok()?.try_into().ok()?;
+    VerifyingKey::from_bytes(&bytes).ok()
+}
@@ -0,0 +1,55 @@
+use super::*;
+
+#[test]
+fn test_signatures_verify_only_for_their_key() {
+    let alice = Identity::generate();
+    let mallory = Identity::generate();
+    let signature = alice.sign(b"hello");
+
+    assert!(verify(&alice.peer_key(), b"hello", &signature));
+    assert!(!verify(&alice.peer_key(), b"hullo", &signature));
+    assert!(!verify(&mallory.peer_key(), b"hello", &signature));
+    assert!(!verify(&alice.peer_key(), b"hello", "not base64!"));
+    // the random keys of older nodes are not keys at all
+    assert!(!verify("alicekey", b"hello", &signature));
+    assert!(!is_peer_key("alicekey"));
+    assert!(is_peer_key(&alice.peer_key()));
+}
+
+#[test]
+fn test_secret_round_trip() {
+    let alice = Identity::generate();
+    let restored = Identity::from_secret(&alice.secret()).unwrap();
+    assert_eq!(restored.peer_key(), alice.peer_key());
+    assert!(Identity::from_secret("short").is_none());
+    assert!(!format!("{:?}", alice).contains(&alice.secret()));
+}
+
+#[test]
+fn test_sealed_mail_opens_only_for_its_recipient() {
+    let alice = Identity::generate();
+    let bob = Identity::generate();
+    let mallory = Identity::generate();
+
+    let sealed = alice.seal(&bob.peer_key(), b"meet at noon").unwrap();
+    assert!(!sealed.contains("noon"));
+    assert_eq!(
+        bob.open(&alice.peer_key(), &sealed).as_deref(),
+        Some(&b"meet at noon"[..])
+    );
+
+    // someone else can't read it, nor pass off their own mail as alice's
+    assert!(mallory.open(&alice.peer_key(), &sealed).is_none());
+    let forged = mallory.seal(&bob.peer_key(), b"send money").unwrap();
+    assert!(bob.open(&alice.peer_key(), &forged).is_none());
+
+    // any change to the sealed bytes breaks it
+    let mut bytes = URL_SAFE_NO_PAD.decode(&sealed).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 1;
+    assert!(
+        bob.open(&alice.peer_key(), &URL_SAFE_NO_PAD.encode(bytes))
+            .is_none()
+    );
+    assert!(alice.seal("alicekey", b"hi").is_none());
+}
@@ -0,0 +1,167 @@
+// Invite links
+// mankeli://<username>@<address>?fp=<fingerprint>[&t=<one-time token>]
+// the fingerprint pins the inviter's peer key, the token lets the
+// inviter's node accept the resulting friend request without asking
+
+use crate::db::FriendRequest;
+use crate::error::Result;
+use crate::store::FriendStore;
+use percent_encoding::{NON_ALPHANUMERIC, percent_decode_str, utf8_percent_encode};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use url::{Host, Url};
+
+#[cfg(test)]
+mod tests;
+
+pub const SCHEME: &str = "mankeli";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InviteLink {
+    pub username: String,
+    pub address: String,
+    pub fingerprint: String,
+    pub token: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum InviteLinkError {
+    Malformed(String),
+    WrongScheme(String),
+    MissingField(&'static str),
+}
+
+impl fmt::Display for InviteLinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InviteLinkError::Malformed(e) => write!(f, "malformed invite link: {}", e),
+            InviteLinkError::WrongScheme(s) => {
+                write!(f, "expected a {}:// link, got {}://", SCHEME, s)
+            }
+            InviteLinkError::MissingField(field) => write!(f, "invite link has no {}", field),
+        }
+    }
+}
+
+impl std::error::Error for InviteLinkError {}
+
+// Short, human comparable digest of a peer key
+pub fn fingerprint(peer_key: &str) -> String {
+    Sha256::digest(peer_key.as_bytes())
+        .iter()
+        .take(8)
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// IPv6 hosts need brackets in a URL, SocketAddr already writes them that way
+fn url_host(address: &str) -> String {
+    if let Ok(socket) = address.parse::<SocketAddr>() {
+        return socket.to_string();
+    }
+    match address.parse::<IpAddr>() {
+        Ok(IpAddr::V6(ip)) => format!("[{}]", ip),
+        _ => address.to_string(),
+    }
+}
+
+impl fmt::Display for InviteLink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}://{}@{}?fp={}",
+            SCHEME,
+            utf8_percent_encode(&self.username, NON_ALPHANUMERIC),
+            url_host(&self.address),
+            utf8_percent_encode(&self.fingerprint, NON_ALPHANUMERIC)
+        )?;
+        if let Some(token) = &self.token {
+            write!(f, "&t={}", utf8_percent_encode(token, NON_ALPHANUMERIC))?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for InviteLink {
+    type Err = InviteLinkError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url = Url::parse(s.trim()).map_err(|e| InviteLinkError::Malformed(e.to_string()))?;
+
+        if url.scheme() != SCHEME {
+            return Err(InviteLinkError::WrongScheme(url.scheme().to_string()));
+        }
+
+        let username = percent_decode_str(url.username())
+            .decode_utf8()
+            .map_err(|e| InviteLinkError::Malformed(e.to_string()))?
+            .to_string();
+        if username.is_empty() {
+            return Err(InviteLinkError::MissingField("username"));
+        }
+
+        // host_str keeps IPv6 brackets, which "host:port" needs too
+        let host = url
+            .host_str()
+            .ok_or(InviteLinkError::MissingField("address"))?;
+        let address = match (url.host(), url.port()) {
+            (_, Some(port)) => format!("{}:{}", host, port),
+            (Some(Host::Ipv6(ip)), None) => ip.to_string(),
+            _ => host.to_string(),
+        };
+
+        let mut fingerprint = None;
+        let mut token = None;
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "fp" => fingerprint = Some(value.into_owned()),
+                "t" => token = Some(value.into_owned()),
+                _ => {}
+            }
+        }
+
+        Ok(InviteLink {
+            username,
+            address,
+            fingerprint: fingerprint.ok_or(InviteLinkError::MissingField("fingerprint"))?,
+            token,
+        })
+    }
+}
+
+// Builds a link for our own identity, optionally with a fresh one-time token
+pub async fn create_invite_link<S: FriendStore>(store: &S, one_time: bool) -> Result<InviteLink> {
+    let identity = store.local_identity().await?;
+    let user = store.local_user().await?;
+
+    let token = if one_time {
+        Some(store.create_invite_token().await?)
+    } else {
+        None
+    };
+
+    Ok(InviteLink {
+        username: user.username,
+        address: user.address,
+        fingerprint: fingerprint(&identity.peer_key()),
+        token,
+    })
+}
+
+// Queues a friend invite to the node behind the link
+pub async fn add_from_invite<S: FriendStore>(
+    store: &S,
+    link: &InviteLink,
+    intro: Option<String>,
+) -> Result<()> {
+    let request = FriendRequest {
+        username: link.username.clone(),
+        address: link.address.clone(),
+        intro,
+        fingerprint: Some(link.fingerprint.clone()),
+        invite_token: link.token.clone(),
+    };
+    store.send_invite(&request).await
+}
@@ -0,0 +1,83 @@
+use super::*;
+use crate::db::{MIGRATOR, User, ensure_identity, fetch_users, redeem_invite_token, setup_db};
+use sqlx::SqlitePool;
+
+async fn setup_test_db() -> SqlitePool {
+    let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+    MIGRATOR.run(&pool).await.unwrap();
+    pool
+}
+
+#[test]
+fn test_invite_link_round_trip() {
+    let link = InviteLink {
+        username: "matti meikäläinen".into(),
+        address: "192.168.1.5:8080".into(),
+        fingerprint: fingerprint("somekey"),
+        token: Some("abc123".into()),
+    };
+
+    let encoded = link.to_string();
+    assert!(encoded.starts_with("mankeli://"));
+
+    let parsed: InviteLink = encoded.parse().unwrap();
+    assert_eq!(parsed, link);
+
+    // IPv6 hosts are bracketed, with or without a port
+    for address in ["[2001:db8::5]:8080", "2001:db8::5"] {
+        let link = InviteLink {
+            address: address.into(),
+            ..link.clone()
+        };
+        let encoded = link.to_string();
+        assert!(encoded.contains("@[2001:db8::5]"), "{}", encoded);
+        assert_eq!(encoded.parse::<InviteLink>().unwrap(), link);
+    }
+}
+
+#[test]
+fn test_invite_link_rejects_bad_input() {
+    assert_eq!(
+        "http://alice@1.2.3.4:80?fp=ab".parse::<InviteLink>(),
+        Err(InviteLinkError::WrongScheme("http".into()))
+    );
+    assert_eq!(
+        "mankeli://alice@1.2.3.4:80".parse::<InviteLink>(),
+        Err(InviteLinkError::MissingField("fingerprint"))
+    );
+    assert_eq!(
+        "mankeli://1.2.3.4:80?fp=ab".parse::<InviteLink>(),
+        Err(InviteLinkError::MissingField("username"))
+    );
+}
+
+#[tokio::test]
+async fn test_create_and_add_from_invite() {
+    let pool = setup_test_db().await;
+    let user = User {
+        id: 0,
+        username: "alice".into(),
+        address: "10.0.0.1:8080".into(),
+        peer_key: None,
+    };
+    setup_db(&pool, &user).await.unwrap();
+    let peer_key = ensure_identity(&pool).await.unwrap().peer_key();
+
+    let link = create_invite_link(&pool, true).await.unwrap();
+    assert_eq!(link.username, "alice");
+    assert_eq!(link.address, "10.0.0.1:8080");
+    assert_eq!(link.fingerprint, fingerprint(&peer_key));
+    let token = link.token.clone().expect("one-time token");
+
+    let other = setup_test_db().await;
+    add_from_invite(&other, &link, None).await.unwrap();
+
+    let friends = fetch_users(&other).await.unwrap();
+    assert_eq!(friends.len(), 1);
+    assert_eq!(friends[0].status, 0);
+    assert_eq!(friends[0].fingerprint, Some(link.fingerprint.clone()));
+    assert_eq!(friends[0].invite_token, Some(token.clone()));
+
+    assert!(redeem_invite_token(&pool, &token).await.unwrap());
+    assert!(!redeem_invite_token(&pool, &token).await.unwrap());
+}
@@ -1,6 +1,25 @@
 pub mod api;
 pub mod comms;
+pub mod config;
+pub mod conformance;
+pub mod contacts;
+#[cfg(unix)]
+pub mod control;
 pub mod db;
+pub mod discovery;
+pub mod error;
+pub mod events;
+pub mod identity;
+pub mod invite;
+pub mod logging;
+pub mod metrics;
+pub mod relay;
+pub mod reload;
+pub mod shutdown;
+pub mod store;
+pub mod transport;
+pub mod tui;
+pub mod wakeup;
 
 use crate::api::FriendRequestStatus;
 pub trait StatusLabel {
@@ -15,6 +34,7 @@ impl StatusLabel for i64 {
             1 => FriendRequestStatus::InviteReceived,
             2 => FriendRequestStatus::Accepted,
             3 => FriendRequestStatus::Rejected,
+            4 => FriendRequestStatus::Expired,
             _ => FriendRequestStatus::Rejected,
         }
     }
@@ -25,6 +45,7 @@ impl StatusLabel for i64 {
             1 => "invite_received",
             2 => "accepted",
             3 => "rejected",
+            4 => "expired",
             _ => "unknown",
         }
     }
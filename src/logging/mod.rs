@@ -0,0 +1,95 @@
+// Logging setup
+// Everything goes to rotating files under the log dir so background tasks
+// never write over the interactive prompt.
+
+use crate::config::Config;
+use std::fmt;
+use std::sync::Arc;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::EnvFilter;
+
+#[cfg(test)]
+mod tests;
+
+const LOG_FILE_PREFIX: &str = "mankeli";
+// rotated files kept around before the oldest is deleted
+const MAX_LOG_FILES: usize = 14;
+
+fn rotation(name: &str) -> Rotation {
+    match name.to_lowercase().as_str() {
+        "minutely" => Rotation::MINUTELY,
+        "hourly" => Rotation::HOURLY,
+        "never" => Rotation::NEVER,
+        _ => Rotation::DAILY,
+    }
+}
+
+// The configured level applies to every crate, SQL statements included
+pub fn filter(level: &str) -> EnvFilter {
+    EnvFilter::try_new(level).unwrap_or_else(|_| EnvFilter::new("warn"))
+}
+
+pub fn file_appender(config: &Config) -> std::io::Result<RollingFileAppender> {
+    let dir = config.log_dir_path();
+    std::fs::create_dir_all(&dir)?;
+    RollingFileAppender::builder()
+        .rotation(rotation(&config.log_rotation))
+        .filename_prefix(LOG_FILE_PREFIX)
+        .filename_suffix("log")
+        .max_log_files(MAX_LOG_FILES)
+        .build(dir)
+        .map_err(std::io::Error::other)
+}
+
+// Swaps the level of the installed subscriber, for config reloads
+#[derive(Clone)]
+pub struct LevelHandle {
+    set: Arc<dyn Fn(EnvFilter) -> Result<(), String> + Send + Sync>,
+}
+
+impl LevelHandle {
+    pub fn new(set: impl Fn(EnvFilter) -> Result<(), String> + Send + Sync + 'static) -> Self {
+        LevelHandle { set: Arc::new(set) }
+    }
+
+    pub fn set(&self, level: &str) -> Result<(), String> {
+        (self.set)(filter(level))
+    }
+}
+
+impl fmt::Debug for LevelHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("LevelHandle")
+    }
+}
+
+// Installs the global subscriber. Keep the guard alive until exit, dropping
+// it flushes whatever is still buffered.
+pub fn init(config: &Config) -> std::io::Result<(WorkerGuard, LevelHandle)> {
+    let (writer, guard) = tracing_appender::non_blocking(file_appender(config)?);
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter(&config.log_level))
+        .with_writer(writer)
+        .with_ansi(false);
+
+    // json and text subscribers are different types, so are their handles
+    let (result, level) = if config.log_format.eq_ignore_ascii_case("json") {
+        let builder = builder
+            .json()
+            .with_current_span(true)
+            .with_filter_reloading();
+        let handle = builder.reload_handle();
+        let level =
+            LevelHandle::new(move |filter| handle.reload(filter).map_err(|e| e.to_string()));
+        (builder.try_init(), level)
+    } else {
+        let builder = builder.with_filter_reloading();
+        let handle = builder.reload_handle();
+        let level =
+            LevelHandle::new(move |filter| handle.reload(filter).map_err(|e| e.to_string()));
+        (builder.try_init(), level)
+    };
+    result.map_err(std::io::Error::other)?;
+    Ok((guard, level))
+}
@@ -0,0 +1,27 @@
+use super::*;
+use std::path::PathBuf;
+
+#[test]
+fn test_log_files_land_in_log_dir() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let config = Config {
+        data_dir: data_dir.path().to_path_buf(),
+        log_dir: PathBuf::from("logs"),
+        log_rotation: "never".into(),
+        ..Config::default()
+    };
+
+    let mut appender = file_appender(&config).unwrap();
+    std::io::Write::write_all(&mut appender, b"hello\n").unwrap();
+    drop(appender);
+
+    let written =
+        std::fs::read_to_string(data_dir.path().join("logs").join("mankeli.log")).unwrap();
+    assert_eq!(written, "hello\n");
+}
+
+#[test]
+fn test_filter_falls_back_to_warn() {
+    assert_eq!(filter("debug").to_string(), "debug");
+    assert_eq!(filter("not a=level").to_string(), "warn");
+}
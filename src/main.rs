@@ -1,35 +1,119 @@
+use clap::Parser;
+use ipnet::IpNet;
 use mankeli_chat::StatusLabel;
-use mankeli_chat::api::app;
-use mankeli_chat::comms::{friend_fetcher, message_fetcher};
+use mankeli_chat::api::{FriendRequestStatus, MAX_INTRO_LEN, app};
+use mankeli_chat::comms::{LocalNode, ProtocolVersions, friend_fetcher, message_fetcher};
+use mankeli_chat::config::{CliArgs, Config, ConfigChange, PEER_SERVER, is_loopback_address};
+use mankeli_chat::contacts::{ConflictPolicy, ContactExport, EXPORT_VERSION, export_contacts};
+#[cfg(unix)]
+use mankeli_chat::control::{
+    ControlClient, ControlRequest, ControlResponse, FriendView, bind_control, serve_control,
+};
 use mankeli_chat::db::{
-    FriendRequest, MIGRATOR, OutgoingMessage, User, delete_message, delete_user, fetch_inbox,
-    fetch_outgoing, fetch_users, invite_decision, retr_user, send_invite, send_message_to_que,
-    setup_db,
+    BlockKind, Friend, FriendRequest, MIGRATOR, OutgoingMessage, User, ensure_identity, retr_user,
+    set_user_address, setup_db,
 };
-use serde::Deserialize;
+use mankeli_chat::discovery::{Announcement, Discovery, NearbyPeer};
+use mankeli_chat::events::{Event, EventBus};
+use mankeli_chat::invite::{InviteLink, add_from_invite, create_invite_link, fingerprint};
+use mankeli_chat::logging;
+use mankeli_chat::metrics;
+use mankeli_chat::reload::{Reloader, watch_file};
+use mankeli_chat::shutdown::{Shutdown, ShutdownSignal, termination_signal};
+use mankeli_chat::store::Store;
+use mankeli_chat::transport::{HttpTransport, http_client};
+use mankeli_chat::tui;
+use mankeli_chat::wakeup::Wakeup;
 use sqlx::{ConnectOptions, SqlitePool, sqlite::SqliteConnectOptions};
+use std::collections::HashMap;
 use std::fs;
 use std::io::{self, Write};
-use std::str::FromStr;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::Path;
+use tokio::sync::broadcast::{Receiver, error::RecvError};
 use tokio::time::{Duration, sleep};
 use tracing::log::LevelFilter;
+use tracing::{error, info, warn};
 
 #[cfg(test)]
 mod tests;
 
-#[derive(Debug, Deserialize)]
-
-struct Config {
-    server_address: String,
-    message_fetch_interval: u64,
-    friend_fetch_interval: u64,
-}
+// how long quitting waits for fetch cycles to wrap up
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[tokio::main]
 async fn main() {
-    // Configure logging for SQLx
-    let options = SqliteConnectOptions::from_str("sqlite://mankeli.db")
-        .expect("Failed to parse database URL")
+    let args = CliArgs::parse();
+    let (daemon, attach, tui, new_username) =
+        (args.daemon, args.attach, args.tui, args.username.clone());
+    // kept for reloads, the flags keep overriding the file
+    let flags = args.clone();
+    let config = match Config::load(args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    if let Err(e) = fs::create_dir_all(&config.data_dir) {
+        eprintln!(
+            "Failed to create data dir {}: {}",
+            config.data_dir.display(),
+            e
+        );
+        std::process::exit(1);
+    }
+
+    #[cfg(not(unix))]
+    if daemon || attach {
+        eprintln!("Daemon mode needs Unix domain sockets, which this platform lacks.");
+        std::process::exit(2);
+    }
+
+    // an attached CLI talks to the daemon and never opens the database itself
+    #[cfg(unix)]
+    {
+        let socket = config.control_socket_path();
+        if attach {
+            attach_to_daemon(&socket).await;
+            return;
+        }
+        if !daemon && ControlClient::connect(&socket).await.is_ok() {
+            eprintln!(
+                "A daemon is already running on {}, use --attach to control it.",
+                socket.display()
+            );
+            std::process::exit(1);
+        }
+    }
+
+    // background tasks log to files so they don't garble the prompt
+    let (log_guard, log_level) = match logging::init(&config) {
+        Ok(logging) => logging,
+        Err(e) => {
+            eprintln!(
+                "Failed to set up logging in {}: {}",
+                config.log_dir_path().display(),
+                e
+            );
+            std::process::exit(1);
+        }
+    };
+    info!(data_dir = %config.data_dir.display(), "Starting node");
+
+    let metrics_handle = match metrics::install() {
+        Ok(handle) => handle,
+        Err(e) => {
+            eprintln!("Failed to set up metrics: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // SQL statements show up in the log at debug level
+    let options = SqliteConnectOptions::new()
+        .filename(config.database_path())
         .log_statements(LevelFilter::Debug)
         .create_if_missing(true);
 
@@ -39,86 +123,551 @@ async fn main() {
 
     MIGRATOR.run(&pool).await.unwrap();
 
-    //get config from json
-    let data = fs::read_to_string("config.json").expect("Unable to read file");
-    let config: Config = serde_json::from_str(&data).expect("JSON was not well-formatted");
-
-    let username = read_input("Enter Username: ");
+    let advertised_address = match config.resolve_advertised_address() {
+        Ok(address) => address,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    if is_loopback_address(&advertised_address) {
+        eprintln!(
+            "Warning: advertising loopback address {}, friends on other machines can't reach you. Set advertised_address (or \"auto\").",
+            advertised_address
+        );
+    }
 
-    let user: User = match retr_user(&pool).await {
+    let mut user: User = match retr_user(&pool).await {
         Ok(u) => {
-            println!("\nWelcome back {}!\n", u.username);
+            if !daemon {
+                println!("\nWelcome back {}!\n", u.username);
+            }
             u
         }
         Err(e) => {
+            let username = match new_username {
+                Some(username) => username,
+                None if daemon => {
+                    eprintln!("No user yet, start the daemon once with --username NAME.");
+                    std::process::exit(2);
+                }
+                None => read_input("Enter Username: "),
+            };
             eprintln!(
                 "User not found or error retrieving user: {}. Initializing new user.",
                 e
             );
-            init_db(&pool, username, config.server_address.clone()).await
+            init_db(&pool, username, advertised_address.clone()).await
+        }
+    };
+
+    if user.address != advertised_address {
+        match set_user_address(&pool, &advertised_address).await {
+            Ok(_) => {
+                println!(
+                    "Advertised address changed from {} to {}",
+                    user.address, advertised_address
+                );
+                user.address = advertised_address;
+            }
+            Err(e) => eprintln!("Failed to update advertised address: {}", e),
+        }
+    }
+
+    let identity = ensure_identity(&pool)
+        .await
+        .expect("Failed to set up our keypair");
+
+    let shutdown = Shutdown::new();
+
+    // Optional LAN discovery
+    let mut discovery_task = None;
+    let discovery = match &config.discovery {
+        Some(discovery_config) => {
+            let me = Announcement::new(
+                &user.username,
+                &user.address,
+                &fingerprint(&identity.peer_key()),
+            );
+            match Discovery::start(discovery_config, me, shutdown.subscribe()).await {
+                Ok((discovery, task)) => {
+                    discovery_task = Some(task);
+                    Some(discovery)
+                }
+                Err(e) => {
+                    eprintln!("Failed to start LAN discovery: {}", e);
+                    None
+                }
+            }
         }
+        None => None,
     };
 
+    let events = EventBus::new();
+    let versions = ProtocolVersions::new();
+    let wakeup = Wakeup::new();
+    let reloader = Reloader::new(flags, config.clone(), wakeup.clone(), Some(log_level));
+
     //start message server
-    let app = app(pool.clone()); //probably not good idea
+    let mut app = app(pool.clone(), events.clone()); //probably not good idea
+
+    // metrics name our friends, so they are only served when asked for and
+    // preferably on a separate listener that stays local
+    let mut metrics_server = None;
+    match config.metrics_address.as_deref() {
+        None => {}
+        Some(PEER_SERVER) => {
+            eprintln!(
+                "Warning: serving metrics on the peer server, anyone who can reach the node sees who your friends are."
+            );
+            app = app.merge(metrics::router(metrics_handle));
+        }
+        Some(address) => {
+            if !is_loopback_address(address) {
+                eprintln!(
+                    "Warning: serving metrics on {}, anyone who can reach it sees who your friends are.",
+                    address
+                );
+            }
+            let listener = match tokio::net::TcpListener::bind(address).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    eprintln!("Failed to bind metrics listener {}: {}", address, e);
+                    std::process::exit(1);
+                }
+            };
+            let mut signal = shutdown.subscribe();
+            let router = metrics::router(metrics_handle);
+            metrics_server = Some(tokio::spawn(async move {
+                if let Err(err) = axum::serve(listener, router)
+                    .with_graceful_shutdown(async move { signal.wait().await })
+                    .await
+                {
+                    error!("Metrics server error: {}", err);
+                }
+            }));
+        }
+    }
 
     // Start the server
 
-    let listener = tokio::net::TcpListener::bind(config.server_address)
+    let listener = tokio::net::TcpListener::bind(&config.bind_address)
         .await
         .unwrap();
 
-    // Spawn the Axum server
-    tokio::spawn(async move {
-        if let Err(err) = axum::serve(listener, app).await {
-            eprintln!("Server error: {}", err);
+    // Spawn the Axum server, in-flight requests finish before it stops
+    let server = tokio::spawn({
+        let mut signal = shutdown.subscribe();
+        async move {
+            let service = app.into_make_service_with_connect_info::<SocketAddr>();
+            if let Err(err) = axum::serve(listener, service)
+                .with_graceful_shutdown(async move { signal.wait().await })
+                .await
+            {
+                error!("Server error: {}", err);
+            }
         }
     });
 
     // Spawn friend fetcher
-    tokio::spawn({
+    let friend_task = tokio::spawn({
         let pool = pool.clone();
-        let interval = config.friend_fetch_interval;
+        let events = events.clone();
+        let versions = versions.clone();
+        let client = http_client(&config);
+        let config = reloader.subscribe();
+        let pause = wakeup.friend_fetcher(shutdown.subscribe());
         async move {
-            let _ = friend_fetcher(&pool, interval).await;
+            friend_fetcher(
+                &pool,
+                &HttpTransport::new(client),
+                &versions,
+                &events,
+                config,
+                pause,
+            )
+            .await;
         }
     });
 
     // Spawn message fetcher
-    tokio::spawn({
+    let message_task = tokio::spawn({
         let pool = pool.clone();
+        let events = events.clone();
+        let versions = versions.clone();
         let username = user.username.clone();
         let address = user.address.clone();
-        let interval = config.message_fetch_interval;
+        let relay = config.relay_address.clone();
+        let client = http_client(&config);
+        let config = reloader.subscribe();
+        let pause = wakeup.message_fetcher(shutdown.subscribe());
         async move {
-            let _ = message_fetcher(&pool, &username, &address, interval).await;
+            message_fetcher(
+                &pool,
+                &HttpTransport::new(client),
+                &versions,
+                &events,
+                LocalNode {
+                    username: &username,
+                    address: &address,
+                    relay: relay.as_deref(),
+                },
+                config,
+                pause,
+            )
+            .await;
         }
     });
 
-    println!("\nWelcome {}!\n", &user.username);
+    // nothing in the background may print over the full-screen UI either
+    let to_log = daemon || tui;
+    let notifier = tokio::spawn(notify_events(
+        events.subscribe(),
+        to_log,
+        shutdown.subscribe(),
+    ));
+
+    let follower = tokio::spawn(
+        wakeup
+            .clone()
+            .follow_events(events.subscribe(), shutdown.subscribe()),
+    );
+
+    let config_watcher = tokio::spawn(watch_config(reloader.clone(), to_log, shutdown.subscribe()));
+
+    let mut tasks = vec![
+        server,
+        friend_task,
+        message_task,
+        notifier,
+        follower,
+        config_watcher,
+    ];
+    tasks.extend(metrics_server);
+    tasks.extend(discovery_task);
+
+    if daemon {
+        #[cfg(unix)]
+        {
+            let socket = config.control_socket_path();
+            let listener = match bind_control(&socket).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    eprintln!("Failed to open control socket {}: {}", socket.display(), e);
+                    std::process::exit(1);
+                }
+            };
+            println!(
+                "Daemon running as {}, control socket at {}",
+                user.username,
+                socket.display()
+            );
+            tasks.push(tokio::spawn(serve_control(
+                listener,
+                socket,
+                pool.clone(),
+                wakeup.clone(),
+                reloader.clone(),
+                shutdown.subscribe(),
+            )));
+        }
+
+        termination_signal().await;
+        println!("Received termination signal.");
+    } else if tui {
+        tokio::select! {
+            result = tui::run(&pool, &wakeup, events.subscribe()) => {
+                if let Err(e) = result {
+                    eprintln!("Terminal UI failed: {}", e);
+                }
+            }
+            _ = termination_signal() => println!("Received termination signal."),
+        }
+    } else {
+        println!("\nWelcome {}!\n", &user.username);
+
+        sleep(Duration::from_secs(2)).await;
+
+        // stdin reads block, so the command loop gets its own thread instead of
+        // tying up a runtime worker the server and fetchers need
+        let commands = tokio::task::spawn_blocking({
+            let pool = pool.clone();
+            let wakeup = wakeup.clone();
+            let reloader = reloader.clone();
+            let runtime = tokio::runtime::Handle::current();
+            move || runtime.block_on(command_loop(pool, discovery, wakeup, reloader))
+        });
+        tokio::select! {
+            _ = commands => {}
+            _ = termination_signal() => println!("\nReceived termination signal."),
+        }
+    }
+
+    println!("Shutting down, waiting for background tasks to finish their current cycle...");
+    shutdown.trigger();
+    let tasks = futures::future::join_all(tasks);
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, tasks).await.is_err() {
+        warn!("Background tasks did not stop in time, exiting anyway");
+        eprintln!("Background tasks did not stop in time, exiting anyway.");
+    }
+    pool.close().await;
+    info!("Shut down cleanly");
+    println!("Goodbye!");
+    // exit() skips destructors, flush the log writer first
+    drop(log_guard);
+
+    // the command loop may still be parked on a blocking stdin read, which
+    // would keep the runtime from shutting down on its own
+    std::process::exit(0);
+}
+
+// Tells the user what happened in the background as it happens. The daemon
+// has no terminal and the full-screen UI shows them itself, so there they
+// only go to the log.
+async fn notify_events(mut rx: Receiver<Event>, to_log: bool, mut shutdown: ShutdownSignal) {
+    loop {
+        let event = tokio::select! {
+            received = rx.recv() => received,
+            _ = shutdown.wait() => break,
+        };
+        match event {
+            Ok(event) if to_log => info!(%event, "Event"),
+            Ok(event) => println!("\n* {}", event),
+            Err(RecvError::Lagged(missed)) => warn!(missed, "Event notifications fell behind"),
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+// Reloads the config whenever its file changes. Editors tend to write a file
+// in several steps, so changes are given a moment to settle first.
+async fn watch_config(reloader: Reloader, to_log: bool, mut shutdown: ShutdownSignal) {
+    let path = reloader.file_path();
+    let (_watcher, mut changed) = match watch_file(&path) {
+        Ok(watch) => watch,
+        Err(e) => {
+            warn!("Not watching {} for changes: {}", path.display(), e);
+            return;
+        }
+    };
+    loop {
+        tokio::select! {
+            received = changed.recv() => if received.is_none() { break },
+            _ = shutdown.wait() => break,
+        }
+        if shutdown.sleep(Duration::from_millis(500)).await {
+            break;
+        }
+        while changed.try_recv().is_ok() {}
+
+        match reloader.reload() {
+            Ok(changes) if changes.is_empty() => {}
+            Ok(changes) if to_log => {
+                for change in changes {
+                    info!(%change, "Config change");
+                }
+            }
+            Ok(changes) => {
+                println!("\n* Config reloaded:");
+                print_changes(&changes);
+            }
+            Err(e) if to_log => warn!("Config not reloaded, keeping the old one: {}", e),
+            Err(e) => println!("\n* Config not reloaded, keeping the old one: {}", e),
+        }
+    }
+}
 
-    sleep(Duration::from_secs(2)).await;
+fn print_changes(changes: &[ConfigChange]) {
+    if changes.is_empty() {
+        println!("Config reloaded, nothing changed.");
+    }
+    for change in changes {
+        println!("  {}", change);
+    }
+}
 
+async fn command_loop<S: Store>(
+    store: S,
+    discovery: Option<Discovery>,
+    wakeup: Wakeup,
+    reloader: Reloader,
+) {
     loop {
-        let prompt =
-            "\nAvailable commands: inbox, friends, send, outbound, quit\nPlease enter something: ";
+        let prompt = "\nAvailable commands: inbox, friends, send, outbound, health, sync, reload, quit\nPlease enter something: ";
 
         let cmd = read_input(prompt).to_lowercase();
 
         match cmd.as_str() {
-            "inbox" => read_inbox(&pool).await,
-            "friends" => read_friends(&pool).await,
-            "send" => send_message(&pool).await,
-            "outbound" => view_outbound(&pool).await,
-            "quit" => {
-                println!("Goodbye!");
-                break;
+            "inbox" => read_inbox(&store).await,
+            "friends" => read_friends(&store, discovery.as_ref(), &wakeup).await,
+            "send" => send_message(&store, &wakeup).await,
+            "outbound" => view_outbound(&store).await,
+            "health" => view_health(&store).await,
+            "sync" => {
+                wakeup.sync_now();
+                println!("Syncing with friends now.");
             }
+            "reload" => match reloader.reload() {
+                Ok(changes) => print_changes(&changes),
+                Err(e) => println!("Config not reloaded, keeping the old one: {}", e),
+            },
+            "export" => export_friends(&store).await,
+            "import" => import_friends(&store, &wakeup).await,
+            "quit" => break,
             _ => println!("Unknown command."),
         }
     }
 }
 
+// Interactive CLI against a running daemon, only the commands the control
+// socket supports
+#[cfg(unix)]
+async fn attach_to_daemon(socket: &Path) {
+    let mut client = match ControlClient::connect(socket).await {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("No daemon listening on {}: {}", socket.display(), e);
+            std::process::exit(1);
+        }
+    };
+
+    match client.request(&ControlRequest::Status).await {
+        Ok(ControlResponse::Status { username, address }) => {
+            println!("\nAttached to {} ({})\n", username, address)
+        }
+        Ok(ControlResponse::Error(e)) | Err(e) => {
+            eprintln!("Daemon did not answer: {}", e);
+            std::process::exit(1);
+        }
+        Ok(_) => {}
+    }
+
+    loop {
+        let prompt = "\nAvailable commands: inbox, friends, send, outbound, sync, reload, quit\nPlease enter something: ";
+        let request = match read_input(prompt).to_lowercase().as_str() {
+            "inbox" => ControlRequest::Inbox,
+            "friends" => ControlRequest::Friends,
+            "outbound" => ControlRequest::Outbound,
+            "sync" => {
+                println!("Syncing with friends now.");
+                ControlRequest::Sync
+            }
+            "reload" => ControlRequest::Reload,
+            "send" => {
+                println!("Please fill the following fields");
+                ControlRequest::Send {
+                    to: read_input("Recipient (name, or #label to send to a group): "),
+                    subject: read_input("Subject: "),
+                    content: read_input("Content: "),
+                }
+            }
+            "quit" => break,
+            _ => {
+                println!("Unknown command.");
+                continue;
+            }
+        };
+
+        let response = match client.request(&request).await {
+            Ok(response) => response,
+            Err(e) => {
+                eprintln!("{}", e);
+                break;
+            }
+        };
+
+        match response {
+            ControlResponse::Inbox(messages) => {
+                if messages.is_empty() {
+                    println!("Your inbox is empty.");
+                }
+                for message in messages {
+                    println!(
+                        "[{}] From: {} | Subject: {}\n{}\n",
+                        message.id, message.sender, message.subject, message.message
+                    );
+                }
+            }
+            ControlResponse::Outbound(outbound) => {
+                if outbound.is_empty() {
+                    println!("You don't have any outbound messages.");
+                }
+                for message in outbound {
+                    println!(
+                        "To: {} | Subject: {} | Sent: {:?}",
+                        message.recipient, message.subject, message.sent
+                    );
+                }
+            }
+            ControlResponse::Friends(friends) => attached_friends(&mut client, friends).await,
+            ControlResponse::Queued { recipients } if recipients.is_empty() => {
+                println!("No accepted friends with that label.")
+            }
+            ControlResponse::Queued { recipients } => {
+                println!("Message queued for {}!", recipients.join(", "))
+            }
+            ControlResponse::Reloaded(changes) => print_changes(&changes),
+            ControlResponse::Error(e) => eprintln!("Daemon error: {}", e),
+            ControlResponse::Status { .. } | ControlResponse::Ok => {}
+        }
+    }
+    println!("Detached, the daemon keeps running.");
+}
+
+#[cfg(unix)]
+async fn attached_friends(client: &mut ControlClient, friends: Vec<FriendView>) {
+    println!("Your friends:");
+    for fr in &friends {
+        println!(
+            "{:<4} {} ({}) {}",
+            fr.id,
+            fr.display_name(),
+            fr.address,
+            fr.status.status_str()
+        );
+    }
+
+    let choice = read_input("\na: add, i: answer invite, r: remove, b: back\n> ");
+    let request = match choice.as_str() {
+        "a" => ControlRequest::AddFriend {
+            username: read_input("Enter username of user: "),
+            address: read_input("Enter ip/hostname of user: "),
+            intro: Some(read_input(&format!(
+                "Introduce yourself (optional, max {} chars): ",
+                MAX_INTRO_LEN
+            ))),
+        },
+        "i" => {
+            let Ok(id) = read_input("Invite id: ").parse::<i64>() else {
+                println!("Invalid input: must be a number.");
+                return;
+            };
+            let accept = match read_input("a: accept, r: reject: ").as_str() {
+                "a" => true,
+                "r" => false,
+                _ => {
+                    println!("Invalid command. Use 'a' to accept or 'r' to reject.");
+                    return;
+                }
+            };
+            ControlRequest::RespondInvite { id, accept }
+        }
+        "r" => {
+            let Ok(id) = read_input("Enter friend id to remove them: ").parse::<i64>() else {
+                println!("Invalid input: must be a number.");
+                return;
+            };
+            ControlRequest::RemoveFriend { id }
+        }
+        _ => return,
+    };
+
+    match client.request(&request).await {
+        Ok(ControlResponse::Error(e)) | Err(e) => eprintln!("Daemon error: {}", e),
+        Ok(_) => println!("Done."),
+    }
+}
+
 fn read_input(prompt: &str) -> String {
     print!("{}", prompt);
     io::stdout().flush().unwrap();
@@ -133,8 +682,9 @@ fn read_input(prompt: &str) -> String {
 async fn init_db(pool: &SqlitePool, username: String, address: String) -> User {
     let user = User {
         id: 0, // ID will be auto-generated by the DB
-        username: username,
-        address: address,
+        username,
+        address,
+        peer_key: None, // a keypair is generated on insert
     };
 
     setup_db(pool, &user)
@@ -146,8 +696,8 @@ async fn init_db(pool: &SqlitePool, username: String, address: String) -> User {
         .expect("Failed to retrieve newly created user")
 }
 
-async fn read_inbox(pool: &SqlitePool) {
-    let inbox = match fetch_inbox(pool).await {
+async fn read_inbox<S: Store>(store: &S) {
+    let inbox = match store.fetch_inbox().await {
         Ok(messages) => messages,
         Err(e) => {
             eprintln!("Error fetching inbox: {}", e);
@@ -160,12 +710,14 @@ async fn read_inbox(pool: &SqlitePool) {
         return;
     }
 
+    let names = display_names(store).await;
+
     println!("Your inbox:");
     for (i, message) in inbox.iter().enumerate() {
         println!(
             "{}. From: {}, Subject: {}",
             i + 1,
-            message.sender,
+            display_name(&names, &message.sender),
             message.subject
         );
     }
@@ -175,7 +727,7 @@ async fn read_inbox(pool: &SqlitePool) {
         io::stdout().flush().unwrap();
 
         let mut input = String::new();
-        if let Err(_) = io::stdin().read_line(&mut input) {
+        if io::stdin().read_line(&mut input).is_err() {
             println!("Failed to read input. Try again.");
             continue;
         }
@@ -192,19 +744,21 @@ async fn read_inbox(pool: &SqlitePool) {
                 let message = &inbox[index - 1];
                 println!(
                     "\nFrom: {}\nSubject: {}\n\n{}",
-                    message.sender, message.subject, message.message
+                    display_name(&names, &message.sender),
+                    message.subject,
+                    message.message
                 );
 
                 print!("Delete this message? (y/n): ");
                 io::stdout().flush().unwrap();
 
                 let mut del_input = String::new();
-                if let Ok(_) = io::stdin().read_line(&mut del_input) {
-                    if del_input.trim().eq_ignore_ascii_case("y") {
-                        match delete_message(pool, message.id).await {
-                            Ok(_) => println!("Message deleted."),
-                            Err(e) => println!("Failed to delete message: {}", e),
-                        }
+                if io::stdin().read_line(&mut del_input).is_ok()
+                    && del_input.trim().eq_ignore_ascii_case("y")
+                {
+                    match store.delete_message(message.id).await {
+                        Ok(_) => println!("Message deleted."),
+                        Err(e) => println!("Failed to delete message: {}", e),
                     }
                 }
 
@@ -217,34 +771,58 @@ async fn read_inbox(pool: &SqlitePool) {
     }
 }
 
-async fn read_friends(pool: &SqlitePool) {
-    // To-Do ADD option to accept and decline friend reques
+async fn read_friends<S: Store>(store: &S, discovery: Option<&Discovery>, wakeup: &Wakeup) {
+    let mut label_filter: Option<String> = None;
+
     loop {
-        let friends = match fetch_users(pool).await {
+        let friends = match store.fetch_friends().await {
             Ok(friends) => friends,
             Err(e) => {
                 eprintln!("Error fetching users: {}", e);
                 return;
             }
         };
+        let labels = match store.fetch_friend_labels().await {
+            Ok(labels) => labels,
+            Err(e) => {
+                eprintln!("Error fetching labels: {}", e);
+                return;
+            }
+        };
+        let no_labels = Vec::new();
 
-        println!("Your friends:");
+        match &label_filter {
+            Some(label) => println!("Your friends labelled '{}':", label),
+            None => println!("Your friends:"),
+        }
 
-        if friends.is_empty() {
+        let shown: Vec<&Friend> = friends
+            .iter()
+            .filter(|fr| {
+                label_filter.as_ref().is_none_or(|label| {
+                    labels
+                        .get(&fr.id)
+                        .is_some_and(|l| l.iter().any(|x| x == label))
+                })
+            })
+            .collect();
+
+        if shown.is_empty() {
             println!("You don't have any friends yet.");
         } else {
             println!(
-                "{:<4} {:<15} {:<25} {:<18} {}",
-                "ID", "Username", "Address", "Status", "Added At (UTC)"
+                "{:<4} {:<15} {:<25} {:<18} {:<20} Added At (UTC)",
+                "ID", "Name", "Address", "Status", "Labels"
             );
-            println!("{}", "-".repeat(80));
-            for fr in friends {
+            println!("{}", "-".repeat(100));
+            for fr in shown {
                 println!(
-                    "{:<4} {:<15} {:<25} {:<18} {}",
+                    "{:<4} {:<15} {:<25} {:<18} {:<20} {}",
                     fr.id,
-                    fr.username,
+                    fr.display_name(),
                     fr.address,
                     fr.status.status_str(),
+                    labels.get(&fr.id).unwrap_or(&no_labels).join(","),
                     fr.added_at
                         .map(|dt| dt.to_string())
                         .unwrap_or("N/A".to_string())
@@ -252,8 +830,10 @@ async fn read_friends(pool: &SqlitePool) {
             }
         }
 
-        let response =
-            read_input("a: Add Friend, r: remove Friend, i: invites, b: go back: ").to_lowercase();
+        let response = read_input(
+            "a: Add Friend, l: add from invite link, s: share invite link, n: nearby, m: edit details, f: filter by label, v: set relay, e: re-invite, r: remove Friend, i: invites, x: block, u: unblock, b: go back: ",
+        )
+        .to_lowercase();
 
         if response.as_str() == "b" {
             println!("Returning to main menu...");
@@ -264,12 +844,26 @@ async fn read_friends(pool: &SqlitePool) {
             "a" => {
                 let username = read_input("Enter username of user: ");
                 let address = read_input("Enter ip/hostname of user: ");
+                let intro = read_input(&format!(
+                    "Introduce yourself (optional, max {} chars): ",
+                    MAX_INTRO_LEN
+                ));
+                if intro.chars().count() > MAX_INTRO_LEN {
+                    println!("Introduction is too long.");
+                    continue;
+                }
                 let request = FriendRequest {
-                    username: username,
-                    address: address,
+                    username,
+                    address,
+                    intro: (!intro.is_empty()).then_some(intro),
+                    fingerprint: None,
+                    invite_token: None,
                 };
-                match send_invite(pool, &request).await {
-                    Ok(_) => println!("Friend invite sent!"),
+                match store.send_invite(&request).await {
+                    Ok(_) => {
+                        wakeup.wake_friend_fetcher();
+                        println!("Friend invite sent!");
+                    }
                     Err(e) => {
                         eprintln!("Error sending invite: {}", e);
                     }
@@ -281,7 +875,7 @@ async fn read_friends(pool: &SqlitePool) {
                 match id.trim().parse::<i64>() {
                     Ok(friend_id) => {
                         println!("Removing friend with id: {}", friend_id);
-                        match delete_user(pool, friend_id).await {
+                        match store.delete_friend(friend_id).await {
                             Ok(_) => println!("Friend removed."),
                             Err(e) => eprintln!("Failed to remove friend: {}", e),
                         }
@@ -292,7 +886,24 @@ async fn read_friends(pool: &SqlitePool) {
                 }
             }
             "i" => {
-                // Print invites
+                let pending: Vec<&Friend> = friends
+                    .iter()
+                    .filter(|f| f.status.status_enum() == FriendRequestStatus::InviteReceived)
+                    .collect();
+
+                if pending.is_empty() {
+                    println!("You don't have any pending invites.");
+                    continue;
+                }
+
+                println!("Pending invites:");
+                for fr in pending {
+                    println!("{:<4} {} ({})", fr.id, fr.username, fr.address);
+                    if let Some(intro) = &fr.intro {
+                        println!("     \"{}\"", intro);
+                    }
+                }
+
                 let id = read_input("Select id to accept/reject request: ");
                 let cmd = read_input("a: accept, r: reject: ");
 
@@ -308,8 +919,10 @@ async fn read_friends(pool: &SqlitePool) {
                         };
 
                         if let Some(accept) = decision {
-                            match invite_decision(pool, friend_id, accept).await {
+                            match store.invite_decision(friend_id, accept).await {
                                 Ok(_) => {
+                                    // an accepted friend is also someone new to fetch from
+                                    wakeup.sync_now();
                                     if accept {
                                         println!("Friend request accepted.");
                                     } else {
@@ -325,36 +938,372 @@ async fn read_friends(pool: &SqlitePool) {
                     }
                 }
             }
+            "l" => {
+                let link = read_input("Paste invite link: ");
+                match link.parse::<InviteLink>() {
+                    Ok(link) => {
+                        let intro = read_input(&format!(
+                            "Introduce yourself (optional, max {} chars): ",
+                            MAX_INTRO_LEN
+                        ));
+                        if intro.chars().count() > MAX_INTRO_LEN {
+                            println!("Introduction is too long.");
+                            continue;
+                        }
+                        let intro = (!intro.is_empty()).then_some(intro);
+                        match add_from_invite(store, &link, intro).await {
+                            Ok(_) => {
+                                wakeup.wake_friend_fetcher();
+                                println!(
+                                    "Friend invite sent to {} ({}), fingerprint {}",
+                                    link.username, link.address, link.fingerprint
+                                );
+                            }
+                            Err(e) => eprintln!("Error sending invite: {}", e),
+                        }
+                    }
+                    Err(e) => println!("Invalid invite link: {}", e),
+                }
+            }
+            "s" => {
+                let one_time = read_input("Include a one-time auto-accept token? (y/n): ")
+                    .eq_ignore_ascii_case("y");
+                match create_invite_link(store, one_time).await {
+                    Ok(link) => println!("Share this link:\n{}", link),
+                    Err(e) => eprintln!("Failed to create invite link: {}", e),
+                }
+            }
+            "n" => match discovery {
+                Some(discovery) => invite_nearby(store, discovery, &friends, wakeup).await,
+                None => println!("LAN discovery is not enabled in the config file."),
+            },
+            "v" => {
+                let id = read_input("Enter friend id to set their relay: ");
+                match id.trim().parse::<i64>() {
+                    Ok(friend_id) => {
+                        let relay = read_input("Enter relay ip/hostname (empty to clear): ");
+                        let relay = (!relay.is_empty()).then_some(relay);
+                        match store.set_friend_relay(friend_id, relay.as_deref()).await {
+                            Ok(_) => {
+                                wakeup.wake_message_fetcher();
+                                println!("Relay updated.");
+                            }
+                            Err(e) => eprintln!("Failed to update relay: {}", e),
+                        }
+                    }
+                    Err(e) => {
+                        println!("Invalid input: must be a number. Error: {}", e);
+                    }
+                }
+            }
+            "e" => {
+                let id = read_input("Enter friend id to invite again: ");
+                match id.trim().parse::<i64>() {
+                    Ok(friend_id) => match store.reinvite(friend_id).await {
+                        Ok(true) => {
+                            wakeup.wake_friend_fetcher();
+                            println!("Friend invite sent again!");
+                        }
+                        Ok(false) => {
+                            println!("Only pending, rejected or expired invites can be re-sent.")
+                        }
+                        Err(e) => eprintln!("Error sending invite: {}", e),
+                    },
+                    Err(e) => {
+                        println!("Invalid input: must be a number. Error: {}", e);
+                    }
+                }
+            }
+            "m" => edit_friend_details(store, &friends, &labels).await,
+            "f" => {
+                let label = read_input("Show only friends with label (empty shows all): ");
+                label_filter = (!label.is_empty()).then_some(label);
+            }
+            "x" => block_peer(store, &friends).await,
+            "u" => unblock_peer(store).await,
 
             _ => {
-                println!("Invalid input. Please enter 'a', 'r', or 'b'.");
+                println!(
+                    "Invalid input. Please enter 'a', 'l', 's', 'n', 'm', 'f', 'v', 'e', 'r', 'i', 'x', 'u' or 'b'."
+                );
+            }
+        }
+    }
+}
+
+async fn edit_friend_details<S: Store>(
+    store: &S,
+    friends: &[Friend],
+    labels: &HashMap<i64, Vec<String>>,
+) {
+    let id = read_input("Enter friend id to edit: ");
+    let friend = match id.trim().parse::<i64>() {
+        Ok(id) => match friends.iter().find(|f| f.id == id) {
+            Some(friend) => friend,
+            None => {
+                println!("No friend with that id.");
+                return;
+            }
+        },
+        Err(e) => {
+            println!("Invalid input: must be a number. Error: {}", e);
+            return;
+        }
+    };
+
+    let current_labels = labels.get(&friend.id).cloned().unwrap_or_default();
+    println!(
+        "\nUsername: {}\nNickname: {}\nNotes: {}\nLabels: {}",
+        friend.username,
+        friend.nickname.as_deref().unwrap_or("-"),
+        friend.notes.as_deref().unwrap_or("-"),
+        current_labels.join(", ")
+    );
+    println!("Leave a field empty to keep it, enter '-' to clear it.");
+
+    let edit = |prompt: &str, current: Option<&str>| -> Option<String> {
+        match read_input(prompt).as_str() {
+            "" => current.map(str::to_string),
+            "-" => None,
+            value => Some(value.to_string()),
+        }
+    };
+
+    let nickname = edit("Nickname: ", friend.nickname.as_deref());
+    let notes = edit("Notes: ", friend.notes.as_deref());
+    let new_labels = match edit(
+        "Labels (comma separated): ",
+        Some(&current_labels.join(",")),
+    ) {
+        Some(value) => value
+            .split(',')
+            .map(|l| l.trim().to_string())
+            .filter(|l| !l.is_empty())
+            .collect(),
+        None => Vec::new(),
+    };
+
+    if let Err(e) = store
+        .set_friend_details(friend.id, nickname.as_deref(), notes.as_deref())
+        .await
+    {
+        eprintln!("Failed to update details: {}", e);
+        return;
+    }
+    match store.set_friend_labels(friend.id, &new_labels).await {
+        Ok(_) => println!("Details updated."),
+        Err(e) => eprintln!("Failed to update labels: {}", e),
+    }
+}
+
+// Maps usernames to the local nickname where one is set
+async fn display_names<S: Store>(store: &S) -> HashMap<String, String> {
+    match store.fetch_friends().await {
+        Ok(friends) => friends
+            .iter()
+            .map(|f| (f.username.clone(), f.display_name().to_string()))
+            .collect(),
+        Err(_) => HashMap::new(),
+    }
+}
+
+fn display_name<'a>(names: &'a HashMap<String, String>, username: &'a str) -> &'a str {
+    names.get(username).map(String::as_str).unwrap_or(username)
+}
+
+async fn invite_nearby<S: Store>(
+    store: &S,
+    discovery: &Discovery,
+    friends: &[Friend],
+    wakeup: &Wakeup,
+) {
+    let nearby: Vec<NearbyPeer> = discovery
+        .nearby()
+        .into_iter()
+        .filter(|peer| !friends.iter().any(|f| f.username == peer.username))
+        .collect();
+
+    if nearby.is_empty() {
+        println!("No new peers found nearby.");
+        return;
+    }
+
+    println!("Nearby peers:");
+    println!(
+        "{:<4} {:<15} {:<25} Fingerprint",
+        "No", "Username", "Address"
+    );
+    println!("{}", "-".repeat(80));
+    for (i, peer) in nearby.iter().enumerate() {
+        println!(
+            "{:<4} {:<15} {:<25} {}",
+            i + 1,
+            peer.username,
+            peer.address,
+            peer.fingerprint
+        );
+    }
+
+    let input = read_input("Enter number to send an invite, or 'b' to go back: ");
+    if input.eq_ignore_ascii_case("b") {
+        return;
+    }
+
+    match input.parse::<usize>() {
+        Ok(index) if index > 0 && index <= nearby.len() => {
+            let peer = &nearby[index - 1];
+            let request = FriendRequest {
+                username: peer.username.clone(),
+                address: peer.address.clone(),
+                intro: None,
+                fingerprint: Some(peer.fingerprint.clone()),
+                invite_token: None,
+            };
+            match store.send_invite(&request).await {
+                Ok(_) => {
+                    wakeup.wake_friend_fetcher();
+                    println!("Friend invite sent to {}!", peer.username);
+                }
+                Err(e) => eprintln!("Error sending invite: {}", e),
             }
         }
+        _ => println!("Invalid input. Please enter a valid number or 'b'."),
     }
 }
 
-async fn send_message(pool: &SqlitePool) {
+async fn block_peer<S: Store>(store: &S, friends: &[Friend]) {
+    let kind = read_input("Block (f)riend by id, (u)sername, (k)ey or (a)ddress/CIDR: ");
+
+    let kind = match kind.trim() {
+        "f" => {
+            let id = read_input("Enter friend id to block: ");
+            let friend = match id.trim().parse::<i64>() {
+                Ok(id) => friends.iter().find(|f| f.id == id),
+                Err(e) => {
+                    println!("Invalid input: must be a number. Error: {}", e);
+                    return;
+                }
+            };
+
+            match friend {
+                Some(friend) => match store.block_friend(friend).await {
+                    Ok(_) => println!("{} blocked.", friend.username),
+                    Err(e) => eprintln!("Failed to block friend: {}", e),
+                },
+                None => println!("No friend with that id."),
+            }
+            return;
+        }
+        "u" => BlockKind::Username,
+        "k" => BlockKind::PeerKey,
+        "a" => BlockKind::Address,
+        _ => {
+            println!("Invalid command. Use 'f', 'u', 'k' or 'a'.");
+            return;
+        }
+    };
+
+    let value = read_input("Value to block: ");
+    if value.is_empty() {
+        println!("Nothing to block.");
+        return;
+    }
+    if kind == BlockKind::Address && value.contains('/') && value.parse::<IpNet>().is_err() {
+        println!("Invalid CIDR range: {}", value);
+        return;
+    }
+
+    match store.add_block(kind, &value).await {
+        Ok(_) => println!("Blocked {} {}.", kind.as_str(), value),
+        Err(e) => eprintln!("Failed to add block: {}", e),
+    }
+}
+
+async fn unblock_peer<S: Store>(store: &S) {
+    let blocks = match store.fetch_blocks().await {
+        Ok(blocks) => blocks,
+        Err(e) => {
+            eprintln!("Error fetching block list: {}", e);
+            return;
+        }
+    };
+
+    if blocks.is_empty() {
+        println!("Your block list is empty.");
+        return;
+    }
+
+    println!("{:<4} {:<10} Value", "ID", "Kind");
+    println!("{}", "-".repeat(80));
+    for block in &blocks {
+        println!("{:<4} {:<10} {}", block.id, block.kind, block.value);
+    }
+
+    let id = read_input("Enter id to unblock: ");
+    match id.trim().parse::<i64>() {
+        Ok(block_id) => match store.remove_block(block_id).await {
+            Ok(_) => println!("Unblocked."),
+            Err(e) => eprintln!("Failed to unblock: {}", e),
+        },
+        Err(e) => println!("Invalid input: must be a number. Error: {}", e),
+    }
+}
+
+async fn send_message<S: Store>(store: &S, wakeup: &Wakeup) {
     println!("Please fill the following fields");
-    let send_to = read_input("Recipient: ");
+    let send_to = read_input("Recipient (name, or #label to send to a group): ");
     let subject = read_input("Subject: ");
     let content = read_input("Content: ");
 
+    if let Some(label) = send_to.strip_prefix('#') {
+        match store
+            .queue_message_to_label(label, &subject, &content)
+            .await
+        {
+            Ok(recipients) if recipients.is_empty() => {
+                println!("No accepted friends with label '{}'.", label)
+            }
+            Ok(recipients) => {
+                wakeup.wake_message_fetcher();
+                let names = display_names(store).await;
+                let recipients: Vec<&str> =
+                    recipients.iter().map(|r| display_name(&names, r)).collect();
+                println!("Message queued for {}!", recipients.join(", "));
+            }
+            Err(e) => eprintln!("Error queuing message: {}", e),
+        }
+        return;
+    }
+
+    // nicknames are local, the queue wants the peer's username
+    let send_to = match store.resolve_recipient(&send_to).await {
+        Ok(username) => username,
+        Err(e) => {
+            eprintln!("Error queuing message: {}", e);
+            return;
+        }
+    };
+
     let message = OutgoingMessage {
         send_to,
         subject,
         content,
     };
 
-    match send_message_to_que(pool, &message).await {
-        Ok(_) => println!("Message queued!"),
+    // friends behind a relay get theirs deposited by the message fetcher
+    match store.queue_message(&message).await {
+        Ok(_) => {
+            wakeup.wake_message_fetcher();
+            println!("Message queued!");
+        }
         Err(e) => {
             eprintln!("Error queuing message: {}", e);
         }
     };
 }
 
-async fn view_outbound(pool: &SqlitePool) {
-    let outbound = match fetch_outgoing(pool).await {
+async fn view_outbound<S: Store>(store: &S) {
+    let outbound = match store.fetch_outgoing().await {
         Ok(outbound) => outbound,
         Err(e) => {
             eprintln!("Error fetching outbound messages: {}", e);
@@ -362,6 +1311,8 @@ async fn view_outbound(pool: &SqlitePool) {
         }
     };
 
+    let names = display_names(store).await;
+
     println!("Your outbound mail:");
     if outbound.is_empty() {
         println!("You don't have any outbound messages.");
@@ -369,8 +1320,142 @@ async fn view_outbound(pool: &SqlitePool) {
         for message in outbound {
             println!(
                 "To: {} | Subject: {} | Sent: {:?}",
-                message.recipient, message.subject, message.sent
+                display_name(&names, &message.recipient),
+                message.subject,
+                message.sent
             )
         }
     }
 }
+
+async fn view_health<S: Store>(store: &S) {
+    let health = match store.fetch_peer_health().await {
+        Ok(health) => health,
+        Err(e) => {
+            eprintln!("Error fetching peer health: {}", e);
+            return;
+        }
+    };
+
+    if health.is_empty() {
+        println!("No peers contacted yet.");
+        return;
+    }
+
+    let names = display_names(store).await;
+    let now = chrono::Utc::now().naive_utc();
+    println!(
+        "{:<15} {:<9} {:<9} {:<20} {:<20} Last error",
+        "Name", "State", "Failures", "Last success (UTC)", "Next attempt (UTC)"
+    );
+    println!("{}", "-".repeat(100));
+    for peer in health {
+        let state = if peer.consecutive_failures == 0 {
+            "ok"
+        } else if peer.is_due(now) {
+            "retrying"
+        } else {
+            "backoff"
+        };
+        println!(
+            "{:<15} {:<9} {:<9} {:<20} {:<20} {}",
+            display_name(&names, &peer.username),
+            state,
+            peer.consecutive_failures,
+            peer.last_success_at
+                .map(|dt| dt.format("%Y-%m-%d %H:%M:%S").to_string())
+                .unwrap_or("N/A".to_string()),
+            peer.next_attempt_at
+                .filter(|_| peer.consecutive_failures > 0)
+                .map(|dt| dt.format("%Y-%m-%d %H:%M:%S").to_string())
+                .unwrap_or("now".to_string()),
+            peer.last_error.as_deref().unwrap_or("-")
+        );
+    }
+}
+
+async fn export_friends<S: Store>(store: &S) {
+    let path = read_input("Export to file [contacts.json]: ");
+    let path = if path.is_empty() {
+        "contacts.json".to_string()
+    } else {
+        path
+    };
+
+    let export = match export_contacts(store).await {
+        Ok(export) => export,
+        Err(e) => {
+            eprintln!("Error exporting contacts: {}", e);
+            return;
+        }
+    };
+
+    let json = match serde_json::to_string_pretty(&export) {
+        Ok(json) => json,
+        Err(e) => {
+            eprintln!("Error encoding contacts: {}", e);
+            return;
+        }
+    };
+
+    match fs::write(&path, json) {
+        Ok(_) => println!("Exported {} contacts to {}.", export.contacts.len(), path),
+        Err(e) => eprintln!("Error writing {}: {}", path, e),
+    }
+}
+
+async fn import_friends<S: Store>(store: &S, wakeup: &Wakeup) {
+    let path = read_input("Import from file [contacts.json]: ");
+    let path = if path.is_empty() {
+        "contacts.json".to_string()
+    } else {
+        path
+    };
+
+    let export: ContactExport = match fs::read_to_string(&path)
+        .map_err(|e| e.to_string())
+        .and_then(|data| serde_json::from_str(&data).map_err(|e| e.to_string()))
+    {
+        Ok(export) => export,
+        Err(e) => {
+            eprintln!("Error reading {}: {}", path, e);
+            return;
+        }
+    };
+
+    if export.version > EXPORT_VERSION {
+        println!(
+            "{} was written by a newer version (format {}), cannot import.",
+            path, export.version
+        );
+        return;
+    }
+
+    let policy = match read_input(
+        "When a username already exists: s: skip, o: overwrite, m: merge missing details [s]: ",
+    )
+    .as_str()
+    {
+        "" | "s" => ConflictPolicy::Skip,
+        "o" => ConflictPolicy::Overwrite,
+        "m" => ConflictPolicy::Merge,
+        _ => {
+            println!("Invalid choice.");
+            return;
+        }
+    };
+    let resend = read_input("Send pending invites again? (y/n): ").eq_ignore_ascii_case("y");
+
+    match store.import_contacts(&export, policy, resend).await {
+        Ok(report) => {
+            if report.reinvited > 0 {
+                wakeup.wake_friend_fetcher();
+            }
+            println!(
+                "Imported {}, updated {}, skipped {}, invites re-sent {}.",
+                report.imported, report.updated, report.skipped, report.reinvited
+            );
+        }
+        Err(e) => eprintln!("Error importing contacts: {}", e),
+    }
+}
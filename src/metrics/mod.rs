@@ -0,0 +1,144 @@
+// Prometheus metrics
+// recorded through the `metrics` facade the same way logging goes through
+// tracing, so db, api and comms count things without carrying a handle
+// around. main installs the Prometheus recorder and serves /metrics; with
+// no recorder installed (tests, embedders) every call is a no-op.
+
+use crate::api::FriendRequestStatus;
+use ::metrics::{
+    Unit, counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram,
+};
+use axum::{Router, http::header, response::IntoResponse, routing::get};
+use metrics_exporter_prometheus::{BuildError, Matcher, PrometheusBuilder, PrometheusHandle};
+use std::time::Duration;
+
+#[cfg(test)]
+mod tests;
+
+const MESSAGES_QUEUED: &str = "mankeli_messages_queued_total";
+const MESSAGES_FETCHED: &str = "mankeli_messages_fetched_total";
+const MESSAGES_INGESTED: &str = "mankeli_messages_ingested_total";
+const FETCH_CYCLE_DURATION: &str = "mankeli_fetch_cycle_duration_seconds";
+const PEER_FAILURES: &str = "mankeli_peer_failures_total";
+const RELAY_FAILURES: &str = "mankeli_relay_failures_total";
+const FRIEND_REQUESTS: &str = "mankeli_friend_requests_total";
+const OUTGOING_QUEUE_DEPTH: &str = "mankeli_outgoing_queue_depth";
+const HTTP_REQUEST_DURATION: &str = "mankeli_http_request_duration_seconds";
+
+// from a fast local request up to a fetch cycle waiting on slow peers
+const DURATION_BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+fn builder() -> Result<PrometheusBuilder, BuildError> {
+    PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Suffix("_seconds".into()), &DURATION_BUCKETS)
+}
+
+// Installs the process wide recorder, the handle renders what it collected
+pub fn install() -> Result<PrometheusHandle, BuildError> {
+    let handle = builder()?.install_recorder()?;
+    describe();
+    Ok(handle)
+}
+
+fn describe() {
+    describe_counter!(
+        MESSAGES_QUEUED,
+        "Messages queued for sending by the local user"
+    );
+    describe_counter!(
+        MESSAGES_FETCHED,
+        "Queued messages that left the outbox, by friend fetch or relay deposit"
+    );
+    describe_counter!(
+        MESSAGES_INGESTED,
+        "Messages fetched from friends or our relay and stored in the inbox"
+    );
+    describe_histogram!(
+        FETCH_CYCLE_DURATION,
+        Unit::Seconds,
+        "How long one message or friend fetch cycle took"
+    );
+    describe_counter!(PEER_FAILURES, "Failed attempts to reach a friend");
+    describe_counter!(
+        RELAY_FAILURES,
+        "Failed deposits at and collections from relays"
+    );
+    describe_counter!(
+        FRIEND_REQUESTS,
+        "Friend requests sent to and received from peers, by status"
+    );
+    describe_gauge!(
+        OUTGOING_QUEUE_DEPTH,
+        "Messages waiting in the outbox, as of the last fetch cycle"
+    );
+    describe_histogram!(
+        HTTP_REQUEST_DURATION,
+        Unit::Seconds,
+        "Peer server request latency by route"
+    );
+}
+
+// /metrics in the Prometheus text format
+pub fn router(handle: PrometheusHandle) -> Router {
+    Router::new().route(
+        "/metrics",
+        get(move || {
+            let handle = handle.clone();
+            async move {
+                (
+                    [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+                    handle.render(),
+                )
+                    .into_response()
+            }
+        }),
+    )
+}
+
+pub fn messages_queued(count: usize) {
+    counter!(MESSAGES_QUEUED).increment(count as u64);
+}
+
+pub fn messages_fetched(count: usize, relay: bool) {
+    let via = if relay { "relay" } else { "direct" };
+    counter!(MESSAGES_FETCHED, "via" => via).increment(count as u64);
+}
+
+pub fn messages_ingested(count: usize) {
+    counter!(MESSAGES_INGESTED).increment(count as u64);
+}
+
+// `fetcher` is "messages" or "friends"
+pub fn fetch_cycle(fetcher: &'static str, elapsed: Duration) {
+    histogram!(FETCH_CYCLE_DURATION, "fetcher" => fetcher).record(elapsed);
+}
+
+pub fn peer_failure(friend: &str) {
+    counter!(PEER_FAILURES, "friend" => friend.to_string()).increment(1);
+}
+
+// `operation` is "deposit" or "collect", kept apart from the friend's own health
+pub fn relay_failure(relay: &str, operation: &'static str) {
+    counter!(RELAY_FAILURES, "relay" => relay.to_string(), "operation" => operation).increment(1);
+}
+
+// `direction` is "sent" or "received"
+pub fn friend_request(direction: &'static str, status: FriendRequestStatus) {
+    counter!(FRIEND_REQUESTS, "direction" => direction, "status" => status.as_str()).increment(1);
+}
+
+pub fn outgoing_queue_depth(depth: u64) {
+    gauge!(OUTGOING_QUEUE_DEPTH).set(depth as f64);
+}
+
+pub fn http_request(method: &str, route: &str, status: u16, elapsed: Duration) {
+    histogram!(
+        HTTP_REQUEST_DURATION,
+        "method" => method.to_string(),
+        "route" => route.to_string(),
+        "status" => status.to_string(),
+    )
+    .record(elapsed);
+}
@@ -0,0 +1,43 @@
+use super::*;
+use axum::body::{Body, to_bytes};
+use axum::http::Request;
+use tower::ServiceExt;
+
+#[tokio::test]
+async fn test_metrics_render_in_prometheus_format() {
+    let recorder = builder().unwrap().build_recorder();
+    let handle = recorder.handle();
+
+    ::metrics::with_local_recorder(&recorder, || {
+        describe();
+        messages_queued(2);
+        messages_fetched(1, true);
+        peer_failure("dave");
+        friend_request("received", FriendRequestStatus::InviteSent);
+        outgoing_queue_depth(3);
+        http_request("POST", "/fetch_messages", 200, Duration::from_millis(20));
+    });
+
+    let response = router(handle)
+        .oneshot(Request::get("/metrics").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(
+        response.headers()[header::CONTENT_TYPE],
+        "text/plain; version=0.0.4"
+    );
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let text = String::from_utf8(body.to_vec()).unwrap();
+
+    for line in [
+        "mankeli_messages_queued_total 2",
+        "mankeli_messages_fetched_total{via=\"relay\"} 1",
+        "mankeli_peer_failures_total{friend=\"dave\"} 1",
+        "mankeli_friend_requests_total{direction=\"received\",status=\"invite_sent\"} 1",
+        "mankeli_outgoing_queue_depth 3",
+        "mankeli_http_request_duration_seconds_bucket{method=\"POST\",route=\"/fetch_messages\",status=\"200\",le=\"0.025\"} 1",
+    ] {
+        assert!(text.contains(line), "missing {}\n{}", line, text);
+    }
+    assert!(text.contains("# HELP mankeli_peer_failures_total"));
+}
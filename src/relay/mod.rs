@@ -0,0 +1,274 @@
+// Store-and-forward relay
+// a relay keeps a mailbox per recipient peer key. Nodes that can't be
+// reached directly get their mail deposited here by friends and collect it
+// by polling. Payloads are sealed to the recipient's key, the relay can't
+// read them. Collecting and acking are signed with the mailbox's key, and
+// mail stays until the recipient acks the ids it ingested, or for
+// MAILBOX_TTL_DAYS. Anyone may deposit, so one depositing IP only gets
+// MAX_PER_ORIGIN slots of a mailbox.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::api::{ApiError, SIGNATURE_MAX_AGE};
+use crate::identity::{self, Identity};
+use axum::{
+    Extension, Router,
+    extract::{ConnectInfo, Json},
+    routing::get,
+    routing::post,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{SqlitePool, migrate::Migrator};
+use tracing::error;
+
+#[cfg(test)]
+mod tests;
+
+pub static RELAY_MIGRATOR: Migrator = sqlx::migrate!("./migrations_relay");
+
+// Upper bounds so a single peer can't fill up the relay
+pub const MAX_PAYLOAD_BYTES: usize = 64 * 1024;
+pub const MAX_MAILBOX_SIZE: i64 = 1000;
+// sender names are whatever the depositor says, the quota goes by its IP
+pub const MAX_PER_ORIGIN: i64 = 100;
+// uncollected mail is dropped after this
+pub const MAILBOX_TTL_DAYS: i64 = 14;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RelayEnvelope {
+    pub sender: String,
+    pub payload: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DepositInput {
+    pub recipient: String,
+    pub envelopes: Vec<RelayEnvelope>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CollectInput {
+    pub recipient: String,
+    pub signed_at: i64,
+    pub signature: String,
+}
+
+impl CollectInput {
+    pub fn new(identity: &Identity, now: i64) -> Self {
+        let recipient = identity.peer_key();
+        let signature = identity.sign(&collect_bytes(&recipient, now));
+        CollectInput {
+            recipient,
+            signed_at: now,
+            signature,
+        }
+    }
+
+    pub fn is_signed(&self, now: i64) -> bool {
+        (now - self.signed_at).abs() <= SIGNATURE_MAX_AGE
+            && identity::verify(
+                &self.recipient,
+                &collect_bytes(&self.recipient, self.signed_at),
+                &self.signature,
+            )
+    }
+}
+
+fn collect_bytes(recipient: &str, signed_at: i64) -> Vec<u8> {
+    serde_json::to_vec(&("mankeli-relay-collect", recipient, signed_at))
+        .expect("tuples of strings serialize")
+}
+
+// One deposited envelope, the id is what the recipient acks
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RelayMail {
+    pub id: i64,
+    pub sender: String,
+    pub payload: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CollectResponse {
+    pub mail: Vec<RelayMail>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AckInput {
+    pub recipient: String,
+    pub ids: Vec<i64>,
+    pub signed_at: i64,
+    pub signature: String,
+}
+
+impl AckInput {
+    pub fn new(identity: &Identity, ids: Vec<i64>, now: i64) -> Self {
+        let recipient = identity.peer_key();
+        let signature = identity.sign(&ack_bytes(&recipient, &ids, now));
+        AckInput {
+            recipient,
+            ids,
+            signed_at: now,
+            signature,
+        }
+    }
+
+    pub fn is_signed(&self, now: i64) -> bool {
+        (now - self.signed_at).abs() <= SIGNATURE_MAX_AGE
+            && identity::verify(
+                &self.recipient,
+                &ack_bytes(&self.recipient, &self.ids, self.signed_at),
+                &self.signature,
+            )
+    }
+}
+
+fn ack_bytes(recipient: &str, ids: &[i64], signed_at: i64) -> Vec<u8> {
+    serde_json::to_vec(&("mankeli-relay-ack", recipient, ids, signed_at))
+        .expect("tuples of strings serialize")
+}
+
+pub fn relay_app(pool: SqlitePool) -> Router {
+    Router::new()
+        .route("/", get(|| async { "Hello, this is a mankeli-chat relay" }))
+        .route("/deposit", post(deposit_handler))
+        .route("/collect", post(collect_handler))
+        .route("/ack", post(ack_handler))
+        .layer(Extension(Arc::new(pool)))
+}
+
+fn unsigned() -> ApiError {
+    ApiError::InvalidInput("Signature is missing, invalid or too old.".into())
+}
+
+async fn drop_expired(pool: &SqlitePool) -> Result<(), ApiError> {
+    sqlx::query("DELETE FROM mailbox WHERE deposited_at < datetime('now', ?)")
+        .bind(format!("-{} days", MAILBOX_TTL_DAYS))
+        .execute(pool)
+        .await
+        .map_err(storage_error)?;
+    Ok(())
+}
+
+fn storage_error(e: sqlx::Error) -> ApiError {
+    error!("Relay storage error: {:?}", e);
+    ApiError::InternalServerError("Relay storage failed".into())
+}
+
+pub async fn deposit_handler(
+    Extension(pool): Extension<Arc<SqlitePool>>,
+    connect_info: Option<Extension<ConnectInfo<SocketAddr>>>,
+    Json(input): Json<DepositInput>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    if !identity::is_peer_key(&input.recipient) {
+        return Err(ApiError::InvalidInput(
+            "Recipient must be a peer key.".into(),
+        ));
+    }
+    if input
+        .envelopes
+        .iter()
+        .any(|env| env.payload.len() > MAX_PAYLOAD_BYTES)
+    {
+        return Err(ApiError::InvalidInput(format!(
+            "Payload larger than {} bytes.",
+            MAX_PAYLOAD_BYTES
+        )));
+    }
+
+    let origin = connect_info
+        .map(|Extension(ConnectInfo(addr))| addr.ip().to_string())
+        .unwrap_or_default();
+
+    drop_expired(&pool).await?;
+    let mut tx = pool.begin().await.map_err(storage_error)?;
+
+    let (queued, from_origin): (i64, i64) = sqlx::query_as(
+        "SELECT COUNT(*), COUNT(CASE WHEN origin = ? THEN 1 END) FROM mailbox WHERE recipient = ?",
+    )
+    .bind(&origin)
+    .bind(&input.recipient)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(storage_error)?;
+
+    let count = input.envelopes.len() as i64;
+    if queued + count > MAX_MAILBOX_SIZE {
+        return Err(ApiError::InvalidInput("Recipient mailbox is full.".into()));
+    }
+    if from_origin + count > MAX_PER_ORIGIN {
+        return Err(ApiError::InvalidInput(
+            "Too much mail from you in this mailbox.".into(),
+        ));
+    }
+
+    for envelope in &input.envelopes {
+        sqlx::query("INSERT INTO mailbox (recipient, sender, payload, origin) VALUES (?, ?, ?, ?)")
+            .bind(&input.recipient)
+            .bind(&envelope.sender)
+            .bind(&envelope.payload)
+            .bind(&origin)
+            .execute(&mut *tx)
+            .await
+            .map_err(storage_error)?;
+    }
+
+    tx.commit().await.map_err(storage_error)?;
+
+    Ok(Json(
+        serde_json::json!({ "deposited": input.envelopes.len() }),
+    ))
+}
+
+pub async fn collect_handler(
+    Extension(pool): Extension<Arc<SqlitePool>>,
+    Json(input): Json<CollectInput>,
+) -> Result<Json<CollectResponse>, ApiError> {
+    if !input.is_signed(chrono::Utc::now().timestamp()) {
+        return Err(unsigned());
+    }
+
+    drop_expired(&pool).await?;
+    let rows: Vec<(i64, String, String)> =
+        sqlx::query_as("SELECT id, sender, payload FROM mailbox WHERE recipient = ? ORDER BY id")
+            .bind(&input.recipient)
+            .fetch_all(&*pool)
+            .await
+            .map_err(storage_error)?;
+
+    let mail = rows
+        .into_iter()
+        .map(|(id, sender, payload)| RelayMail {
+            id,
+            sender,
+            payload,
+        })
+        .collect();
+
+    Ok(Json(CollectResponse { mail }))
+}
+
+// Drops the mail the recipient says it has ingested
+pub async fn ack_handler(
+    Extension(pool): Extension<Arc<SqlitePool>>,
+    Json(input): Json<AckInput>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    if !input.is_signed(chrono::Utc::now().timestamp()) {
+        return Err(unsigned());
+    }
+
+    let mut tx = pool.begin().await.map_err(storage_error)?;
+    let mut acked = 0;
+    for id in &input.ids {
+        acked += sqlx::query("DELETE FROM mailbox WHERE recipient = ? AND id = ?")
+            .bind(&input.recipient)
+            .bind(id)
+            .execute(&mut *tx)
+            .await
+            .map_err(storage_error)?
+            .rows_affected();
+    }
+    tx.commit().await.map_err(storage_error)?;
+
+    Ok(Json(serde_json::json!({ "acked": acked })))
+}
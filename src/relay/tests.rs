@@ -0,0 +1,235 @@
+use super::*;
+use axum::{
+    body::{Body, to_bytes},
+    http::{Request, StatusCode},
+};
+use std::net::SocketAddr;
+use tower::ServiceExt;
+
+async fn setup_relay_db() -> SqlitePool {
+    let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+    RELAY_MIGRATOR.run(&pool).await.unwrap();
+    pool
+}
+
+async fn post(app: Router, uri: &str, body: String) -> (StatusCode, Vec<u8>) {
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri(uri)
+                .header("Content-Type", "application/json")
+                .body(Body::from(body))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let status = response.status();
+    let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, bytes.to_vec())
+}
+
+fn envelope(sender: &str, payload: &str) -> RelayEnvelope {
+    RelayEnvelope {
+        sender: sender.into(),
+        payload: payload.into(),
+    }
+}
+
+async fn collect(pool: &SqlitePool, input: &CollectInput) -> (StatusCode, Vec<RelayMail>) {
+    let (status, body) = post(
+        relay_app(pool.clone()),
+        "/collect",
+        serde_json::to_string(input).unwrap(),
+    )
+    .await;
+    let mail = serde_json::from_slice::<CollectResponse>(&body)
+        .map(|response| response.mail)
+        .unwrap_or_default();
+    (status, mail)
+}
+
+#[tokio::test]
+async fn test_deposit_and_collect() {
+    let pool = setup_relay_db().await;
+    let bob = Identity::generate();
+    let now = chrono::Utc::now().timestamp();
+
+    let deposit = DepositInput {
+        recipient: bob.peer_key(),
+        envelopes: vec![envelope("alice", "first"), envelope("alice", "second")],
+    };
+    let (status, _) = post(
+        relay_app(pool.clone()),
+        "/deposit",
+        serde_json::to_string(&deposit).unwrap(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, mail) = collect(&pool, &CollectInput::new(&bob, now)).await;
+    assert_eq!(status, StatusCode::OK);
+    let payloads: Vec<&str> = mail.iter().map(|m| m.payload.as_str()).collect();
+    assert_eq!(payloads, vec!["first", "second"]);
+
+    // mail stays until it is acked
+    let (_, again) = collect(&pool, &CollectInput::new(&bob, now)).await;
+    assert_eq!(again, mail);
+    let ack = AckInput::new(&bob, vec![mail[0].id], now);
+    let (status, _) = post(
+        relay_app(pool.clone()),
+        "/ack",
+        serde_json::to_string(&ack).unwrap(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (_, left) = collect(&pool, &CollectInput::new(&bob, now)).await;
+    assert_eq!(left, vec![mail[1].clone()]);
+}
+
+#[tokio::test]
+async fn test_mailbox_needs_its_key() {
+    let pool = setup_relay_db().await;
+    let bob = Identity::generate();
+    let mallory = Identity::generate();
+    let now = chrono::Utc::now().timestamp();
+
+    let deposit = DepositInput {
+        recipient: bob.peer_key(),
+        envelopes: vec![envelope("alice", "first")],
+    };
+    post(
+        relay_app(pool.clone()),
+        "/deposit",
+        serde_json::to_string(&deposit).unwrap(),
+    )
+    .await;
+
+    // someone else's signature, a stale one or none at all won't open it
+    let mut forged = CollectInput::new(&mallory, now);
+    forged.recipient = bob.peer_key();
+    let (status, _) = collect(&pool, &forged).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let stale = CollectInput::new(&bob, now - SIGNATURE_MAX_AGE - 60);
+    let (status, _) = collect(&pool, &stale).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = post(
+        relay_app(pool.clone()),
+        "/collect",
+        serde_json::json!({ "recipient": bob.peer_key() }).to_string(),
+    )
+    .await;
+    assert!(status.is_client_error());
+
+    // nor can they ack it away
+    let mut ack = AckInput::new(&mallory, vec![1], now);
+    ack.recipient = bob.peer_key();
+    let (status, _) = post(
+        relay_app(pool.clone()),
+        "/ack",
+        serde_json::to_string(&ack).unwrap(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    // and acking your own mailbox doesn't touch bob's
+    let ack = AckInput::new(&mallory, vec![1], now);
+    post(
+        relay_app(pool.clone()),
+        "/ack",
+        serde_json::to_string(&ack).unwrap(),
+    )
+    .await;
+    let (_, mail) = collect(&pool, &CollectInput::new(&bob, now)).await;
+    assert_eq!(mail.len(), 1);
+
+    // mailboxes are peer keys, not usernames
+    let deposit = DepositInput {
+        recipient: "bob".into(),
+        envelopes: vec![envelope("alice", "first")],
+    };
+    let (status, _) = post(
+        relay_app(pool.clone()),
+        "/deposit",
+        serde_json::to_string(&deposit).unwrap(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_oversized_payload_is_rejected() {
+    let pool = setup_relay_db().await;
+
+    let deposit = DepositInput {
+        recipient: Identity::generate().peer_key(),
+        envelopes: vec![envelope("alice", &"x".repeat(MAX_PAYLOAD_BYTES + 1))],
+    };
+    let (status, _) = post(
+        relay_app(pool.clone()),
+        "/deposit",
+        serde_json::to_string(&deposit).unwrap(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM mailbox")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(count, 0);
+}
+
+#[tokio::test]
+async fn test_one_origin_cannot_fill_a_mailbox() {
+    let pool = setup_relay_db().await;
+    let bob = Identity::generate();
+    let deposit_from = |ip: &str| {
+        let deposit = DepositInput {
+            recipient: bob.peer_key(),
+            envelopes: (0..MAX_PER_ORIGIN)
+                .map(|i| envelope("mallory", &i.to_string()))
+                .collect(),
+        };
+        let addr: SocketAddr = format!("{}:4000", ip).parse().unwrap();
+        relay_app(pool.clone()).oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/deposit")
+                .header("Content-Type", "application/json")
+                .extension(ConnectInfo(addr))
+                .body(Body::from(serde_json::to_string(&deposit).unwrap()))
+                .unwrap(),
+        )
+    };
+
+    let response = deposit_from("10.0.0.66").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = deposit_from("10.0.0.66").await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    // someone else still gets through
+    let response = deposit_from("10.0.0.1").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_old_mail_expires() {
+    let pool = setup_relay_db().await;
+    let bob = Identity::generate();
+    sqlx::query(
+        "INSERT INTO mailbox (recipient, sender, payload, deposited_at) VALUES (?, 'alice', 'stale', datetime('now', ?)), (?, 'alice', 'fresh', CURRENT_TIMESTAMP)",
+    )
+    .bind(bob.peer_key())
+    .bind(format!("-{} days", MAILBOX_TTL_DAYS + 1))
+    .bind(bob.peer_key())
+    .execute(&pool)
+    .await
+    .unwrap();
+
+    let (_, mail) = collect(
+        &pool,
+        &CollectInput::new(&bob, chrono::Utc::now().timestamp()),
+    )
+    .await;
+    let payloads: Vec<&str> = mail.iter().map(|m| m.payload.as_str()).collect();
+    assert_eq!(payloads, vec!["fresh"]);
+}
@@ -0,0 +1,143 @@
+// Config hot reload
+// A reload reads the config file and environment again on top of the flags
+// the node started with, validates the result and hands it to the running
+// tasks through a watch channel. Fetch intervals and limits, invite expiry
+// and the log level apply right away. Everything else is reported against the config
+// the node started with, until a restart picks it up.
+
+use crate::config::{Applies, CliArgs, Config, ConfigChange, ConfigError};
+use crate::logging::LevelHandle;
+use crate::wakeup::Wakeup;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, watch};
+use tracing::{info, warn};
+
+#[cfg(test)]
+mod tests;
+
+#[derive(Debug, Clone)]
+pub struct Reloader {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    args: CliArgs,
+    started: Config,
+    tx: watch::Sender<Config>,
+    wakeup: Wakeup,
+    log_level: Option<LevelHandle>,
+    // one reload at a time, so changes are reported against the right config
+    lock: Mutex<()>,
+}
+
+impl Reloader {
+    // `args` are the flags the node started with, they keep overriding the file
+    pub fn new(
+        args: CliArgs,
+        config: Config,
+        wakeup: Wakeup,
+        log_level: Option<LevelHandle>,
+    ) -> Self {
+        let (tx, _) = watch::channel(config.clone());
+        Reloader {
+            inner: Arc::new(Inner {
+                args,
+                started: config,
+                tx,
+                wakeup,
+                log_level,
+                lock: Mutex::new(()),
+            }),
+        }
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Config> {
+        self.inner.tx.subscribe()
+    }
+
+    pub fn current(&self) -> Config {
+        self.inner.tx.borrow().clone()
+    }
+
+    pub fn file_path(&self) -> PathBuf {
+        Config::file_path(&self.inner.args, |key| std::env::var(key).ok()).0
+    }
+
+    pub fn reload(&self) -> Result<Vec<ConfigChange>, ConfigError> {
+        self.reload_with(|key| std::env::var(key).ok())
+    }
+
+    // Loads and applies the config again, the running one stays on errors
+    pub fn reload_with(
+        &self,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Result<Vec<ConfigChange>, ConfigError> {
+        let _guard = self.inner.lock.lock().unwrap();
+        let mut new = Config::load_with(self.inner.args.clone(), var)?;
+        let current = self.current();
+
+        let mut changes: Vec<ConfigChange> = current
+            .changes(&new)
+            .into_iter()
+            .filter(|change| change.applies == Applies::Live)
+            .collect();
+        let live = changes.len();
+        changes.extend(
+            self.inner
+                .started
+                .changes(&new)
+                .into_iter()
+                .filter(|change| change.applies != Applies::Live),
+        );
+
+        if let Some(change) = changes[..live]
+            .iter_mut()
+            .find(|change| change.field == "log_level")
+            && let Some(handle) = &self.inner.log_level
+            && let Err(e) = handle.set(&new.log_level)
+        {
+            // the subscriber still logs at the old level, so does the config
+            warn!("Failed to change the log level: {}", e);
+            change.applies = Applies::Failed;
+            new.log_level = current.log_level;
+        }
+        self.inner.tx.send_replace(new);
+        // the fetchers pick up new intervals on their next cycle, run it now
+        if live > 0 {
+            self.inner.wakeup.sync_now();
+        }
+        info!(changes = changes.len(), "Config reloaded");
+        Ok(changes)
+    }
+}
+
+// Watches the directory of `path`, editors often replace the file instead
+// of writing to it. Each change to the file sends one message; keep the
+// watcher alive for as long as they are wanted.
+pub fn watch_file(path: &Path) -> notify::Result<(RecommendedWatcher, mpsc::Receiver<()>)> {
+    let (tx, rx) = mpsc::channel(1);
+    let file_name = path.file_name().map(|name| name.to_os_string());
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let Ok(event) = event else { return };
+        let relevant = matches!(
+            event.kind,
+            EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+        ) && event
+            .paths
+            .iter()
+            .any(|changed| changed.file_name() == file_name.as_deref());
+        if relevant {
+            // a full channel already has a reload coming
+            let _ = tx.try_send(());
+        }
+    })?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    Ok((watcher, rx))
+}
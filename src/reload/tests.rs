@@ -0,0 +1,131 @@
+use super::*;
+use crate::shutdown::Shutdown;
+use crate::wakeup::Resume;
+use std::time::Duration;
+
+fn no_env(_: &str) -> Option<String> {
+    None
+}
+
+#[tokio::test]
+async fn test_reload_applies_and_reports_changes() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("config.json");
+    std::fs::write(&file, r#"{ "message_fetch_interval": 30 }"#).unwrap();
+    let args = CliArgs {
+        data_dir: Some(dir.path().to_path_buf()),
+        // flags keep winning over the file
+        friend_fetch_interval: Some(7),
+        ..Default::default()
+    };
+    let config = Config::load_with(args.clone(), no_env).unwrap();
+
+    let shutdown = Shutdown::new();
+    let wakeup = Wakeup::new();
+    let mut pause = wakeup.message_fetcher(shutdown.subscribe());
+    let reloader = Reloader::new(args, config, wakeup, None);
+    let rx = reloader.subscribe();
+    assert_eq!(reloader.file_path(), file);
+
+    std::fs::write(
+        &file,
+        r#"{ "message_fetch_interval": 10, "friend_fetch_interval": 1, "bind_address": "127.0.0.1:9999" }"#,
+    )
+    .unwrap();
+    let changes = reloader.reload_with(no_env).unwrap();
+    let fields: Vec<(&str, Applies)> = changes
+        .iter()
+        .map(|c| (c.field.as_str(), c.applies))
+        .collect();
+    assert_eq!(
+        fields,
+        [
+            ("message_fetch_interval", Applies::Live),
+            ("bind_address", Applies::Rebind)
+        ]
+    );
+    assert_eq!(changes[0].to_string(), "message_fetch_interval: 30 -> 10");
+    assert!(
+        changes[1]
+            .to_string()
+            .ends_with("(restart the node to rebind)")
+    );
+    assert_eq!(rx.borrow().message_fetch_interval, 10);
+    assert_eq!(rx.borrow().friend_fetch_interval, 7);
+    // the fetchers run a cycle with the new intervals straight away
+    assert_eq!(pause.wait(Duration::from_secs(60)).await, Resume::Woken);
+
+    // the rebind is still pending, the interval is not news anymore
+    let changes = reloader.reload_with(no_env).unwrap();
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].field, "bind_address");
+
+    // a broken file leaves the running config alone
+    std::fs::write(&file, r#"{ "message_fetch_interval": 0 }"#).unwrap();
+    let err = reloader.reload_with(no_env).unwrap_err();
+    assert!(
+        err.to_string().contains("message_fetch_interval"),
+        "{}",
+        err
+    );
+    assert_eq!(reloader.current().message_fetch_interval, 10);
+}
+
+#[test]
+fn test_log_level_that_fails_to_apply_is_kept() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("config.json");
+    let args = CliArgs {
+        data_dir: Some(dir.path().to_path_buf()),
+        ..Default::default()
+    };
+    let config = Config::load_with(args.clone(), no_env).unwrap();
+    let broken = LevelHandle::new(|_| Err("subscriber is gone".into()));
+    let reloader = Reloader::new(args, config, Wakeup::new(), Some(broken));
+
+    std::fs::write(
+        &file,
+        r#"{ "log_level": "debug", "message_fetch_concurrency": 3 }"#,
+    )
+    .unwrap();
+    let changes = reloader.reload_with(no_env).unwrap();
+    let fields: Vec<(&str, Applies)> = changes
+        .iter()
+        .map(|c| (c.field.as_str(), c.applies))
+        .collect();
+    assert_eq!(
+        fields,
+        [
+            ("message_fetch_concurrency", Applies::Live),
+            ("log_level", Applies::Failed)
+        ]
+    );
+    assert!(
+        changes[1]
+            .to_string()
+            .ends_with("(failed to apply, kept the old value)")
+    );
+    assert_eq!(reloader.current().log_level, "warn");
+    assert_eq!(reloader.current().message_fetch_concurrency, 3);
+
+    // the next reload tries again
+    let changes = reloader.reload_with(no_env).unwrap();
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].applies, Applies::Failed);
+}
+
+#[tokio::test]
+async fn test_file_changes_are_noticed() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("config.json");
+    std::fs::write(&file, "{}").unwrap();
+    let (_watcher, mut rx) = watch_file(&file).unwrap();
+
+    // other files in the same directory don't count
+    std::fs::write(dir.path().join("notes.txt"), "hello").unwrap();
+    std::fs::write(&file, r#"{ "log_level": "debug" }"#).unwrap();
+    tokio::time::timeout(Duration::from_secs(5), rx.recv())
+        .await
+        .expect("no change noticed")
+        .unwrap();
+}
@@ -0,0 +1,20 @@
+use super::*;
+use tokio::time::Instant;
+
+#[tokio::test]
+async fn test_sleep_is_cut_short_by_shutdown() {
+    let shutdown = Shutdown::new();
+    let mut signal = shutdown.subscribe();
+
+    // a plain timeout runs out without shutdown
+    assert!(!signal.sleep(Duration::from_millis(10)).await);
+
+    let started = Instant::now();
+    let sleeper = tokio::spawn(async move { signal.sleep(Duration::from_secs(60)).await });
+    shutdown.trigger();
+    assert!(sleeper.await.unwrap());
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    // late subscribers see the shutdown straight away
+    assert!(shutdown.subscribe().is_triggered());
+}
@@ -0,0 +1,1128 @@
+// Storage traits
+// api, comms and the CLI go through MessageStore/FriendStore instead of
+// taking a SqlitePool, so a node can also run on MemoryStore (tests,
+// embedding). SqlitePool implements both on top of the db functions.
+// Only setting up the database (migrations, the first user, the address we
+// advertise) is left to db.
+
+use crate::api::Message;
+use crate::contacts::{self, ConflictPolicy, ContactExport, ImportReport, status_code};
+use crate::db::{
+    self, BlockKind, Blocked, Friend, FriendRequest, InboxMessage, IncomingInvite, InviteOutcome,
+    Outgoing, OutgoingMessage, Peer, PeerHealth, User,
+};
+use crate::error::{Error, Result};
+use crate::identity::Identity;
+use chrono::{NaiveDateTime, Utc};
+use sqlx::SqlitePool;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[cfg(test)]
+mod tests;
+
+pub trait MessageStore: Send + Sync {
+    fn fetch_inbox(&self) -> impl Future<Output = Result<Vec<InboxMessage>>> + Send;
+
+    fn delete_message(&self, id: i64) -> impl Future<Output = Result<()>> + Send;
+
+    // Messages fetched from peers
+    fn ingest_messages(&self, messages: Vec<Message>) -> impl Future<Output = Result<()>> + Send;
+
+    // Mail from our relay, keyed by the digest of its sealed payload. Mail
+    // taken in before is skipped, returns what was new.
+    fn ingest_relay_mail(
+        &self,
+        mail: Vec<(String, Message)>,
+    ) -> impl Future<Output = Result<Vec<Message>>> + Send;
+
+    fn queue_message(&self, message: &OutgoingMessage) -> impl Future<Output = Result<()>> + Send;
+
+    // Queues the message to every accepted friend with the label, all of
+    // them or none. Returns who it went to
+    fn queue_message_to_label(
+        &self,
+        label: &str,
+        subject: &str,
+        content: &str,
+    ) -> impl Future<Output = Result<Vec<String>>> + Send;
+
+    fn fetch_outgoing(&self) -> impl Future<Output = Result<Vec<Outgoing>>> + Send;
+
+    // Unsent messages queued for `username`
+    fn pending_messages_for(
+        &self,
+        username: &str,
+    ) -> impl Future<Output = Result<Vec<Outgoing>>> + Send;
+
+    fn mark_messages_sent(&self, ids: &[i64]) -> impl Future<Output = Result<()>> + Send;
+
+    // How many queued messages are still unsent
+    fn outgoing_queue_depth(&self) -> impl Future<Output = Result<i64>> + Send;
+}
+
+pub trait FriendStore: Send + Sync {
+    fn local_user(&self) -> impl Future<Output = Result<User>> + Send;
+
+    // our keypair, made on first use
+    fn local_identity(&self) -> impl Future<Output = Result<Identity>> + Send;
+
+    fn fetch_friends(&self) -> impl Future<Output = Result<Vec<Friend>>> + Send;
+
+    fn fetch_active_friends(&self) -> impl Future<Output = Result<Vec<Friend>>> + Send;
+
+    // Friend rows whose status change the peer hasn't heard about yet
+    fn fetch_unsent_friend_updates(
+        &self,
+    ) -> impl Future<Output = Result<(User, Vec<Friend>)>> + Send;
+
+    fn mark_friend_update_sent(&self, username: &str) -> impl Future<Output = Result<()>> + Send;
+
+    fn send_invite(&self, request: &FriendRequest) -> impl Future<Output = Result<()>> + Send;
+
+    fn invite_decision(&self, id: i64, accept: bool) -> impl Future<Output = Result<()>> + Send;
+
+    fn delete_friend(&self, id: i64) -> impl Future<Output = Result<()>> + Send;
+
+    // Username of the friend `name` (a username or nickname) refers to,
+    // unknown names come back as they are
+    fn resolve_recipient(&self, name: &str) -> impl Future<Output = Result<String>> + Send;
+
+    // Starts a pending, rejected or expired invite over, false for anyone else
+    fn reinvite(&self, id: i64) -> impl Future<Output = Result<bool>> + Send;
+
+    fn set_friend_relay(
+        &self,
+        id: i64,
+        relay_address: Option<&str>,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    // Nicknames must not clash with another friend's nickname or username
+    fn set_friend_details(
+        &self,
+        id: i64,
+        nickname: Option<&str>,
+        notes: Option<&str>,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    // Labels of every friend keyed by friend id
+    fn fetch_friend_labels(&self)
+    -> impl Future<Output = Result<HashMap<i64, Vec<String>>>> + Send;
+
+    fn set_friend_labels(
+        &self,
+        friend_id: i64,
+        labels: &[String],
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    // All of the export or nothing, see contacts::import_contacts
+    fn import_contacts(
+        &self,
+        export: &ContactExport,
+        policy: ConflictPolicy,
+        resend_pending: bool,
+    ) -> impl Future<Output = Result<ImportReport>> + Send;
+
+    fn expire_stale_invites(&self, max_age: Duration) -> impl Future<Output = Result<u64>> + Send;
+
+    fn receive_invite(
+        &self,
+        invite: &IncomingInvite<'_>,
+    ) -> impl Future<Output = Result<InviteOutcome>> + Send;
+
+    fn fetch_invite_state(
+        &self,
+        username: &str,
+        address: &str,
+    ) -> impl Future<Output = Result<Option<(i64, Option<String>)>>> + Send;
+
+    fn accept_invite(
+        &self,
+        username: &str,
+        address: &str,
+        peer_key: Option<&str>,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    fn reject_invite(
+        &self,
+        username: &str,
+        address: &str,
+    ) -> impl Future<Output = Result<bool>> + Send;
+
+    fn is_blocked(&self, peer: &Peer<'_>) -> impl Future<Output = Result<bool>> + Send;
+
+    fn fetch_blocks(&self) -> impl Future<Output = Result<Vec<Blocked>>> + Send;
+
+    fn add_block(&self, kind: BlockKind, value: &str) -> impl Future<Output = Result<()>> + Send;
+
+    fn remove_block(&self, id: i64) -> impl Future<Output = Result<()>> + Send;
+
+    // Blocks the friend's username (and key if we know it) and drops them
+    fn block_friend(&self, friend: &Friend) -> impl Future<Output = Result<()>> + Send;
+
+    // One-time token for an invite link, an invite carrying it is accepted
+    fn create_invite_token(&self) -> impl Future<Output = Result<String>> + Send;
+
+    fn fetch_peer_health(&self) -> impl Future<Output = Result<Vec<PeerHealth>>> + Send;
+
+    fn record_peer_success(&self, username: &str) -> impl Future<Output = Result<()>> + Send;
+
+    // Returns the new consecutive failure count
+    fn record_peer_failure(
+        &self,
+        username: &str,
+        error: &str,
+    ) -> impl Future<Output = Result<i64>> + Send;
+
+    fn schedule_peer_retry(
+        &self,
+        username: &str,
+        next_attempt_at: NaiveDateTime,
+    ) -> impl Future<Output = Result<()>> + Send;
+}
+
+// Everything a node needs, what api::app and the fetchers are generic over
+pub trait Store: MessageStore + FriendStore + Clone + 'static {}
+
+impl<S: MessageStore + FriendStore + Clone + 'static> Store for S {}
+
+impl MessageStore for SqlitePool {
+    async fn fetch_inbox(&self) -> Result<Vec<InboxMessage>> {
+        db::fetch_inbox(self).await
+    }
+
+    async fn delete_message(&self, id: i64) -> Result<()> {
+        db::delete_message(self, id).await
+    }
+
+    async fn ingest_messages(&self, messages: Vec<Message>) -> Result<()> {
+        db::batch_ingest(self, messages).await
+    }
+
+    async fn ingest_relay_mail(&self, mail: Vec<(String, Message)>) -> Result<Vec<Message>> {
+        db::ingest_relay_mail(self, mail).await
+    }
+
+    async fn queue_message(&self, message: &OutgoingMessage) -> Result<()> {
+        db::send_message_to_que(self, message).await
+    }
+
+    async fn queue_message_to_label(
+        &self,
+        label: &str,
+        subject: &str,
+        content: &str,
+    ) -> Result<Vec<String>> {
+        db::send_message_to_label(self, label, subject, content).await
+    }
+
+    async fn fetch_outgoing(&self) -> Result<Vec<Outgoing>> {
+        db::fetch_outgoing(self).await
+    }
+
+    async fn pending_messages_for(&self, username: &str) -> Result<Vec<Outgoing>> {
+        db::fetch_messages_for_user(self, username.to_string()).await
+    }
+
+    async fn mark_messages_sent(&self, ids: &[i64]) -> Result<()> {
+        db::mark_messages_as_sent(self, ids).await
+    }
+
+    async fn outgoing_queue_depth(&self) -> Result<i64> {
+        db::count_unsent_messages(self).await
+    }
+}
+
+impl FriendStore for SqlitePool {
+    async fn local_user(&self) -> Result<User> {
+        db::retr_user(self).await
+    }
+
+    async fn local_identity(&self) -> Result<Identity> {
+        db::ensure_identity(self).await
+    }
+
+    async fn fetch_friends(&self) -> Result<Vec<Friend>> {
+        db::fetch_users(self).await
+    }
+
+    async fn fetch_active_friends(&self) -> Result<Vec<Friend>> {
+        db::fetch_active_friends(self).await
+    }
+
+    async fn fetch_unsent_friend_updates(&self) -> Result<(User, Vec<Friend>)> {
+        db::fetch_unsent_friend_updt(self).await
+    }
+
+    async fn mark_friend_update_sent(&self, username: &str) -> Result<()> {
+        db::update_friend_status_as_sent(self, &username.to_string()).await
+    }
+
+    async fn send_invite(&self, request: &FriendRequest) -> Result<()> {
+        db::send_invite(self, request).await
+    }
+
+    async fn invite_decision(&self, id: i64, accept: bool) -> Result<()> {
+        db::invite_decision(self, id, accept).await
+    }
+
+    async fn delete_friend(&self, id: i64) -> Result<()> {
+        db::delete_user(self, id).await
+    }
+
+    async fn resolve_recipient(&self, name: &str) -> Result<String> {
+        db::resolve_recipient(self, name).await
+    }
+
+    async fn reinvite(&self, id: i64) -> Result<bool> {
+        db::reinvite(self, id).await
+    }
+
+    async fn set_friend_relay(&self, id: i64, relay_address: Option<&str>) -> Result<()> {
+        db::set_friend_relay(self, id, relay_address).await
+    }
+
+    async fn set_friend_details(
+        &self,
+        id: i64,
+        nickname: Option<&str>,
+        notes: Option<&str>,
+    ) -> Result<()> {
+        db::set_friend_details(self, id, nickname, notes).await
+    }
+
+    async fn fetch_friend_labels(&self) -> Result<HashMap<i64, Vec<String>>> {
+        db::fetch_friend_labels(self).await
+    }
+
+    async fn set_friend_labels(&self, friend_id: i64, labels: &[String]) -> Result<()> {
+        db::set_friend_labels(self, friend_id, labels).await
+    }
+
+    async fn import_contacts(
+        &self,
+        export: &ContactExport,
+        policy: ConflictPolicy,
+        resend_pending: bool,
+    ) -> Result<ImportReport> {
+        contacts::import_contacts(self, export, policy, resend_pending).await
+    }
+
+    async fn expire_stale_invites(&self, max_age: Duration) -> Result<u64> {
+        db::expire_stale_invites(self, max_age).await
+    }
+
+    async fn receive_invite(&self, invite: &IncomingInvite<'_>) -> Result<InviteOutcome> {
+        db::receive_invite(self, invite).await
+    }
+
+    async fn fetch_invite_state(
+        &self,
+        username: &str,
+        address: &str,
+    ) -> Result<Option<(i64, Option<String>)>> {
+        db::fetch_invite_state(self, username, address).await
+    }
+
+    async fn accept_invite(
+        &self,
+        username: &str,
+        address: &str,
+        peer_key: Option<&str>,
+    ) -> Result<()> {
+        db::accept_invite(self, username, address, peer_key).await
+    }
+
+    async fn reject_invite(&self, username: &str, address: &str) -> Result<bool> {
+        db::reject_invite(self, username, address).await
+    }
+
+    async fn is_blocked(&self, peer: &Peer<'_>) -> Result<bool> {
+        db::is_blocked(self, peer).await
+    }
+
+    async fn fetch_blocks(&self) -> Result<Vec<Blocked>> {
+        db::fetch_blocks(self).await
+    }
+
+    async fn add_block(&self, kind: BlockKind, value: &str) -> Result<()> {
+        db::add_block(self, kind, value).await
+    }
+
+    async fn remove_block(&self, id: i64) -> Result<()> {
+        db::remove_block(self, id).await
+    }
+
+    async fn block_friend(&self, friend: &Friend) -> Result<()> {
+        db::block_friend(self, friend).await
+    }
+
+    async fn create_invite_token(&self) -> Result<String> {
+        db::create_invite_token(self).await
+    }
+
+    async fn fetch_peer_health(&self) -> Result<Vec<PeerHealth>> {
+        db::fetch_peer_health(self).await
+    }
+
+    async fn record_peer_success(&self, username: &str) -> Result<()> {
+        db::record_peer_success(self, username).await
+    }
+
+    async fn record_peer_failure(&self, username: &str, error: &str) -> Result<i64> {
+        db::record_peer_failure(self, username, error).await
+    }
+
+    async fn schedule_peer_retry(
+        &self,
+        username: &str,
+        next_attempt_at: NaiveDateTime,
+    ) -> Result<()> {
+        db::schedule_peer_retry(self, username, next_attempt_at).await
+    }
+}
+
+// Keeps a whole node in memory, clones share the same state. Mirrors the
+// SQLite behaviour closely enough for the api and fetchers not to care.
+#[derive(Clone)]
+pub struct MemoryStore {
+    state: Arc<Mutex<MemoryState>>,
+}
+
+struct MemoryState {
+    user: User,
+    identity: Identity,
+    // friend rows plus their "sent" flag
+    friends: Vec<(Friend, bool)>,
+    inbox: Vec<InboxMessage>,
+    outgoing: Vec<Outgoing>,
+    health: HashMap<String, PeerHealth>,
+    blocks: Vec<Blocked>,
+    // friend id to labels
+    labels: HashMap<i64, Vec<String>>,
+    invite_tokens: HashSet<String>,
+    relay_seen: HashSet<String>,
+    next_id: i64,
+}
+
+impl MemoryState {
+    fn next_id(&mut self) -> i64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn friend_mut(&mut self, username: &str) -> Option<&mut (Friend, bool)> {
+        self.friends
+            .iter_mut()
+            .find(|(f, _)| f.username == username)
+    }
+
+    fn friend_by_id(&mut self, id: i64) -> Option<&mut (Friend, bool)> {
+        self.friends.iter_mut().find(|(f, _)| f.id == id)
+    }
+
+    // the friend's labels and health go with their row
+    fn remove_friend(&mut self, id: i64) {
+        if let Some(pos) = self.friends.iter().position(|(f, _)| f.id == id) {
+            let (friend, _) = self.friends.remove(pos);
+            self.health.remove(&friend.username);
+            self.labels.remove(&id);
+        }
+    }
+
+    fn add_block(&mut self, kind: BlockKind, value: &str) {
+        let kind = kind.as_str();
+        if self
+            .blocks
+            .iter()
+            .any(|b| b.kind == kind && b.value == value)
+        {
+            return;
+        }
+        let id = self.next_id();
+        self.blocks.push(Blocked {
+            id,
+            kind: kind.to_string(),
+            value: value.to_string(),
+            added_at: Some(now()),
+        });
+    }
+}
+
+fn now() -> NaiveDateTime {
+    Utc::now().naive_utc()
+}
+
+impl MemoryStore {
+    pub fn new(username: &str, address: &str) -> Self {
+        let identity = Identity::generate();
+        let user = User {
+            id: 1,
+            username: username.to_string(),
+            address: address.to_string(),
+            peer_key: Some(identity.peer_key()),
+        };
+        MemoryStore {
+            state: Arc::new(Mutex::new(MemoryState {
+                user,
+                identity,
+                friends: Vec::new(),
+                inbox: Vec::new(),
+                outgoing: Vec::new(),
+                health: HashMap::new(),
+                blocks: Vec::new(),
+                labels: HashMap::new(),
+                invite_tokens: HashSet::new(),
+                relay_seen: HashSet::new(),
+                next_id: 0,
+            })),
+        }
+    }
+
+    fn with<T>(&self, f: impl FnOnce(&mut MemoryState) -> T) -> T {
+        f(&mut self.state.lock().unwrap())
+    }
+}
+
+fn new_friend(id: i64, username: &str, address: &str, status: i64) -> Friend {
+    Friend {
+        id,
+        username: username.to_string(),
+        address: address.to_string(),
+        status,
+        added_at: Some(now()),
+        peer_key: None,
+        intro: None,
+        fingerprint: None,
+        invite_token: None,
+        relay_address: None,
+        nickname: None,
+        notes: None,
+    }
+}
+
+impl MessageStore for MemoryStore {
+    async fn fetch_inbox(&self) -> Result<Vec<InboxMessage>> {
+        Ok(self.with(|s| s.inbox.clone()))
+    }
+
+    async fn delete_message(&self, id: i64) -> Result<()> {
+        self.with(|s| s.inbox.retain(|m| m.id != id));
+        Ok(())
+    }
+
+    async fn ingest_messages(&self, messages: Vec<Message>) -> Result<()> {
+        self.with(|s| {
+            for msg in messages {
+                let id = s.next_id();
+                s.inbox.push(InboxMessage {
+                    id,
+                    sender: msg.sender,
+                    subject: msg.subject,
+                    message: msg.body,
+                    received_at: Some(now()),
+                });
+            }
+        });
+        Ok(())
+    }
+
+    async fn ingest_relay_mail(&self, mail: Vec<(String, Message)>) -> Result<Vec<Message>> {
+        Ok(self.with(|s| {
+            let mut fresh = Vec::new();
+            for (digest, msg) in mail {
+                if !s.relay_seen.insert(digest) {
+                    continue;
+                }
+                let id = s.next_id();
+                s.inbox.push(InboxMessage {
+                    id,
+                    sender: msg.sender.clone(),
+                    subject: msg.subject.clone(),
+                    message: msg.body.clone(),
+                    received_at: Some(now()),
+                });
+                fresh.push(msg);
+            }
+            fresh
+        }))
+    }
+
+    async fn queue_message(&self, message: &OutgoingMessage) -> Result<()> {
+        self.with(|s| {
+            let address = s
+                .friends
+                .iter()
+                .find(|(f, _)| f.username == message.send_to)
+                .map(|(f, _)| f.address.clone())
+                .ok_or_else(|| Error::NotFound(format!("no friend named {}", message.send_to)))?;
+            let id = s.next_id();
+            let sender = s.user.username.clone();
+            s.outgoing.push(Outgoing {
+                id,
+                sender,
+                recipient: message.send_to.clone(),
+                recipient_address: address,
+                subject: message.subject.clone(),
+                body: message.content.clone(),
+                queued_at: Some(now()),
+                sent: Some(false),
+            });
+            crate::metrics::messages_queued(1);
+            Ok(())
+        })
+    }
+
+    async fn queue_message_to_label(
+        &self,
+        label: &str,
+        subject: &str,
+        content: &str,
+    ) -> Result<Vec<String>> {
+        Ok(self.with(|s| {
+            let mut rows: Vec<(String, String)> = s
+                .friends
+                .iter()
+                .filter(|(f, _)| {
+                    f.status == 2
+                        && s.labels
+                            .get(&f.id)
+                            .is_some_and(|labels| labels.iter().any(|l| l == label))
+                })
+                .map(|(f, _)| (f.username.clone(), f.address.clone()))
+                .collect();
+            rows.sort();
+
+            let sender = s.user.username.clone();
+            for (username, address) in &rows {
+                let id = s.next_id();
+                s.outgoing.push(Outgoing {
+                    id,
+                    sender: sender.clone(),
+                    recipient: username.clone(),
+                    recipient_address: address.clone(),
+                    subject: subject.to_string(),
+                    body: content.to_string(),
+                    queued_at: Some(now()),
+                    sent: Some(false),
+                });
+            }
+            crate::metrics::messages_queued(rows.len());
+            rows.into_iter().map(|(username, _)| username).collect()
+        }))
+    }
+
+    async fn fetch_outgoing(&self) -> Result<Vec<Outgoing>> {
+        Ok(self.with(|s| s.outgoing.clone()))
+    }
+
+    async fn pending_messages_for(&self, username: &str) -> Result<Vec<Outgoing>> {
+        Ok(self.with(|s| {
+            s.outgoing
+                .iter()
+                .filter(|m| m.recipient == username && m.sent != Some(true))
+                .cloned()
+                .collect()
+        }))
+    }
+
+    async fn mark_messages_sent(&self, ids: &[i64]) -> Result<()> {
+        self.with(|s| {
+            for msg in s.outgoing.iter_mut().filter(|m| ids.contains(&m.id)) {
+                msg.sent = Some(true);
+            }
+        });
+        Ok(())
+    }
+
+    async fn outgoing_queue_depth(&self) -> Result<i64> {
+        Ok(self.with(|s| s.outgoing.iter().filter(|m| m.sent != Some(true)).count() as i64))
+    }
+}
+
+impl FriendStore for MemoryStore {
+    async fn local_user(&self) -> Result<User> {
+        Ok(self.with(|s| s.user.clone()))
+    }
+
+    async fn local_identity(&self) -> Result<Identity> {
+        Ok(self.with(|s| s.identity.clone()))
+    }
+
+    async fn fetch_friends(&self) -> Result<Vec<Friend>> {
+        Ok(self.with(|s| s.friends.iter().map(|(f, _)| f.clone()).collect()))
+    }
+
+    async fn fetch_active_friends(&self) -> Result<Vec<Friend>> {
+        Ok(self.with(|s| {
+            s.friends
+                .iter()
+                .filter(|(f, _)| f.status == 2)
+                .map(|(f, _)| f.clone())
+                .collect()
+        }))
+    }
+
+    async fn fetch_unsent_friend_updates(&self) -> Result<(User, Vec<Friend>)> {
+        Ok(self.with(|s| {
+            let pending = s
+                .friends
+                .iter()
+                .filter(|(_, sent)| !sent)
+                .map(|(f, _)| f.clone())
+                .collect();
+            (s.user.clone(), pending)
+        }))
+    }
+
+    async fn mark_friend_update_sent(&self, username: &str) -> Result<()> {
+        self.with(|s| {
+            if let Some((_, sent)) = s.friend_mut(username) {
+                *sent = true;
+            }
+        });
+        Ok(())
+    }
+
+    async fn send_invite(&self, request: &FriendRequest) -> Result<()> {
+        self.with(|s| {
+            if let Some((friend, sent)) = s.friend_mut(&request.username) {
+                // someone who rejected us, or whose invite expired, can be invited again
+                if !matches!(friend.status, 3 | 4) {
+                    return Err(Error::Validation(format!(
+                        "{} is already in the friend list",
+                        request.username
+                    )));
+                }
+                friend.status = 0;
+                friend.address = request.address.clone();
+                friend.intro = request.intro.clone();
+                friend.fingerprint = request.fingerprint.clone().or(friend.fingerprint.take());
+                friend.invite_token = request.invite_token.clone();
+                friend.added_at = Some(now());
+                *sent = false;
+                return Ok(());
+            }
+
+            let id = s.next_id();
+            let mut friend = new_friend(id, &request.username, &request.address, 0);
+            friend.intro = request.intro.clone();
+            friend.fingerprint = request.fingerprint.clone();
+            friend.invite_token = request.invite_token.clone();
+            s.friends.push((friend, false));
+            Ok(())
+        })
+    }
+
+    async fn invite_decision(&self, id: i64, accept: bool) -> Result<()> {
+        self.with(|s| {
+            let (friend, sent) = s
+                .friends
+                .iter_mut()
+                .find(|(f, _)| f.id == id && f.status == 1)
+                .ok_or_else(|| Error::NotFound(format!("no invite to answer with id {}", id)))?;
+            friend.status = if accept { 2 } else { 3 };
+            *sent = false;
+            Ok(())
+        })
+    }
+
+    async fn delete_friend(&self, id: i64) -> Result<()> {
+        self.with(|s| s.remove_friend(id));
+        Ok(())
+    }
+
+    async fn resolve_recipient(&self, name: &str) -> Result<String> {
+        self.with(|s| {
+            if s.friends.iter().any(|(f, _)| f.username == name) {
+                return Ok(name.to_string());
+            }
+            let mut matches: Vec<String> = s
+                .friends
+                .iter()
+                .filter(|(f, _)| f.nickname.as_deref() == Some(name))
+                .map(|(f, _)| f.username.clone())
+                .collect();
+            matches.sort();
+            match matches.len() {
+                0 => Ok(name.to_string()),
+                1 => Ok(matches.remove(0)),
+                _ => Err(Error::Validation(format!(
+                    "ambiguous recipient '{}', it is the nickname of {}",
+                    name,
+                    matches.join(", ")
+                ))),
+            }
+        })
+    }
+
+    async fn reinvite(&self, id: i64) -> Result<bool> {
+        Ok(self.with(|s| match s.friend_by_id(id) {
+            Some((friend, sent)) if matches!(friend.status, 0 | 3 | 4) => {
+                friend.status = 0;
+                friend.added_at = Some(now());
+                *sent = false;
+                true
+            }
+            _ => false,
+        }))
+    }
+
+    async fn set_friend_relay(&self, id: i64, relay_address: Option<&str>) -> Result<()> {
+        self.with(|s| {
+            if let Some((friend, _)) = s.friend_by_id(id) {
+                friend.relay_address = relay_address.map(str::to_string);
+            }
+        });
+        Ok(())
+    }
+
+    async fn set_friend_details(
+        &self,
+        id: i64,
+        nickname: Option<&str>,
+        notes: Option<&str>,
+    ) -> Result<()> {
+        self.with(|s| {
+            if let Some(nickname) = nickname {
+                let taken = s.friends.iter().find(|(f, _)| {
+                    f.id != id
+                        && (f.nickname.as_deref() == Some(nickname) || f.username == nickname)
+                });
+                if let Some((friend, _)) = taken {
+                    return Err(Error::Validation(format!(
+                        "nickname '{}' is already used for {}",
+                        nickname, friend.username
+                    )));
+                }
+            }
+            if let Some((friend, _)) = s.friend_by_id(id) {
+                friend.nickname = nickname.map(str::to_string);
+                friend.notes = notes.map(str::to_string);
+            }
+            Ok(())
+        })
+    }
+
+    async fn fetch_friend_labels(&self) -> Result<HashMap<i64, Vec<String>>> {
+        Ok(self.with(|s| {
+            s.labels
+                .iter()
+                .filter(|(_, labels)| !labels.is_empty())
+                .map(|(id, labels)| {
+                    let mut labels = labels.clone();
+                    labels.sort();
+                    (*id, labels)
+                })
+                .collect()
+        }))
+    }
+
+    async fn set_friend_labels(&self, friend_id: i64, labels: &[String]) -> Result<()> {
+        self.with(|s| {
+            if s.friend_by_id(friend_id).is_none() {
+                return Err(Error::NotFound(format!("no friend with id {}", friend_id)));
+            }
+            let mut unique = labels.to_vec();
+            unique.sort();
+            unique.dedup();
+            s.labels.insert(friend_id, unique);
+            Ok(())
+        })
+    }
+
+    async fn import_contacts(
+        &self,
+        export: &ContactExport,
+        policy: ConflictPolicy,
+        resend_pending: bool,
+    ) -> Result<ImportReport> {
+        Ok(self.with(|s| {
+            let mut report = ImportReport::default();
+            for contact in &export.contacts {
+                let Some(status) = status_code(&contact.status) else {
+                    report.skipped += 1;
+                    continue;
+                };
+                let resend = resend_pending && status == 0;
+                let sent = !resend;
+
+                let existing = s
+                    .friends
+                    .iter()
+                    .position(|(f, _)| f.username == contact.username);
+                let mut reset = false;
+                let friend_id = match (existing, policy) {
+                    (Some(_), ConflictPolicy::Skip) => {
+                        report.skipped += 1;
+                        continue;
+                    }
+                    (Some(pos), ConflictPolicy::Overwrite) => {
+                        let (friend, was_sent) = &mut s.friends[pos];
+                        friend.address = contact.address.clone();
+                        friend.status = status;
+                        friend.peer_key = contact.peer_key.clone();
+                        friend.fingerprint = contact.fingerprint.clone();
+                        friend.relay_address = contact.relay_address.clone();
+                        friend.nickname = contact.nickname.clone();
+                        friend.notes = contact.notes.clone();
+                        if status == 0 {
+                            friend.intro = None;
+                            friend.invite_token = None;
+                        }
+                        *was_sent = sent;
+                        let id = friend.id;
+                        s.labels.remove(&id);
+                        report.updated += 1;
+                        reset = resend;
+                        id
+                    }
+                    (Some(pos), ConflictPolicy::Merge) => {
+                        let (friend, _) = &mut s.friends[pos];
+                        friend.peer_key = friend.peer_key.take().or(contact.peer_key.clone());
+                        friend.fingerprint =
+                            friend.fingerprint.take().or(contact.fingerprint.clone());
+                        friend.relay_address = friend
+                            .relay_address
+                            .take()
+                            .or(contact.relay_address.clone());
+                        friend.nickname = friend.nickname.take().or(contact.nickname.clone());
+                        friend.notes = friend.notes.take().or(contact.notes.clone());
+                        report.updated += 1;
+                        friend.id
+                    }
+                    (None, _) => {
+                        let id = s.next_id();
+                        let mut friend =
+                            new_friend(id, &contact.username, &contact.address, status);
+                        friend.peer_key = contact.peer_key.clone();
+                        friend.fingerprint = contact.fingerprint.clone();
+                        friend.relay_address = contact.relay_address.clone();
+                        friend.nickname = contact.nickname.clone();
+                        friend.notes = contact.notes.clone();
+                        s.friends.push((friend, sent));
+                        report.imported += 1;
+                        reset = resend;
+                        id
+                    }
+                };
+
+                let labels = s.labels.entry(friend_id).or_default();
+                for label in &contact.labels {
+                    if !labels.contains(label) {
+                        labels.push(label.clone());
+                    }
+                }
+
+                if reset {
+                    if let Some((friend, _)) = s.friend_by_id(friend_id) {
+                        friend.added_at = Some(now());
+                    }
+                    report.reinvited += 1;
+                }
+            }
+            report
+        }))
+    }
+
+    async fn expire_stale_invites(&self, max_age: Duration) -> Result<u64> {
+        let cutoff = now() - chrono::Duration::from_std(max_age).unwrap_or(chrono::Duration::MAX);
+        Ok(self.with(|s| {
+            let mut expired = 0;
+            for (friend, sent) in s.friends.iter_mut() {
+                if friend.status == 0 && friend.added_at.is_some_and(|at| at < cutoff) {
+                    friend.status = 4;
+                    *sent = true;
+                    expired += 1;
+                }
+            }
+            expired
+        }))
+    }
+
+    async fn receive_invite(&self, invite: &IncomingInvite<'_>) -> Result<InviteOutcome> {
+        Ok(self.with(|s| {
+            let auto_accept = invite
+                .invite_token
+                .is_some_and(|token| s.invite_tokens.contains(token));
+            let (status, sent) = if auto_accept { (2, false) } else { (1, true) };
+            let outcome = if auto_accept {
+                InviteOutcome::Accepted
+            } else {
+                InviteOutcome::Received
+            };
+            if let Some((friend, was_sent)) = s.friend_mut(invite.username) {
+                if !matches!(friend.status, 3 | 4) {
+                    return InviteOutcome::Ignored;
+                }
+                friend.address = invite.address.to_string();
+                friend.status = status;
+                friend.added_at = Some(now());
+                friend.peer_key = invite
+                    .peer_key
+                    .map(str::to_string)
+                    .or(friend.peer_key.take());
+                friend.intro = invite.intro.map(str::to_string);
+                *was_sent = sent;
+            } else {
+                let id = s.next_id();
+                let mut friend = new_friend(id, invite.username, invite.address, status);
+                friend.peer_key = invite.peer_key.map(str::to_string);
+                friend.intro = invite.intro.map(str::to_string);
+                s.friends.push((friend, sent));
+            }
+            // tokens are only used up by an invite that was stored
+            if let Some(token) = invite.invite_token.filter(|_| auto_accept) {
+                s.invite_tokens.remove(token);
+            }
+            outcome
+        }))
+    }
+
+    async fn fetch_invite_state(
+        &self,
+        username: &str,
+        address: &str,
+    ) -> Result<Option<(i64, Option<String>)>> {
+        Ok(self.with(|s| {
+            s.friends
+                .iter()
+                .find(|(f, _)| f.username == username && f.address == address)
+                .map(|(f, _)| (f.status, f.fingerprint.clone()))
+        }))
+    }
+
+    async fn accept_invite(
+        &self,
+        username: &str,
+        address: &str,
+        peer_key: Option<&str>,
+    ) -> Result<()> {
+        self.with(|s| {
+            if let Some((friend, _)) = s.friend_mut(username) {
+                friend.status = 2;
+                friend.added_at = Some(now());
+                friend.peer_key = peer_key.map(str::to_string).or(friend.peer_key.take());
+                return;
+            }
+            let id = s.next_id();
+            let mut friend = new_friend(id, username, address, 2);
+            friend.peer_key = peer_key.map(str::to_string);
+            s.friends.push((friend, true));
+        });
+        Ok(())
+    }
+
+    async fn reject_invite(&self, username: &str, address: &str) -> Result<bool> {
+        Ok(self.with(|s| {
+            match s
+                .friends
+                .iter_mut()
+                .find(|(f, _)| f.username == username && f.address == address && f.status == 0)
+            {
+                Some((friend, sent)) => {
+                    friend.status = 3;
+                    friend.added_at = Some(now());
+                    *sent = true;
+                    true
+                }
+                None => false,
+            }
+        }))
+    }
+
+    async fn is_blocked(&self, peer: &Peer<'_>) -> Result<bool> {
+        Ok(self.with(|s| s.blocks.iter().any(|b| b.matches(peer))))
+    }
+
+    async fn fetch_blocks(&self) -> Result<Vec<Blocked>> {
+        Ok(self.with(|s| s.blocks.clone()))
+    }
+
+    async fn add_block(&self, kind: BlockKind, value: &str) -> Result<()> {
+        self.with(|s| s.add_block(kind, value));
+        Ok(())
+    }
+
+    async fn remove_block(&self, id: i64) -> Result<()> {
+        self.with(|s| s.blocks.retain(|b| b.id != id));
+        Ok(())
+    }
+
+    async fn block_friend(&self, friend: &Friend) -> Result<()> {
+        self.with(|s| {
+            s.add_block(BlockKind::Username, &friend.username);
+            if let Some(key) = &friend.peer_key {
+                s.add_block(BlockKind::PeerKey, key);
+            }
+            s.remove_friend(friend.id);
+        });
+        Ok(())
+    }
+
+    async fn create_invite_token(&self) -> Result<String> {
+        let token = db::generate_invite_token();
+        self.with(|s| s.invite_tokens.insert(token.clone()));
+        Ok(token)
+    }
+
+    async fn fetch_peer_health(&self) -> Result<Vec<PeerHealth>> {
+        Ok(self.with(|s| {
+            let mut health: Vec<PeerHealth> = s.health.values().cloned().collect();
+            health.sort_by(|a, b| a.username.cmp(&b.username));
+            health
+        }))
+    }
+
+    async fn record_peer_success(&self, username: &str) -> Result<()> {
+        self.with(|s| {
+            let health = s
+                .health
+                .entry(username.to_string())
+                .or_insert_with(|| empty_health(username));
+            health.consecutive_failures = 0;
+            health.last_success_at = Some(now());
+            health.next_attempt_at = None;
+        });
+        Ok(())
+    }
+
+    async fn record_peer_failure(&self, username: &str, error: &str) -> Result<i64> {
+        Ok(self.with(|s| {
+            let health = s
+                .health
+                .entry(username.to_string())
+                .or_insert_with(|| empty_health(username));
+            health.consecutive_failures += 1;
+            health.last_error = Some(error.to_string());
+            health.last_failure_at = Some(now());
+            health.consecutive_failures
+        }))
+    }
+
+    async fn schedule_peer_retry(
+        &self,
+        username: &str,
+        next_attempt_at: NaiveDateTime,
+    ) -> Result<()> {
+        self.with(|s| {
+            if let Some(health) = s.health.get_mut(username) {
+                health.next_attempt_at = Some(next_attempt_at);
+            }
+        });
+        Ok(())
+    }
+}
+
+fn empty_health(username: &str) -> PeerHealth {
+    PeerHealth {
+        username: username.to_string(),
+        consecutive_failures: 0,
+        last_error: None,
+        last_success_at: None,
+        last_failure_at: None,
+        next_attempt_at: None,
+    }
+}
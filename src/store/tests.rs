@@ -0,0 +1,514 @@
+use super::*;
+use crate::api::app;
+use crate::comms::{ProtocolVersions, process_friend_messages, send_friend_request};
+use crate::contacts::{Contact, EXPORT_VERSION, export_contacts};
+use crate::db::{MIGRATOR, setup_db};
+use crate::events::EventBus;
+use crate::transport::InMemoryTransport;
+use chrono::NaiveDate;
+
+async fn sqlite_store(username: &str, address: &str) -> SqlitePool {
+    let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+    MIGRATOR.run(&pool).await.unwrap();
+    let user = User {
+        id: 0,
+        username: username.to_string(),
+        address: address.to_string(),
+        peer_key: None,
+    };
+    setup_db(&pool, &user).await.unwrap();
+    pool
+}
+
+async fn sqlite_node(transport: &InMemoryTransport, username: &str, address: &str) -> SqlitePool {
+    let pool = sqlite_store(username, address).await;
+    transport.register(address, app(pool.clone(), EventBus::new()));
+    pool
+}
+
+async fn deliver_friend_updates<S: Store>(store: &S, transport: &InMemoryTransport) {
+    let (me, pending) = store.fetch_unsent_friend_updates().await.unwrap();
+    let identity = store.local_identity().await.unwrap();
+    for friend in pending {
+        send_friend_request(
+            store,
+            transport,
+            &ProtocolVersions::new(),
+            &me.username,
+            &friend,
+            &me.address,
+            &identity,
+        )
+        .await
+        .unwrap();
+    }
+}
+
+async fn friend_named<S: Store>(store: &S, username: &str) -> Friend {
+    store
+        .fetch_friends()
+        .await
+        .unwrap()
+        .into_iter()
+        .find(|f| f.username == username)
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_memory_store_node_talks_to_sqlite_node() {
+    let transport = InMemoryTransport::new();
+    let alice = MemoryStore::new("alice", "alice.test:8080");
+    transport.register("alice.test:8080", app(alice.clone(), EventBus::new()));
+    let bob = sqlite_node(&transport, "bob", "bob.test:8080").await;
+
+    let invite = FriendRequest {
+        username: "bob".into(),
+        address: "bob.test:8080".into(),
+        intro: Some("hi bob".into()),
+        fingerprint: None,
+        invite_token: None,
+    };
+    alice.send_invite(&invite).await.unwrap();
+    deliver_friend_updates(&alice, &transport).await;
+
+    let incoming = friend_named(&bob, "alice").await;
+    assert_eq!(incoming.status, 1);
+    bob.invite_decision(incoming.id, true).await.unwrap();
+    deliver_friend_updates(&bob, &transport).await;
+    assert_eq!(friend_named(&alice, "bob").await.status, 2);
+    assert!(
+        alice
+            .fetch_unsent_friend_updates()
+            .await
+            .unwrap()
+            .1
+            .is_empty()
+    );
+
+    let message = OutgoingMessage {
+        send_to: "bob".into(),
+        subject: "lunch".into(),
+        content: "noon?".into(),
+    };
+    alice.queue_message(&message).await.unwrap();
+
+    let alice_seen_by_bob = friend_named(&bob, "alice").await;
+    process_friend_messages(
+        &bob,
+        &transport,
+        &ProtocolVersions::new(),
+        &EventBus::new(),
+        "bob",
+        "bob.test:8080",
+        &alice_seen_by_bob,
+    )
+    .await
+    .unwrap();
+    let inbox = bob.fetch_inbox().await.unwrap();
+    assert_eq!(inbox.len(), 1);
+    assert_eq!(inbox[0].message, "noon?");
+    assert!(alice.pending_messages_for("bob").await.unwrap().is_empty());
+}
+
+fn invite<'a>(username: &'a str, token: &'a str) -> IncomingInvite<'a> {
+    IncomingInvite {
+        username,
+        address: "x.test:8080",
+        peer_key: None,
+        intro: None,
+        invite_token: Some(token),
+    }
+}
+
+// Both stores go through the same checks, each on a fresh store, so
+// MemoryStore can't quietly drift away from what SQLite does
+async fn check_store<S: Store, F: Future<Output = S>>(new_store: impl Fn() -> F) {
+    check_queueing(&new_store().await).await;
+    check_recipients(&new_store().await).await;
+    check_labels(&new_store().await).await;
+    check_invites(&new_store().await).await;
+    check_invite_tokens(&new_store().await).await;
+    check_contact_import(&new_store().await).await;
+    check_blocks(&new_store().await).await;
+    check_relay_mail(&new_store().await).await;
+    check_peer_health(&new_store().await).await;
+}
+
+#[tokio::test]
+async fn test_sqlite_store_behaviour() {
+    check_store(|| sqlite_store("alice", "alice.test:8080")).await;
+}
+
+#[tokio::test]
+async fn test_memory_store_behaviour() {
+    check_store(|| async { MemoryStore::new("alice", "alice.test:8080") }).await;
+}
+
+fn contact(username: &str, status: &str) -> Contact {
+    Contact {
+        username: username.to_string(),
+        address: format!("{}.test:8080", username),
+        status: status.to_string(),
+        peer_key: None,
+        fingerprint: None,
+        relay_address: None,
+        nickname: None,
+        notes: None,
+        labels: Vec::new(),
+    }
+}
+
+async fn import<S: Store>(
+    store: &S,
+    contacts: Vec<Contact>,
+    policy: ConflictPolicy,
+) -> ImportReport {
+    let export = ContactExport {
+        version: EXPORT_VERSION,
+        contacts,
+    };
+    store.import_contacts(&export, policy, true).await.unwrap()
+}
+
+fn message_to(send_to: &str) -> OutgoingMessage {
+    OutgoingMessage {
+        send_to: send_to.into(),
+        subject: "hi".into(),
+        content: "hello".into(),
+    }
+}
+
+fn relay_mail(digest: &str, body: &str) -> (String, Message) {
+    let message = Message {
+        sender: "bob".into(),
+        subject: "hi".into(),
+        body: body.into(),
+    };
+    (digest.to_string(), message)
+}
+
+async fn check_queueing<S: Store>(store: &S) {
+    let error = store
+        .queue_message(&message_to("nobody"))
+        .await
+        .unwrap_err();
+    assert!(matches!(error, Error::NotFound(_)));
+
+    import(
+        store,
+        vec![contact("bob", "accepted")],
+        ConflictPolicy::Skip,
+    )
+    .await;
+    store.queue_message(&message_to("bob")).await.unwrap();
+    store.queue_message(&message_to("bob")).await.unwrap();
+    assert_eq!(store.outgoing_queue_depth().await.unwrap(), 2);
+
+    let pending = store.pending_messages_for("bob").await.unwrap();
+    assert_eq!(pending.len(), 2);
+    assert_eq!(pending[0].sender, "alice");
+    assert_eq!(pending[0].recipient_address, "bob.test:8080");
+
+    store.mark_messages_sent(&[]).await.unwrap();
+    store.mark_messages_sent(&[pending[0].id]).await.unwrap();
+    assert_eq!(store.pending_messages_for("bob").await.unwrap().len(), 1);
+    assert_eq!(store.outgoing_queue_depth().await.unwrap(), 1);
+    assert_eq!(store.fetch_outgoing().await.unwrap().len(), 2);
+}
+
+async fn check_recipients<S: Store>(store: &S) {
+    let mut carol = contact("carol", "accepted");
+    carol.nickname = Some("cee".into());
+    // imports can bring in nicknames that clash
+    let mut dave = contact("dave", "accepted");
+    dave.nickname = Some("dee".into());
+    let mut erin = contact("erin", "accepted");
+    erin.nickname = Some("dee".into());
+    let mut frank = contact("frank", "accepted");
+    frank.nickname = Some("carol".into());
+    import(store, vec![carol, dave, erin, frank], ConflictPolicy::Skip).await;
+
+    // usernames win over nicknames, unknown names pass through
+    assert_eq!(store.resolve_recipient("carol").await.unwrap(), "carol");
+    assert_eq!(store.resolve_recipient("cee").await.unwrap(), "carol");
+    assert_eq!(store.resolve_recipient("nobody").await.unwrap(), "nobody");
+    let error = store.resolve_recipient("dee").await.unwrap_err();
+    assert!(matches!(error, Error::Validation(_)));
+
+    let carol = friend_named(store, "carol").await;
+    for taken in ["dave", "dee"] {
+        let error = store
+            .set_friend_details(carol.id, Some(taken), None)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, Error::Validation(_)));
+    }
+    store
+        .set_friend_details(carol.id, Some("cee"), Some("met at work"))
+        .await
+        .unwrap();
+    store
+        .set_friend_relay(carol.id, Some("relay.test:9000"))
+        .await
+        .unwrap();
+    let carol = friend_named(store, "carol").await;
+    assert_eq!(carol.notes.as_deref(), Some("met at work"));
+    assert_eq!(carol.relay_address.as_deref(), Some("relay.test:9000"));
+}
+
+async fn check_labels<S: Store>(store: &S) {
+    let friends = vec![
+        contact("carol", "accepted"),
+        contact("bob", "accepted"),
+        contact("dave", "invite_sent"),
+    ];
+    import(store, friends, ConflictPolicy::Skip).await;
+    let bob = friend_named(store, "bob").await;
+    let carol = friend_named(store, "carol").await;
+    let dave = friend_named(store, "dave").await;
+
+    let labels = |names: &[&str]| names.iter().map(|l| l.to_string()).collect::<Vec<_>>();
+    store
+        .set_friend_labels(bob.id, &labels(&["work", "family", "work"]))
+        .await
+        .unwrap();
+    store
+        .set_friend_labels(carol.id, &labels(&["work"]))
+        .await
+        .unwrap();
+    store
+        .set_friend_labels(dave.id, &labels(&["work"]))
+        .await
+        .unwrap();
+    let stored = store.fetch_friend_labels().await.unwrap();
+    assert_eq!(stored[&bob.id], labels(&["family", "work"]));
+
+    // only accepted friends get label broadcasts
+    let recipients = store
+        .queue_message_to_label("work", "hi", "hello")
+        .await
+        .unwrap();
+    assert_eq!(recipients, ["bob", "carol"]);
+    assert_eq!(store.outgoing_queue_depth().await.unwrap(), 2);
+    let recipients = store
+        .queue_message_to_label("nobody", "hi", "hello")
+        .await
+        .unwrap();
+    assert!(recipients.is_empty());
+
+    store.delete_friend(bob.id).await.unwrap();
+    assert!(
+        !store
+            .fetch_friend_labels()
+            .await
+            .unwrap()
+            .contains_key(&bob.id)
+    );
+}
+
+async fn check_invites<S: Store>(store: &S) {
+    let request = FriendRequest {
+        username: "bob".into(),
+        address: "bob.test:8080".into(),
+        intro: Some("hi bob".into()),
+        fingerprint: None,
+        invite_token: None,
+    };
+    store.send_invite(&request).await.unwrap();
+    assert!(store.send_invite(&request).await.is_err());
+    let bob = friend_named(store, "bob").await;
+    assert_eq!(bob.status, 0);
+
+    // only invites we received can be answered
+    let error = store.invite_decision(bob.id, true).await.unwrap_err();
+    assert!(matches!(error, Error::NotFound(_)));
+
+    let mut carol = invite("carol", "");
+    carol.invite_token = None;
+    let outcome = store.receive_invite(&carol).await.unwrap();
+    assert_eq!(outcome, InviteOutcome::Received);
+    let carol = friend_named(store, "carol").await;
+    assert_eq!(carol.status, 1);
+    store.invite_decision(carol.id, false).await.unwrap();
+    assert_eq!(friend_named(store, "carol").await.status, 3);
+    assert!(store.invite_decision(carol.id, true).await.is_err());
+
+    // rejected invites can start over, friends can't
+    assert!(store.reject_invite("bob", "bob.test:8080").await.unwrap());
+    assert_eq!(friend_named(store, "bob").await.status, 3);
+    assert!(store.reinvite(bob.id).await.unwrap());
+    assert_eq!(friend_named(store, "bob").await.status, 0);
+    store
+        .accept_invite("bob", "bob.test:8080", Some("bobkey"))
+        .await
+        .unwrap();
+    let bob = friend_named(store, "bob").await;
+    assert_eq!((bob.status, bob.peer_key.as_deref()), (2, Some("bobkey")));
+    assert!(!store.reinvite(bob.id).await.unwrap());
+}
+
+async fn check_invite_tokens<S: Store>(store: &S) {
+    // a token accepts once, and only when the invite is stored
+    let token = store.create_invite_token().await.unwrap();
+    let outcome = store.receive_invite(&invite("bob", &token)).await.unwrap();
+    assert_eq!(outcome, InviteOutcome::Accepted);
+    let outcome = store
+        .receive_invite(&invite("carol", &token))
+        .await
+        .unwrap();
+    assert_eq!(outcome, InviteOutcome::Received);
+
+    let token = store.create_invite_token().await.unwrap();
+    let outcome = store.receive_invite(&invite("bob", &token)).await.unwrap();
+    assert_eq!(outcome, InviteOutcome::Ignored);
+    let outcome = store.receive_invite(&invite("dave", &token)).await.unwrap();
+    assert_eq!(outcome, InviteOutcome::Accepted);
+    assert_eq!(friend_named(store, "dave").await.status, 2);
+
+    let outcome = store
+        .receive_invite(&invite("erin", "made-up"))
+        .await
+        .unwrap();
+    assert_eq!(outcome, InviteOutcome::Received);
+}
+
+async fn check_contact_import<S: Store>(store: &S) {
+    let mut bob = contact("bob", "accepted");
+    bob.nickname = Some("b".into());
+    bob.labels = vec!["work".into()];
+    let friends = vec![
+        bob,
+        contact("carol", "invite_sent"),
+        contact("dave", "bogus"),
+    ];
+    let report = import(store, friends, ConflictPolicy::Skip).await;
+    assert_eq!(
+        report,
+        ImportReport {
+            imported: 2,
+            skipped: 1,
+            updated: 0,
+            reinvited: 1,
+        }
+    );
+    let (_, pending) = store.fetch_unsent_friend_updates().await.unwrap();
+    assert_eq!(pending.len(), 1);
+    assert_eq!(pending[0].username, "carol");
+
+    // merging keeps what we have and fills in the rest
+    let mut bob = contact("bob", "rejected");
+    bob.nickname = Some("bobby".into());
+    bob.notes = Some("met at work".into());
+    bob.labels = vec!["family".into()];
+    let report = import(store, vec![bob.clone()], ConflictPolicy::Merge).await;
+    assert_eq!(report.updated, 1);
+    let merged = friend_named(store, "bob").await;
+    assert_eq!(merged.status, 2);
+    assert_eq!(merged.nickname.as_deref(), Some("b"));
+    assert_eq!(merged.notes.as_deref(), Some("met at work"));
+    let labels = store.fetch_friend_labels().await.unwrap();
+    assert_eq!(labels[&merged.id], ["family", "work"]);
+
+    // overwriting replaces the row and its labels
+    let report = import(store, vec![bob], ConflictPolicy::Overwrite).await;
+    assert_eq!(report.updated, 1);
+    let overwritten = friend_named(store, "bob").await;
+    assert_eq!(overwritten.status, 3);
+    assert_eq!(overwritten.nickname.as_deref(), Some("bobby"));
+    let labels = store.fetch_friend_labels().await.unwrap();
+    assert_eq!(labels[&overwritten.id], ["family"]);
+
+    let export = export_contacts(store).await.unwrap();
+    let bob = export
+        .contacts
+        .iter()
+        .find(|c| c.username == "bob")
+        .unwrap();
+    assert_eq!((bob.status.as_str(), bob.labels.len()), ("rejected", 1));
+}
+
+async fn check_blocks<S: Store>(store: &S) {
+    store
+        .add_block(BlockKind::Username, "mallory")
+        .await
+        .unwrap();
+    store
+        .add_block(BlockKind::Username, "mallory")
+        .await
+        .unwrap();
+    let blocks = store.fetch_blocks().await.unwrap();
+    assert_eq!(blocks.len(), 1);
+
+    let mallory = Peer {
+        username: "mallory",
+        peer_key: None,
+        address: Some("mallory.test:8080"),
+        ip: None,
+    };
+    assert!(store.is_blocked(&mallory).await.unwrap());
+    store.remove_block(blocks[0].id).await.unwrap();
+    assert!(!store.is_blocked(&mallory).await.unwrap());
+
+    // blocking a friend also blocks their key and drops them
+    let mut bob = contact("bob", "accepted");
+    bob.peer_key = Some("bobkey".into());
+    import(store, vec![bob], ConflictPolicy::Skip).await;
+    let bob = friend_named(store, "bob").await;
+    store.block_friend(&bob).await.unwrap();
+    assert_eq!(store.fetch_blocks().await.unwrap().len(), 2);
+    assert!(store.fetch_friends().await.unwrap().is_empty());
+    let renamed = Peer {
+        username: "robert",
+        peer_key: Some("bobkey"),
+        address: None,
+        ip: None,
+    };
+    assert!(store.is_blocked(&renamed).await.unwrap());
+}
+
+async fn check_relay_mail<S: Store>(store: &S) {
+    let mail = vec![relay_mail("d1", "one"), relay_mail("d2", "two")];
+    assert_eq!(store.ingest_relay_mail(mail).await.unwrap().len(), 2);
+
+    // mail we took in before is skipped
+    let mail = vec![relay_mail("d1", "one"), relay_mail("d3", "three")];
+    let fresh = store.ingest_relay_mail(mail).await.unwrap();
+    assert_eq!(fresh.len(), 1);
+    assert_eq!(fresh[0].body, "three");
+
+    let inbox = store.fetch_inbox().await.unwrap();
+    assert_eq!(inbox.len(), 3);
+    store.delete_message(inbox[0].id).await.unwrap();
+    let (_, direct) = relay_mail("d1", "direct");
+    store.ingest_messages(vec![direct]).await.unwrap();
+    assert_eq!(store.fetch_inbox().await.unwrap().len(), 3);
+}
+
+async fn check_peer_health<S: Store>(store: &S) {
+    import(
+        store,
+        vec![contact("bob", "accepted")],
+        ConflictPolicy::Skip,
+    )
+    .await;
+    assert_eq!(store.record_peer_failure("bob", "down").await.unwrap(), 1);
+    assert_eq!(store.record_peer_failure("bob", "down").await.unwrap(), 2);
+
+    let retry_at = NaiveDate::from_ymd_opt(2030, 1, 1)
+        .unwrap()
+        .and_hms_opt(12, 0, 0)
+        .unwrap();
+    store.schedule_peer_retry("bob", retry_at).await.unwrap();
+    let health = store.fetch_peer_health().await.unwrap();
+    assert_eq!(health[0].last_error.as_deref(), Some("down"));
+    assert_eq!(health[0].next_attempt_at, Some(retry_at));
+
+    store.record_peer_success("bob").await.unwrap();
+    let health = store.fetch_peer_health().await.unwrap();
+    assert_eq!(health[0].consecutive_failures, 0);
+    assert_eq!(health[0].next_attempt_at, None);
+
+    let bob = friend_named(store, "bob").await;
+    store.delete_friend(bob.id).await.unwrap();
+    assert!(store.fetch_peer_health().await.unwrap().is_empty());
+}
@@ -0,0 +1,217 @@
+// Peer transport
+// The fetchers only ever do three things with a peer: ask which protocol
+// versions it speaks, pull the mail it has queued for us and hand it a
+// friend request. Transport abstracts those so nodes can talk over HTTP, or
+// be wired together inside one process.
+
+use crate::api::{FetchMessageInput, FetchMessageResponse, FriendInput, NodeInfo, versioned_path};
+use crate::config::Config;
+use crate::error::{Error, Result};
+use axum::Router;
+use axum::body::{Body, to_bytes};
+use axum::http::{Request, header};
+use reqwest::Client;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tower::ServiceExt;
+
+#[cfg(test)]
+mod tests;
+
+pub trait Transport: Send + Sync {
+    // GET /info, nodes from before it existed answer 404
+    fn info(&self, address: &str) -> impl Future<Output = Result<NodeInfo>> + Send;
+
+    // Messages `address` has queued for us, the peer marks them sent
+    fn fetch_messages(
+        &self,
+        address: &str,
+        version: u32,
+        input: &FetchMessageInput,
+    ) -> impl Future<Output = Result<FetchMessageResponse>> + Send;
+
+    // Delivers an invite, acceptance or rejection to `address`
+    fn friend_request(
+        &self,
+        address: &str,
+        version: u32,
+        input: &FriendInput,
+    ) -> impl Future<Output = Result<()>> + Send;
+}
+
+// The real thing: JSON over HTTP to http://<address>/...
+#[derive(Debug, Clone, Default)]
+pub struct HttpTransport {
+    client: Client,
+}
+
+impl HttpTransport {
+    pub fn new(client: Client) -> Self {
+        HttpTransport { client }
+    }
+}
+
+// Client for peers and relays. A peer that takes the connection and then
+// hangs times out like one that is down, instead of stalling a fetch cycle.
+pub fn http_client(config: &Config) -> Client {
+    Client::builder()
+        .connect_timeout(Duration::from_secs(config.connect_timeout))
+        .timeout(Duration::from_secs(config.request_timeout))
+        .build()
+        .expect("HTTP client with timeouts builds")
+}
+
+impl Transport for HttpTransport {
+    async fn info(&self, address: &str) -> Result<NodeInfo> {
+        let res = self
+            .client
+            .get(format!("http://{}/info", address))
+            .send()
+            .await?;
+
+        if !res.status().is_success() {
+            return Err(Error::from_status(res.status()));
+        }
+        Ok(res.json::<NodeInfo>().await?)
+    }
+
+    async fn fetch_messages(
+        &self,
+        address: &str,
+        version: u32,
+        input: &FetchMessageInput,
+    ) -> Result<FetchMessageResponse> {
+        let res = self
+            .client
+            .post(format!(
+                "http://{}{}",
+                address,
+                versioned_path(version, "fetch_messages")
+            ))
+            .json(input)
+            .send()
+            .await?;
+
+        if !res.status().is_success() {
+            return Err(Error::from_status(res.status()));
+        }
+        Ok(res.json::<FetchMessageResponse>().await?)
+    }
+
+    async fn friend_request(&self, address: &str, version: u32, input: &FriendInput) -> Result<()> {
+        let res = self
+            .client
+            .post(format!(
+                "http://{}{}",
+                address,
+                versioned_path(version, "friend_request")
+            ))
+            .json(input)
+            .send()
+            .await?;
+
+        if !res.status().is_success() {
+            return Err(Error::from_status(res.status()));
+        }
+        Ok(())
+    }
+}
+
+// Routes requests straight into the api Router of nodes registered under an
+// address, no sockets involved. Clones share the same set of nodes.
+#[derive(Clone, Default)]
+pub struct InMemoryTransport {
+    nodes: Arc<RwLock<HashMap<String, Router>>>,
+}
+
+impl InMemoryTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Makes `router` (usually api::app) reachable at `address`
+    pub fn register(&self, address: &str, router: Router) {
+        self.nodes
+            .write()
+            .unwrap()
+            .insert(address.to_string(), router);
+    }
+
+    // Takes a node offline, requests to it fail like a refused connection
+    pub fn unregister(&self, address: &str) {
+        self.nodes.write().unwrap().remove(address);
+    }
+
+    async fn send<O: DeserializeOwned>(
+        &self,
+        address: &str,
+        method: &str,
+        path: &str,
+        body: Body,
+    ) -> Result<O> {
+        let router = self
+            .nodes
+            .read()
+            .unwrap()
+            .get(address)
+            .cloned()
+            .ok_or_else(|| Error::Network(format!("no node listening at {}", address)))?;
+
+        let request = Request::builder()
+            .method(method)
+            .uri(path)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .map_err(|e| Error::protocol(e.to_string()))?;
+
+        let response = router
+            .oneshot(request)
+            .await
+            .map_err(|e| Error::Network(e.to_string()))?;
+        if !response.status().is_success() {
+            return Err(Error::from_status(response.status()));
+        }
+
+        let body = to_bytes(response.into_body(), usize::MAX)
+            .await
+            .map_err(|e| Error::Network(e.to_string()))?;
+        serde_json::from_slice(&body).map_err(|e| Error::protocol(format!("Parse error: {}", e)))
+    }
+
+    async fn post<I: Serialize, O: DeserializeOwned>(
+        &self,
+        address: &str,
+        path: &str,
+        input: &I,
+    ) -> Result<O> {
+        let body = Body::from(serde_json::to_vec(input)?);
+        self.send(address, "POST", path, body).await
+    }
+}
+
+impl Transport for InMemoryTransport {
+    async fn info(&self, address: &str) -> Result<NodeInfo> {
+        self.send(address, "GET", "/info", Body::empty()).await
+    }
+
+    async fn fetch_messages(
+        &self,
+        address: &str,
+        version: u32,
+        input: &FetchMessageInput,
+    ) -> Result<FetchMessageResponse> {
+        let path = versioned_path(version, "fetch_messages");
+        self.post(address, &path, input).await
+    }
+
+    async fn friend_request(&self, address: &str, version: u32, input: &FriendInput) -> Result<()> {
+        let path = versioned_path(version, "friend_request");
+        self.post::<_, serde_json::Value>(address, &path, input)
+            .await
+            .map(|_| ())
+    }
+}
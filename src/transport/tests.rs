@@ -0,0 +1,143 @@
+use super::*;
+use crate::api::app;
+use crate::comms::{ProtocolVersions, process_friend_messages, send_friend_request};
+use crate::db::{
+    FriendRequest, MIGRATOR, OutgoingMessage, User, ensure_identity, fetch_inbox,
+    fetch_unsent_friend_updt, fetch_users, invite_decision, send_invite, send_message_to_que,
+    setup_db,
+};
+use crate::events::EventBus;
+use sqlx::SqlitePool;
+
+async fn node(transport: &InMemoryTransport, username: &str, address: &str) -> SqlitePool {
+    let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+    MIGRATOR.run(&pool).await.unwrap();
+    let user = User {
+        id: 0,
+        username: username.to_string(),
+        address: address.to_string(),
+        peer_key: None,
+    };
+    setup_db(&pool, &user).await.unwrap();
+    transport.register(address, app(pool.clone(), EventBus::new()));
+    pool
+}
+
+// What one friend fetcher cycle does, minus the health bookkeeping
+async fn deliver_friend_updates(pool: &SqlitePool, transport: &InMemoryTransport) {
+    let (me, pending) = fetch_unsent_friend_updt(pool).await.unwrap();
+    let identity = ensure_identity(pool).await.unwrap();
+    for friend in pending {
+        send_friend_request(
+            pool,
+            transport,
+            &ProtocolVersions::new(),
+            &me.username,
+            &friend,
+            &me.address,
+            &identity,
+        )
+        .await
+        .unwrap();
+    }
+}
+
+async fn friend_named(pool: &SqlitePool, username: &str) -> crate::db::Friend {
+    fetch_users(pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .find(|f| f.username == username)
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_nodes_befriend_and_message_in_memory() {
+    let transport = InMemoryTransport::new();
+    let alice = node(&transport, "alice", "alice.test:8080").await;
+    let bob = node(&transport, "bob", "bob.test:8080").await;
+
+    let invite = FriendRequest {
+        username: "bob".into(),
+        address: "bob.test:8080".into(),
+        intro: Some("hi bob".into()),
+        fingerprint: None,
+        invite_token: None,
+    };
+    send_invite(&alice, &invite).await.unwrap();
+    deliver_friend_updates(&alice, &transport).await;
+
+    let incoming = friend_named(&bob, "alice").await;
+    assert_eq!(incoming.status, 1);
+    assert_eq!(incoming.intro.as_deref(), Some("hi bob"));
+
+    invite_decision(&bob, incoming.id, true).await.unwrap();
+    deliver_friend_updates(&bob, &transport).await;
+    assert_eq!(friend_named(&alice, "bob").await.status, 2);
+
+    let message = OutgoingMessage {
+        send_to: "bob".into(),
+        subject: "lunch".into(),
+        content: "noon?".into(),
+    };
+    send_message_to_que(&alice, &message).await.unwrap();
+
+    let alice_seen_by_bob = friend_named(&bob, "alice").await;
+    let versions = ProtocolVersions::new();
+    process_friend_messages(
+        &bob,
+        &transport,
+        &versions,
+        &EventBus::new(),
+        "bob",
+        "bob.test:8080",
+        &alice_seen_by_bob,
+    )
+    .await
+    .unwrap();
+    let inbox = fetch_inbox(&bob).await.unwrap();
+    assert_eq!(inbox.len(), 1);
+    assert_eq!(inbox[0].sender, "alice");
+    assert_eq!(inbox[0].message, "noon?");
+    assert_eq!(versions.get("alice.test:8080"), Some(1));
+
+    // an offline node looks like a refused connection
+    transport.unregister("alice.test:8080");
+    let error = process_friend_messages(
+        &bob,
+        &transport,
+        &versions,
+        &EventBus::new(),
+        "bob",
+        "bob.test:8080",
+        &alice_seen_by_bob,
+    )
+    .await
+    .unwrap_err();
+    assert!(matches!(error, Error::Network(_)));
+    assert_eq!(versions.get("alice.test:8080"), None);
+}
+
+#[tokio::test]
+async fn test_hanging_peer_times_out() {
+    // takes the connection and never answers
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let mut open = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            open.push(stream);
+        }
+    });
+
+    let config = crate::config::Config {
+        request_timeout: 1,
+        ..Default::default()
+    };
+    let transport = HttpTransport::new(http_client(&config));
+    let started = std::time::Instant::now();
+    let error = transport.info(&address).await.unwrap_err();
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    assert!(matches!(error, Error::Network(_)), "{:?}", error);
+    assert!(error.is_peer_failure());
+}
@@ -0,0 +1,808 @@
+// Full-screen terminal UI
+// Conversations on the left, the open conversation with a compose box in
+// the middle, friends on the right and a status bar with peer connectivity
+// at the bottom. The screen is redrawn from the database whenever the event
+// bus reports something, and every few seconds for peer health, so nothing
+// in the background writes over it.
+
+use crate::StatusLabel;
+use crate::api::FriendRequestStatus;
+use crate::db::{Friend, InboxMessage, Outgoing, OutgoingMessage, PeerHealth, User};
+use crate::error::Result;
+use crate::events::Event;
+use crate::store::{FriendStore, MessageStore};
+use crate::wakeup::Wakeup;
+use chrono::NaiveDateTime;
+use ratatui::Frame;
+use ratatui::crossterm::event::{self, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Position, Rect};
+use ratatui::style::{Color, Modifier, Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph, Wrap};
+use std::collections::HashMap;
+use std::io;
+use std::time::Duration;
+use tokio::sync::broadcast::{Receiver, error::RecvError};
+use tokio::sync::mpsc;
+
+#[cfg(test)]
+mod tests;
+
+// how often peer health is read again when no events come in
+const REFRESH_INTERVAL: Duration = Duration::from_secs(3);
+
+// Everything the screen shows, read from the store in one go
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    pub me: Option<User>,
+    pub friends: Vec<Friend>,
+    pub inbox: Vec<InboxMessage>,
+    pub outbound: Vec<Outgoing>,
+    pub health: Vec<PeerHealth>,
+}
+
+impl Snapshot {
+    pub async fn load<S: MessageStore + FriendStore>(store: &S) -> Result<Self> {
+        Ok(Snapshot {
+            me: Some(store.local_user().await?),
+            friends: store.fetch_friends().await?,
+            inbox: store.fetch_inbox().await?,
+            outbound: store.fetch_outgoing().await?,
+            health: store.fetch_peer_health().await?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Focus {
+    Conversations,
+    Messages,
+    Friends,
+    Compose,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Subject,
+    Body,
+}
+
+// What a key press asks of the store or the fetchers
+#[derive(Debug, PartialEq, Eq)]
+pub enum Action {
+    Quit,
+    Sync,
+    Send(OutgoingMessage),
+    Decide { id: i64, accept: bool },
+}
+
+// Whether we have heard from a peer lately
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Connectivity {
+    Online,
+    Retrying,
+    Offline,
+    Unknown,
+}
+
+impl Connectivity {
+    fn of(health: Option<&PeerHealth>, now: NaiveDateTime) -> Self {
+        match health {
+            None => Connectivity::Unknown,
+            Some(peer) if peer.consecutive_failures == 0 => match peer.last_success_at {
+                Some(_) => Connectivity::Online,
+                None => Connectivity::Unknown,
+            },
+            Some(peer) if peer.is_due(now) => Connectivity::Retrying,
+            Some(_) => Connectivity::Offline,
+        }
+    }
+
+    fn label(&self) -> (&'static str, Color) {
+        match self {
+            Connectivity::Online => ("online", Color::Green),
+            Connectivity::Retrying => ("retrying", Color::Yellow),
+            Connectivity::Offline => ("offline", Color::Red),
+            Connectivity::Unknown => ("-", Color::DarkGray),
+        }
+    }
+}
+
+// One line in the conversation list
+#[derive(Debug, Clone)]
+struct Conversation {
+    username: String,
+    name: String,
+    last: Option<NaiveDateTime>,
+}
+
+// One message of the open conversation, either direction
+#[derive(Debug, Clone)]
+struct Entry {
+    from_me: bool,
+    subject: String,
+    body: String,
+    at: Option<NaiveDateTime>,
+    delivered: bool,
+}
+
+#[derive(Debug)]
+pub struct App {
+    data: Snapshot,
+    focus: Focus,
+    conversations: Vec<Conversation>,
+    // username of the open conversation, kept when the list reorders
+    open: Option<String>,
+    thread: Vec<Entry>,
+    // None follows the newest message
+    message: Option<usize>,
+    friend: usize,
+    field: Field,
+    subject: String,
+    body: String,
+    unread: HashMap<String, usize>,
+    notice: Option<String>,
+}
+
+impl Default for App {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl App {
+    pub fn new() -> Self {
+        App {
+            data: Snapshot::default(),
+            focus: Focus::Conversations,
+            conversations: Vec::new(),
+            open: None,
+            thread: Vec::new(),
+            message: None,
+            friend: 0,
+            field: Field::Subject,
+            subject: String::new(),
+            body: String::new(),
+            unread: HashMap::new(),
+            notice: None,
+        }
+    }
+
+    pub fn focus(&self) -> Focus {
+        self.focus
+    }
+
+    pub fn open_conversation(&self) -> Option<&str> {
+        self.open.as_deref()
+    }
+
+    pub fn unread(&self, username: &str) -> usize {
+        self.unread.get(username).copied().unwrap_or(0)
+    }
+
+    pub fn notice(&self) -> Option<&str> {
+        self.notice.as_deref()
+    }
+
+    pub fn set_notice(&mut self, notice: impl Into<String>) {
+        self.notice = Some(notice.into());
+    }
+
+    // Takes fresh data, the open conversation and selections stay put
+    pub fn update(&mut self, data: Snapshot) {
+        self.data = data;
+
+        let mut last: HashMap<&str, Option<NaiveDateTime>> = HashMap::new();
+        for friend in &self.data.friends {
+            if friend.status.status_enum() == FriendRequestStatus::Accepted {
+                last.entry(&friend.username).or_default();
+            }
+        }
+        let times = self
+            .data
+            .inbox
+            .iter()
+            .map(|m| (m.sender.as_str(), m.received_at))
+            .chain(
+                self.data
+                    .outbound
+                    .iter()
+                    .map(|m| (m.recipient.as_str(), m.queued_at)),
+            );
+        for (username, at) in times {
+            let newest = last.entry(username).or_default();
+            *newest = (*newest).max(at);
+        }
+
+        let mut conversations: Vec<Conversation> = last
+            .into_iter()
+            .map(|(username, last)| Conversation {
+                username: username.to_string(),
+                name: self.display_name(username).to_string(),
+                last,
+            })
+            .collect();
+        // most recent first, then by name
+        conversations.sort_by(|a, b| b.last.cmp(&a.last).then_with(|| a.name.cmp(&b.name)));
+        self.conversations = conversations;
+
+        if self
+            .open
+            .as_ref()
+            .is_none_or(|open| !self.conversations.iter().any(|c| &c.username == open))
+        {
+            self.open = self.conversations.first().map(|c| c.username.clone());
+        }
+        self.friend = self.friend.min(self.data.friends.len().saturating_sub(1));
+        self.load_thread();
+    }
+
+    // Something happened in the background, say so in the status bar
+    pub fn on_event(&mut self, event: &Event) {
+        if let Event::NewMessage { sender, .. } = event
+            && self.open.as_deref() != Some(sender.as_str())
+        {
+            *self.unread.entry(sender.clone()).or_default() += 1;
+        }
+        self.notice = Some(event.to_string());
+    }
+
+    pub fn handle_key(&mut self, key: KeyEvent) -> Option<Action> {
+        if key.kind != KeyEventKind::Press {
+            return None;
+        }
+        if key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('c') {
+            return Some(Action::Quit);
+        }
+        if self.focus == Focus::Compose {
+            return self.compose_key(key);
+        }
+
+        match key.code {
+            KeyCode::Char('q') => return Some(Action::Quit),
+            KeyCode::Char('s') => return Some(Action::Sync),
+            KeyCode::Tab => self.focus = self.next_pane(1),
+            KeyCode::BackTab => self.focus = self.next_pane(-1),
+            KeyCode::Up | KeyCode::Char('k') => self.move_selection(-1),
+            KeyCode::Down | KeyCode::Char('j') => self.move_selection(1),
+            KeyCode::Char('c') => self.start_compose(),
+            KeyCode::Enter => match self.focus {
+                Focus::Conversations => self.focus = Focus::Messages,
+                Focus::Messages => self.start_compose(),
+                Focus::Friends => self.open_friend(),
+                Focus::Compose => {}
+            },
+            KeyCode::Char(c @ ('a' | 'r')) if self.focus == Focus::Friends => {
+                let friend = self.data.friends.get(self.friend)?;
+                if friend.status.status_enum() != FriendRequestStatus::InviteReceived {
+                    self.notice = Some(format!(
+                        "No invite from {} to answer",
+                        friend.display_name()
+                    ));
+                    return None;
+                }
+                return Some(Action::Decide {
+                    id: friend.id,
+                    accept: c == 'a',
+                });
+            }
+            _ => {}
+        }
+        None
+    }
+
+    fn compose_key(&mut self, key: KeyEvent) -> Option<Action> {
+        let field = match self.field {
+            Field::Subject => &mut self.subject,
+            Field::Body => &mut self.body,
+        };
+        match key.code {
+            KeyCode::Esc => self.focus = Focus::Messages,
+            KeyCode::Tab | KeyCode::BackTab => {
+                self.field = match self.field {
+                    Field::Subject => Field::Body,
+                    Field::Body => Field::Subject,
+                }
+            }
+            KeyCode::Backspace => {
+                field.pop();
+            }
+            KeyCode::Enter if self.field == Field::Subject => self.field = Field::Body,
+            KeyCode::Enter => {
+                let send_to = self.open.clone()?;
+                if self.body.trim().is_empty() {
+                    self.notice = Some("Nothing to send yet".into());
+                    return None;
+                }
+                let message = OutgoingMessage {
+                    send_to,
+                    subject: std::mem::take(&mut self.subject),
+                    content: std::mem::take(&mut self.body),
+                };
+                self.field = Field::Subject;
+                self.focus = Focus::Messages;
+                self.message = None;
+                return Some(Action::Send(message));
+            }
+            KeyCode::Char(c)
+                if !key
+                    .modifiers
+                    .intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) =>
+            {
+                field.push(c)
+            }
+            _ => {}
+        }
+        None
+    }
+
+    fn next_pane(&self, step: isize) -> Focus {
+        const PANES: [Focus; 3] = [Focus::Conversations, Focus::Messages, Focus::Friends];
+        let current = PANES.iter().position(|p| *p == self.focus).unwrap_or(0);
+        PANES[(current as isize + step).rem_euclid(PANES.len() as isize) as usize]
+    }
+
+    fn move_selection(&mut self, step: isize) {
+        match self.focus {
+            Focus::Conversations => {
+                let current = self.selected_conversation().unwrap_or(0);
+                if let Some(next) = step_index(current, step, self.conversations.len()) {
+                    self.select_conversation(next);
+                }
+            }
+            Focus::Messages => {
+                let current = self.message.unwrap_or(self.thread.len().saturating_sub(1));
+                self.message = step_index(current, step, self.thread.len())
+                    // moving down to the newest message follows new ones again
+                    .filter(|next| *next + 1 < self.thread.len());
+            }
+            Focus::Friends => {
+                if let Some(next) = step_index(self.friend, step, self.data.friends.len()) {
+                    self.friend = next;
+                }
+            }
+            Focus::Compose => {}
+        }
+    }
+
+    fn start_compose(&mut self) {
+        match &self.open {
+            Some(_) => self.focus = Focus::Compose,
+            None => self.notice = Some("Pick a conversation first".into()),
+        }
+    }
+
+    fn open_friend(&mut self) {
+        let Some(friend) = self.data.friends.get(self.friend) else {
+            return;
+        };
+        let username = friend.username.clone();
+        match self
+            .conversations
+            .iter()
+            .position(|c| c.username == username)
+        {
+            Some(index) => {
+                self.select_conversation(index);
+                self.focus = Focus::Messages;
+            }
+            None => {
+                self.notice = Some(format!(
+                    "{} is not a friend yet ({})",
+                    friend.display_name(),
+                    friend.status.status_str()
+                ))
+            }
+        }
+    }
+
+    fn selected_conversation(&self) -> Option<usize> {
+        let open = self.open.as_ref()?;
+        self.conversations.iter().position(|c| &c.username == open)
+    }
+
+    fn select_conversation(&mut self, index: usize) {
+        self.open = self.conversations.get(index).map(|c| c.username.clone());
+        self.message = None;
+        self.load_thread();
+    }
+
+    fn load_thread(&mut self) {
+        self.thread.clear();
+        let Some(open) = &self.open else { return };
+        self.unread.remove(open);
+
+        let received = self
+            .data
+            .inbox
+            .iter()
+            .filter(|m| &m.sender == open)
+            .map(|m| Entry {
+                from_me: false,
+                subject: m.subject.clone(),
+                body: m.message.clone(),
+                at: m.received_at,
+                delivered: true,
+            });
+        let sent = self
+            .data
+            .outbound
+            .iter()
+            .filter(|m| &m.recipient == open)
+            .map(|m| Entry {
+                from_me: true,
+                subject: m.subject.clone(),
+                body: m.body.clone(),
+                at: m.queued_at,
+                delivered: m.sent == Some(true),
+            });
+        self.thread.extend(received.chain(sent));
+        self.thread.sort_by_key(|entry| entry.at);
+        if self.message.is_some_and(|m| m >= self.thread.len()) {
+            self.message = None;
+        }
+    }
+
+    fn display_name<'a>(&'a self, username: &'a str) -> &'a str {
+        self.data
+            .friends
+            .iter()
+            .find(|f| f.username == username)
+            .map(|f| f.display_name())
+            .unwrap_or(username)
+    }
+
+    fn connectivity(&self, username: &str, now: NaiveDateTime) -> Connectivity {
+        let health = self.data.health.iter().find(|h| h.username == username);
+        Connectivity::of(health, now)
+    }
+
+    pub fn draw(&self, frame: &mut Frame) {
+        let [main, status] =
+            Layout::vertical([Constraint::Min(0), Constraint::Length(2)]).areas(frame.area());
+        let [conversations, middle, friends] = Layout::horizontal([
+            Constraint::Percentage(25),
+            Constraint::Percentage(50),
+            Constraint::Percentage(25),
+        ])
+        .areas(main);
+        let [messages, compose] =
+            Layout::vertical([Constraint::Min(0), Constraint::Length(4)]).areas(middle);
+
+        let now = chrono::Utc::now().naive_utc();
+        self.draw_conversations(frame, conversations);
+        self.draw_messages(frame, messages);
+        self.draw_compose(frame, compose);
+        self.draw_friends(frame, friends, now);
+        self.draw_status(frame, status, now);
+    }
+
+    fn pane(&self, title: String, focus: Focus) -> Block<'static> {
+        let block = Block::bordered().title(title);
+        match self.focus == focus {
+            true => block.border_style(Style::new().fg(Color::Cyan)),
+            false => block,
+        }
+    }
+
+    fn draw_conversations(&self, frame: &mut Frame, area: Rect) {
+        let items: Vec<ListItem> = self
+            .conversations
+            .iter()
+            .map(|c| {
+                let mut line = vec![Span::raw(c.name.clone())];
+                let unread = self.unread(&c.username);
+                if unread > 0 {
+                    line.push(Span::raw(format!(" ({})", unread)).bold().fg(Color::Yellow));
+                }
+                ListItem::new(Line::from(line))
+            })
+            .collect();
+        let list = List::new(items)
+            .block(self.pane(" Conversations ".into(), Focus::Conversations))
+            .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        let mut state = ListState::default().with_selected(self.selected_conversation());
+        frame.render_stateful_widget(list, area, &mut state);
+    }
+
+    fn draw_messages(&self, frame: &mut Frame, area: Rect) {
+        let title = match &self.open {
+            Some(open) => format!(" {} ", self.display_name(open)),
+            None => " Messages ".into(),
+        };
+        let block = self.pane(title, Focus::Messages);
+        if self.thread.is_empty() {
+            let hint = match self.open {
+                Some(_) => "No messages yet, press c to write one.",
+                None => "Add friends with the plain CLI to start a conversation.",
+            };
+            frame.render_widget(Paragraph::new(hint).dark_gray().block(block), area);
+            return;
+        }
+
+        let them = self
+            .open
+            .as_deref()
+            .map(|o| self.display_name(o))
+            .unwrap_or("");
+        let items: Vec<ListItem> = self
+            .thread
+            .iter()
+            .map(|entry| {
+                let at = entry
+                    .at
+                    .map(|at| at.format("%m-%d %H:%M").to_string())
+                    .unwrap_or_default();
+                let (who, color) = match entry.from_me {
+                    true => ("you", Color::Blue),
+                    false => (them, Color::Green),
+                };
+                let mut header = vec![
+                    Span::raw(at).dark_gray(),
+                    Span::raw(" "),
+                    Span::raw(who.to_string()).fg(color).bold(),
+                    Span::raw(": "),
+                    Span::raw(entry.subject.clone()).bold(),
+                ];
+                if !entry.delivered {
+                    header.push(Span::raw(" (queued)").dark_gray());
+                }
+                let mut lines = vec![Line::from(header)];
+                lines.extend(entry.body.lines().map(|l| Line::raw(format!("  {}", l))));
+                ListItem::new(lines)
+            })
+            .collect();
+        let selected = self.message.unwrap_or(self.thread.len() - 1);
+        let mut list = List::new(items).block(block);
+        if self.focus == Focus::Messages {
+            list = list.highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        }
+        let mut state = ListState::default().with_selected(Some(selected));
+        frame.render_stateful_widget(list, area, &mut state);
+    }
+
+    fn draw_compose(&self, frame: &mut Frame, area: Rect) {
+        let title = match &self.open {
+            Some(open) => format!(" To {} ", self.display_name(open)),
+            None => " Compose ".into(),
+        };
+        let block = self.pane(title, Focus::Compose);
+        let label = |field: Field, text: &'static str| match self.focus == Focus::Compose
+            && self.field == field
+        {
+            true => Span::raw(text).cyan(),
+            false => Span::raw(text).dark_gray(),
+        };
+        let text = vec![
+            Line::from(vec![
+                label(Field::Subject, "Subject: "),
+                Span::raw(self.subject.clone()),
+            ]),
+            Line::from(vec![
+                label(Field::Body, "Message: "),
+                Span::raw(self.body.clone()),
+            ]),
+        ];
+        frame.render_widget(Paragraph::new(text).block(block), area);
+
+        if self.focus == Focus::Compose {
+            let (row, text) = match self.field {
+                Field::Subject => (0, &self.subject),
+                Field::Body => (1, &self.body),
+            };
+            let column = "Subject: ".len() + text.chars().count();
+            let x = (area.x + 1 + column as u16).min(area.right().saturating_sub(2));
+            frame.set_cursor_position(Position::new(x, area.y + 1 + row));
+        }
+    }
+
+    fn draw_friends(&self, frame: &mut Frame, area: Rect, now: NaiveDateTime) {
+        let items: Vec<ListItem> = self
+            .data
+            .friends
+            .iter()
+            .map(|friend| {
+                let state = match friend.status.status_enum() {
+                    FriendRequestStatus::Accepted => {
+                        let (label, color) = self.connectivity(&friend.username, now).label();
+                        Span::raw(label).fg(color)
+                    }
+                    FriendRequestStatus::InviteReceived => {
+                        Span::raw("invite: a/r").fg(Color::Yellow)
+                    }
+                    _ => Span::raw(friend.status.status_str()).dark_gray(),
+                };
+                ListItem::new(Line::from(vec![
+                    Span::raw(friend.display_name().to_string()),
+                    Span::raw("  "),
+                    state,
+                ]))
+            })
+            .collect();
+        let mut list = List::new(items).block(self.pane(" Friends ".into(), Focus::Friends));
+        if self.focus == Focus::Friends {
+            list = list.highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        }
+        let selected = (!self.data.friends.is_empty()).then_some(self.friend);
+        let mut state = ListState::default().with_selected(selected);
+        frame.render_stateful_widget(list, area, &mut state);
+    }
+
+    fn draw_status(&self, frame: &mut Frame, area: Rect, now: NaiveDateTime) {
+        let accepted: Vec<&Friend> = self
+            .data
+            .friends
+            .iter()
+            .filter(|f| f.status.status_enum() == FriendRequestStatus::Accepted)
+            .collect();
+        let online = accepted
+            .iter()
+            .filter(|f| self.connectivity(&f.username, now) == Connectivity::Online)
+            .count();
+        let offline: Vec<&str> = accepted
+            .iter()
+            .filter(|f| {
+                matches!(
+                    self.connectivity(&f.username, now),
+                    Connectivity::Offline | Connectivity::Retrying
+                )
+            })
+            .map(|f| f.display_name())
+            .collect();
+
+        let me = match &self.data.me {
+            Some(me) => format!(" {} @ {} ", me.username, me.address),
+            None => " ".into(),
+        };
+        let mut status = vec![
+            Span::raw(me).reversed(),
+            Span::raw(format!(" {}/{} peers online", online, accepted.len())),
+        ];
+        if !offline.is_empty() {
+            status.push(Span::raw(format!(", unreachable: {}", offline.join(", "))).red());
+        }
+        if let Some(notice) = &self.notice {
+            status.push(Span::raw(format!(" | {}", notice)).yellow());
+        }
+
+        let keys = match self.focus {
+            Focus::Compose => "Tab subject/message  Enter next/send  Esc back",
+            Focus::Friends => {
+                "Tab pane  ↑↓ move  Enter open  a/r accept/reject invite  s sync  q quit"
+            }
+            _ => "Tab pane  ↑↓ move  Enter open  c compose  s sync  q quit",
+        };
+        let text = vec![Line::from(status), Line::raw(keys).dark_gray()];
+        frame.render_widget(Paragraph::new(text).wrap(Wrap { trim: false }), area);
+    }
+}
+
+// Moves `current` by `step` within `len` items, None when there are none
+fn step_index(current: usize, step: isize, len: usize) -> Option<usize> {
+    (len > 0).then(|| current.saturating_add_signed(step).min(len - 1))
+}
+
+// Puts the terminal back when the UI stops, also when its future is dropped
+struct TerminalGuard;
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        ratatui::restore();
+    }
+}
+
+// Runs the UI until the user quits
+pub async fn run<S: MessageStore + FriendStore>(
+    store: &S,
+    wakeup: &Wakeup,
+    mut events: Receiver<Event>,
+) -> io::Result<()> {
+    let mut app = App::new();
+    refresh(store, &mut app).await;
+
+    let mut terminal = ratatui::try_init()?;
+    let _guard = TerminalGuard;
+
+    // terminal reads block, they get a thread of their own
+    let (tx, mut input) = mpsc::channel(16);
+    std::thread::spawn(move || read_terminal(tx));
+
+    let mut ticks = tokio::time::interval(REFRESH_INTERVAL);
+    let mut events_open = true;
+    loop {
+        terminal.draw(|frame| app.draw(frame))?;
+
+        let reload = tokio::select! {
+            read = input.recv() => match read {
+                Some(Ok(event::Event::Key(key))) => match app.handle_key(key) {
+                    Some(Action::Quit) => break,
+                    Some(action) => {
+                        perform(store, wakeup, &mut app, action).await;
+                        true
+                    }
+                    None => false,
+                },
+                // resizes only need the redraw
+                Some(Ok(_)) => false,
+                Some(Err(e)) => return Err(e),
+                None => break,
+            },
+            received = events.recv(), if events_open => match received {
+                Ok(event) => {
+                    app.on_event(&event);
+                    true
+                }
+                Err(RecvError::Lagged(_)) => true,
+                Err(RecvError::Closed) => {
+                    events_open = false;
+                    false
+                }
+            },
+            _ = ticks.tick() => true,
+        };
+        if reload {
+            refresh(store, &mut app).await;
+        }
+    }
+    Ok(())
+}
+
+async fn refresh<S: MessageStore + FriendStore>(store: &S, app: &mut App) {
+    match Snapshot::load(store).await {
+        Ok(data) => app.update(data),
+        Err(e) => app.set_notice(format!("Failed to read the database: {}", e)),
+    }
+}
+
+async fn perform<S: MessageStore + FriendStore>(
+    store: &S,
+    wakeup: &Wakeup,
+    app: &mut App,
+    action: Action,
+) {
+    match action {
+        Action::Quit => {}
+        Action::Sync => {
+            wakeup.sync_now();
+            app.set_notice("Syncing with friends now");
+        }
+        // friends behind a relay get theirs deposited by the message fetcher
+        Action::Send(message) => match store.queue_message(&message).await {
+            Ok(_) => {
+                wakeup.wake_message_fetcher();
+                app.set_notice(format!("Message queued for {}", message.send_to));
+            }
+            Err(e) => app.set_notice(format!("Error queuing message: {}", e)),
+        },
+        Action::Decide { id, accept } => match store.invite_decision(id, accept).await {
+            Ok(_) => {
+                // an accepted friend is also someone new to fetch from
+                wakeup.sync_now();
+                app.set_notice(match accept {
+                    true => "Invite accepted",
+                    false => "Invite rejected",
+                });
+            }
+            Err(e) => app.set_notice(format!("Error answering the invite: {}", e)),
+        },
+    }
+}
+
+// Forwards terminal input until the UI stops listening
+fn read_terminal(tx: mpsc::Sender<io::Result<event::Event>>) {
+    loop {
+        match event::poll(Duration::from_millis(250)) {
+            Ok(true) => {
+                if tx.blocking_send(event::read()).is_err() {
+                    break;
+                }
+            }
+            Ok(false) if tx.is_closed() => break,
+            Ok(false) => {}
+            Err(e) => {
+                let _ = tx.blocking_send(Err(e));
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use super::*;
+use ratatui::Terminal;
+use ratatui::backend::TestBackend;
+
+fn at(minute: u32) -> Option<NaiveDateTime> {
+    chrono::NaiveDate::from_ymd_opt(2026, 10, 19).and_then(|d| d.and_hms_opt(12, minute, 0))
+}
+
+fn friend(id: i64, username: &str, status: i64) -> Friend {
+    Friend {
+        id,
+        username: username.into(),
+        address: format!("{}.test:8080", username),
+        status,
+        added_at: None,
+        peer_key: None,
+        intro: None,
+        fingerprint: None,
+        invite_token: None,
+        relay_address: None,
+        nickname: None,
+        notes: None,
+    }
+}
+
+fn health(username: &str, failures: i64) -> PeerHealth {
+    PeerHealth {
+        username: username.into(),
+        consecutive_failures: failures,
+        last_error: None,
+        last_success_at: at(0),
+        last_failure_at: None,
+        next_attempt_at: None,
+    }
+}
+
+fn snapshot() -> Snapshot {
+    Snapshot {
+        me: Some(User {
+            id: 0,
+            username: "alice".into(),
+            address: "alice.test:8080".into(),
+            peer_key: None,
+        }),
+        friends: vec![
+            friend(1, "bob", 2),
+            friend(2, "carol", 2),
+            friend(3, "dave", 1),
+        ],
+        inbox: vec![InboxMessage {
+            id: 1,
+            sender: "carol".into(),
+            subject: "lunch".into(),
+            message: "noon?".into(),
+            received_at: at(5),
+        }],
+        outbound: vec![Outgoing {
+            id: 1,
+            sender: "alice".into(),
+            recipient: "carol".into(),
+            recipient_address: "carol.test:8080".into(),
+            subject: "re: lunch".into(),
+            body: "sure".into(),
+            queued_at: at(6),
+            sent: Some(false),
+        }],
+        health: vec![health("bob", 0), health("carol", 3)],
+    }
+}
+
+fn press(app: &mut App, code: KeyCode) -> Option<Action> {
+    app.handle_key(KeyEvent::new(code, KeyModifiers::NONE))
+}
+
+fn type_text(app: &mut App, text: &str) {
+    for c in text.chars() {
+        assert_eq!(press(app, KeyCode::Char(c)), None);
+    }
+}
+
+fn render(app: &App) -> String {
+    let mut terminal = Terminal::new(TestBackend::new(120, 20)).unwrap();
+    terminal.draw(|frame| app.draw(frame)).unwrap();
+    let buffer = terminal.backend().buffer();
+    (0..buffer.area.height)
+        .map(|y| {
+            (0..buffer.area.width)
+                .map(|x| buffer[(x, y)].symbol())
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[test]
+fn test_navigate_and_compose() {
+    let mut app = App::new();
+    app.update(snapshot());
+    // the latest conversation opens first, invites aren't conversations
+    assert_eq!(app.open_conversation(), Some("carol"));
+    press(&mut app, KeyCode::Down);
+    assert_eq!(app.open_conversation(), Some("bob"));
+    press(&mut app, KeyCode::Down);
+    assert_eq!(app.open_conversation(), Some("bob"));
+    press(&mut app, KeyCode::Up);
+    assert_eq!(app.open_conversation(), Some("carol"));
+
+    // the open conversation stays open when the list reorders
+    let mut data = snapshot();
+    data.inbox[0].sender = "bob".into();
+    data.inbox[0].received_at = at(30);
+    app.update(data);
+    assert_eq!(app.open_conversation(), Some("carol"));
+
+    assert_eq!(press(&mut app, KeyCode::Char('c')), None);
+    assert_eq!(app.focus(), Focus::Compose);
+    type_text(&mut app, "hey");
+    press(&mut app, KeyCode::Enter);
+    // q is just a letter while writing
+    type_text(&mut app, "quick one");
+    assert_eq!(
+        press(&mut app, KeyCode::Enter),
+        Some(Action::Send(OutgoingMessage {
+            send_to: "carol".into(),
+            subject: "hey".into(),
+            content: "quick one".into(),
+        }))
+    );
+    assert_eq!(app.focus(), Focus::Messages);
+
+    // an empty message isn't sent
+    press(&mut app, KeyCode::Enter);
+    press(&mut app, KeyCode::Tab);
+    assert_eq!(press(&mut app, KeyCode::Enter), None);
+    press(&mut app, KeyCode::Esc);
+
+    // answering the invite from the friends pane
+    press(&mut app, KeyCode::Tab);
+    assert_eq!(app.focus(), Focus::Friends);
+    assert_eq!(press(&mut app, KeyCode::Char('a')), None);
+    press(&mut app, KeyCode::Down);
+    press(&mut app, KeyCode::Down);
+    assert_eq!(
+        press(&mut app, KeyCode::Char('r')),
+        Some(Action::Decide {
+            id: 3,
+            accept: false
+        })
+    );
+    press(&mut app, KeyCode::Up);
+    press(&mut app, KeyCode::Up);
+    press(&mut app, KeyCode::Enter);
+    assert_eq!(app.open_conversation(), Some("bob"));
+    assert_eq!(app.focus(), Focus::Messages);
+
+    assert_eq!(press(&mut app, KeyCode::Char('s')), Some(Action::Sync));
+    assert_eq!(press(&mut app, KeyCode::Char('q')), Some(Action::Quit));
+    assert_eq!(
+        app.handle_key(KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL)),
+        Some(Action::Quit)
+    );
+}
+
+#[test]
+fn test_events_mark_unread_and_show_up() {
+    let mut app = App::new();
+    app.update(snapshot());
+    app.on_event(&Event::NewMessage {
+        sender: "bob".into(),
+        subject: "hi".into(),
+    });
+    // the open conversation has nothing unread
+    app.on_event(&Event::NewMessage {
+        sender: "carol".into(),
+        subject: "more".into(),
+    });
+    assert_eq!(app.unread("bob"), 1);
+    assert_eq!(app.unread("carol"), 0);
+    assert_eq!(app.notice(), Some("New message from carol: more"));
+
+    press(&mut app, KeyCode::Down);
+    assert_eq!(app.open_conversation(), Some("bob"));
+    assert_eq!(app.unread("bob"), 0);
+}
+
+#[test]
+fn test_screen_shows_panes_and_connectivity() {
+    let mut app = App::new();
+    app.update(snapshot());
+    let screen = render(&app);
+
+    for text in [
+        "Conversations",
+        "To carol",
+        "carol: lunch",
+        "you: re: lunch (queued)",
+        "noon?",
+        "bob  online",
+        "carol  retrying",
+        "dave  invite: a/r",
+        "alice @ alice.test:8080",
+        "1/2 peers online, unreachable: carol",
+    ] {
+        assert!(screen.contains(text), "{:?} missing from\n{}", text, screen);
+    }
+}
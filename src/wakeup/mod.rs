@@ -0,0 +1,106 @@
+// Fetcher wakeups
+// The fetchers wait up to their interval between cycles. Anything that gives
+// them work (a new invite, a decision, a friend accepting, a manual sync)
+// wakes them through a Wakeup handle instead, so the interval is only an
+// upper bound. Wakeups while a cycle runs are kept, several become one.
+
+use crate::events::Event;
+use crate::shutdown::ShutdownSignal;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::sync::broadcast::{Receiver, error::RecvError};
+
+#[cfg(test)]
+mod tests;
+
+#[derive(Debug, Clone, Default)]
+pub struct Wakeup {
+    friends: Arc<Notify>,
+    messages: Arc<Notify>,
+}
+
+// What one fetcher waits on between cycles
+#[derive(Debug)]
+pub struct Pause {
+    wake: Arc<Notify>,
+    shutdown: ShutdownSignal,
+}
+
+impl Wakeup {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Friend updates are waiting to be sent
+    pub fn wake_friend_fetcher(&self) {
+        self.friends.notify_one();
+    }
+
+    // Friends to fetch from or messages to deposit at a relay
+    pub fn wake_message_fetcher(&self) {
+        self.messages.notify_one();
+    }
+
+    pub fn sync_now(&self) {
+        self.wake_friend_fetcher();
+        self.wake_message_fetcher();
+    }
+
+    pub fn friend_fetcher(&self, shutdown: ShutdownSignal) -> Pause {
+        Pause {
+            wake: self.friends.clone(),
+            shutdown,
+        }
+    }
+
+    pub fn message_fetcher(&self, shutdown: ShutdownSignal) -> Pause {
+        Pause {
+            wake: self.messages.clone(),
+            shutdown,
+        }
+    }
+
+    // Peers change our state through the server too. A friend accepting
+    // gives the message fetcher someone new, and an invite accepted by its
+    // token has our acceptance waiting to go out.
+    pub async fn follow_events(self, mut rx: Receiver<Event>, mut shutdown: ShutdownSignal) {
+        loop {
+            let event = tokio::select! {
+                received = rx.recv() => received,
+                _ = shutdown.wait() => break,
+            };
+            match event {
+                Ok(Event::InviteAccepted { .. }) => self.sync_now(),
+                Ok(_) => {}
+                // something may have been missed, a spare cycle costs little
+                Err(RecvError::Lagged(_)) => self.sync_now(),
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+}
+
+// Why a pause ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resume {
+    Elapsed,
+    Woken,
+    Shutdown,
+}
+
+impl Pause {
+    // Waits `duration` unless woken or shut down first
+    pub async fn wait(&mut self, duration: Duration) -> Resume {
+        tokio::select! {
+            stop = self.shutdown.sleep(duration) => match stop {
+                true => Resume::Shutdown,
+                false => Resume::Elapsed,
+            },
+            _ = self.wake.notified() => match self.shutdown.is_triggered() {
+                true => Resume::Shutdown,
+                false => Resume::Woken,
+            },
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use super::*;
+use crate::events::EventBus;
+use crate::shutdown::Shutdown;
+use tokio::time::Instant;
+
+#[tokio::test]
+async fn test_wakeups_cut_the_pause_short() {
+    let shutdown = Shutdown::new();
+    let wakeup = Wakeup::new();
+    let mut friends = wakeup.friend_fetcher(shutdown.subscribe());
+    let mut messages = wakeup.message_fetcher(shutdown.subscribe());
+
+    // the interval still runs out on its own
+    assert_eq!(
+        friends.wait(Duration::from_millis(10)).await,
+        Resume::Elapsed
+    );
+
+    // a wakeup before the pause is kept, and several make one
+    let started = Instant::now();
+    wakeup.wake_friend_fetcher();
+    wakeup.wake_friend_fetcher();
+    assert_eq!(friends.wait(Duration::from_secs(60)).await, Resume::Woken);
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert_eq!(
+        friends.wait(Duration::from_millis(10)).await,
+        Resume::Elapsed
+    );
+
+    // only the fetcher that was woken wakes
+    wakeup.wake_message_fetcher();
+    let started = Instant::now();
+    assert_eq!(
+        friends.wait(Duration::from_millis(50)).await,
+        Resume::Elapsed
+    );
+    assert!(started.elapsed() >= Duration::from_millis(50));
+    assert_eq!(messages.wait(Duration::from_secs(60)).await, Resume::Woken);
+
+    let sleeper = tokio::spawn(async move { messages.wait(Duration::from_secs(60)).await });
+    shutdown.trigger();
+    assert_eq!(sleeper.await.unwrap(), Resume::Shutdown);
+}
+
+#[tokio::test]
+async fn test_accepted_invites_wake_both_fetchers() {
+    let shutdown = Shutdown::new();
+    let events = EventBus::new();
+    let wakeup = Wakeup::new();
+    let mut friends = wakeup.friend_fetcher(shutdown.subscribe());
+    let mut messages = wakeup.message_fetcher(shutdown.subscribe());
+    let follower = tokio::spawn(
+        wakeup
+            .clone()
+            .follow_events(events.subscribe(), shutdown.subscribe()),
+    );
+
+    events.publish(Event::InviteReceived {
+        username: "bob".into(),
+        address: "bob.test:8080".into(),
+        intro: None,
+    });
+    events.publish(Event::InviteAccepted {
+        username: "alice".into(),
+    });
+    let started = Instant::now();
+    assert_eq!(friends.wait(Duration::from_secs(60)).await, Resume::Woken);
+    assert_eq!(messages.wait(Duration::from_secs(60)).await, Resume::Woken);
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    shutdown.trigger();
+    follower.await.unwrap();
+}